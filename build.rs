@@ -0,0 +1,19 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let commit = Command::new("git")
+        .arg("rev-parse").arg("--short").arg("HEAD")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let built_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    println!("cargo:rustc-env=HOMEBACK_COMMIT={}", commit);
+    println!("cargo:rustc-env=HOMEBACK_BUILT_AT={}", built_at);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
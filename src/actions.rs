@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use itertools::Itertools;
+use log::{info, error};
+use serde::Deserialize;
+use serde_json::Value;
+use crate::audio;
+use crate::dvbc::Channel;
+use crate::process::VideoPlayerArgs;
+use crate::state::AppState;
+
+const VOLUME_STEP: u32 = 5;
+
+// things a button on a remote can do
+// the favorites preview is a page of the frontend, HomeBack has no way to open it on the TV, so there is no action for it
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    ZapNext,
+    ZapPrevious,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    Stop,
+}
+
+impl Action {
+    // holding the button down should only repeat these
+    pub fn repeats(&self) -> bool {
+        matches!(self, Action::VolumeUp | Action::VolumeDown)
+    }
+}
+
+// parses a button mapping like "KEY_CHANNELUP=zap_next,KEY_STOP=stop", invalid entries are logged and skipped
+pub fn parse_mapping(name: &str, mapping: &str) -> HashMap<String, Action> {
+    mapping.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let parsed = entry.split_once('=')
+                .and_then(|(button, action)| Some((button.trim().to_string(), serde_json::from_value(Value::String(action.trim().to_string())).ok()?)));
+            if parsed.is_none() {
+                error!("could not parse {} entry: {}", name, entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+// blocks, so don't call this from the async runtime
+pub fn perform(state: &AppState, action: Action) {
+    info!("performing {:?}", action);
+    let result = match action {
+        Action::ZapNext => zap(state, 1),
+        Action::ZapPrevious => zap(state, -1),
+        Action::VolumeUp => audio::get_volume().and_then(|volume| audio::set_volume(volume + VOLUME_STEP)).map(|_| ()),
+        Action::VolumeDown => audio::get_volume().and_then(|volume| audio::set_volume(volume.saturating_sub(VOLUME_STEP))).map(|_| ()),
+        Action::Mute => audio::is_muted().and_then(|muted| audio::set_muted(!muted)).map(|_| ()),
+        Action::Stop => state.video_player.stop(),
+    };
+    if let Err(error) = result {
+        error!("could not perform {:?}: {}", action, error);
+    }
+}
+
+pub fn play_dvbc(state: &AppState, channel_name: &str) -> std::io::Result<()> {
+    let channels = state.dvbc.get_channels().ok_or_else(|| std::io::Error::other("no DvbC channels available"))?;
+    let channel = channels.tv.iter().chain(&channels.radio).find(|channel| channel.name == channel_name)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("there is no channel {}", channel_name)))?;
+    start_channel(state, channel)
+}
+
+// switches to the neighbouring tv channel, or to the first one if no channel is playing
+fn zap(state: &AppState, offset: isize) -> std::io::Result<()> {
+    let channels = match state.dvbc.get_channels() {
+        Some(channels) if !channels.tv.is_empty() => channels,
+        _ => return Err(std::io::Error::other("no DvbC channels available")),
+    };
+
+    let current = state.video_player.running().and_then(|args| match &*args {
+        VideoPlayerArgs::DvbC(channel) => channels.tv.iter().position(|tv| tv.name == channel.name),
+        _ => None,
+    });
+    let next = match current {
+        Some(index) => (index as isize + offset).rem_euclid(channels.tv.len() as isize) as usize,
+        None => 0,
+    };
+    start_channel(state, &channels.tv[next])
+}
+
+fn start_channel(state: &AppState, channel: &Channel) -> std::io::Result<()> {
+    state.player_tuner.start(&channel.name, || state.video_player.start(VideoPlayerArgs::DvbC(channel.clone())))
+        .map_err(|busy| std::io::Error::other(format!("no free tuner, used by {}", busy.occupied.iter().map(|tuner| format!("{} of {}", tuner.purpose, tuner.channel)).join(", "))))??;
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::env;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use log::{info, error};
+use reqwest::blocking::Client;
+use serde_json::json;
+use crate::download;
+use crate::events::{Event, Events};
+
+// Sonarr or Radarr, which import the finished downloads of their category folder
+struct App {
+    name: &'static str,
+    url: String,
+    api_key: String,
+    category: String,  // folder in the DOWNLOAD_FOLDER
+    command: &'static str,
+}
+
+impl App {
+
+    fn from_env() -> Vec<App> {
+        let mut apps = Vec::new();
+        for (name, prefix, category, command) in [("Sonarr", "SONARR", "tv", "DownloadedEpisodesScan"), ("Radarr", "RADARR", "movies", "DownloadedMoviesScan")] {
+            if let (Ok(url), Ok(api_key)) = (env::var(format!("{}_URL", prefix)), env::var(format!("{}_API_KEY", prefix))) {
+                let category = env::var(format!("{}_CATEGORY", prefix)).unwrap_or(category.to_string());
+                apps.push(App { name, url: url.trim_end_matches('/').to_string(), api_key, category, command });
+            }
+        }
+        apps
+    }
+
+    fn wants(&self, path: &str) -> bool {
+        Path::new(path).starts_with(&self.category)
+    }
+
+    fn import(&self, client: &Client, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // the apps have to see the file under the same path, so they should run on this machine or share the folder
+        let location = download::download_location(path)?;
+        client.post(format!("{}/api/v3/command", self.url))
+            .header("X-Api-Key", &self.api_key)
+            .json(&json!({ "name": self.command, "path": location, "importMode": "Move" }))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+// hands finished downloads in the category folders over to Sonarr and Radarr
+pub fn start(events: &Events) {
+    let apps = App::from_env();
+    if apps.is_empty() {
+        return;
+    }
+    for app in &apps {
+        info!("Handing downloads in {}/ over to {}", app.category, app.name);
+    }
+
+    let receiver = events.subscribe();
+    thread::spawn(move || {
+        let client = Client::builder().timeout(Duration::from_secs(10)).build().unwrap();
+        for event in receiver {
+            if let Event::DownloadFinished { path, .. } = &*event {
+                for app in apps.iter().filter(|app| app.wants(path)) {
+                    info!("asking {} to import {}", app.name, path);
+                    if let Err(error) = app.import(&client, path) {
+                        error!("could not hand {} over to {}: {}", path, app.name, error);
+                    }
+                }
+            }
+        }
+    });
+}
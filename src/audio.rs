@@ -0,0 +1,82 @@
+use std::io;
+use std::process::Stdio;
+use std::str;
+use log::info;
+use regex::Regex;
+use crate::tools;
+
+// pactl talks to PulseAudio as well as to PipeWire through pipewire-pulse
+const SINK: &str = "@DEFAULT_SINK@";
+pub const MAX_VOLUME: u32 = 150;
+
+fn pactl(args: &[&str]) -> io::Result<String> {
+    let output = tools::command("pactl")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("pactl {} failed: {}", args.join(" "), stderr.trim())));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn invalid_output(output: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("could not parse output of pactl: {}", output.trim()))
+}
+
+// volume of the default sink in percent, the average over all channels
+pub fn get_volume() -> io::Result<u32> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"(\d+)%").unwrap();
+    }
+
+    // looks like "Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB"
+    let output = pactl(&["get-sink-volume", SINK])?;
+    let volumes: Vec<u32> = RE.captures_iter(output.lines().next().unwrap_or(""))
+        .filter_map(|capture| capture[1].parse().ok())
+        .collect();
+
+    if volumes.is_empty() {
+        return Err(invalid_output(&output));
+    }
+    Ok(volumes.iter().sum::<u32>() / volumes.len() as u32)
+}
+
+pub fn set_volume(volume: u32) -> io::Result<u32> {
+    let volume = volume.min(MAX_VOLUME);
+    info!("setting volume to {}%", volume);
+    pactl(&["set-sink-volume", SINK, &format!("{}%", volume)])?;
+    Ok(volume)
+}
+
+pub fn is_muted() -> io::Result<bool> {
+    // looks like "Mute: no"
+    let output = pactl(&["get-sink-mute", SINK])?;
+    match output.trim().strip_prefix("Mute:").map(str::trim) {
+        Some("yes") => Ok(true),
+        Some("no") => Ok(false),
+        _ => Err(invalid_output(&output)),
+    }
+}
+
+pub fn set_muted(muted: bool) -> io::Result<bool> {
+    info!("{} audio", if muted { "muting" } else { "unmuting" });
+    pactl(&["set-sink-mute", SINK, if muted { "1" } else { "0" }])?;
+    Ok(muted)
+}
+
+// librespot closes its stream when it pauses, so an uncorked stream of the program is one that plays
+pub fn is_playing(binary: &str) -> io::Result<bool> {
+    Ok(plays(&pactl(&["list", "sink-inputs"])?, binary))
+}
+
+fn plays(sink_inputs: &str, binary: &str) -> bool {
+    let binary = format!("application.process.binary = \"{}\"", binary);
+    sink_inputs.split("Sink Input #")
+        .any(|input| input.lines().any(|line| line.trim() == binary) && input.lines().any(|line| line.trim() == "Corked: no"))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,19 @@
+use super::*;
+
+fn sink_input(binary: &str, corked: &str) -> String {
+    format!("Sink Input #42\n\tDriver: protocol-native.c\n\tCorked: {}\n\tMute: no\n\tProperties:\n\t\tapplication.name = \"{}\"\n\t\tapplication.process.binary = \"{}\"\n", corked, binary, binary)
+}
+
+#[test]
+fn an_uncorked_stream_of_the_program_plays() {
+    let inputs = format!("{}\n{}", sink_input("mpv", "no"), sink_input("librespot", "no"));
+
+    assert!(plays(&inputs, "librespot"));
+}
+
+#[test]
+fn a_corked_or_missing_stream_does_not_play() {
+    assert!(!plays(&sink_input("librespot", "yes"), "librespot"));
+    assert!(!plays(&sink_input("mpv", "no"), "librespot"));
+    assert!(!plays("", "librespot"));
+}
@@ -0,0 +1,48 @@
+use std::env;
+use actix_web::{http, HttpRequest, HttpResponse};
+
+// the ADMIN_TOKEN, without it the endpoints that check it stay open like the rest of the API
+pub struct Auth {
+    admin_token: Option<String>,
+}
+
+impl Auth {
+
+    pub fn from_env() -> Self {
+        Self::new(env::var("ADMIN_TOKEN").ok())
+    }
+
+    pub fn new(admin_token: Option<String>) -> Self {
+        Self { admin_token: admin_token.filter(|token| !token.is_empty()) }
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.admin_token.is_some()
+    }
+
+    // whether the request has `Authorization: Bearer <ADMIN_TOKEN>`, always false without ADMIN_TOKEN
+    pub fn is_admin(&self, request: &HttpRequest) -> bool {
+        let header = request.headers().get(http::header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+        self.admin_token.as_deref().is_some_and(|token| is_token(header, token))
+    }
+
+    // for the endpoints that change something, open to everyone unless ADMIN_TOKEN is set
+    pub fn is_allowed(&self, request: &HttpRequest) -> bool {
+        !self.is_configured() || self.is_admin(request)
+    }
+}
+
+pub fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((http::header::WWW_AUTHENTICATE, "Bearer"))
+        .json(serde_json::json!({ "error": "unauthorized", "message": "needs the ADMIN_TOKEN as bearer token" }))
+}
+
+fn is_token(header: Option<&str>, token: &str) -> bool {
+    let Some(sent) = header.and_then(|header| header.strip_prefix("Bearer ")) else { return false };
+    // compares every byte, so the time it takes doesn't tell how much of the token was right
+    sent.len() == token.len() && sent.bytes().zip(token.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests;
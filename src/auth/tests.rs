@@ -0,0 +1,16 @@
+use super::*;
+
+#[test]
+fn the_token_has_to_be_sent_as_bearer() {
+    assert!(is_token(Some("Bearer s3cret"), "s3cret"));
+    assert!(!is_token(Some("s3cret"), "s3cret"));
+    assert!(!is_token(Some("Basic s3cret"), "s3cret"));
+    assert!(!is_token(None, "s3cret"));
+}
+
+#[test]
+fn a_different_token_is_rejected() {
+    assert!(!is_token(Some("Bearer s3creT"), "s3cret"));
+    assert!(!is_token(Some("Bearer s3cret2"), "s3cret"));
+    assert!(!is_token(Some("Bearer "), "s3cret"));
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{info, warn, error};
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+use crate::actions::{self, Action};
+use crate::process;
+use crate::state::AppState;
+use crate::tools;
+
+lazy_static! {
+    // only one cec-client can use the adapter, so while the remote is monitored all commands go through that one
+    static ref MONITOR: Mutex<Option<Monitor>> = Mutex::new(None);
+}
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the CEC user control codes of the buttons that can be mapped in CEC_BUTTONS
+const BUTTONS: [(&str, u8); 34] = [
+    ("select", 0x00), ("up", 0x01), ("down", 0x02), ("left", 0x03), ("right", 0x04), ("exit", 0x0D),
+    ("0", 0x20), ("1", 0x21), ("2", 0x22), ("3", 0x23), ("4", 0x24), ("5", 0x25), ("6", 0x26), ("7", 0x27), ("8", 0x28), ("9", 0x29),
+    ("channel_up", 0x30), ("channel_down", 0x31), ("previous_channel", 0x32),
+    ("volume_up", 0x41), ("volume_down", 0x42), ("mute", 0x43),
+    ("play", 0x44), ("stop", 0x45), ("pause", 0x46), ("record", 0x47), ("rewind", 0x48), ("fast_forward", 0x49), ("forward", 0x4B), ("backward", 0x4C),
+    ("blue", 0x71), ("red", 0x72), ("green", 0x73), ("yellow", 0x74),
+];
+
+struct Monitor {
+    _process: Child,
+    stdin: ChildStdin,
+    output: Receiver<String>,
+}
+
+const TV: &str = "0";
+pub const MAX_HDMI_PORT: u8 = 15;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Input {
+    HomeBack,  // make the HTPC the active source
+    Hdmi(u8),  // switch to the given HDMI port of the TV
+}
+
+// runs a single command through cec-client and returns its output
+fn cec_client(command: &str) -> io::Result<String> {
+    let mut monitor = MONITOR.lock().unwrap();
+    if let Some(running) = &mut *monitor {
+        match send_to_monitor(running, command) {
+            Ok(output) => return Ok(output),
+            Err(error) => {
+                warn!("cec-client monitoring the remote is gone ({}), no longer listening for buttons", error);
+                process::unregister(running._process.id());
+                *monitor = None;
+            },
+        }
+    }
+    drop(monitor);
+    run_single(command)
+}
+
+fn run_single(command: &str) -> io::Result<String> {
+    let mut child = tools::command("cec-client")
+        .arg("-s")              // single command mode, read commands from stdin and exit
+        .arg("-d").arg("1")     // only log errors
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    child.stdin.take().unwrap().write_all(command.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("cec-client '{}' failed with {}", command, output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// the monitor prints everything in one stream, so this collects what came in shortly after the command
+fn send_to_monitor(monitor: &mut Monitor, command: &str) -> io::Result<String> {
+    while monitor.output.try_recv().is_ok() {} // drop what was printed before
+    writeln!(monitor.stdin, "{}", command)?;
+
+    if !command.starts_with("pow") {
+        return Ok(String::new());
+    }
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    loop {
+        let line = monitor.output.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("no response to '{}' from cec-client", command)))?;
+        if line.contains("power status:") {
+            return Ok(line);
+        }
+    }
+}
+
+pub fn set_power(on: bool) -> io::Result<()> {
+    info!("turning TV {}", if on { "on" } else { "off" });
+    cec_client(&format!("{} {}", if on { "on" } else { "standby" }, TV))?;
+    Ok(())
+}
+
+pub fn is_powered_on() -> io::Result<bool> {
+    // looks like "power status: on", or "standby", "in transition from standby to on", ...
+    let output = cec_client(&format!("pow {}", TV))?;
+    let status = output.lines()
+        .find_map(|line| line.trim().strip_prefix("power status:"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("could not parse output of cec-client: {}", output.trim())))?;
+    Ok(status.trim() == "on")
+}
+
+pub fn set_input(input: &Input) -> io::Result<()> {
+    info!("switching TV input to {:?}", input);
+    let command = match input {
+        Input::HomeBack => "as".to_string(),
+        // broadcast an active source message for physical address n.0.0.0
+        Input::Hdmi(port) => format!("tx 1F:82:{:X}0:00", port),
+    };
+    cec_client(&command)?;
+    Ok(())
+}
+
+// with CEC_AUTO_POWER_ON when a player starts, cec-client is slow so this doesn't wait for it
+pub fn auto_power_on() {
+    std::thread::spawn(|| {
+        if let Err(err) = set_power(true).and_then(|_| set_input(&Input::HomeBack)) {
+            error!("could not turn on TV: {}", err);
+        }
+    });
+}
+
+// starts a cec-client that stays running and performs the actions mapped to the TV remote buttons in CEC_BUTTONS
+pub fn listen(state: Arc<AppState>) {
+    let names = actions::parse_mapping("CEC_BUTTONS", &env::var("CEC_BUTTONS").unwrap_or_default());
+    let buttons: HashMap<u8, Action> = names.into_iter()
+        .filter_map(|(name, action)| match BUTTONS.iter().find(|(button, _)| *button == name) {
+            Some(&(_, code)) => Some((code, action)),
+            None => { error!("unknown CEC button: {}", name); None },
+        })
+        .collect();
+    if buttons.is_empty() {
+        return;
+    }
+
+    let mut process = match tools::command("cec-client")
+        .arg("-d").arg("8") // log the traffic, that's where the key presses show up
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(process) => process,
+        Err(error) => { error!("could not start cec-client to listen for the TV remote: {}", error); return },
+    };
+    info!("Listening for TV remote buttons through CEC");
+    process::register("cec-client", process.id());
+
+    let stdin = process.stdin.take().unwrap();
+    let stdout = process.stdout.take().unwrap();
+    let (sender, output) = mpsc::channel();
+    *MONITOR.lock().unwrap() = Some(Monitor { _process: process, stdin, output });
+
+    thread::spawn(move || {
+        lazy_static! {
+            // looks like "TRAFFIC: [   12345]	>> 01:44:41", 0x44 is the user control pressed opcode followed by the button
+            static ref RE: Regex = Regex::new(r"(?i)>> [0-9a-f]{2}:44:([0-9a-f]{2})").unwrap();
+        }
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            match RE.captures(&line).and_then(|capture| u8::from_str_radix(&capture[1], 16).ok()) {
+                Some(code) => if let Some(&action) = buttons.get(&code) {
+                    actions::perform(&state, action);
+                },
+                None => { let _ = sender.send(line); },
+            }
+        }
+        warn!("cec-client listening for the TV remote stopped");
+    });
+}
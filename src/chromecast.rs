@@ -0,0 +1,73 @@
+use std::io;
+use std::process::Stdio;
+use log::info;
+use serde::Serialize;
+use crate::tools;
+
+pub const MAX_DEVICE_NAME_LENGTH: usize = 100;
+
+#[derive(Serialize)]
+pub struct Device {
+    pub name: String,
+    pub address: String,
+}
+
+// catt finds the devices over mDNS and does the casting
+fn catt(args: &[&str]) -> io::Result<String> {
+    let output = tools::command("catt")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("catt {} failed: {}", args.join(" "), stderr.trim())));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub fn is_device_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_DEVICE_NAME_LENGTH
+}
+
+// the Chromecasts and Google TVs on the network, this takes a few seconds
+pub fn devices() -> io::Result<Vec<Device>> {
+    // looks like "192.168.1.23 - Living Room - Google Inc. Chromecast"
+    Ok(catt(&["scan"])?.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, " - ");
+            let address = parts.next()?.trim();
+            let name = parts.next()?.trim();
+            address.parse::<std::net::IpAddr>().ok()?;
+            Some(Device { name: name.to_string(), address: address.to_string() })
+        })
+        .collect())
+}
+
+pub fn cast(device: &str, title: &str, url: &str) -> io::Result<()> {
+    info!("casting {} to {}", title, device);
+    catt(&["-d", device, "cast", "-t", title, url])?;
+    Ok(())
+}
+
+pub fn stop(device: &str) -> io::Result<()> {
+    info!("stopping {}", device);
+    catt(&["-d", device, "stop"])?;
+    Ok(())
+}
+
+// Chromecasts can't run streamlink, so they get the url of the HLS stream itself
+pub fn twitch_stream_url(stream: &str) -> io::Result<String> {
+    let output = tools::command("streamlink")
+        .arg("--stream-url")
+        .arg(format!("https://twitch.tv/{}", stream))
+        .arg("best")
+        .stdin(Stdio::null())
+        .output()?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        return Err(io::Error::other(format!("could not get stream url of {}: {}", stream, stdout.trim())));
+    }
+    Ok(stdout.trim().to_string())
+}
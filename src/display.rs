@@ -0,0 +1,49 @@
+use std::io;
+use std::process::Stdio;
+use log::info;
+use crate::tools;
+
+// xset talks to the X server given by DISPLAY, the same one firefox and the players open their windows on
+fn xset(args: &[&str]) -> io::Result<String> {
+    let output = tools::command("xset")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("xset {} failed: {}", args.join(" "), stderr.trim())));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub fn is_blanked() -> io::Result<bool> {
+    // looks like "  Monitor is On", but only if DPMS is enabled
+    let output = xset(&["q"])?;
+    Ok(output.lines().any(|line| line.trim() == "Monitor is Off"))
+}
+
+pub fn set_blanked(blanked: bool) -> io::Result<bool> {
+    info!("{} display", if blanked { "blanking" } else { "unblanking" });
+    if blanked {
+        xset(&["dpms", "force", "off"])?;
+    } else {
+        // forcing the monitor on also wakes it from the screensaver
+        xset(&["dpms", "force", "on"])?;
+        xset(&["s", "reset"])?;
+    }
+    Ok(blanked)
+}
+
+// keeps the screensaver and DPMS from turning off the display, e.g. while a video is playing
+pub fn inhibit_screensaver() -> io::Result<()> {
+    info!("inhibiting screensaver");
+    xset(&["s", "off", "-dpms"])?;
+    Ok(())
+}
+
+pub fn allow_screensaver() -> io::Result<()> {
+    info!("allowing screensaver");
+    xset(&["s", "on", "+dpms"])?;
+    Ok(())
+}
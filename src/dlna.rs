@@ -0,0 +1,464 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{info, warn, error};
+use regex::Regex;
+use reqwest::{Method, Url};
+use reqwest::blocking::Client;
+use socket2::{Domain, Protocol, Socket, Type};
+use uuid::Uuid;
+use crate::audio;
+use crate::events::Event;
+use crate::process::VideoPlayerArgs;
+use crate::state::AppState;
+use crate::validation;
+use crate::xml::{xml_element, xml_escape, xml_unescape};
+
+const SSDP_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+const MAX_AGE: u64 = 1800;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(300);
+pub const SUBSCRIPTION_TIMEOUT: u64 = 1800;
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Service {
+    AVTransport,
+    RenderingControl,
+    ConnectionManager,
+}
+
+impl Service {
+    const ALL: [Service; 3] = [Service::AVTransport, Service::RenderingControl, Service::ConnectionManager];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|service| service.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Service::AVTransport       => "AVTransport",
+            Service::RenderingControl  => "RenderingControl",
+            Service::ConnectionManager => "ConnectionManager",
+        }
+    }
+
+    fn urn(&self) -> String {
+        format!("urn:schemas-upnp-org:service:{}:1", self.name())
+    }
+}
+
+// (action, [(argument, direction, state variable)])
+type Action = (&'static str, &'static [(&'static str, &'static str, &'static str)]);
+
+struct Subscription {
+    sid: String,
+    service: Service,
+    callback: String,
+    expires: Instant,
+    seq: u32,
+}
+
+#[derive(Default, PartialEq, Debug)]
+struct Transport {
+    uri: String,
+    title: String,
+}
+
+// makes the player show up as a UPnP media renderer, so phones can cast to it
+pub struct Dlna {
+    udn: String,
+    name: String,
+    base_url: String,
+    transport: Mutex<Transport>,
+    subscriptions: Mutex<Vec<Subscription>>,
+}
+
+impl Dlna {
+
+    pub fn from_env() -> Option<Self> {
+        if !env::var("DLNA_ENABLED").is_ok_and(|value| value == "true") {
+            return None;
+        }
+        // the id has to stay the same over restarts, otherwise control points show the renderer several times
+        let uuid = fs::read_to_string("/etc/machine-id").ok()
+            .and_then(|id| Uuid::parse_str(id.trim()).ok())
+            .unwrap_or_else(Uuid::new_v4);
+        let base_url = env::var("DLNA_BASE_URL").unwrap_or_else(|_| {
+            let port = env::var("ADDR").ok()
+                .and_then(|addrs| addrs.split(',').next().and_then(|addr| addr.trim().parse::<SocketAddr>().ok()))
+                .map_or(23559, |addr| addr.port());
+            format!("http://{}:{}", local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)), port)
+        });
+        Some(Self {
+            udn: format!("uuid:{}", uuid),
+            name: env::var("DLNA_NAME").unwrap_or("HomeBack".to_string()),
+            base_url,
+            transport: Default::default(),
+            subscriptions: Default::default(),
+        })
+    }
+
+    pub fn description(&self) -> String {
+        let services: String = Service::ALL.iter().map(|service| format!(
+            "<service><serviceType>{urn}</serviceType><serviceId>urn:upnp-org:serviceId:{name}</serviceId><SCPDURL>/dlna/{name}.xml</SCPDURL><controlURL>/dlna/control/{name}</controlURL><eventSubURL>/dlna/event/{name}</eventSubURL></service>",
+            urn = service.urn(), name = service.name()
+        )).collect();
+        format!(
+            r#"<?xml version="1.0"?><root xmlns="urn:schemas-upnp-org:device-1-0"><specVersion><major>1</major><minor>0</minor></specVersion><device><deviceType>{}</deviceType><friendlyName>{}</friendlyName><manufacturer>HomeBack</manufacturer><modelName>HomeBack</modelName><UDN>{}</UDN><serviceList>{}</serviceList></device></root>"#,
+            DEVICE_TYPE, xml_escape(&self.name), self.udn, services
+        )
+    }
+
+    pub fn scpd(&self, service: Service) -> String {
+        let actions: &[Action] = match service {
+            Service::AVTransport => &[
+                ("SetAVTransportURI", &[("InstanceID", "in", "A_ARG_TYPE_InstanceID"), ("CurrentURI", "in", "AVTransportURI"), ("CurrentURIMetaData", "in", "AVTransportURIMetaData")]),
+                ("Play", &[("InstanceID", "in", "A_ARG_TYPE_InstanceID"), ("Speed", "in", "TransportPlaySpeed")]),
+                ("Stop", &[("InstanceID", "in", "A_ARG_TYPE_InstanceID")]),
+                ("GetTransportInfo", &[("InstanceID", "in", "A_ARG_TYPE_InstanceID"), ("CurrentTransportState", "out", "TransportState"), ("CurrentTransportStatus", "out", "TransportStatus"), ("CurrentSpeed", "out", "TransportPlaySpeed")]),
+                ("GetMediaInfo", &[("InstanceID", "in", "A_ARG_TYPE_InstanceID"), ("NrTracks", "out", "NumberOfTracks"), ("MediaDuration", "out", "CurrentMediaDuration"), ("CurrentURI", "out", "AVTransportURI"), ("CurrentURIMetaData", "out", "AVTransportURIMetaData")]),
+                ("GetPositionInfo", &[("InstanceID", "in", "A_ARG_TYPE_InstanceID"), ("Track", "out", "CurrentTrack"), ("TrackDuration", "out", "CurrentTrackDuration"), ("TrackURI", "out", "AVTransportURI"), ("RelTime", "out", "RelativeTimePosition"), ("AbsTime", "out", "AbsoluteTimePosition")]),
+            ],
+            Service::RenderingControl => &[
+                ("GetVolume", &[("InstanceID", "in", "A_ARG_TYPE_InstanceID"), ("Channel", "in", "A_ARG_TYPE_Channel"), ("CurrentVolume", "out", "Volume")]),
+                ("SetVolume", &[("InstanceID", "in", "A_ARG_TYPE_InstanceID"), ("Channel", "in", "A_ARG_TYPE_Channel"), ("DesiredVolume", "in", "Volume")]),
+                ("GetMute", &[("InstanceID", "in", "A_ARG_TYPE_InstanceID"), ("Channel", "in", "A_ARG_TYPE_Channel"), ("CurrentMute", "out", "Mute")]),
+                ("SetMute", &[("InstanceID", "in", "A_ARG_TYPE_InstanceID"), ("Channel", "in", "A_ARG_TYPE_Channel"), ("DesiredMute", "in", "Mute")]),
+            ],
+            Service::ConnectionManager => &[
+                ("GetProtocolInfo", &[("Source", "out", "SourceProtocolInfo"), ("Sink", "out", "SinkProtocolInfo")]),
+                ("GetCurrentConnectionIDs", &[("ConnectionIDs", "out", "CurrentConnectionIDs")]),
+            ],
+        };
+        let action_list: String = actions.iter().map(|(name, arguments)| format!(
+            "<action><name>{}</name><argumentList>{}</argumentList></action>",
+            name,
+            arguments.iter().map(|(argument, direction, variable)| format!("<argument><name>{}</name><direction>{}</direction><relatedStateVariable>{}</relatedStateVariable></argument>", argument, direction, variable)).collect::<String>()
+        )).collect();
+        let mut variables: Vec<&str> = actions.iter().flat_map(|(_, arguments)| arguments.iter().map(|(_, _, variable)| *variable)).collect();
+        variables.sort();
+        variables.dedup();
+        let evented = if service == Service::ConnectionManager { vec![] } else { vec!["LastChange"] };
+        let state_table: String = variables.iter().map(|variable| (variable, "no"))
+            .chain(evented.iter().map(|variable| (variable, "yes")))
+            .map(|(variable, events)| format!("<stateVariable sendEvents=\"{}\"><name>{}</name><dataType>{}</dataType></stateVariable>", events, variable, data_type(variable)))
+            .collect();
+        format!(
+            r#"<?xml version="1.0"?><scpd xmlns="urn:schemas-upnp-org:service-1-0"><specVersion><major>1</major><minor>0</minor></specVersion><actionList>{}</actionList><serviceStateTable>{}</serviceStateTable></scpd>"#,
+            action_list, state_table
+        )
+    }
+
+    fn transport_state(&self, state: &AppState) -> &'static str {
+        let transport = self.transport.lock().unwrap();
+        match state.video_player.running().as_deref() {
+            Some(VideoPlayerArgs::Url { url, .. }) if *url == transport.uri => "PLAYING",
+            _ if transport.uri.is_empty() => "NO_MEDIA_PRESENT",
+            _ => "STOPPED",
+        }
+    }
+
+    // handles a SOAP request, returns the body of the response or the UPnP error code
+    pub fn control(&self, state: &AppState, service: Service, soap_action: &str, body: &str) -> Result<String, u16> {
+        let action = action_name(soap_action);
+        let arguments: Vec<(&str, String)> = match (service, action) {
+            (Service::AVTransport, "SetAVTransportURI") => {
+                let transport = transport_uri(body)?;
+                info!("DLNA: got {} ({})", transport.title, transport.uri);
+                *self.transport.lock().unwrap() = transport;
+                vec![]
+            },
+            (Service::AVTransport, "Play") => {
+                let (uri, title) = {
+                    let transport = self.transport.lock().unwrap();
+                    (transport.uri.clone(), transport.title.clone())
+                };
+                if uri.is_empty() {
+                    return Err(701);
+                }
+                state.video_player.start(VideoPlayerArgs::Url { name: title, url: uri, profile: None }).map_err(|error| { error!("could not start player: {}", error); 704u16 })?;
+                vec![]
+            },
+            (Service::AVTransport, "Stop") | (Service::AVTransport, "Pause") => {
+                if self.transport_state(state) == "PLAYING" {
+                    state.video_player.stop().map_err(|_| 704u16)?;
+                }
+                vec![]
+            },
+            (Service::AVTransport, "GetTransportInfo") => vec![
+                ("CurrentTransportState", self.transport_state(state).to_string()),
+                ("CurrentTransportStatus", "OK".to_string()),
+                ("CurrentSpeed", "1".to_string()),
+            ],
+            (Service::AVTransport, "GetMediaInfo") => vec![
+                ("NrTracks", "1".to_string()),
+                ("MediaDuration", "NOT_IMPLEMENTED".to_string()),
+                ("CurrentURI", self.transport.lock().unwrap().uri.clone()),
+                ("CurrentURIMetaData", String::new()),
+            ],
+            (Service::AVTransport, "GetPositionInfo") => vec![
+                ("Track", "1".to_string()),
+                ("TrackDuration", "NOT_IMPLEMENTED".to_string()),
+                ("TrackURI", self.transport.lock().unwrap().uri.clone()),
+                ("RelTime", "NOT_IMPLEMENTED".to_string()),
+                ("AbsTime", "NOT_IMPLEMENTED".to_string()),
+            ],
+            (Service::RenderingControl, "GetVolume") => vec![("CurrentVolume", audio::get_volume().map_err(|_| 501u16)?.min(100).to_string())],
+            (Service::RenderingControl, "SetVolume") => {
+                let volume = soap_argument(body, "DesiredVolume").and_then(|volume| volume.parse().ok()).ok_or(402u16)?;
+                audio::set_volume(volume).map_err(|_| 501u16)?;
+                vec![]
+            },
+            (Service::RenderingControl, "GetMute") => vec![("CurrentMute", if audio::is_muted().map_err(|_| 501u16)? { "1" } else { "0" }.to_string())],
+            (Service::RenderingControl, "SetMute") => {
+                let muted = soap_argument(body, "DesiredMute").ok_or(402u16)?;
+                audio::set_muted(muted == "1" || muted == "true").map_err(|_| 501u16)?;
+                vec![]
+            },
+            (Service::ConnectionManager, "GetProtocolInfo") => vec![("Source", String::new()), ("Sink", "http-get:*:*:*".to_string())],
+            (Service::ConnectionManager, "GetCurrentConnectionIDs") => vec![("ConnectionIDs", "0".to_string())],
+            _ => return Err(401),
+        };
+
+        let arguments: String = arguments.iter().map(|(name, value)| format!("<{0}>{1}</{0}>", name, xml_escape(value))).collect();
+        Ok(soap_envelope(&format!(r#"<u:{0}Response xmlns:u="{1}">{2}</u:{0}Response>"#, action, service.urn(), arguments)))
+    }
+
+    // adds or renews a subscription, returns its SID
+    pub fn subscribe(self: &Arc<Self>, state: Arc<AppState>, service: Service, requester: IpAddr, callback: Option<&str>, sid: Option<&str>) -> Option<String> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|subscription| subscription.expires > Instant::now());
+        let expires = Instant::now() + Duration::from_secs(SUBSCRIPTION_TIMEOUT);
+
+        if let Some(sid) = sid {
+            let subscription = subscriptions.iter_mut().find(|subscription| subscription.sid == sid)?;
+            subscription.expires = expires;
+            return Some(sid.to_string());
+        }
+
+        let callback = callback_url(callback?, requester)?;
+        let sid = format!("uuid:{}", Uuid::new_v4());
+        subscriptions.push(Subscription { sid: sid.clone(), service, callback, expires, seq: 0 });
+
+        // the initial event has to arrive after the response to the subscription
+        let dlna = self.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            dlna.notify(&state, Some(service));
+        });
+        Some(sid)
+    }
+
+    pub fn unsubscribe(&self, sid: &str) {
+        self.subscriptions.lock().unwrap().retain(|subscription| subscription.sid != sid);
+    }
+
+    fn last_change(&self, state: &AppState, service: Service) -> String {
+        let event = match service {
+            Service::AVTransport => format!(
+                r#"<Event xmlns="urn:schemas-upnp-org:metadata-1-0/AVT/"><InstanceID val="0"><TransportState val="{}"/><AVTransportURI val="{}"/></InstanceID></Event>"#,
+                self.transport_state(state), xml_escape(&self.transport.lock().unwrap().uri)
+            ),
+            Service::RenderingControl => format!(
+                r#"<Event xmlns="urn:schemas-upnp-org:metadata-1-0/RCS/"><InstanceID val="0"><Volume channel="Master" val="{}"/><Mute channel="Master" val="{}"/></InstanceID></Event>"#,
+                audio::get_volume().unwrap_or(0).min(100), if audio::is_muted().unwrap_or(false) { 1 } else { 0 }
+            ),
+            Service::ConnectionManager => return String::new(),
+        };
+        format!(r#"<?xml version="1.0"?><e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0"><e:property><LastChange>{}</LastChange></e:property></e:propertyset>"#, xml_escape(&event))
+    }
+
+    // sends the current state to the subscribers of the service, or of all services
+    fn notify(&self, state: &AppState, service: Option<Service>) {
+        let client = Client::builder().timeout(Duration::from_secs(5)).build().unwrap();
+        let mut deliveries = Vec::new();
+        {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            subscriptions.retain(|subscription| subscription.expires > Instant::now());
+            for subscription in subscriptions.iter_mut().filter(|subscription| service.is_none_or(|service| service == subscription.service)) {
+                deliveries.push((subscription.callback.clone(), subscription.sid.clone(), subscription.seq, subscription.service));
+                subscription.seq += 1;
+            }
+        }
+        for (callback, sid, seq, service) in deliveries {
+            let result = client.request(Method::from_bytes(b"NOTIFY").unwrap(), &callback)
+                .header("Content-Type", "text/xml; charset=\"utf-8\"")
+                .header("NT", "upnp:event")
+                .header("NTS", "upnp:propchange")
+                .header("SID", &sid)
+                .header("SEQ", seq.to_string())
+                .body(self.last_change(state, service))
+                .send();
+            if let Err(error) = result {
+                warn!("DLNA: could not notify {}: {}", callback, error);
+            }
+        }
+    }
+
+    fn notification_types(&self) -> Vec<(String, String)> {
+        let mut types = vec![
+            ("upnp:rootdevice".to_string(), format!("{}::upnp:rootdevice", self.udn)),
+            (self.udn.clone(), self.udn.clone()),
+            (DEVICE_TYPE.to_string(), format!("{}::{}", self.udn, DEVICE_TYPE)),
+        ];
+        types.extend(Service::ALL.iter().map(|service| (service.urn(), format!("{}::{}", self.udn, service.urn()))));
+        types
+    }
+
+    fn ssdp_message(&self, start_line: &str, target_header: &str, target: &str, usn: &str, extra: &str) -> String {
+        format!(
+            "{}\r\nCACHE-CONTROL: max-age={}\r\nLOCATION: {}/dlna/description.xml\r\nSERVER: Linux UPnP/1.0 HomeBack/{}\r\n{}: {}\r\nUSN: {}\r\n{}\r\n",
+            start_line, MAX_AGE, self.base_url, env!("CARGO_PKG_VERSION"), target_header, target, usn, extra
+        )
+    }
+
+    fn announce(&self, socket: &UdpSocket, alive: bool) {
+        for (target, usn) in self.notification_types() {
+            let message = self.ssdp_message("NOTIFY * HTTP/1.1", "NT", &target, &usn, &format!("HOST: {}:{}\r\nNTS: ssdp:{}\r\n", SSDP_ADDR, SSDP_PORT, if alive { "alive" } else { "byebye" }));
+            if let Err(error) = socket.send_to(message.as_bytes(), (SSDP_ADDR, SSDP_PORT)) {
+                warn!("DLNA: could not announce: {}", error);
+                return;
+            }
+        }
+    }
+
+    pub fn announce_byebye(&self) {
+        match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) {
+            Ok(socket) => self.announce(&socket, false),
+            Err(error) => warn!("DLNA: could not say goodbye: {}", error),
+        }
+    }
+
+    fn answer_search(&self, socket: &UdpSocket, request: &str, from: SocketAddr) {
+        lazy_static! {
+            static ref ST: Regex = Regex::new(r"(?im)^ST:\s*(.+?)\s*$").unwrap();
+        }
+        if !request.starts_with("M-SEARCH") {
+            return;
+        }
+        let search_target = match ST.captures(request) {
+            Some(capture) => capture[1].to_string(),
+            None => return,
+        };
+        for (target, usn) in self.notification_types() {
+            if search_target == "ssdp:all" || search_target == target {
+                let message = self.ssdp_message("HTTP/1.1 200 OK", "ST", &target, &usn, "EXT:\r\n");
+                let _ = socket.send_to(message.as_bytes(), from);
+            }
+        }
+    }
+}
+
+// starts answering SSDP searches and announcing the renderer, and sends UPnP events when the player changes
+pub fn start(state: Arc<AppState>) {
+    let dlna = match &state.dlna {
+        Some(dlna) => dlna.clone(),
+        None => return,
+    };
+
+    let socket = match ssdp_socket() {
+        Ok(socket) => socket,
+        Err(error) => { error!("DLNA: could not listen for SSDP: {}", error); return },
+    };
+    info!("DLNA: announcing {} at {}", dlna.name, dlna.base_url);
+
+    let ssdp_dlna = dlna.clone();
+    thread::spawn(move || {
+        let dlna = ssdp_dlna;
+        let mut last_announce: Option<Instant> = None;
+        let mut buffer = [0; 2048];
+        loop {
+            if last_announce.is_none_or(|last| last.elapsed() >= ANNOUNCE_INTERVAL) {
+                dlna.announce(&socket, true);
+                last_announce = Some(Instant::now());
+            }
+            // the timeout makes sure the announcements go out even if nobody is searching
+            if let Ok((length, from)) = socket.recv_from(&mut buffer) {
+                dlna.answer_search(&socket, &String::from_utf8_lossy(&buffer[..length]), from);
+            }
+        }
+    });
+
+    let events = state.events.subscribe();
+    thread::spawn(move || for event in events {
+        if let Event::PlayerStarted { .. } | Event::PlayerStopped = *event {
+            dlna.notify(&state, Some(Service::AVTransport));
+        }
+    });
+}
+
+fn ssdp_socket() -> io::Result<UdpSocket> {
+    // other renderers or media servers on this machine listen on the same port
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, SSDP_PORT)).into())?;
+    socket.join_multicast_v4(&SSDP_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_read_timeout(Some(Duration::from_secs(30)))?;
+    Ok(socket.into())
+}
+
+// the address other machines reach us on, connecting a udp socket doesn't send anything
+pub fn local_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((SSDP_ADDR, SSDP_PORT)).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+fn data_type(variable: &str) -> &'static str {
+    match variable {
+        "A_ARG_TYPE_InstanceID" | "Volume" | "NumberOfTracks" | "CurrentTrack" => "ui4",
+        "Mute" => "boolean",
+        _ => "string",
+    }
+}
+
+pub fn soap_envelope(body: &str) -> String {
+    format!(r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body>{}</s:Body></s:Envelope>"#, body)
+}
+
+pub fn soap_error(code: u16) -> String {
+    soap_envelope(&format!(
+        r#"<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>{}</errorCode></UPnPError></detail></s:Fault>"#,
+        code
+    ))
+}
+
+// the action of a SOAPACTION header like "urn:schemas-upnp-org:service:AVTransport:1#Play"
+fn action_name(soap_action: &str) -> &str {
+    soap_action.trim_matches('"').rsplit('#').next().unwrap_or_default()
+}
+
+// only http(s) goes to the player, anything else could be a local file or an option for mpv
+fn transport_uri(body: &str) -> Result<Transport, u16> {
+    let uri = soap_argument(body, "CurrentURI").ok_or(402u16)?;
+    if !validation::is_http_url(&uri) {
+        warn!("DLNA: rejected {}", uri);
+        return Err(714);
+    }
+    let title = soap_argument(body, "CurrentURIMetaData").and_then(|metadata| xml_element(&metadata, "dc:title")).map(|title| xml_unescape(&title)).unwrap_or_else(|| uri.clone());
+    Ok(Transport { uri, title })
+}
+
+// looks like "<http://192.168.1.23:49152/notify>", possibly several of them
+// the events only go back to the subscriber, otherwise anyone could have them sent to any host
+fn callback_url(header: &str, requester: IpAddr) -> Option<String> {
+    header.split('<')
+        .filter_map(|part| part.split_once('>').map(|(url, _)| url.trim()))
+        .find(|url| validation::is_http_url(url) && Url::parse(url).ok()
+            .and_then(|url| url.host_str()?.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok())
+            .is_some_and(|host| host.to_canonical() == requester.to_canonical()))
+        .map(str::to_string)
+}
+
+// the value of an argument in a SOAP request, unescaped
+fn soap_argument(body: &str, name: &str) -> Option<String> {
+    xml_element(body, name).map(|value| xml_unescape(&value))
+}
+
+#[cfg(test)]
+mod tests;
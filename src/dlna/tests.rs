@@ -0,0 +1,61 @@
+use super::*;
+
+fn set_uri(uri: &str, metadata: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:SetAVTransportURI xmlns:u="urn:schemas-upnp-org:service:AVTransport:1"><InstanceID>0</InstanceID><CurrentURI>{}</CurrentURI><CurrentURIMetaData>{}</CurrentURIMetaData></u:SetAVTransportURI></s:Body></s:Envelope>"#,
+        uri, metadata
+    )
+}
+
+fn phone() -> IpAddr {
+    "192.168.1.23".parse().unwrap()
+}
+
+#[test]
+fn the_action_is_taken_from_the_soap_action() {
+    assert_eq!(action_name(r#""urn:schemas-upnp-org:service:AVTransport:1#SetAVTransportURI""#), "SetAVTransportURI");
+    assert_eq!(action_name("urn:schemas-upnp-org:service:RenderingControl:1#GetVolume"), "GetVolume");
+}
+
+#[test]
+fn the_uri_and_title_are_read_escaped() {
+    let metadata = "&lt;DIDL-Lite xmlns:dc=&quot;http://purl.org/dc/elements/1.1/&quot;&gt;&lt;item&gt;&lt;dc:title&gt;Tom &amp;amp; Jerry&lt;/dc:title&gt;&lt;/item&gt;&lt;/DIDL-Lite&gt;";
+    let transport = transport_uri(&set_uri("http://192.168.1.23:8080/video.mp4?a=1&amp;b=2", metadata)).unwrap();
+    assert_eq!(transport, Transport { uri: "http://192.168.1.23:8080/video.mp4?a=1&b=2".to_string(), title: "Tom & Jerry".to_string() });
+}
+
+#[test]
+fn without_metadata_the_uri_is_the_title() {
+    let transport = transport_uri(&set_uri("https://example.com/stream.m3u8", "")).unwrap();
+    assert_eq!(transport.title, "https://example.com/stream.m3u8");
+}
+
+#[test]
+fn only_http_uris_are_played() {
+    assert_eq!(transport_uri(&set_uri("file:///etc/passwd", "")), Err(714));
+    assert_eq!(transport_uri(&set_uri("/home/user/video.mkv", "")), Err(714));
+    assert_eq!(transport_uri(&set_uri("--script=/tmp/evil.lua", "")), Err(714));
+    assert_eq!(transport_uri("<s:Envelope><s:Body><u:SetAVTransportURI></u:SetAVTransportURI></s:Body></s:Envelope>"), Err(402));
+}
+
+#[test]
+fn the_callback_has_to_point_back_at_the_subscriber() {
+    assert_eq!(callback_url("<http://192.168.1.23:49152/notify>", phone()).as_deref(), Some("http://192.168.1.23:49152/notify"));
+    assert_eq!(callback_url("<http://10.0.0.1/notify>", phone()), None);
+    assert_eq!(callback_url("<http://router.local/notify>", phone()), None);
+    assert_eq!(callback_url("<file:///192.168.1.23/notify>", phone()), None);
+    assert_eq!(callback_url("http://192.168.1.23:49152/notify", phone()), None);
+}
+
+#[test]
+fn the_first_callback_of_the_subscriber_is_used() {
+    let header = "<http://10.0.0.1/notify><http://192.168.1.23:49152/first><http://192.168.1.23:49152/second>";
+    assert_eq!(callback_url(header, phone()).as_deref(), Some("http://192.168.1.23:49152/first"));
+}
+
+#[test]
+fn a_subscriber_on_ipv6_matches_its_mapped_address() {
+    let mapped: IpAddr = "::ffff:192.168.1.23".parse().unwrap();
+    assert_eq!(callback_url("<http://192.168.1.23:49152/notify>", mapped).as_deref(), Some("http://192.168.1.23:49152/notify"));
+    assert_eq!(callback_url("<http://[fe80::1]:49152/notify>", "fe80::1".parse().unwrap()).as_deref(), Some("http://[fe80::1]:49152/notify"));
+}
@@ -1,42 +1,108 @@
 use std::env;
 use std::fs;
 use std::io;
-use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use actix_web::rt::spawn;
+use actix_web::rt::time::sleep;
 use futures::StreamExt;
-use log::info;
+use log::{info, warn};
 use reqwest::Client;
+use reqwest::header::{HeaderMap, HeaderValue, RANGE, REFERER, USER_AGENT};
+use reqwest::StatusCode;
+use tokio::io::{AsyncWriteExt, BufWriter};
 use uuid::Uuid;
-use serde::Serialize;
-use super::files::sanitize_path;
+use serde::{Serialize, Deserialize};
+use super::files::{self, PathError, Root};
+use super::store::{Repository, Store};
+use super::events::{Event, Events};
 use lazy_static::lazy_static;
 use regex::Regex;
 
 const MAX_PARALLEL_DOWNLOADS: usize = 4;
-
-lazy_static! {
-    static ref SCAN_FOLDER :     PathBuf = PathBuf::from(env::var("SCAN_FOLDER").expect("SCAN_FOLDER not set"));
-    static ref DOWNLOAD_FOLDER : PathBuf = PathBuf::from(env::var("DOWNLOAD_FOLDER").expect("DOWNLOAD_FOLDER not set"));
-}
+const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+const PROGRESS_BYTES: u64 = 1024 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
 
 pub fn read_scan_folder() -> io::Result<Vec<String>> { 
-    Ok(fs::read_dir(&*SCAN_FOLDER)?
+    Ok(fs::read_dir(Root::Scan.folder())?
         .filter_map(|file| file.ok())
-        .filter(|file| file.file_type().map_or(false, |f_type| f_type.is_file()))
+        .filter(|file| file.file_type().is_ok_and(|f_type| f_type.is_file()))
         .map(|file| file.file_name().into_string().unwrap())
         .collect())
 }
 
+lazy_static! {
+    // the links of every scan file that was read, with the modification time of the file at that point
+    static ref SCAN_LINKS: Mutex<HashMap<String, (SystemTime, Vec<String>)>> = Mutex::new(HashMap::new());
+    // finished downloads are moved into the subfolder of the first rule that matches their name
+    static ref MOVE_RULES: Vec<(Regex, String)> = env::var("DOWNLOAD_RULES").unwrap_or_default().split(',')
+        .filter_map(|rule| rule.split_once('='))
+        .map(|(glob, subfolder)| (glob_regex(glob.trim()), subfolder.trim().to_string()))
+        .collect();
+}
+
+// * and ? of the glob, ignoring the case like the file names on a share
+fn glob_regex(glob: &str) -> Regex {
+    let pattern: String = glob.chars().map(|c| match c {
+        '*' => ".*".to_string(),
+        '?' => ".".to_string(),
+        c => regex::escape(&c.to_string()),
+    }).collect();
+    Regex::new(&format!("(?i)^{}$", pattern)).unwrap()
+}
+
+// where the download is moved by the rules, relative to the DOWNLOAD_FOLDER
+// the folders it was downloaded into are kept below the subfolder, a download already in there stays
+fn moved_path(path: &Path, rules: &[(Regex, String)]) -> Option<PathBuf> {
+    let name = path.file_name()?;
+    let (_, subfolder) = rules.iter().find(|(glob, _)| glob.is_match(&name.to_string_lossy()))?;
+    let subfolder = Path::new(subfolder);
+    if path.starts_with(subfolder) {
+        return None;
+    }
+    Some(subfolder.join(path))
+}
+
 pub fn read_scan_file(file: String) -> io::Result<Vec<String>> {
+    let path = files::resolve(Root::Scan, &file)?;
+    let modified = fs::metadata(&path)?.modified()?;
+    if let Some((read_at, links)) = SCAN_LINKS.lock().unwrap().get(&file) {
+        if *read_at == modified {
+            return Ok(links.clone());
+        }
+    }
+
+    let links = find_links(&fs::read_to_string(&path)?);
+    info!("found {} links in {}", links.len(), file);
+    SCAN_LINKS.lock().unwrap().insert(file, (modified, links.clone()));
+    Ok(links)
+}
+
+// reads all scan files in the background, so the first requests after a start don't have to
+pub fn warm_scan_cache() {
+    thread::spawn(|| {
+        let files = match read_scan_folder() {
+            Ok(files) => files,
+            Err(error) => { warn!("could not read the scan folder: {}", error); return; },
+        };
+        SCAN_LINKS.lock().unwrap().retain(|file, _| files.contains(file));
+        for file in files {
+            if let Err(error) = read_scan_file(file.clone()) {
+                warn!("could not read scan file {}: {}", file, error);
+            }
+        }
+    });
+}
+
+fn find_links(content: &str) -> Vec<String> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r#"https://[A-Za-z0-9]+?\.hi10an[^>";]*"#).unwrap();
     }
 
-    let content: &str = &fs::read_to_string(SCAN_FOLDER.join(sanitize_path(&file)))?;
-    
     let mut links = RE.find_iter(content)
         .map(|m| m.as_str().to_string() )
         .filter(|link| !link.starts_with("https://stream."))
@@ -44,9 +110,75 @@ pub fn read_scan_file(file: String) -> io::Result<Vec<String>> {
 
     links.sort();
     links.dedup();
+    links
+}
 
-    info!("found {} links in {}", links.len(), file);
-    return Ok(links);
+// the variables a naming template of a scan batch can use
+pub const TEMPLATE_VARIABLES: [&str; 5] = ["original_name", "show", "season", "episode", "scan"];
+
+// percent encoded, like the links in the scan files
+fn original_name(link: &str) -> String {
+    let name = link.split(['?', '#']).next().unwrap_or_default().rsplit('/').next().unwrap_or_default();
+    let bytes = name.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], name.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => { decoded.push(byte); i += 3; },
+            (byte, _) => { decoded.push(byte); i += 1; },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// "Show.Name.S02E03.1080p.mkv" or "[Group] Show Name - 03 [1080p].mkv", the second has no season
+fn episode_variables(name: &str) -> HashMap<&'static str, String> {
+    lazy_static! {
+        static ref SEASON_EPISODE: Regex = Regex::new(r"(?i)^(?P<show>.+?)[ ._-]+S(?P<season>\d{1,2})E(?P<episode>\d{1,3})").unwrap();
+        static ref ABSOLUTE_EPISODE: Regex = Regex::new(r"^(?:\[[^\]]*\]\s*)?(?P<show>.+?)\s+-\s+(?P<episode>\d{1,4})(?:v\d)?\b").unwrap();
+    }
+    let Some(captures) = SEASON_EPISODE.captures(name).or_else(|| ABSOLUTE_EPISODE.captures(name)) else {
+        return HashMap::new();
+    };
+    let number = |value: &str| value.trim_start_matches('0').to_string();
+    let mut variables = HashMap::new();
+    variables.insert("show", captures["show"].replace(['.', '_'], " ").trim().to_string());
+    if let Some(season) = captures.name("season") {
+        variables.insert("season", number(season.as_str()));
+    }
+    let episode = number(&captures["episode"]);
+    variables.insert("episode", if episode.is_empty() { "0".to_string() } else { episode });
+    variables
+}
+
+// the path of a link of a scan batch by a template like "{show}/Season {season}/{original_name}"
+// a folder with a variable that isn't known for the link is left out, so files without a season end up in the folder of the show
+pub fn templated_path(template: &str, link: &str, scan: &str) -> String {
+    let original_name = original_name(link);
+    let mut variables = episode_variables(&original_name);
+    variables.insert("scan", Path::new(scan).file_stem().map_or(scan.to_string(), |stem| stem.to_string_lossy().into_owned()));
+    variables.insert("original_name", original_name.clone());
+
+    lazy_static! {
+        static ref VARIABLE: Regex = Regex::new(r"\{([a-z_]+)\}").unwrap();
+    }
+    // a value is never more than one component of the path
+    let fill = |component: &str| {
+        let mut complete = true;
+        let filled = VARIABLE.replace_all(component, |captures: &regex::Captures| match variables.get(&captures[1]) {
+            Some(value) => value.replace(['/', '\\'], "_"),
+            None => { complete = false; String::new() },
+        }).into_owned();
+        Some(filled).filter(|filled| complete && !filled.trim().is_empty())
+    };
+    let mut components: Vec<&str> = template.split('/').filter(|component| !component.is_empty()).collect();
+    let name = components.pop().and_then(&fill).unwrap_or(original_name);
+    components.into_iter().filter_map(fill).chain([name]).collect::<Vec<_>>().join("/")
+}
+
+// where a download with that path ends up
+pub fn download_location(path: &str) -> Result<PathBuf, PathError> {
+    files::resolve(Root::Download, path)
 }
 
 #[derive(Serialize, Debug)]
@@ -55,9 +187,9 @@ pub struct File {
     pub size: Option<u64>,
 }
 pub fn read_downloads_subfolder(subfolder: String) -> io::Result<Vec<File>> {
-    let files: Vec<_> = fs::read_dir(DOWNLOAD_FOLDER.join(sanitize_path(&subfolder)))?
+    let files: Vec<_> = fs::read_dir(files::resolve(Root::Download, &subfolder)?)?
         .filter_map(|file| file.ok())
-        .filter(|file| file.file_type().map_or(false, |f_type| f_type.is_file()))
+        .filter(|file| file.file_type().is_ok_and(|f_type| f_type.is_file()))
         .map(|file| File{name: file.file_name().into_string().unwrap(), size: file.metadata().ok().map(|metadata| metadata.len())})
         .collect();
 
@@ -65,20 +197,47 @@ pub fn read_downloads_subfolder(subfolder: String) -> io::Result<Vec<File>> {
     Ok(files)
 }
 
+// where the download manager gets the time from, so the tests can move it along
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
 pub struct DownloadManager {
+    context: Context,
+    active: [Arc<Mutex<Option<Download>>>; MAX_PARALLEL_DOWNLOADS],
+}
+
+// what every running download needs, cloned into its task
+#[derive(Clone)]
+struct Context {
     client: Client,
+    folder: PathBuf,
+    clock: Arc<dyn Clock>,
     queue: Arc<Mutex<VecDeque<Download>>>,
-    active: [Arc<Mutex<Option<Download>>>; MAX_PARALLEL_DOWNLOADS],
+    persisted: Repository<Download>,
+    events: Arc<Events>,
+    // why new downloads wait, e.g. low disk space, running ones finish
+    paused: Arc<Mutex<HashSet<&'static str>>>,
 }
 
-#[derive(Serialize, Clone, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum Status {
     Created,
     Running,
     Cancelled,
+    Interrupted, // by a shutdown, will be restarted on the next start
+    Paused, // stopped to free the line, continues where it stopped once the pause ends
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Download {
     status: Status,
     pub uuid: Uuid,
@@ -86,18 +245,67 @@ pub struct Download {
     path: PathBuf,
     current_size: u64,
     size: Option<u64>,
+    #[serde(default, flatten)]
+    headers: RequestHeaders,
+}
+
+// sent instead of DOWNLOAD_USER_AGENT and DOWNLOAD_REFERER, for hosts that only serve browsers or links from their own pages
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct RequestHeaders {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub referer: Option<String>,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct DownloadSummary {
+    active: usize,
+    queued: usize,
+    current_size: u64,
+    size: u64, // of the active downloads that reported one
+    paused: bool,
 }
 
 #[derive(Serialize)]
-pub struct Downloads {    
-    queue: Arc<Mutex<VecDeque<Download>>>,    
+pub struct Downloads {
+    queue: Arc<Mutex<VecDeque<Download>>>,
     active_downloads: Vec<Download>,
+    paused: bool,
 }
 
 impl DownloadManager {
-    
-    pub fn new() -> DownloadManager {
-        return DownloadManager { client: Client::new(), queue: Arc::new(Mutex::new(VecDeque::new())), active: Default::default()};
+
+    pub fn from_env(clock: Arc<dyn Clock>, store: Arc<Store>, events: Arc<Events>) -> io::Result<DownloadManager> {
+        let invalid = |name: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a valid header", name));
+        // some hosts reject the default user agent of reqwest
+        let mut client = Client::builder();
+        if let Ok(user_agent) = env::var("DOWNLOAD_USER_AGENT") {
+            client = client.user_agent(HeaderValue::from_str(&user_agent).map_err(|_| invalid("DOWNLOAD_USER_AGENT"))?);
+        }
+        if let Ok(referer) = env::var("DOWNLOAD_REFERER") {
+            let mut headers = HeaderMap::new();
+            headers.insert(REFERER, HeaderValue::from_str(&referer).map_err(|_| invalid("DOWNLOAD_REFERER"))?);
+            client = client.default_headers(headers);
+        }
+        Ok(Self::new(client.build().unwrap(), Root::Download.folder().to_path_buf(), clock, store, events))
+    }
+
+    pub fn new(client: Client, folder: PathBuf, clock: Arc<dyn Clock>, store: Arc<Store>, events: Arc<Events>) -> DownloadManager {
+        let persisted = Repository::new(store, "downloads");
+        let context = Context { client, folder, clock, queue: Arc::default(), persisted, events, paused: Arc::default() };
+        DownloadManager { context, active: Default::default() }
+    }
+
+    // restarts the downloads that were still queued or running when HomeBack was stopped
+    pub fn resume_persisted(&self) {
+        let persisted = self.context.persisted.all();
+        if !persisted.is_empty() {
+            info!("Resuming {} persisted Downloads", persisted.len());
+        }
+        for (_, download) in persisted {
+            self.enqueue(Download { status: Status::Created, current_size: 0, size: None, ..download });
+        }
     }
 
     pub fn get_download(&self, uuid: Uuid) -> Option<Download> {
@@ -112,7 +320,7 @@ impl DownloadManager {
         }
 
         // search queue
-        let q = self.queue.lock().unwrap();
+        let q = self.context.queue.lock().unwrap();
         for download in q.iter() {
             if download.uuid == uuid {
                 return Some(download.clone());
@@ -126,10 +334,52 @@ impl DownloadManager {
         let active_downloads = self.active.iter()
             .filter_map(|dl| dl.lock().unwrap().clone())
             .collect();
-        Downloads { queue: self.queue.clone(), active_downloads }
+        Downloads { queue: self.context.queue.clone(), active_downloads, paused: self.is_paused() }
     }
 
-    pub fn cancel_download(&self, uuid: Uuid) {        
+    pub fn get_summary(&self) -> DownloadSummary {
+        let active: Vec<Download> = self.active.iter()
+            .filter_map(|dl| dl.lock().unwrap().clone())
+            .collect();
+        DownloadSummary {
+            active: active.len(),
+            queued: self.context.queue.lock().unwrap().len(),
+            current_size: active.iter().map(|dl| dl.current_size).sum(),
+            size: active.iter().filter_map(|dl| dl.size).sum(),
+            paused: self.is_paused(),
+        }
+    }
+
+    pub fn has_active(&self) -> bool {
+        self.active.iter().any(|dl| dl.lock().unwrap().is_some())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        !self.context.paused.lock().unwrap().is_empty()
+    }
+
+    // new downloads are queued while paused for any reason, resuming the last one starts them in the free slots
+    pub fn set_paused(&self, reason: &'static str, paused: bool) {
+        {
+            let mut reasons = self.context.paused.lock().unwrap();
+            let changed = if paused { reasons.insert(reason) } else { reasons.remove(reason) };
+            if !changed || !reasons.is_empty() {
+                return;
+            }
+        }
+        let mut queue = self.context.queue.lock().unwrap();
+        for slot in self.active.iter() {
+            let mut s = slot.lock().unwrap();
+            if s.is_some() {continue;}
+            match queue.pop_front() {
+                Some(download) => *s = Some(download),
+                None => break,
+            }
+            spawn(Self::download_and_queue_next(self.context.clone(), slot.clone()));
+        }
+    }
+
+    pub fn cancel_download(&self, uuid: Uuid) {
         // search active downloads
         for download in self.active.iter() {
             let mut dl = download.lock().unwrap();
@@ -142,21 +392,75 @@ impl DownloadManager {
         }
 
         // search queue
-        self.queue.lock().unwrap().retain(|dl| dl.uuid != uuid);
+        let removed = {
+            let mut queue = self.context.queue.lock().unwrap();
+            queue.iter().position(|dl| dl.uuid == uuid).and_then(|i| queue.remove(i))
+        };
+        self.context.persisted.remove(&uuid.to_string());
+
+        // a paused one left what it had so far, only then the file is its own
+        if let Some(dl) = removed.filter(|dl| dl.current_size > 0) {
+            if let Ok(path) = files::resolve_in(&self.context.folder, &dl.path) {
+                info!("Download was Cancelled while paused {:?}", dl);
+                spawn(async move {
+                    if let Err(error) = tokio::fs::remove_file(&path).await {
+                        warn!("could not remove {:?}: {}", path, error);
+                    }
+                });
+            }
+        }
     }
 
-    pub fn trigger_download(&'static self, url: String, path: String) -> Download {
+    // unlike set_paused this stops the running ones too, they are queued again with what they have on disk
+    pub fn pause_running(&self) {
+        for download in self.active.iter() {
+            if let Some(d) = download.lock().unwrap().as_mut().filter(|d| d.status == Status::Running) {
+                d.status = Status::Paused;
+            }
+        }
+    }
+
+    pub async fn shutdown(&self) {
+        // empty the queue first, so finishing downloads don't start new ones
+        self.context.queue.lock().unwrap().clear();
+        for download in self.active.iter() {
+            if let Some(d) = download.lock().unwrap().as_mut() {
+                d.status = Status::Interrupted;
+            }
+        }
+
+        // give the cancelled downloads some time to remove their partial files
+        for _ in 0..50 {
+            if self.active.iter().all(|download| download.lock().unwrap().is_none()) {
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        info!("Not all Downloads finished cancelling before shutdown");
+    }
+
+    pub fn trigger_download(&self, url: String, path: String, headers: RequestHeaders) -> Result<Download, PathError> {
+        files::resolve_in(&self.context.folder, &path)?;
         let raw_download = Download{
             status: Status::Created,
             uuid: Uuid::new_v4(),
             url,
-            path: sanitize_path(&path),
+            path: PathBuf::from(path),
             current_size: 0,
-            size: None
+            size: None,
+            headers,
         };
+        self.context.persisted.put(&raw_download.uuid.to_string(), &raw_download);
+        Ok(self.enqueue(raw_download))
+    }
 
+    fn enqueue(&self, raw_download: Download) -> Download {
         // to avoid Deadlocks, we need to lock the queue first
-        let mut queue = self.queue.lock().unwrap();
+        let mut queue = self.context.queue.lock().unwrap();
+        if self.is_paused() {
+            queue.push_back(raw_download.clone());
+            return raw_download;
+        }
 
         // check if there is an empty active Download slot
         for slot in self.active.iter() {
@@ -165,10 +469,7 @@ impl DownloadManager {
             if s.is_some() {continue;}
 
             *s = Some(raw_download.clone());
-            let c2 = self.client.clone();
-            let s2 = slot.clone();
-            let q2 = self.queue.clone();
-            spawn(Self::download_and_queue_next(c2, s2, q2));
+            spawn(Self::download_and_queue_next(self.context.clone(), slot.clone()));
             return raw_download;
         }
 
@@ -177,80 +478,170 @@ impl DownloadManager {
         raw_download
     }
 
-    async fn download_and_queue_next(client: Client, download: Arc<Mutex<Option<Download>>>, queue: Arc<Mutex<VecDeque<Download>>>) -> Result<(), Box<dyn std::error::Error>> {
-        let result = Self::download(client.clone(), download.clone()).await;
-        // remove the file if the download was cancelled
-        if let Ok(Some(path)) = &result {
-            info!("Download was Cancelled {:?}", download);
-            fs::remove_file(path)?;
+    async fn download_and_queue_next(context: Context, download: Arc<Mutex<Option<Download>>>) -> Result<(), Box<dyn std::error::Error>> {
+        let result = Self::download(&context, download.clone()).await;
+
+        // interrupted downloads stay persisted, so they are restarted on the next start
+        if let Some(dl) = &*download.lock().unwrap() {
+            if dl.status != Status::Interrupted && dl.status != Status::Paused {
+                context.persisted.remove(&dl.uuid.to_string());
+            }
+            let path = dl.path.to_string_lossy().into_owned();
+            match &result {
+                Ok(None) => context.events.publish(Event::DownloadFinished { uuid: dl.uuid, path }),
+                Err(error) => context.events.publish(Event::DownloadFailed { uuid: dl.uuid, path, error: error.to_string() }),
+                Ok(Some(_)) => {}, // cancelled
+            }
         }
-        
-        Self::queue_next(client, download, queue).await; // make sure this is always called, otherwise the download slot will never be freed
+
+        // a paused download keeps its file and asks for the rest once it runs again
+        let restart = download.lock().unwrap().as_ref().filter(|dl| dl.status == Status::Paused).cloned();
+        let paused = restart.is_some();
+        if let Some(dl) = restart {
+            context.queue.lock().unwrap().push_front(Download { status: Status::Created, ..dl });
+        }
+
+        // remove the file if the download was cancelled
+        let removed = match &result {
+            Ok(Some(path)) if !paused => {
+                info!("Download was Cancelled {:?}", download);
+                tokio::fs::remove_file(path).await
+            },
+            _ => Ok(()),
+        };
+
+        Self::queue_next(context, download); // make sure this is always called, otherwise the download slot will never be freed
+        removed?;
         result.map(|_| ()) // propagate error
     }
 
-    async fn download(client: Client, download: Arc<Mutex<Option<Download>>>) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
-        let (response_future, path) = {
+    async fn download(context: &Context, download: Arc<Mutex<Option<Download>>>) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+        let (mut request, path, downloaded) = {
             let mut dl_guard = download.lock().unwrap();
-            
-            let mut dl = match dl_guard.as_mut() {
+
+            let dl = match dl_guard.as_mut() {
                 Some(dl) => dl,
                 None => return Err("Should start Download but Mutex is empty".into()),
             };
 
             dl.status = Status::Running;
-            let response_future = client.get(&dl.url).send();
-            let path = DOWNLOAD_FOLDER.join(&dl.path);
-            (response_future, path)
+            // checked again, the folders could have changed while it was queued
+            let path = files::resolve_in(&context.folder, &dl.path)?;
+            let mut request = context.client.get(&dl.url);
+            if let Some(user_agent) = &dl.headers.user_agent {
+                request = request.header(USER_AGENT, user_agent);
+            }
+            if let Some(referer) = &dl.headers.referer {
+                request = request.header(REFERER, referer);
+            }
+            (request, path, dl.current_size)
         };
 
+        // a paused one continues after what is on disk, unless the file is gone or was changed in the meantime
+        let on_disk = tokio::fs::metadata(&path).await.map_or(0, |metadata| metadata.len());
+        let offset = if downloaded > 0 && on_disk == downloaded { downloaded } else { 0 };
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
 
         // set size
-        let response = response_future.await?;
+        let response = request.send().await?.error_for_status()?;
+        // a host without ranges sends all of it, then it starts over
+        let resumed = offset > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+        let offset = if resumed { offset } else { 0 };
         {
             let mut dl_guard = download.lock().unwrap();
             match dl_guard.as_mut() {
-                Some(mut dl) => dl.size = response.content_length(),
+                Some(dl) => {
+                    dl.size = response.content_length().map(|length| offset + length);
+                    dl.current_size = offset;
+                },
                 None => return Err("Should set Download Size but Mutex is empty".into()),
             };
         }
-        
+
         // download
         info!("Starting Dowload: {:?}", download);
-        fs::create_dir_all(path.parent().unwrap())?;
-        let mut file = fs::File::create(&path)?;
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let file = match resumed {
+            true => tokio::fs::OpenOptions::new().append(true).open(&path).await?,
+            false => tokio::fs::File::create(&path).await?,
+        };
+        // every write of a tokio file goes through the blocking pool, so the small chunks are collected first
+        let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, file);
         let mut stream = response.bytes_stream();
+        // the chunks are small, so the progress is only shared (and cancelling checked) every few of them
+        let mut unreported = 0;
+        let mut reported_at = context.clock.now();
         while let Some(item) = stream.next().await {
 
             let chunk = item?;
-            file.write_all(&chunk)?;
+            file.write_all(&chunk).await?;
+            unreported += chunk.len() as u64;
+            if unreported < PROGRESS_BYTES && context.clock.now().duration_since(reported_at) < PROGRESS_INTERVAL {
+                continue;
+            }
 
-            let mut dl_guard = download.lock().unwrap();
-            match dl_guard.as_mut() {
-                Some(mut dl) => {
-                    dl.current_size += chunk.len() as u64;
-                    if dl.status == Status::Cancelled {return Ok(Some(path))}
-                },
-                None => return Err("Should update Download Size but Mutex is empty".into()),
+            let stopped = {
+                let mut dl_guard = download.lock().unwrap();
+                match dl_guard.as_mut() {
+                    Some(dl) => {
+                        dl.current_size += unreported;
+                        matches!(dl.status, Status::Cancelled | Status::Interrupted | Status::Paused)
+                    },
+                    None => return Err("Should update Download Size but Mutex is empty".into()),
+                }
             };
+            unreported = 0;
+            reported_at = context.clock.now();
+            if stopped {
+                file.flush().await?;
+                return Ok(Some(path));
+            }
         }
+        file.flush().await?;
+        let relative = match download.lock().unwrap().as_mut() {
+            Some(dl) => { dl.current_size += unreported; dl.path.clone() },
+            None => return Err("Should update Download Size but Mutex is empty".into()),
+        };
 
         info!("Finished Dowload: {:?}", download);
+        if let Some(moved) = moved_path(&relative, &MOVE_RULES) {
+            match Self::move_download(&context.folder, &path, &moved).await {
+                Ok(()) => if let Some(dl) = download.lock().unwrap().as_mut() {
+                    info!("Moved {:?} to {:?}", dl.path, moved);
+                    dl.path = moved;
+                },
+                Err(error) => warn!("could not move {:?} to {:?}: {}", relative, moved, error),
+            }
+        }
         Ok(None)
     }
 
-    async fn queue_next(client: Client, download: Arc<Mutex<Option<Download>>>, queue: Arc<Mutex<VecDeque<Download>>>) {
+    async fn move_download(folder: &Path, from: &Path, to: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let target = files::resolve_in(folder, to)?;
+        if tokio::fs::symlink_metadata(&target).await.is_ok() {
+            return Err(format!("{:?} already exists", to).into());
+        }
+        tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+        tokio::fs::rename(from, &target).await?;
+        Ok(())
+    }
+
+    fn queue_next(context: Context, download: Arc<Mutex<Option<Download>>>) {
         // lock the queue first to avoid deadlocks
-        let mut q = queue.lock().unwrap();
+        let mut q = context.queue.lock().unwrap();
         let mut dl_guard = download.lock().unwrap();
-        match q.pop_front() {
+        let next = if context.paused.lock().unwrap().is_empty() { q.pop_front() } else { None };
+        match next {
             Some(new_dl) => {
                 *dl_guard = Some(new_dl);
-                let dl2 = download.clone();
-                let q2 = queue.clone();
-                spawn(Self::download_and_queue_next(client, dl2, q2));
+                spawn(Self::download_and_queue_next(context.clone(), download.clone()));
             },
             None => *dl_guard = None,
         };
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,286 @@
+use std::sync::mpsc::Receiver;
+use std::fs;
+use super::*;
+use crate::testing::{body, eventually, Fixture, ManualClock, MockServer, TempFolder};
+
+// long enough to still be running when the test looks, the server stops sending once the client hangs up
+fn slow(size: usize, seed: u8) -> Fixture {
+    Fixture::Slow { body: body(size, seed), chunk: 16 * 1024, delay: Duration::from_millis(20), ranges: true }
+}
+
+fn without_ranges(size: usize, seed: u8) -> Fixture {
+    Fixture::Slow { body: body(size, seed), chunk: 16 * 1024, delay: Duration::from_millis(20), ranges: false }
+}
+
+// pauses the running download once it wrote something, it is queued again with what it has
+async fn pause(harness: &Harness, download: &Download) -> u64 {
+    assert!(eventually(|| harness.manager.get_download(download.uuid).is_some_and(|download| download.current_size > 0)).await);
+    harness.manager.set_paused("test", true);
+    harness.manager.pause_running();
+    harness.report();
+    assert!(eventually(|| harness.summary() == (0, 1)).await);
+    harness.manager.get_download(download.uuid).unwrap().current_size
+}
+
+struct Harness {
+    server: MockServer,
+    clock: Arc<ManualClock>,
+    folder: TempFolder, // holds downloads/ and the store
+    manager: DownloadManager,
+    events: Receiver<Arc<Event>>,
+}
+
+impl Harness {
+
+    fn new() -> Self {
+        let folder = TempFolder::new();
+        fs::create_dir(folder.join("downloads")).unwrap();
+        Self::with(MockServer::start(), folder)
+    }
+
+    // a new manager on the same store and folder, like a restart of HomeBack
+    fn restart(self) -> Self {
+        let Self { server, folder, .. } = self;
+        Self::with(server, folder)
+    }
+
+    fn with(server: MockServer, folder: TempFolder) -> Self {
+        let clock = ManualClock::new();
+        let store = Arc::new(Store::open(folder.join("store.json")).unwrap());
+        let events = Arc::new(Events::default());
+        let manager = DownloadManager::new(Client::new(), folder.join("downloads"), clock.clone(), store, events.clone());
+        Self { server, clock, folder, manager, events: events.subscribe() }
+    }
+
+    fn download(&self, fixture_path: &str, fixture: Fixture) -> Download {
+        let url = self.server.serve(fixture_path, fixture);
+        self.manager.trigger_download(url, fixture_path.trim_start_matches('/').to_string(), RequestHeaders::default()).unwrap()
+    }
+
+    fn file(&self, path: &str) -> PathBuf {
+        self.folder.join("downloads").join(path)
+    }
+
+    // lets the running downloads see their new status with the next chunk
+    fn report(&self) {
+        self.clock.advance(PROGRESS_INTERVAL);
+    }
+
+    fn summary(&self) -> (usize, usize) {
+        let summary = self.manager.get_summary();
+        (summary.active, summary.queued)
+    }
+
+    fn status(&self, download: &Download) -> Option<Status> {
+        self.manager.get_download(download.uuid).map(|download| download.status)
+    }
+
+    fn persisted(&self) -> usize {
+        self.manager.context.persisted.all().len()
+    }
+
+    fn received(&self) -> Vec<Arc<Event>> {
+        self.events.try_iter().collect()
+    }
+}
+
+#[actix_web::test]
+async fn downloads_the_file() {
+    let harness = Harness::new();
+    // more than PROGRESS_BYTES, so the progress is reported on the way
+    let download = harness.download("/episode.mkv", Fixture::Body(body(3 * 1024 * 1024, 1)));
+
+    assert!(eventually(|| harness.status(&download).is_none()).await);
+    assert_eq!(fs::read(harness.file("episode.mkv")).unwrap(), body(3 * 1024 * 1024, 1));
+    assert_eq!(harness.persisted(), 0);
+    let events = harness.received();
+    assert!(matches!(&*events[0], Event::DownloadFinished { uuid, path } if *uuid == download.uuid && path == "episode.mkv"));
+}
+
+#[actix_web::test]
+async fn an_error_status_fails_the_download() {
+    let harness = Harness::new();
+    let download = harness.download("/missing.mkv", Fixture::Status(404));
+
+    assert!(eventually(|| harness.status(&download).is_none()).await);
+    assert!(!harness.file("missing.mkv").exists());
+    assert_eq!(harness.persisted(), 0);
+    assert!(matches!(&*harness.received()[0], Event::DownloadFailed { .. }));
+}
+
+#[actix_web::test]
+async fn downloads_beyond_the_parallel_ones_are_queued() {
+    let harness = Harness::new();
+    let downloads: Vec<_> = (0..MAX_PARALLEL_DOWNLOADS + 1).map(|i| harness.download(&format!("/{}.mkv", i), slow(10 * 1024 * 1024, i as u8))).collect();
+
+    assert_eq!(harness.summary(), (MAX_PARALLEL_DOWNLOADS, 1));
+    assert!(eventually(|| (0..MAX_PARALLEL_DOWNLOADS).all(|i| harness.server.hits(&format!("/{}.mkv", i)) == 1)).await);
+    assert_eq!(harness.status(&downloads[MAX_PARALLEL_DOWNLOADS]), Some(Status::Created));
+    assert_eq!(harness.server.hits(&format!("/{}.mkv", MAX_PARALLEL_DOWNLOADS)), 0);
+}
+
+#[actix_web::test]
+async fn a_cancelled_download_frees_its_slot_for_the_queue() {
+    let harness = Harness::new();
+    let downloads: Vec<_> = (0..MAX_PARALLEL_DOWNLOADS + 1).map(|i| harness.download(&format!("/{}.mkv", i), slow(10 * 1024 * 1024, i as u8))).collect();
+    assert!(eventually(|| harness.server.hits("/0.mkv") == 1 && harness.file("0.mkv").exists()).await);
+
+    harness.manager.cancel_download(downloads[0].uuid);
+    assert_eq!(harness.status(&downloads[0]), Some(Status::Cancelled));
+    harness.report();
+
+    assert!(eventually(|| harness.status(&downloads[0]).is_none()).await);
+    assert!(eventually(|| !harness.file("0.mkv").exists()).await);
+    let queued = format!("/{}.mkv", MAX_PARALLEL_DOWNLOADS);
+    assert!(eventually(|| harness.server.hits(&queued) == 1).await);
+    assert_eq!(harness.status(&downloads[MAX_PARALLEL_DOWNLOADS]), Some(Status::Running));
+    assert_eq!(harness.summary(), (MAX_PARALLEL_DOWNLOADS, 0));
+    assert_eq!(harness.persisted(), MAX_PARALLEL_DOWNLOADS);
+    // cancelling is not a failure
+    assert!(harness.received().is_empty());
+}
+
+#[actix_web::test]
+async fn a_cancelled_queued_download_is_never_started() {
+    let harness = Harness::new();
+    let downloads: Vec<_> = (0..MAX_PARALLEL_DOWNLOADS + 1).map(|i| harness.download(&format!("/{}.mkv", i), slow(10 * 1024 * 1024, i as u8))).collect();
+
+    harness.manager.cancel_download(downloads[MAX_PARALLEL_DOWNLOADS].uuid);
+
+    assert_eq!(harness.summary(), (MAX_PARALLEL_DOWNLOADS, 0));
+    assert_eq!(harness.status(&downloads[MAX_PARALLEL_DOWNLOADS]), None);
+    assert_eq!(harness.persisted(), MAX_PARALLEL_DOWNLOADS);
+}
+
+#[actix_web::test]
+async fn a_cancelled_paused_download_removes_its_file() {
+    let harness = Harness::new();
+    let download = harness.download("/episode.mkv", slow(2 * 1024 * 1024, 7));
+    harness.report();
+    pause(&harness, &download).await;
+    assert!(harness.file("episode.mkv").exists());
+
+    harness.manager.cancel_download(download.uuid);
+
+    assert!(eventually(|| !harness.file("episode.mkv").exists()).await);
+    assert_eq!(harness.summary(), (0, 0));
+    assert_eq!(harness.persisted(), 0);
+}
+
+#[actix_web::test]
+async fn interrupted_downloads_resume_after_a_restart() {
+    let harness = Harness::new();
+    let download = harness.download("/episode.mkv", slow(1024 * 1024, 2));
+    assert!(eventually(|| harness.file("episode.mkv").exists()).await);
+
+    harness.report();
+    harness.manager.shutdown().await;
+    assert_eq!(harness.status(&download), None);
+    assert!(!harness.file("episode.mkv").exists());
+    assert_eq!(harness.persisted(), 1);
+
+    let harness = harness.restart();
+    harness.manager.resume_persisted();
+    assert_eq!(harness.summary(), (1, 0));
+    assert!(eventually(|| harness.status(&download).is_none()).await);
+    assert_eq!(harness.server.hits("/episode.mkv"), 2);
+    assert_eq!(fs::read(harness.file("episode.mkv")).unwrap(), body(1024 * 1024, 2));
+    assert_eq!(harness.persisted(), 0);
+}
+
+#[actix_web::test]
+async fn new_downloads_wait_while_paused() {
+    let harness = Harness::new();
+    harness.manager.set_paused("test", true);
+    harness.manager.set_paused("other", true);
+    let download = harness.download("/episode.mkv", Fixture::Body(body(1024, 3)));
+    assert_eq!(harness.summary(), (0, 1));
+
+    // only once all reasons are gone
+    harness.manager.set_paused("test", false);
+    assert_eq!(harness.summary(), (0, 1));
+    harness.manager.set_paused("other", false);
+    assert_eq!(harness.summary(), (1, 0));
+
+    assert!(eventually(|| harness.status(&download).is_none()).await);
+    assert_eq!(fs::read(harness.file("episode.mkv")).unwrap(), body(1024, 3));
+}
+
+#[actix_web::test]
+async fn paused_running_downloads_continue_where_they_stopped() {
+    let harness = Harness::new();
+    let download = harness.download("/episode.mkv", slow(2 * 1024 * 1024, 4));
+    harness.report();
+
+    let downloaded = pause(&harness, &download).await;
+    assert_eq!(harness.status(&download), Some(Status::Created));
+    assert_eq!(fs::metadata(harness.file("episode.mkv")).unwrap().len(), downloaded);
+    assert_eq!(harness.persisted(), 1);
+
+    harness.manager.set_paused("test", false);
+    assert!(eventually(|| harness.status(&download).is_none()).await);
+    assert_eq!(harness.server.hits("/episode.mkv"), 2);
+    assert_eq!(harness.server.header("/episode.mkv", "range"), Some(format!("bytes={}-", downloaded)));
+    assert_eq!(fs::read(harness.file("episode.mkv")).unwrap(), body(2 * 1024 * 1024, 4));
+}
+
+#[actix_web::test]
+async fn paused_downloads_start_over_without_ranges() {
+    let harness = Harness::new();
+    let download = harness.download("/episode.mkv", without_ranges(2 * 1024 * 1024, 5));
+    harness.report();
+
+    pause(&harness, &download).await;
+    harness.manager.set_paused("test", false);
+
+    assert!(eventually(|| harness.status(&download).is_none()).await);
+    assert_eq!(harness.server.hits("/episode.mkv"), 2);
+    assert_eq!(fs::read(harness.file("episode.mkv")).unwrap(), body(2 * 1024 * 1024, 5));
+}
+
+#[actix_web::test]
+async fn the_headers_of_the_download_are_sent() {
+    let harness = Harness::new();
+    let url = harness.server.serve("/episode.mkv", Fixture::Body(body(1024, 5)));
+    let headers = RequestHeaders { user_agent: Some("Mozilla/5.0".to_string()), referer: Some("https://example.com/episode".to_string()) };
+    let download = harness.manager.trigger_download(url, "episode.mkv".to_string(), headers).unwrap();
+
+    assert!(eventually(|| harness.status(&download).is_none()).await);
+    assert_eq!(harness.server.header("/episode.mkv", "user-agent").as_deref(), Some("Mozilla/5.0"));
+    assert_eq!(harness.server.header("/episode.mkv", "referer").as_deref(), Some("https://example.com/episode"));
+}
+
+#[actix_web::test]
+async fn the_headers_are_kept_for_a_restart() {
+    let harness = Harness::new();
+    let url = harness.server.serve("/episode.mkv", slow(1024 * 1024, 6));
+    let headers = RequestHeaders { user_agent: Some("Mozilla/5.0".to_string()), referer: None };
+    let download = harness.manager.trigger_download(url, "episode.mkv".to_string(), headers).unwrap();
+    assert!(eventually(|| harness.file("episode.mkv").exists()).await);
+    harness.report();
+    harness.manager.shutdown().await;
+
+    let harness = harness.restart();
+    harness.manager.resume_persisted();
+    assert!(eventually(|| harness.status(&download).is_none()).await);
+    assert_eq!(harness.server.hits("/episode.mkv"), 2);
+    assert_eq!(harness.server.header("/episode.mkv", "user-agent").as_deref(), Some("Mozilla/5.0"));
+    assert_eq!(harness.server.header("/episode.mkv", "referer"), None);
+}
+
+fn rules() -> Vec<(Regex, String)> {
+    vec![(glob_regex("*S01E*"), "Show/Season 1".to_string())]
+}
+
+#[test]
+fn a_moved_download_keeps_its_folders() {
+    assert_eq!(Some(PathBuf::from("Show/Season 1/a.S01E01.mkv")), moved_path(Path::new("a.S01E01.mkv"), &rules()));
+    assert_eq!(Some(PathBuf::from("Show/Season 1/batch/2024/a.S01E01.mkv")), moved_path(Path::new("batch/2024/a.S01E01.mkv"), &rules()));
+}
+
+#[test]
+fn a_download_in_the_subfolder_is_not_moved() {
+    assert_eq!(None, moved_path(Path::new("Show/Season 1/a.S01E01.mkv"), &rules()));
+    assert_eq!(None, moved_path(Path::new("Show/Season 1/extras/a.S01E01.mkv"), &rules()));
+    assert_eq!(None, moved_path(Path::new("a.S02E01.mkv"), &rules()));
+}
@@ -1,81 +1,315 @@
-use std::env;
-use log::info;
-use std::time::{Duration, Instant};
-use reqwest::blocking::Client;
+use crate::store::{Repository, Store};
+
+use actix_web::web::Bytes;
+use futures::future::{try_join3, BoxFuture};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use reqwest::Client;
 use std::sync::{Arc, Mutex};
+use tokio::sync::{self, Notify};
+use tokio::time::timeout;
 
-// TODO switch to non-blocking reqwest
 // TODO more logging
 
-pub struct DvbC {
+// how often the channels are checked, they are fetched again if they are older than an hour or came from the store
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub type FetchError = Box<dyn Error + Send + Sync>;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Playlist {
+    TvHd,
+    TvSd,
+    Radio,
+}
+
+pub trait PlaylistSource: Send + Sync {
+    fn fetch_playlist(&self, playlist: Playlist) -> BoxFuture<'_, Result<String, FetchError>>;
+}
+
+pub struct RouterPlaylists {
     client: Client,
     url_hd: String,
     url_sd: String,
     url_radio: String,
+}
+
+impl RouterPlaylists {
+    pub fn new(router_url: &str) -> Self {
+        Self {
+            client:    Client::builder().timeout(Duration::from_secs(2)).build().unwrap(),
+            url_hd:    format!("{}{}", router_url, "/dvb/m3u/tvhd.m3u"),
+            url_sd:    format!("{}{}", router_url, "/dvb/m3u/tvsd.m3u"),
+            url_radio: format!("{}{}", router_url, "/dvb/m3u/radio.m3u"),
+        }
+    }
+}
+
+impl PlaylistSource for RouterPlaylists {
+    fn fetch_playlist(&self, playlist: Playlist) -> BoxFuture<'_, Result<String, FetchError>> {
+        let url = match playlist {
+            Playlist::TvHd  => &self.url_hd,
+            Playlist::TvSd  => &self.url_sd,
+            Playlist::Radio => &self.url_radio,
+        };
+        // an error page would look like a playlist without channels
+        Box::pin(async move { Ok(self.client.get(url).send().await?.error_for_status()?.text().await?) })
+    }
+}
+
+pub struct DvbC {
+    source: Box<dyn PlaylistSource>,
     channels: Mutex<Option<Arc<Channels>>>,
+    persisted: Repository<Vec<Channel>>,
+    // channel names, the router lists them in no useful order
+    order: Repository<Vec<String>>,
+    settings: Repository<ChannelSettings>,
+    update_requested: Notify,
+    // one fetch at a time, holds when the last one that succeeded was started
+    last_fetch: sync::Mutex<Option<SystemTime>>,
 }
 
 pub struct Channels {
     pub tv:    Vec<Channel>,
     pub radio: Vec<Channel>,
-    fetched_at: Instant
+    fetched_at: SystemTime,
+    persisted: bool, // loaded from the store because the router could not be reached
+    // the listings are requested a lot more often than the channels change
+    tv_names: Bytes,
+    radio_names: Bytes,
+}
+
+// the channels listed first, in this order, the others follow in the order of the router
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct ChannelOrder {
+    #[serde(default)]
+    pub tv: Vec<String>,
+    #[serde(default)]
+    pub radio: Vec<String>,
 }
 
-#[derive(Clone, PartialEq)]
+// how the player plays one channel, unset ones are left to mpv
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct ChannelSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deinterlace: Option<bool>,
+    // 1 is the first one, channels list the audio description or a second language after it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_track: Option<u32>,
+    // in dB, for the channels that are a lot louder or quieter than the rest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume_offset: Option<i32>,
+    // e.g. "16:9", for channels that send 4:3 with black bars
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aspect: Option<String>,
+}
+
+impl ChannelSettings {
+
+    pub fn repository(store: Arc<Store>) -> Repository<ChannelSettings> {
+        Repository::new(store, "dvbc_settings")
+    }
+
+    pub fn mpv_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(deinterlace) = self.deinterlace {
+            args.push(format!("--deinterlace={}", if deinterlace { "yes" } else { "no" }));
+        }
+        if let Some(track) = self.audio_track {
+            args.push(format!("--aid={}", track));
+        }
+        // added to the filters, so it stacks with the night mode
+        if let Some(offset) = self.volume_offset.filter(|offset| *offset != 0) {
+            args.push(format!("--af-add=lavfi=[volume={}dB]", offset));
+        }
+        if let Some(aspect) = &self.aspect {
+            args.push(format!("--video-aspect-override={}", aspect));
+        }
+        args
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Channel {
     pub name: String,
     pub url: String,
 }
 
+impl Channels {
+
+    fn new(tv: Vec<Channel>, radio: Vec<Channel>, persisted: bool) -> Self {
+        let names = |channels: &[Channel]| Bytes::from(serde_json::to_vec(&channels.iter().map(|channel| &channel.name).collect::<Vec<_>>()).unwrap());
+        let (tv_names, radio_names) = (names(&tv), names(&radio));
+        Channels { tv, radio, fetched_at: SystemTime::now(), persisted, tv_names, radio_names }
+    }
+
+    // the names of the tv channels as a json array
+    pub fn tv_names(&self) -> Bytes {
+        self.tv_names.clone()
+    }
+
+    // the names of the radio channels as a json array
+    pub fn radio_names(&self) -> Bytes {
+        self.radio_names.clone()
+    }
+
+    // changes whenever the channels are fetched again, for the ETags of the listings
+    pub fn version(&self) -> u128 {
+        self.fetched_at.duration_since(UNIX_EPOCH).map_or(0, |fetched| fetched.as_millis())
+    }
+
+}
+
 fn needs_update(channels: &Option<Arc<Channels>>) -> bool {
-    channels.is_none() || Instant::now().duration_since(channels.as_ref().unwrap().fetched_at).as_secs() > 60*60
+    match channels {
+        None => true,
+        Some(channels) => channels.persisted || channels.fetched_at.elapsed().map_or(true, |age| age.as_secs() > 60*60),
+    }
+}
+
+// a stable sort, so the channels without a position keep the order they came in
+fn ordered(mut channels: Vec<Channel>, order: &[String]) -> Vec<Channel> {
+    channels.sort_by_key(|channel| order.iter().position(|name| *name == channel.name).unwrap_or(order.len()));
+    channels
+}
+
+fn parse_playlist(text: &str) -> Vec<Channel> {
+    let mut lines = text.lines().skip(1);
+
+    let mut channels = Vec::new();
+    while let (Some(first), Some(_second), Some(third)) = (lines.next(), lines.next(), lines.next()) {
+        channels.push(Channel {name: String::from(&first[10..]), url: String::from(third)})
+    }
+    channels
 }
 
 impl DvbC {
 
-    pub fn new() -> DvbC {
-        let router_url = env::var("ROUTER_URL").expect("ROUTER_URL not set");
-        return DvbC {
-            client:    Client::builder().timeout(Duration::from_secs(2)).build().unwrap(),
-            url_hd:    format!("{}{}", router_url, "/dvb/m3u/tvhd.m3u"),
-            url_sd:    format!("{}{}", router_url, "/dvb/m3u/tvsd.m3u"),
-            url_radio: format!("{}{}", router_url, "/dvb/m3u/radio.m3u"),
-            channels:  Mutex::new(None),
+    pub fn new(source: impl PlaylistSource + 'static, store: Arc<Store>) -> DvbC {
+        let dvbc = DvbC {
+            source:           Box::new(source),
+            channels:         Mutex::new(None),
+            persisted:        Repository::new(store.clone(), "dvbc_channels"),
+            order:            Repository::new(store.clone(), "dvbc_order"),
+            settings:         ChannelSettings::repository(store),
+            update_requested: Notify::new(),
+            last_fetch:       sync::Mutex::new(None),
         };
+        // until the router answers for the first time
+        *dvbc.channels.lock().unwrap() = dvbc.load_persisted().map(Arc::new);
+        dvbc
     }
 
+    // the last channels fetched by keep_updated, this never waits for the router
     pub fn get_channels(&self) -> Option<Arc<Channels>> {
-        let mut lock = self.channels.lock().unwrap();
-        if needs_update(&*lock) {
-            *lock = self.fetch_all_channels().ok().map(|c| Arc::new(c));
+        self.channels.lock().unwrap().clone()
+    }
+
+    pub fn get_order(&self) -> ChannelOrder {
+        ChannelOrder { tv: self.order.get("tv").unwrap_or_default(), radio: self.order.get("radio").unwrap_or_default() }
+    }
+
+    // applies to the current channels right away, and to every fetch after
+    pub fn set_order(&self, order: ChannelOrder) {
+        self.order.put("tv", &order.tv);
+        self.order.put("radio", &order.radio);
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(current) = channels.as_ref() {
+            *channels = Some(Arc::new(Channels::new(ordered(current.tv.clone(), &order.tv), ordered(current.radio.clone(), &order.radio), current.persisted)));
         }
-        return lock.clone();
+        // channels that lost their position go back to where the router has them
+        self.update_requested.notify_one();
     }
 
-    fn fetch_all_channels(&self) -> Result<Channels, reqwest::Error> {
-        let mut tv =   self.fetch_category(&self.url_hd)?;
-        tv.append(&mut self.fetch_category(&self.url_sd)?);
-        let radio  =   self.fetch_category(&self.url_radio)?;
-        info!("Loaded DvbC: {} TV & {} Radio Channels", tv.len(), radio.len());
-        Ok(Channels {
-            tv,
-            radio: radio,
-            fetched_at: Instant::now()
-        })
-    }
-
-    fn fetch_category(&self, url: &str) -> Result<Vec<Channel>, reqwest::Error> {
-        let text = self.client.get(url).send()?.text()?;
-        let mut lines = text.lines().skip(1);
-        
-        let mut channels = Vec::new();
+    pub fn get_settings(&self, channel: &str) -> ChannelSettings {
+        self.settings.get(channel).unwrap_or_default()
+    }
+
+    pub fn set_settings(&self, channel: &str, settings: &ChannelSettings) {
+        if *settings == ChannelSettings::default() {
+            self.settings.remove(channel);
+        } else {
+            self.settings.put(channel, settings);
+        }
+    }
+
+    // fetches the channels in the background whenever they are outdated or the cache was cleared
+    pub async fn keep_updated(self: Arc<Self>) {
+        let mut requested = false;
         loop {
-            if let (Some(first), Some(_second), Some(third)) = (lines.next(), lines.next(), lines.next()) {
-                channels.push(Channel {name: String::from(&first[10..]), url: String::from(third)})
-            } else {
-                break;
+            let outdated = needs_update(&self.channels.lock().unwrap());
+            if requested || outdated {
+                let _ = self.update(&mut *self.last_fetch.lock().await).await;
             }
+            requested = timeout(CHECK_INTERVAL, self.update_requested.notified()).await.is_ok();
+        }
+    }
+
+    // fetches the channels right away, e.g. after a new channel scan on the router
+    // requests that come in together share one fetch, any fetch that started after the request is fresh enough
+    pub async fn fetch_fresh(&self) -> Result<Arc<Channels>, FetchError> {
+        let requested_at = SystemTime::now();
+        let mut last_fetch = self.last_fetch.lock().await;
+        if last_fetch.is_none_or(|started| started < requested_at) {
+            self.update(&mut last_fetch).await?;
+        }
+        self.get_channels().ok_or_else(|| "the channels were fetched, but are gone".into())
+    }
+
+    async fn update(&self, last_fetch: &mut Option<SystemTime>) -> Result<(), FetchError> {
+        let started = SystemTime::now();
+        match self.fetch_all_channels().await {
+            Ok(channels) => {
+                self.persisted.put("tv", &channels.tv);
+                self.persisted.put("radio", &channels.radio);
+                *self.channels.lock().unwrap() = Some(Arc::new(channels));
+                *last_fetch = Some(started);
+                Ok(())
+            },
+            Err(err) => {
+                warn!("Could not load DvbC Channels: {}", err);
+                let mut lock = self.channels.lock().unwrap();
+                if lock.is_none() {
+                    *lock = self.load_persisted().map(Arc::new);
+                }
+                Err(err)
+            },
         }
-        Ok(channels)
+    }
+
+    // the current channels stay until the new ones are fetched, so they can still be used if the router is not reachable
+    pub fn clear_cache(&self) {
+        info!("Clearing DvbC Channel cache");
+        self.update_requested.notify_one();
+    }
+
+    fn load_persisted(&self) -> Option<Channels> {
+        let tv = self.persisted.get("tv")?;
+        let radio = self.persisted.get("radio")?;
+        info!("Using persisted DvbC Channels");
+        let order = self.get_order();
+        Some(Channels::new(ordered(tv, &order.tv), ordered(radio, &order.radio), true))
+    }
+
+    async fn fetch_all_channels(&self) -> Result<Channels, FetchError> {
+        // at the same time, so a slow router only costs one timeout
+        let (mut tv, mut sd, radio) = try_join3(
+            self.fetch_category(Playlist::TvHd),
+            self.fetch_category(Playlist::TvSd),
+            self.fetch_category(Playlist::Radio),
+        ).await?;
+        tv.append(&mut sd);
+        info!("Loaded DvbC: {} TV & {} Radio Channels", tv.len(), radio.len());
+        let order = self.get_order();
+        Ok(Channels::new(ordered(tv, &order.tv), ordered(radio, &order.radio), false))
+    }
+
+    async fn fetch_category(&self, playlist: Playlist) -> Result<Vec<Channel>, FetchError> {
+        Ok(parse_playlist(&self.source.fetch_playlist(playlist).await?))
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,79 @@
+use super::*;
+use crate::testing::{Fixture, MockServer, TempFolder};
+
+fn playlist(channels: &[(&str, &str)]) -> Fixture {
+    let entries: String = channels.iter().map(|(name, url)| format!("#EXTINF:0,{}\n#EXTVLCOPT:network-caching=1000\n{}\n", name, url)).collect();
+    Fixture::Body(format!("#EXTM3U\n{}", entries).into_bytes())
+}
+
+// a router like the FRITZ!Box, with its three playlists
+fn router() -> (MockServer, String) {
+    let server = MockServer::start();
+    let url = server.serve("/dvb/m3u/tvhd.m3u", playlist(&[("Das Erste HD", "rtsp://router/1"), ("ZDF HD", "rtsp://router/2")]));
+    server.serve("/dvb/m3u/tvsd.m3u", playlist(&[("arte", "rtsp://router/3")]));
+    server.serve("/dvb/m3u/radio.m3u", playlist(&[("Radio1", "rtsp://router/4")]));
+    let router_url = url.trim_end_matches("/dvb/m3u/tvhd.m3u").to_string();
+    (server, router_url)
+}
+
+fn names(channels: &[Channel]) -> Vec<&str> {
+    channels.iter().map(|channel| channel.name.as_str()).collect()
+}
+
+fn open_store(folder: &TempFolder) -> Arc<Store> {
+    Arc::new(Store::open(folder.join("store.json")).unwrap())
+}
+
+#[actix_web::test]
+async fn fetches_the_channels_from_the_router() {
+    let (server, router_url) = router();
+    let folder = TempFolder::new();
+    let dvbc = DvbC::new(RouterPlaylists::new(&router_url), open_store(&folder));
+    assert!(dvbc.get_channels().is_none());
+
+    let channels = dvbc.fetch_fresh().await.unwrap();
+
+    assert_eq!(vec!["Das Erste HD", "ZDF HD", "arte"], names(&channels.tv));
+    assert_eq!(vec!["Radio1"], names(&channels.radio));
+    assert_eq!("rtsp://router/3", channels.tv[2].url);
+    assert!(!channels.persisted);
+    assert_eq!(1, server.hits("/dvb/m3u/radio.m3u"));
+}
+
+#[actix_web::test]
+async fn puts_the_ordered_channels_first() {
+    let (_server, router_url) = router();
+    let folder = TempFolder::new();
+    let dvbc = DvbC::new(RouterPlaylists::new(&router_url), open_store(&folder));
+    dvbc.set_order(ChannelOrder { tv: vec!["arte".to_string(), "ZDF HD".to_string()], radio: Vec::new() });
+
+    let channels = dvbc.fetch_fresh().await.unwrap();
+
+    assert_eq!(vec!["arte", "ZDF HD", "Das Erste HD"], names(&channels.tv));
+}
+
+#[actix_web::test]
+async fn uses_the_persisted_channels_while_the_router_fails() {
+    let (_server, router_url) = router();
+    let folder = TempFolder::new();
+    DvbC::new(RouterPlaylists::new(&router_url), open_store(&folder)).fetch_fresh().await.unwrap();
+
+    // like a restart while the router shows an error page
+    let failing = MockServer::start();
+    let failing_url = failing.serve("/dvb/m3u/tvhd.m3u", Fixture::Status(500));
+    let dvbc = DvbC::new(RouterPlaylists::new(failing_url.trim_end_matches("/dvb/m3u/tvhd.m3u")), open_store(&folder));
+
+    assert!(dvbc.fetch_fresh().await.is_err());
+    let channels = dvbc.get_channels().unwrap();
+    assert!(channels.persisted);
+    assert_eq!(vec!["Das Erste HD", "ZDF HD", "arte"], names(&channels.tv));
+}
+
+#[actix_web::test]
+async fn an_unreachable_router_without_persisted_channels_has_none() {
+    let folder = TempFolder::new();
+    let dvbc = DvbC::new(RouterPlaylists::new("http://127.0.0.1:9"), open_store(&folder));
+
+    assert!(dvbc.fetch_fresh().await.is_err());
+    assert!(dvbc.get_channels().is_none());
+}
@@ -1,172 +1,293 @@
-use super::files::sanitize_path;
+use super::files::{self, PathError, Root};
 use super::dvbc::Channel;
+use super::process;
+use super::progress;
+use super::tuners::{TunerLease, Tuners};
 
 use core::fmt;
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::env;
 use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::process::Child;
 use std::time::SystemTimeError;
 use std::time::{SystemTime, Duration, Instant};
 use std::sync::{Arc, Mutex};
-use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::error::Error;
 use actix_web::rt::spawn;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use actix_web::rt::task::JoinHandle;
-use actix_web::rt::time::interval;
+use actix_web::rt::time::sleep;
 use itertools::Itertools;
 use log::error;
-use log::info;
-use serde::Serialize;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct PreviewSettings {
+    pub url: String, // how the frontend gets the images
+    pub max_bytes: u64,
+    // larger images are left to the url, a request for the whole grid would get too big for the TV browser
+    pub max_inline_bytes: u64,
+}
+
+impl PreviewSettings {
 
-lazy_static! {
-    static ref WEB_BASE_FOLDER : String = env::var("WEB_BASE_FOLDER").expect("WEB_BASE_FOLDER not set");
+    // a PREVIEW_FOLDER outside of the WEB_BASE_FOLDER is served by HomeBack
+    pub fn from_env() -> Self {
+        let url = env::var("PREVIEW_URL").unwrap_or_else(|_| match env::var("PREVIEW_FOLDER") {
+            Ok(_) => "/api/v1/dvbc/tv/preview".to_string(),
+            Err(_) => "/img/tv/preview".to_string(),
+        });
+        let number = |name: &str, default: u64| env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default);
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            max_bytes: number("PREVIEW_MAX_MB", 50) * 1024 * 1024,
+            max_inline_bytes: number("PREVIEW_INLINE_MAX_KB", 100) * 1024,
+        }
+    }
 }
 
 pub struct DvbCPreviews {
+    settings: PreviewSettings,
     waiting: Arc<Mutex<VecDeque<Channel>>>,
-    scheduler: Mutex<JoinHandle<()>>,
+    // wakes the scheduler when something was added to waiting
+    requested: Arc<Notify>,
+    scheduler: Mutex<Option<JoinHandle<()>>>,
+    // the channel the player shows, its preview is a screenshot instead of another stream from the tuner
+    tuned: Arc<Mutex<Option<String>>>,
+    tuners: Arc<Tuners>,
+    // requests still queue up, but nothing new is created
+    paused: Arc<AtomicBool>,
+    // the files of the channels asked for, only these are ever deleted
+    previews: Previews,
 }
 
+type Previews = Arc<Mutex<HashSet<PathBuf>>>;
+
 #[derive(Serialize)]
 pub struct ChannelPreview {
     url: String,
-    created: Option<u128>,
+    created: Option<u128>, // None while the first one is created
+    // the image is older than a few minutes, a new one is on its way
+    stale: bool,
+    // a data url of the image, with ?inline=true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+}
+
+// how soon the frontend needs a preview, the scheduler takes the waiting channels from the back
+#[derive(Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    // on screen right now, runs before everything that waits
+    Visible,
+    // speculative, e.g. the next page of the grid, runs in the order it was requested
+    #[default]
+    Prefetch,
 }
 
 enum FileState {
     New(u128),
-    Old,
+    Old(u128),
     Absent,
 }
 
 impl DvbCPreviews {
 
-    pub fn new() -> Self {
-        Self::clear_preview_dir().unwrap();
+    pub fn from_env(tuners: Arc<Tuners>) -> io::Result<Self> {
+        Self::new(tuners, PreviewSettings::from_env())
+    }
 
-        Self {
+    pub fn new(tuners: Arc<Tuners>, settings: PreviewSettings) -> io::Result<Self> {
+        let folder = Root::Preview.folder();
+        fs::create_dir_all(folder).map_err(|error| io::Error::new(error.kind(), format!("could not create the PREVIEW_FOLDER {:?}: {}", folder, error)))?;
+
+        Ok(Self {
+            settings,
             waiting: Arc::new(Mutex::new(VecDeque::with_capacity(7))),
-            scheduler: Mutex::new(spawn(async {})),
-        }        
+            requested: Arc::new(Notify::new()),
+            scheduler: Mutex::new(None),
+            tuned: Arc::new(Mutex::new(None)),
+            tuners,
+            paused: Arc::default(),
+            previews: Arc::default(),
+        })
+    }
+
+    pub fn set_tuned(&self, channel: Option<String>) {
+        *self.tuned.lock().unwrap() = channel;
     }
 
-    fn clear_preview_dir() -> Result<(), io::Error> {
-        let path = sanitize_path(&format!("{}/img/tv/preview", &*WEB_BASE_FOLDER)).into_os_string().into_string().unwrap();
-        fs::create_dir_all(&path)?;
-        fs::remove_dir_all(&path)?;
-        fs::create_dir(&path)
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+        if !paused {
+            self.requested.notify_one();
+        }
     }
 
-    // TODO on startup, clear the entire folder
+    // only the previews, the folder may be shared with other files
+    pub fn clear(&self) -> Result<(), io::Error> {
+        info!("Clearing DvbC Previews");
+        self.waiting.lock().unwrap().clear();
+        clear_previews(&self.previews)
+    }
 
-    pub fn get_preview(&self, channel: &Channel) -> Result<ChannelPreview, PreviewError> {
-        // TODO this is not as efficient as it could be w.r.t. handling and copying strings
-        let url = sanitize_path(&format!("/img/tv/preview/{}.jpg", &channel.name.replace(" ", "_"))).into_os_string().into_string().unwrap();
-        let path = format!("{}{}", &*WEB_BASE_FOLDER, &url);
+    // starts the scheduler, which runs until shutdown and creates the requested previews
+    pub fn start(&self) {
+        *self.scheduler.lock().unwrap() = Some(spawn(DvbcScheduler::run(self.waiting.clone(), self.requested.clone(), self.tuned.clone(), self.tuners.clone(), self.paused.clone(), self.previews.clone(), self.settings.max_bytes)));
+    }
 
-        let file_exists = match Self::get_preview_from_disk(&path)? {
-            FileState::New(created) => return Ok(ChannelPreview{url, created: Some(created)}),
-            FileState::Old => true,
-            FileState::Absent => false,
+    pub async fn shutdown(&self) {
+        self.waiting.lock().unwrap().clear();
+        let scheduler = self.scheduler.lock().unwrap().take();
+        if let Some(scheduler) = scheduler {
+            scheduler.abort();
+            let _ = scheduler.await; // the scheduler kills its ffmpeg children when it is dropped
+        }
+    }
+
+    pub fn get_preview(&self, channel: &Channel, priority: Priority, inline: bool) -> Result<ChannelPreview, PreviewError> {
+        // TODO this is not as efficient as it could be w.r.t. handling and copying strings
+        let url = format!("{}/{}", self.settings.url, preview_file(channel));
+        let path = files::resolve(Root::Preview, preview_file(channel))?;
+        // also the ones left from before a restart, once the grid asks for them again
+        self.previews.lock().unwrap().insert(path.clone());
+
+        // an old preview is still shown until the new one replaced it
+        let created = match Self::get_preview_from_disk(&path)? {
+            FileState::New(created) => {
+                let image = if inline { inline_image(&path, self.settings.max_inline_bytes) } else { None };
+                return Ok(ChannelPreview{url, created: Some(created), stale: false, image});
+            },
+            FileState::Old(created) => Some(created),
+            FileState::Absent => None,
         };
 
-        self.request_preview(channel, file_exists);
-        Ok(ChannelPreview{url, created: None})
+        self.request_preview(channel, created.is_some(), priority);
+        let image = if inline && created.is_some() { inline_image(&path, self.settings.max_inline_bytes) } else { None };
+        Ok(ChannelPreview{url, created, stale: created.is_some(), image})
     }
 
-    fn get_preview_from_disk(path: &str) -> Result<FileState, PreviewError> {
-        let created = match fs::metadata(&path) {
+    fn get_preview_from_disk(path: &Path) -> Result<FileState, PreviewError> {
+        let created = match fs::metadata(path) {
             Ok(metadata) => metadata.created()?,
             Err(_) => return Ok(FileState::Absent)
         };
 
+        let millis = created.duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
         if created.elapsed().unwrap().as_secs() <= 60*5 {
-            Ok(FileState::New(created.duration_since(SystemTime::UNIX_EPOCH)?.as_millis()))
+            Ok(FileState::New(millis))
         } else {
-            Ok(FileState::Old)
+            Ok(FileState::Old(millis))
         }
     }
 
-    fn request_preview(&self, channel: &Channel, file_exists: bool) {
+    fn request_preview(&self, channel: &Channel, file_exists: bool, priority: Priority) {
         {
             let mut waiting = self.waiting.lock().unwrap();
-            if ( waiting.len() <= 5 || (!file_exists && waiting.len() <= 10) ) &&
-                waiting.iter().find(|wait| wait.name == channel.name).is_none()
-            {
-                waiting.push_front(channel.clone());
+            match priority {
+                Priority::Prefetch => if ( waiting.len() <= 5 || (!file_exists && waiting.len() <= 10) ) &&
+                    waiting.iter().find(|wait| wait.name == channel.name).is_none()
+                {
+                    waiting.push_front(channel.clone());
+                },
+                // the last one shown is what the user scrolled to, so it jumps ahead of the ones before it too
+                Priority::Visible => {
+                    waiting.retain(|wait| wait.name != channel.name);
+                    waiting.push_back(channel.clone());
+                    if waiting.len() > 10 {
+                        waiting.pop_front();
+                    }
+                },
             }
         }
-        self.how_is_the_scheduler_doing();
-    }
-
-    // asking the important questions
-    fn how_is_the_scheduler_doing(&self) {
-        let mut scheduler = self.scheduler.lock().unwrap();
-        if scheduler.is_finished() {
-            *scheduler = spawn(DvbcScheduler::start(self.waiting.clone()));
-        }
+        // if the scheduler is busy, the permit is kept until it waits again
+        self.requested.notify_one();
     }
 }
 
 struct DvbcScheduler {
-    running: [Option<(Child, Channel, Instant)>; 1],
+    running: [Option<(Child, Channel, Instant, TunerLease)>; 1],
     waiting: Arc<Mutex<VecDeque<Channel>>>,
+    tuned: Arc<Mutex<Option<String>>>,
+    tuners: Arc<Tuners>,
+    paused: Arc<AtomicBool>,
+    previews: Previews,
+    max_bytes: u64,
 }
 
 impl DvbcScheduler {
 
-    async fn start(waiting: Arc<Mutex<VecDeque<Channel>>>) {
+    async fn run(waiting: Arc<Mutex<VecDeque<Channel>>>, requested: Arc<Notify>, tuned: Arc<Mutex<Option<String>>>, tuners: Arc<Tuners>, paused: Arc<AtomicBool>, previews: Previews, max_bytes: u64) {
         info!("starting DvbC Preview Sceduler");
 
-        let mut scheduler = DvbcScheduler{ running: [None], waiting };        
-        let mut interval = interval(Duration::from_secs(1));
-        while scheduler.schedule() {
-            interval.tick().await;
+        let mut scheduler = DvbcScheduler{ running: [None], waiting, tuned, tuners, paused, previews, max_bytes };        
+        loop {
+            if scheduler.schedule() {
+                sleep(SCHEDULE_INTERVAL).await;
+            } else {
+                requested.notified().await;
+            }
         }
-
-        info!("stopping DvbC Preview Sceduler");
     }
 
     fn schedule(&mut self) -> bool {
+        let paused = self.paused.load(Ordering::Relaxed);
+        if !paused {
+            self.screenshot_tuned();
+        }
+
         // collect names
         let running_channels = self.running.iter()
             .flat_map(|run| run.iter())
-            .map(|(_, channel, _)| channel.name.clone())
+            .map(|(_, channel, _, _)| channel.name.clone())
             .collect_vec();
 
         // for each in running, if child is done replace with None
         for i in 0..self.running.len() {
-            if let Some((child, channel, instant)) = &mut self.running[i] {
+            if let Some((child, channel, instant, _)) = &mut self.running[i] {
                
                 match child.try_wait() {
                     Ok(Some(status)) => {
                         info!("ffmpeg for {} finished with status {} in {}s", channel.name, status, instant.elapsed().as_secs());
+                        process::unregister(child.id());
                         self.running[i] = None;
+                        evict_previews(&self.previews, self.max_bytes);
                     },
                     Ok(None) => {},
                     Err(err) => {
                         error!("Error getting status of ffmpeg process for {}: {}", channel.name, err);
+                        process::unregister(child.id());
                         self.running[i] = None;
                     },
                 }
             }
         }
 
+        // the running ones are still waited for, resuming wakes the scheduler again
+        if paused {
+            return self.running.iter().any(Option::is_some);
+        }
+
         // count empty slots
         let empty_slots = self.running.iter().filter(|run| run.is_none()).count();
         if empty_slots == 0 {
-            let waiting = self.waiting.lock().unwrap();
-            return !waiting.is_empty();
+            return true;
         }
 
         // remove names from waiting and pop from queue
         let mut to_run = {
             let mut waiting = self.waiting.lock().unwrap();
-            waiting.retain(|channel| !running_channels.iter().any(|name| channel.name == *name));
+            waiting.retain(|channel| !running_channels.contains(&channel.name));
             let waiting_len = waiting.len(); // TODO why do I need this var? sometimes rust confuses me
-            waiting.split_off(waiting_len - empty_slots)
+            waiting.split_off(waiting_len.saturating_sub(empty_slots))
         };
         
         // start preview creation
@@ -176,8 +297,22 @@ impl DvbcScheduler {
             }
             if self.running[i].is_none() {
                 let channel = to_run.pop_back().unwrap();
+                // the player and teletext come first, the preview waits for a free tuner
+                let tuner = match self.tuners.acquire("preview", &channel.name) {
+                    Ok(tuner) => tuner,
+                    Err(_) => {
+                        debug!("no free tuner for the preview of {}", channel.name);
+                        let mut waiting = self.waiting.lock().unwrap();
+                        waiting.push_back(channel);
+                        waiting.extend(to_run.drain(..).rev());
+                        break;
+                    },
+                };
                 match self.create_preview(&channel) {
-                    Ok(child) => self.running[i] = Some(( child, channel, Instant::now() )),
+                    Ok(child) => {
+                        process::register("preview", child.id());
+                        self.running[i] = Some(( child, channel, Instant::now(), tuner ))
+                    },
                     Err(err) => error!("Error creating ffmpeg child process: {}", err),
                 }
             }
@@ -187,13 +322,43 @@ impl DvbcScheduler {
             panic!("there were less open slots then channels removed from waiting. This should never happen!")
         }
 
-        true
+        // whether it has to check again soon
+        self.running.iter().any(Option::is_some) || !self.waiting.lock().unwrap().is_empty()
+    }
+
+    // the tuner only has a few streams, so the playing channel is taken from the player
+    fn screenshot_tuned(&self) {
+        let tuned = match self.tuned.lock().unwrap().clone() {
+            Some(tuned) => tuned,
+            None => return,
+        };
+        let channel = {
+            let mut waiting = self.waiting.lock().unwrap();
+            match waiting.iter().position(|channel| channel.name == tuned) {
+                Some(i) => waiting.remove(i).unwrap(),
+                None => return,
+            }
+        };
+        let screenshot = files::resolve(Root::Preview, preview_file(&channel))
+            .map_err(io::Error::from)
+            .and_then(|path| progress::mpv_command(serde_json::json!(["screenshot-to-file", path.to_string_lossy(), "video"])));
+        match screenshot {
+            Ok(_) => {
+                info!("took a screenshot of {} for its preview", channel.name);
+                evict_previews(&self.previews, self.max_bytes);
+            },
+            Err(err) => {
+                // e.g. mpv is still starting, then it is tuned a second time after all
+                debug!("could not take a screenshot of {}, starting ffmpeg instead: {}", channel.name, err);
+                self.waiting.lock().unwrap().push_back(channel);
+            },
+        }
     }
 
     fn create_preview(&self, channel: &Channel) -> Result<Child, io::Error> {
-        let path = sanitize_path(&format!("{}/img/tv/preview/{}.jpg", &*WEB_BASE_FOLDER, &channel.name.replace(" ", "_"))).into_os_string().into_string().unwrap();
+        let path = files::resolve(Root::Preview, preview_file(channel))?;
         info!("calling ffmpeg to: {:?}", path);
-        Command::new("ffmpeg")
+        process::scoped_command("preview", "ffmpeg")
             .arg("-hide_banner")
             .arg("-loglevel").arg("panic")
             .arg("-y")
@@ -207,6 +372,65 @@ impl DvbcScheduler {
     }
 }
 
+fn preview_file(channel: &Channel) -> String {
+    format!("{}.jpg", channel.name.replace([' ', '/', '\\'], "_"))
+}
+
+fn previews_on_disk(previews: &Previews) -> Vec<(PathBuf, fs::Metadata)> {
+    previews.lock().unwrap().iter()
+        .filter_map(|path| Some((path.clone(), fs::metadata(path).ok()?)))
+        .collect()
+}
+
+fn clear_previews(previews: &Previews) -> Result<(), io::Error> {
+    for (path, _) in previews_on_disk(previews) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// a preview that is looked at is recreated after a few minutes, so the oldest ones are the least recently used
+fn evict_previews(previews: &Previews, max_bytes: u64) {
+    let mut previews = previews_on_disk(previews);
+    previews.sort_by_key(|(_, metadata)| std::cmp::Reverse(metadata.modified().ok()));
+    let mut size = 0;
+    for (path, metadata) in previews {
+        size += metadata.len();
+        if size > max_bytes {
+            debug!("removing {:?}, the previews are larger than PREVIEW_MAX_MB", path);
+            if let Err(err) = fs::remove_file(&path) {
+                error!("could not remove {:?}: {}", path, err);
+            }
+        }
+    }
+}
+
+// None if it is too large or was just replaced
+fn inline_image(path: &Path, max_bytes: u64) -> Option<String> {
+    let image = fs::read(path).ok().filter(|image| image.len() as u64 <= max_bytes)?;
+    Some(format!("data:image/jpeg;base64,{}", BASE64.encode(image)))
+}
+
+// the path of a preview to serve, if the name is one
+pub fn served_preview(file: &str) -> Option<PathBuf> {
+    if !file.ends_with(".jpg") || file.contains(['/', '\\']) {
+        return None;
+    }
+    files::resolve(Root::Preview, file).ok()
+}
+
+impl Drop for DvbcScheduler {
+    fn drop(&mut self) {
+        for (child, channel, _, _) in self.running.iter_mut().flatten() {
+            info!("killing ffmpeg for {}", channel.name);
+            process::unregister(child.id());
+            if let Err(err) = child.kill().and_then(|_| child.wait()) {
+                error!("Error killing ffmpeg process for {}: {}", channel.name, err);
+            }
+        }
+    }
+}
+
 // TODO try to write a macro for this (or find one)
 // TODO or consider just having one big enum error for all of HomeBack
 pub enum PreviewError {
@@ -219,6 +443,11 @@ impl From<io::Error> for PreviewError {
         Self::IO(error)
     }
 }
+impl From<PathError> for PreviewError {
+    fn from(error: PathError) -> Self {
+        Self::IO(error.into())
+    }
+}
 impl From<SystemTimeError> for PreviewError {
     fn from(error: SystemTimeError) -> Self {
         Self::SystemTime(error)
@@ -249,4 +478,7 @@ impl Error for PreviewError {
             Self::SystemTime(error) => error.source(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,41 @@
+use std::thread;
+use super::*;
+use crate::testing::TempFolder;
+
+fn tracked(folder: &Path, names: &[&str]) -> Previews {
+    let previews: Previews = Arc::default();
+    for name in names {
+        fs::write(folder.join(name), vec![0; 10]).unwrap();
+        previews.lock().unwrap().insert(folder.join(name));
+        // the eviction goes by the modified time
+        thread::sleep(Duration::from_millis(20));
+    }
+    previews
+}
+
+#[test]
+fn clearing_leaves_the_other_images_alone() {
+    let folder = TempFolder::new();
+    fs::write(folder.join("logo.jpg"), "logo").unwrap();
+    let previews = tracked(&folder, &["ZDF_HD.jpg", "arte.jpg"]);
+
+    clear_previews(&previews).unwrap();
+
+    assert!(folder.join("logo.jpg").exists());
+    assert!(!folder.join("ZDF_HD.jpg").exists());
+    assert!(!folder.join("arte.jpg").exists());
+}
+
+#[test]
+fn only_the_oldest_previews_are_evicted() {
+    let folder = TempFolder::new();
+    let previews = tracked(&folder, &["Das_Erste_HD.jpg", "ZDF_HD.jpg", "arte.jpg"]);
+    fs::write(folder.join("logo.jpg"), vec![0; 100]).unwrap();
+
+    evict_previews(&previews, 20);
+
+    assert!(folder.join("logo.jpg").exists());
+    assert!(!folder.join("Das_Erste_HD.jpg").exists());
+    assert!(folder.join("ZDF_HD.jpg").exists());
+    assert!(folder.join("arte.jpg").exists());
+}
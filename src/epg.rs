@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use log::{info, warn};
+use regex::Regex;
+use reqwest::Client;
+use serde::{Serialize, Deserialize};
+use crate::dvbc::{Channel, FetchError};
+use crate::state::AppState;
+use crate::store::{Repository, Store};
+use crate::viewing;
+use crate::xml::{xml_attribute, xml_elements, xml_escape, xml_nodes, xml_text};
+
+// the whole guide is in the store, a few days of it are enough for the guide and the recordings
+const DEFAULT_DAYS: u64 = 3;
+const DEFAULT_REFRESH_HOURS: u64 = 6;
+const CHANNELS_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Programme {
+    pub start: u64, // seconds since the epoch
+    pub stop: u64,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subtitle: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // xmltv_ns, "season.episode.part" counted from 0, e.g. "0.4." for S01E05
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub episode: Option<String>,
+}
+
+// what runs on a channel and what comes after it
+#[derive(Serialize, Debug)]
+pub struct NowOn {
+    pub channel: String,
+    pub now: Option<Running>,
+    pub next: Option<Programme>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Running {
+    #[serde(flatten)]
+    pub programme: Programme,
+    pub progress: u8, // percent
+}
+
+// the programmes of the DvbC channels, imported from the XMLTV guide at EPG_URL
+pub struct Epg {
+    client: Client,
+    url: Option<String>,
+    days: u64,
+    refresh_interval: Duration,
+    // by the name of the router channel
+    persisted: Repository<Vec<Programme>>,
+    programmes: Mutex<Arc<HashMap<String, Vec<Programme>>>>,
+}
+
+impl Epg {
+
+    pub fn from_env(store: Arc<Store>) -> Self {
+        let number = |name: &str, default: u64| env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default);
+        let url = env::var("EPG_URL").ok().filter(|url| !url.is_empty());
+        Self::new(url, number("EPG_DAYS", DEFAULT_DAYS), Duration::from_secs(60 * 60 * number("EPG_REFRESH_HOURS", DEFAULT_REFRESH_HOURS)), store)
+    }
+
+    pub fn new(url: Option<String>, days: u64, refresh_interval: Duration, store: Arc<Store>) -> Self {
+        let persisted = Repository::new(store, "epg");
+        let programmes = Mutex::new(Arc::new(persisted.all().into_iter().collect()));
+        Self { client: Client::new(), url, days, refresh_interval, persisted, programmes }
+    }
+
+    pub fn programmes(&self) -> Arc<HashMap<String, Vec<Programme>>> {
+        self.programmes.lock().unwrap().clone()
+    }
+
+    // fetches the guide and keeps the programmes of the channels it has under the names the router gives them
+    pub async fn import(&self, channels: &[Channel], now: u64) -> Result<usize, FetchError> {
+        let Some(url) = &self.url else { return Ok(0) };
+        let xml = self.client.get(url).send().await?.error_for_status()?.text().await?;
+        let programmes = parse_xmltv(&xml, channels, now, now + self.days * 24 * 60 * 60);
+        let count = programmes.values().map(Vec::len).sum();
+        info!("imported {} programmes of {} channels", count, programmes.len());
+        self.persisted.replace_all(programmes.iter().map(|(channel, programmes)| (channel.clone(), programmes.clone())).collect());
+        *self.programmes.lock().unwrap() = Arc::new(programmes);
+        Ok(count)
+    }
+
+    // the running and the next programme of every channel, in the order of the channels
+    pub fn now_on(&self, channels: &[Channel], now: u64) -> Vec<NowOn> {
+        let programmes = self.programmes();
+        channels.iter().map(|channel| {
+            let programmes = programmes.get(&channel.name).map_or(&[][..], Vec::as_slice);
+            // sorted by their start, so the running one is the last one that started
+            let started = programmes.partition_point(|programme| programme.start <= now);
+            let now = started.checked_sub(1).map(|running| &programmes[running]).filter(|running| running.stop > now).map(|running| Running {
+                programme: running.clone(),
+                progress: ((now - running.start) * 100 / (running.stop - running.start)) as u8,
+            });
+            NowOn { channel: channel.name.clone(), now, next: programmes.get(started).cloned() }
+        }).collect()
+    }
+
+    // the channels and programmes as an XMLTV guide, with the channel names as ids
+    pub fn xmltv(&self, channels: &[Channel]) -> String {
+        let programmes = self.programmes();
+        let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?><!DOCTYPE tv SYSTEM "xmltv.dtd"><tv generator-info-name="HomeBack">"#);
+        for channel in channels {
+            xml.push_str(&format!("<channel id=\"{0}\"><display-name>{0}</display-name></channel>", xml_escape(&channel.name)));
+        }
+        for channel in channels {
+            for programme in programmes.get(&channel.name).into_iter().flatten() {
+                xml.push_str(&format!("<programme start=\"{}\" stop=\"{}\" channel=\"{}\"><title>{}</title>", format_time(programme.start), format_time(programme.stop), xml_escape(&channel.name), xml_escape(&programme.title)));
+                if let Some(subtitle) = &programme.subtitle {
+                    xml.push_str(&format!("<sub-title>{}</sub-title>", xml_escape(subtitle)));
+                }
+                if let Some(description) = &programme.description {
+                    xml.push_str(&format!("<desc>{}</desc>", xml_escape(description)));
+                }
+                if let Some(episode) = &programme.episode {
+                    xml.push_str(&format!("<episode-num system=\"xmltv_ns\">{}</episode-num>", xml_escape(episode)));
+                }
+                xml.push_str("</programme>");
+            }
+        }
+        xml.push_str("</tv>");
+        xml
+    }
+}
+
+// the guide names a channel a bit differently than the router, e.g. "Das Erste" for "Das Erste HD"
+fn normalize(name: &str) -> String {
+    let name: String = name.chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_lowercase();
+    match name.strip_suffix("hd") {
+        Some(stripped) if !stripped.is_empty() => stripped.to_string(),
+        _ => name,
+    }
+}
+
+// only the programmes that haven't ended before `from` and start before `until`, by the router channel
+fn parse_xmltv(xml: &str, channels: &[Channel], from: u64, until: u64) -> HashMap<String, Vec<Programme>> {
+    let by_name: HashMap<String, &str> = channels.iter().map(|channel| (normalize(&channel.name), channel.name.as_str())).collect();
+    let mut router_names: HashMap<String, &str> = HashMap::new();
+    for node in xml_nodes(xml, "channel") {
+        let Some(id) = xml_attribute(node, "channel", "id") else { continue };
+        let names = xml_elements(node, "display-name").into_iter().map(xml_text).chain([id.clone()]);
+        if let Some(name) = names.filter_map(|name| by_name.get(&normalize(&name))).next() {
+            router_names.insert(id, name);
+        }
+    }
+
+    let mut programmes: HashMap<String, Vec<Programme>> = HashMap::new();
+    for node in xml_nodes(xml, "programme") {
+        let attribute = |name: &str| xml_attribute(node, "programme", name);
+        let (Some(channel), Some(start), Some(stop)) = (attribute("channel"), attribute("start").and_then(|start| parse_time(&start)), attribute("stop").and_then(|stop| parse_time(&stop))) else {
+            continue;
+        };
+        let Some(&name) = router_names.get(&channel) else { continue };
+        if stop <= from || start >= until || stop <= start {
+            continue;
+        }
+        let text = |element: &str| xml_elements(node, element).into_iter().next().map(xml_text).filter(|text| !text.is_empty());
+        let Some(title) = text("title") else { continue };
+        programmes.entry(name.to_string()).or_default().push(Programme {
+            start,
+            stop,
+            title,
+            subtitle: text("sub-title"),
+            description: text("desc"),
+            episode: episode_number(node),
+        });
+    }
+    for programmes in programmes.values_mut() {
+        programmes.sort_by_key(|programme| programme.start);
+        programmes.dedup_by_key(|programme| programme.start);
+    }
+    programmes
+}
+
+// onscreen numbers like "S01E05" are turned into xmltv_ns, so every programme has the same format
+fn episode_number(node: &str) -> Option<String> {
+    lazy_static! {
+        static ref ONSCREEN: Regex = Regex::new(r"(?i)s(\d{1,3})\s*e(\d{1,4})").unwrap();
+    }
+    let numbers: Vec<(Option<String>, String)> = xml_nodes(node, "episode-num").into_iter()
+        .filter_map(|number| Some((xml_attribute(number, "episode-num", "system"), xml_text(xml_elements(number, "episode-num").first()?))))
+        .collect();
+    if let Some((_, number)) = numbers.iter().find(|(system, _)| system.as_deref() == Some("xmltv_ns")) {
+        return Some(number.split_whitespace().collect());
+    }
+    numbers.iter().find_map(|(_, number)| {
+        let capture = ONSCREEN.captures(number)?;
+        let number = |i: usize| capture[i].parse::<u32>().ok().map(|number| number.saturating_sub(1));
+        Some(format!("{}.{}.", number(1)?, number(2)?))
+    })
+}
+
+// e.g. "20261014201500 +0200", the seconds and the offset can be missing
+pub fn parse_time(time: &str) -> Option<u64> {
+    let (digits, offset) = time.trim().split_once(' ').map_or((time.trim(), None), |(digits, offset)| (digits, Some(offset.trim())));
+    if digits.len() < 12 || !digits.is_ascii() {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| digits.get(range)?.parse::<u32>().ok();
+    let (year, month, day, hour, minute) = (number(0..4)?, number(4..6)?, number(6..8)?, number(8..10)?, number(10..12)?);
+    let second = if digits.len() >= 14 { number(12..14)? } else { 0 };
+    let offset = match offset {
+        Some(offset) if offset.len() == 5 => {
+            let sign = match &offset[..1] { "+" => 1, "-" => -1, _ => return None };
+            sign * (offset[1..3].parse::<i64>().ok()? * 60 * 60 + offset[3..5].parse::<i64>().ok()? * 60)
+        },
+        Some(_) => return None,
+        None => 0,
+    };
+    let days = viewing::days_from_civil(year as i64, month, day);
+    let seconds = days * 24 * 60 * 60 + (hour * 60 * 60 + minute * 60 + second) as i64 - offset;
+    u64::try_from(seconds).ok()
+}
+
+pub fn format_time(time: u64) -> String {
+    let (year, month, day) = viewing::civil_from_days((time / (24 * 60 * 60)) as i64);
+    let seconds = time % (24 * 60 * 60);
+    format!("{:04}{:02}{:02}{:02}{:02}{:02} +0000", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+// imports the guide every EPG_REFRESH_HOURS, once the channels are known, and schedules what the series rules find in it
+pub async fn poll(state: web::Data<AppState>) {
+    if state.epg.url.is_none() {
+        return;
+    }
+    loop {
+        // on the first start there are no channels until the router answered
+        let Some(channels) = state.dvbc.get_channels() else {
+            sleep(CHANNELS_INTERVAL).await;
+            continue;
+        };
+        if !state.quiet_mode.load(Ordering::Relaxed) {
+            let channels: Vec<Channel> = channels.tv.iter().chain(&channels.radio).cloned().collect();
+            match state.epg.import(&channels, now()).await {
+                Ok(_) => { state.recorder.apply_rules(&state.epg.programmes(), now()); },
+                Err(error) => warn!("could not import the EPG: {}", error),
+            }
+        }
+        sleep(state.epg.refresh_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests;
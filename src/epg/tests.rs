@@ -0,0 +1,85 @@
+use super::*;
+use crate::testing::{Fixture, MockServer, TempFolder};
+
+// 2026-10-14 20:00 UTC
+const EVENING: u64 = 1792008000;
+
+const GUIDE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tv>
+  <channel id="daserste.de"><display-name lang="de">Das Erste</display-name></channel>
+  <channel id="zdf.de"><display-name>ZDF</display-name></channel>
+  <channel id="other.de"><display-name>Not On The Router</display-name></channel>
+  <programme start="20261014220000 +0200" stop="20261014221500 +0200" channel="daserste.de">
+    <title lang="de">Tagesschau</title>
+  </programme>
+  <programme start="20261014221500 +0200" stop="20261014230000 +0200" channel="daserste.de">
+    <title>Tatort</title><sub-title>Tod &amp; Teufel</sub-title><desc>Ein Fall.</desc>
+    <episode-num system="onscreen">S03E07</episode-num>
+  </programme>
+  <programme start="20261014200000 +0000" stop="20261014210000 +0000" channel="zdf.de">
+    <title>Heute</title><episode-num system="xmltv_ns">1 . 4 . </episode-num>
+  </programme>
+  <programme start="20261013200000 +0000" stop="20261013210000 +0000" channel="zdf.de"><title>Yesterday</title></programme>
+  <programme start="20261014200000 +0000" stop="20261014210000 +0000" channel="other.de"><title>Elsewhere</title></programme>
+</tv>"#;
+
+fn channels() -> Vec<Channel> {
+    ["Das Erste HD", "ZDF HD", "arte"].into_iter().map(|name| Channel { name: name.to_string(), url: format!("rtsp://router/{}", name) }).collect()
+}
+
+#[test]
+fn parses_times_with_their_offset() {
+    assert_eq!(Some(EVENING), parse_time("20261014220000 +0200"));
+    assert_eq!(Some(EVENING), parse_time("202610142000"));
+    assert_eq!(Some(EVENING + 30), parse_time("20261014193030 -0030"));
+    assert_eq!(None, parse_time("2026101420"));
+    assert_eq!("20261014200000 +0000", format_time(EVENING));
+}
+
+#[test]
+fn keeps_the_programmes_of_the_router_channels() {
+    let programmes = parse_xmltv(GUIDE, &channels(), EVENING, EVENING + 24 * 60 * 60);
+
+    assert_eq!(2, programmes.len());
+    let first = &programmes["Das Erste HD"];
+    assert_eq!(vec!["Tagesschau", "Tatort"], first.iter().map(|programme| programme.title.as_str()).collect::<Vec<_>>());
+    assert_eq!(Some("Tod & Teufel".to_string()), first[1].subtitle);
+    assert_eq!(Some("2.6.".to_string()), first[1].episode);
+    // yesterday's programme is gone
+    assert_eq!(1, programmes["ZDF HD"].len());
+    assert_eq!(Some("1.4.".to_string()), programmes["ZDF HD"][0].episode);
+}
+
+#[actix_web::test]
+async fn exports_the_imported_guide() {
+    let server = MockServer::start();
+    let url = server.serve("/guide.xml", Fixture::Body(GUIDE.as_bytes().to_vec()));
+    let folder = TempFolder::new();
+    let store = Arc::new(Store::open(folder.join("store.json")).unwrap());
+    let epg = Epg::new(Some(url), 1, Duration::from_secs(60), store.clone());
+
+    assert_eq!(3, epg.import(&channels(), EVENING).await.unwrap());
+    let xmltv = epg.xmltv(&channels());
+
+    assert!(xmltv.contains(r#"<channel id="arte"><display-name>arte</display-name></channel>"#));
+    assert!(xmltv.contains(r#"<programme start="20261014201500 +0000" stop="20261014210000 +0000" channel="Das Erste HD"><title>Tatort</title><sub-title>Tod &amp; Teufel</sub-title>"#));
+    // the next start has it from the store
+    assert_eq!(epg.programmes(), Epg::new(None, 1, Duration::from_secs(60), store).programmes());
+}
+
+#[test]
+fn tells_what_runs_now_and_next() {
+    let folder = TempFolder::new();
+    let epg = Epg::new(None, 1, Duration::from_secs(60), Arc::new(Store::open(folder.join("store.json")).unwrap()));
+    *epg.programmes.lock().unwrap() = Arc::new(parse_xmltv(GUIDE, &channels(), EVENING, EVENING + 24 * 60 * 60));
+
+    let now_on = epg.now_on(&channels(), EVENING + 10 * 60);
+
+    assert_eq!(vec!["Das Erste HD", "ZDF HD", "arte"], now_on.iter().map(|now_on| now_on.channel.as_str()).collect::<Vec<_>>());
+    let running = now_on[0].now.as_ref().unwrap();
+    assert_eq!(("Tagesschau", 66), (running.programme.title.as_str(), running.progress));
+    assert_eq!(Some("Tatort"), now_on[0].next.as_ref().map(|next| next.title.as_str()));
+    assert_eq!(16, now_on[1].now.as_ref().unwrap().progress);
+    assert!(now_on[1].next.is_none());
+    assert!(now_on[2].now.is_none() && now_on[2].next.is_none());
+}
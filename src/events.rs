@@ -0,0 +1,111 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use log::debug;
+use serde::Serialize;
+use uuid::Uuid;
+use crate::power;
+
+// something that happened, for integrations that want to react to it
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event")]
+pub enum Event {
+    #[serde(rename = "player.started")]
+    PlayerStarted { source: &'static str, name: String },
+    #[serde(rename = "player.stopped")]
+    PlayerStopped,
+    #[serde(rename = "download.finished")]
+    DownloadFinished { uuid: Uuid, path: String },
+    #[serde(rename = "download.failed")]
+    DownloadFailed { uuid: Uuid, path: String, error: String },
+    #[serde(rename = "twitch.live")]
+    TwitchLive { channel: String, title: String, game: String },
+    // the player followed a raid from one stream to the other
+    #[serde(rename = "twitch.raid")]
+    TwitchRaid { from: String, to: String },
+    // so a phone can open the editor
+    #[serde(rename = "twitch.clip")]
+    TwitchClip { stream: String, id: String, edit_url: String },
+    #[serde(rename = "process.started")]
+    ProcessStarted { kind: &'static str, pid: u32 },
+    #[serde(rename = "process.stopped")]
+    ProcessStopped { kind: &'static str, pid: u32 },
+    // only when it exits on its own, e.g. a crashed player
+    #[serde(rename = "process.exited")]
+    ProcessExited { kind: &'static str, code: Option<i32> },
+    #[serde(rename = "router.unreachable")]
+    RouterUnreachable { error: String },
+    #[serde(rename = "router.reachable")]
+    RouterReachable,
+    #[serde(rename = "postprocessing.finished")]
+    PostProcessingFinished { id: Uuid, path: String },
+    #[serde(rename = "postprocessing.failed")]
+    PostProcessingFailed { id: Uuid, path: String, error: String },
+    // downloads are paused until the space is back above the threshold
+    #[serde(rename = "disk.low")]
+    DiskSpaceLow { folder: &'static str, available: u64 },
+    #[serde(rename = "disk.recovered")]
+    DiskSpaceRecovered { folder: &'static str, available: u64 },
+    // nothing is going on, the host goes down in that many seconds unless it is cancelled
+    #[serde(rename = "idle.warning")]
+    IdleShutdownWarning { action: power::Action, seconds: u64 },
+    #[serde(rename = "idle.cancelled")]
+    IdleShutdownCancelled,
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::PlayerStarted { .. }    => "player.started",
+            Event::PlayerStopped           => "player.stopped",
+            Event::DownloadFinished { .. } => "download.finished",
+            Event::DownloadFailed { .. }   => "download.failed",
+            Event::TwitchLive { .. }       => "twitch.live",
+            Event::TwitchRaid { .. }       => "twitch.raid",
+            Event::TwitchClip { .. }       => "twitch.clip",
+            Event::ProcessStarted { .. }   => "process.started",
+            Event::ProcessStopped { .. }   => "process.stopped",
+            Event::ProcessExited { .. }    => "process.exited",
+            Event::RouterUnreachable { .. } => "router.unreachable",
+            Event::RouterReachable         => "router.reachable",
+            Event::PostProcessingFinished { .. } => "postprocessing.finished",
+            Event::PostProcessingFailed { .. } => "postprocessing.failed",
+            Event::DiskSpaceLow { .. }     => "disk.low",
+            Event::DiskSpaceRecovered { .. } => "disk.recovered",
+            Event::IdleShutdownWarning { .. } => "idle.warning",
+            Event::IdleShutdownCancelled   => "idle.cancelled",
+        }
+    }
+
+    // filters are event names, or a prefix followed by a * like "player.*"
+    pub fn matches(&self, filter: &str) -> bool {
+        match filter.strip_suffix('*') {
+            Some(prefix) => self.name().starts_with(prefix),
+            None => self.name() == filter,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Events {
+    subscribers: Mutex<Vec<Sender<Arc<Event>>>>,
+}
+
+impl Events {
+
+    pub fn subscribe(&self) -> Receiver<Arc<Event>> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.lock().unwrap().is_empty()
+    }
+
+    pub fn publish(&self, event: Event) {
+        debug!("publishing {:?}", event);
+        let event = Arc::new(event);
+        // a subscriber that dropped its receiver is not interested anymore
+        self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
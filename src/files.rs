@@ -1,5 +1,215 @@
+use std::env;
+use std::fmt;
+use std::io::{self, SeekFrom};
 use std::path::{Path, PathBuf, Component};
+use std::sync::OnceLock;
+use actix_web::web::Bytes;
+use futures::{stream, Stream};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
-pub fn sanitize_path(path: &str) -> PathBuf {
-    Path::new(path).components().filter(|c| c != &Component::ParentDir).collect()
-}
\ No newline at end of file
+// where the roots are, the first ones a state was created with stay for as long as the process runs
+#[derive(Clone, Debug)]
+pub struct Folders {
+    pub scan: PathBuf,
+    pub download: PathBuf,
+    pub web_base: PathBuf,
+    pub preview: PathBuf, // where the frontend used to find them, if they don't get their own folder
+}
+
+static FOLDERS: OnceLock<Folders> = OnceLock::new();
+
+impl Folders {
+
+    pub fn from_env() -> io::Result<Self> {
+        let folder = |name: &str| env::var(name).map(PathBuf::from).map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("{} not set", name)));
+        let web_base = folder("WEB_BASE_FOLDER")?;
+        Ok(Self {
+            scan: folder("SCAN_FOLDER")?,
+            download: folder("DOWNLOAD_FOLDER")?,
+            preview: folder("PREVIEW_FOLDER").unwrap_or_else(|_| web_base.join("img/tv/preview")),
+            web_base,
+        })
+    }
+
+    pub fn set(self) -> &'static Folders {
+        FOLDERS.get_or_init(|| self)
+    }
+}
+
+// the folders paths from requests are resolved in, nothing outside of them is ever read or written
+#[derive(Clone, Copy, Debug)]
+pub enum Root {
+    Scan,
+    Download,
+    Preview,
+}
+
+impl Root {
+    pub fn folder(self) -> &'static Path {
+        let folders = FOLDERS.get().expect("the folders are set with the state");
+        match self {
+            Root::Scan     => &folders.scan,
+            Root::Download => &folders.download,
+            Root::Preview  => &folders.preview,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PathError {
+    Absolute(String),  // only paths relative to the root are accepted
+    Escapes(String),   // through .. or a symlink pointing outside of the root
+    Io(io::Error),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::Absolute(path) => write!(f, "{} is not a relative path", path),
+            PathError::Escapes(path)  => write!(f, "{} is outside of the folder", path),
+            PathError::Io(error)      => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl From<io::Error> for PathError {
+    fn from(error: io::Error) -> Self {
+        PathError::Io(error)
+    }
+}
+
+impl From<PathError> for io::Error {
+    fn from(error: PathError) -> Self {
+        match error {
+            PathError::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::PermissionDenied, error.to_string()),
+        }
+    }
+}
+
+// resolves a path relative to a root, the file itself doesn't have to exist yet (downloads, uploads)
+// the existing part is canonicalized, so symlinks may point around inside the root but not out of it
+pub fn resolve(root: Root, path: impl AsRef<Path>) -> Result<PathBuf, PathError> {
+    resolve_in(root.folder(), path)
+}
+
+// like `resolve`, for a folder that isn't one of the roots, e.g. the one the download manager was given
+pub fn resolve_in(folder: &Path, path: impl AsRef<Path>) -> Result<PathBuf, PathError> {
+    let path = path.as_ref();
+    let display = || path.to_string_lossy().into_owned();
+    for component in path.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {},
+            Component::ParentDir => return Err(PathError::Escapes(display())),
+            Component::RootDir | Component::Prefix(_) => return Err(PathError::Absolute(display())),
+        }
+    }
+
+    let base = folder.canonicalize()?;
+    let joined = base.join(path);
+    let mut existing = joined.as_path();
+    let canonical = loop {
+        match existing.canonicalize() {
+            Ok(canonical) => break canonical,
+            // a dangling symlink would be followed when the file is created
+            Err(error) if error.kind() == io::ErrorKind::NotFound && existing.symlink_metadata().is_err() => {
+                existing = existing.parent().expect("the root exists, so this stops there");
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(PathError::Escapes(display())),
+            Err(error) => return Err(error.into()),
+        }
+    };
+    if !canonical.starts_with(&base) {
+        return Err(PathError::Escapes(display()));
+    }
+    match joined.strip_prefix(existing).unwrap() {
+        missing if missing.as_os_str().is_empty() => Ok(canonical),
+        missing => Ok(canonical.join(missing)),
+    }
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).map(str::to_lowercase);
+    match extension.as_deref() {
+        Some("mp4") | Some("m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mov") => "video/quicktime",
+        Some("ts") => "video/mp2t",
+        Some("mpg") | Some("mpeg") => "video/mpeg",
+        Some("mp3") => "audio/mpeg",
+        Some("m4a") => "audio/mp4",
+        Some("flac") => "audio/flac",
+        Some("ogg") | Some("opus") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("srt") => "application/x-subrip",
+        Some("vtt") => "text/vtt",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ByteRange {
+    Whole,
+    Part(u64, u64), // the first and last byte
+    Unsatisfiable,
+}
+
+// what a "bytes=..." range header asks for. A header that can't be parsed is ignored, like the RFC says
+// only single ranges are supported, players don't ask for more, several ranges get the whole file
+pub fn parse_range(header: &str, size: u64) -> ByteRange {
+    let Some((start, end)) = header.trim().strip_prefix("bytes=").filter(|ranges| !ranges.contains(',')).and_then(|range| range.split_once('-')) else {
+        return ByteRange::Whole;
+    };
+    let parse = |number: &str| number.parse::<u64>().ok();
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match parse(suffix) {
+            Some(0) => return ByteRange::Unsatisfiable,
+            Some(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+            None => return ByteRange::Whole,
+        },
+        (start, "") => match parse(start) {
+            Some(start) => (start, size.saturating_sub(1)),
+            None => return ByteRange::Whole,
+        },
+        (start, end) => match (parse(start), parse(end)) {
+            (Some(start), Some(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+            _ => return ByteRange::Whole,
+        },
+    };
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Part(start, end)
+}
+
+// streams length bytes of the file from start on
+pub async fn stream(mut file: File, start: u64, length: u64) -> io::Result<impl Stream<Item = io::Result<Bytes>>> {
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(stream::unfold((file, length), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut buffer = vec![0; CHUNK_SIZE.min(remaining as usize)];
+        match file.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(read) => {
+                buffer.truncate(read);
+                Some((Ok(Bytes::from(buffer)), (file, remaining - read as u64)))
+            },
+            Err(error) => Some((Err(error), (file, 0))),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,60 @@
+use futures::TryStreamExt;
+use super::*;
+use crate::testing::{body, TempFolder};
+
+#[test]
+fn reads_a_range() {
+    assert_eq!(ByteRange::Part(0, 499), parse_range("bytes=0-499", 1000));
+    assert_eq!(ByteRange::Part(500, 999), parse_range(" bytes=500-999 ", 1000));
+    // the end is cut to the size
+    assert_eq!(ByteRange::Part(900, 999), parse_range("bytes=900-5000", 1000));
+}
+
+#[test]
+fn reads_an_open_ended_range() {
+    assert_eq!(ByteRange::Part(100, 999), parse_range("bytes=100-", 1000));
+    assert_eq!(ByteRange::Part(999, 999), parse_range("bytes=999-", 1000));
+}
+
+#[test]
+fn reads_a_suffix_range() {
+    assert_eq!(ByteRange::Part(800, 999), parse_range("bytes=-200", 1000));
+    // more than there is is all of it
+    assert_eq!(ByteRange::Part(0, 999), parse_range("bytes=-5000", 1000));
+}
+
+#[test]
+fn a_range_past_the_end_is_unsatisfiable() {
+    assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=1000-", 1000));
+    assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=2000-3000", 1000));
+    assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=-0", 1000));
+    assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=0-", 0));
+    assert_eq!(ByteRange::Unsatisfiable, parse_range("bytes=-100", 0));
+}
+
+#[test]
+fn several_ranges_get_the_whole_file() {
+    assert_eq!(ByteRange::Whole, parse_range("bytes=0-99,200-299", 1000));
+    assert_eq!(ByteRange::Whole, parse_range("bytes=0-99, -100", 1000));
+}
+
+#[test]
+fn a_malformed_range_is_ignored() {
+    assert_eq!(ByteRange::Whole, parse_range("items=0-99", 1000));
+    assert_eq!(ByteRange::Whole, parse_range("bytes=500-100", 1000));
+    assert_eq!(ByteRange::Whole, parse_range("bytes=a-b", 1000));
+    assert_eq!(ByteRange::Whole, parse_range("bytes=100", 1000));
+    assert_eq!(ByteRange::Whole, parse_range("bytes=-", 1000));
+}
+
+#[actix_web::test]
+async fn streams_the_range_of_a_file() {
+    let folder = TempFolder::new();
+    let content = body(300_000, 7);
+    std::fs::write(folder.join("a.mkv"), &content).unwrap();
+
+    let file = File::open(folder.join("a.mkv")).await.unwrap();
+    let chunks: Vec<Bytes> = stream(file, 1000, 200_000).await.unwrap().try_collect().await.unwrap();
+
+    assert_eq!(content[1000..201_000], chunks.concat());
+}
@@ -0,0 +1,210 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use futures::future::join;
+use log::{info, warn};
+use reqwest::Client;
+use serde::Serialize;
+use uuid::Uuid;
+use crate::events::Event;
+use crate::state::AppState;
+use crate::stats::{self, SystemStats};
+use crate::tools;
+
+#[cfg(unix)]
+const REQUIRED_BINARIES: [&str; 6] = ["streamlink", "mpv", "ffmpeg", "firefox", "ps", "kill"];
+#[cfg(windows)]
+const REQUIRED_BINARIES: [&str; 5] = ["streamlink", "mpv", "ffmpeg", "firefox", "taskkill"];
+// only needed by some features, so they are reported in the status but don't affect readiness
+const OPTIONAL_BINARIES: [&str; 12] = ["pactl", "cec-client", "xset", "xdotool", "systemctl", "systemd-run", "vcgencmd", "mosquitto_pub", "mosquitto_sub", "catt", "librespot", "ffprobe"];
+const TWITCH_API_URL: &str = "https://api.twitch.tv/helix";
+const ROUTER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct Health {
+    client: Client,
+    router_m3u_url: String,
+    folders: Vec<(&'static str, PathBuf)>,
+    router: Mutex<Option<Result<(), String>>>, // the last check of watch_router
+}
+
+#[derive(Serialize, Debug)]
+pub struct SystemStatus {
+    binaries: Vec<BinaryStatus>,
+    folders: Vec<FolderStatus>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BinaryStatus {
+    name: &'static str,
+    path: Option<PathBuf>,
+    version: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FolderStatus {
+    name: &'static str,
+    path: PathBuf,
+    exists: bool,
+    writable: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    dependencies: Vec<DependencyStatus>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DependencyStatus {
+    name: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl DependencyStatus {
+    fn from_result(name: &str, result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Self { name: name.to_string(), ok: true, error: None },
+            Err(error) => Self { name: name.to_string(), ok: false, error: Some(error) },
+        }
+    }
+}
+
+pub fn validate_binaries() {
+    tools::validate(REQUIRED_BINARIES.iter().chain(OPTIONAL_BINARIES.iter()).copied());
+}
+
+fn get_version(name: &str) -> Option<String> {
+    let flag = if name == "ffmpeg" { "-version" } else { "--version" };
+    let output = tools::command(name).arg(flag).stdin(Stdio::null()).output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout.lines().next().map(|line| line.trim().to_string()).filter(|line| !line.is_empty())
+}
+
+fn is_writable(path: &Path) -> bool {
+    let probe = path.join(format!(".home_back_probe_{}", Uuid::new_v4()));
+    match fs::File::create(&probe) {
+        Ok(_) => fs::remove_file(&probe).is_ok(),
+        Err(_) => false,
+    }
+}
+
+impl Health {
+
+    pub fn new(router_url: &str, folders: Vec<(&'static str, PathBuf)>) -> Self {
+        Self {
+            client: Client::builder().timeout(Duration::from_secs(2)).build().unwrap(),
+            router_m3u_url: format!("{}{}", router_url, "/dvb/m3u/tvhd.m3u"),
+            folders,
+            router: Mutex::new(None),
+        }
+    }
+
+    // whether watch_router could reach the router the last time, None before the first check
+    pub fn router_reachable(&self) -> Option<bool> {
+        self.router.lock().unwrap().as_ref().map(Result::is_ok)
+    }
+
+    // this calls every binary, so it blocks for a moment
+    pub fn system_status(&self) -> SystemStatus {
+        let binaries = REQUIRED_BINARIES.iter().chain(OPTIONAL_BINARIES.iter())
+            .map(|&name| {
+                let path = tools::path(name);
+                let version = path.as_ref().and_then(|_| get_version(name));
+                BinaryStatus { name, path, version }
+            })
+            .collect();
+
+        let folders = self.folders.iter()
+            .map(|(name, path)| FolderStatus { name, path: path.clone(), exists: path.is_dir(), writable: is_writable(path) })
+            .collect();
+
+        SystemStatus { binaries, folders }
+    }
+
+    // measures cpu load and network throughput, so it blocks for a moment
+    pub fn system_stats(&self) -> SystemStats {
+        stats::collect(&self.folders)
+    }
+
+    pub fn log_system_status(&self) {
+        let status = self.system_status();
+        for binary in status.binaries {
+            match (binary.path, binary.version) {
+                (Some(path), Some(version)) => info!("Found {} at {:?}: {}", binary.name, path, version),
+                (Some(path), None) => warn!("Found {} at {:?}, but could not determine its version", binary.name, path),
+                (None, _) => warn!("{} not found, features using it will fail", binary.name),
+            }
+        }
+        for folder in status.folders {
+            if !folder.writable {
+                warn!("{} {:?} is not writable", folder.name, folder.path);
+            }
+        }
+    }
+
+    pub async fn check_readiness(&self) -> Readiness {
+        let known = self.router.lock().unwrap().clone();
+        let (router, twitch) = match known {
+            Some(router) => (router, self.check_twitch().await),
+            None => join(self.check_router(), self.check_twitch()).await,
+        };
+
+        let mut dependencies = vec![
+            DependencyStatus::from_result("router", router),
+            DependencyStatus::from_result("twitch", twitch),
+        ];
+        dependencies.extend(REQUIRED_BINARIES.iter().map(|binary| DependencyStatus::from_result(
+            binary,
+            if tools::path(binary).is_some() { Ok(()) } else { Err(format!("{} not found", binary)) }
+        )));
+
+        let ready = dependencies.iter().all(|dependency| dependency.ok);
+        Readiness { ready, dependencies }
+    }
+
+    async fn check_router(&self) -> Result<(), String> {
+        self.client.get(&self.router_m3u_url).send().await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+
+    // only the changes are published, and the first check if the router is not reachable right away
+    fn router_checked(&self, result: Result<(), String>) -> Option<Event> {
+        let previous = self.router.lock().unwrap().replace(result.clone());
+        match (previous.map(|previous| previous.is_ok()), result) {
+            (Some(false), Ok(())) => { info!("The router is reachable again"); Some(Event::RouterReachable) },
+            (None | Some(true), Err(error)) => { warn!("The router is not reachable: {}", error); Some(Event::RouterUnreachable { error }) },
+            _ => None,
+        }
+    }
+
+    // any response means the API is reachable, we don't send a token so expect a 401
+    async fn check_twitch(&self) -> Result<(), String> {
+        self.client.get(TWITCH_API_URL).send().await
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+}
+
+// checks the router every 30 seconds, so the frontend learns about a missing tuner before it asks for channels
+pub async fn watch_router(state: web::Data<AppState>) {
+    loop {
+        let result = state.health.check_router().await;
+        let came_back = result.is_ok() && state.health.router_reachable() == Some(false);
+        if let Some(event) = state.health.router_checked(result) {
+            state.events.publish(event);
+        }
+        // the channels from the store may be outdated
+        if came_back {
+            state.dvbc.clear_cache();
+        }
+        sleep(ROUTER_CHECK_INTERVAL).await;
+    }
+}
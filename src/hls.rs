@@ -0,0 +1,189 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use log::{info, warn};
+use crate::dlna;
+use crate::dvbc::Channel;
+use crate::process;
+use crate::tuners::{TunerLease, Tuners, TunersBusy};
+
+pub const PLAYLIST: &str = "index.m3u8";
+const SEGMENT_SECONDS: u32 = 2;
+const START_TIMEOUT: Duration = Duration::from_secs(15);
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// the Chromecast asks for the playlist every few seconds, once it stops the restream is not watched anymore
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// ffmpeg writes the playlist with every segment, an old one means the router stopped sending
+const STALL_TIMEOUT: Duration = Duration::from_secs(20);
+
+// restreams a DVB-C channel as HLS for a Chromecast, which can't play the MPEG-TS the router sends
+// there is one restream at a time, it takes a tuner and stops once nobody fetches it anymore
+pub struct HlsRestream {
+    folder: PathBuf,
+    base_url: String, // where the Chromecast reaches the API
+    tuners: Arc<Tuners>,
+    running: Mutex<Option<Running>>,
+}
+
+struct Running {
+    channel: String,
+    child: Child,
+    fetched: Instant,
+    _tuner: TunerLease,
+}
+
+#[derive(Debug)]
+pub enum HlsError {
+    Busy(TunersBusy),
+    Io(io::Error),
+}
+
+impl HlsRestream {
+
+    pub fn new(tuners: Arc<Tuners>, folder: PathBuf, base_url: String) -> Arc<Self> {
+        Arc::new(Self { folder, base_url: base_url.trim_end_matches('/').to_string(), tuners, running: Mutex::default() })
+    }
+
+    // guessed like the DLNA address unless CAST_BASE_URL is set
+    pub fn from_env(tuners: Arc<Tuners>) -> Arc<Self> {
+        let base_url = env::var("CAST_BASE_URL").unwrap_or_else(|_| {
+            let port = env::var("ADDR").ok()
+                .and_then(|addrs| addrs.split(',').next().and_then(|addr| addr.trim().parse::<SocketAddr>().ok()))
+                .map_or(23559, |addr| addr.port());
+            format!("http://{}:{}", dlna::local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)), port)
+        });
+        Self::new(tuners, env::temp_dir().join("home_back-hls"), base_url)
+    }
+
+    pub fn start_watching(self: &Arc<Self>) {
+        let restream = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(CHECK_INTERVAL);
+            restream.stop_unused();
+        });
+    }
+
+    // starts the restream of the channel, once ffmpeg wrote the first segment the Chromecast can play the returned url
+    // a restream of another channel is stopped first, the same channel keeps running
+    pub fn start(&self, channel: &Channel) -> Result<String, HlsError> {
+        let url = format!("{}/api/v1/dvbc/hls/{}", self.base_url, PLAYLIST);
+        {
+            let mut running = self.running.lock().unwrap();
+            if running.as_ref().is_some_and(|running| running.channel == channel.name) {
+                return Ok(url);
+            }
+            if let Some(previous) = running.take() {
+                self.stop_running(previous);
+            }
+        }
+        // the old restream gave its tuner back by now
+        let tuner = self.tuners.acquire("cast", &channel.name).map_err(HlsError::Busy)?;
+        let _ = fs::remove_dir_all(&self.folder);
+        fs::create_dir_all(&self.folder).map_err(HlsError::Io)?;
+
+        // the Chromecast plays H.264 and AAC, the video is copied and the mp2 or ac3 audio converted
+        let mut child = process::scoped_command("cast", "ffmpeg")
+            .arg("-hide_banner").arg("-loglevel").arg("error")
+            .arg("-i").arg(&channel.url)
+            .arg("-map").arg("0:v:0?").arg("-map").arg("0:a:0")
+            .arg("-c:v").arg("copy").arg("-c:a").arg("aac")
+            .arg("-f").arg("hls")
+            .arg("-hls_time").arg(SEGMENT_SECONDS.to_string())
+            .arg("-hls_list_size").arg("6")
+            .arg("-hls_flags").arg("delete_segments+omit_endlist")
+            .arg("-hls_segment_filename").arg(self.folder.join("segment%d.ts"))
+            .arg(self.folder.join(PLAYLIST))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(HlsError::Io)?;
+        process::register("cast", child.id());
+
+        let started = Instant::now();
+        while !self.folder.join(PLAYLIST).exists() {
+            let exited = child.try_wait().ok().flatten().is_some();
+            if exited || started.elapsed() > START_TIMEOUT {
+                let _ = child.kill();
+                let _ = child.wait();
+                process::unregister(child.id());
+                return Err(HlsError::Io(io::Error::other(format!("ffmpeg wrote no playlist for {}", channel.name))));
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+        info!("restreaming {} as HLS", channel.name);
+        *self.running.lock().unwrap() = Some(Running { channel: channel.name.clone(), child, fetched: Instant::now(), _tuner: tuner });
+        Ok(url)
+    }
+
+    pub fn stop(&self) {
+        if let Some(running) = self.running.lock().unwrap().take() {
+            self.stop_running(running);
+        }
+    }
+
+    // the playlist or a segment of the running restream, None for anything else
+    pub fn file(&self, name: &str) -> Option<PathBuf> {
+        if !is_file_name(name) {
+            return None;
+        }
+        let mut running = self.running.lock().unwrap();
+        running.as_mut()?.fetched = Instant::now();
+        Some(self.folder.join(name))
+    }
+
+    fn stop_unused(&self) {
+        let mut running = self.running.lock().unwrap();
+        let Some(current) = running.as_mut() else { return };
+        let exited = current.child.try_wait().ok().flatten().is_some();
+        let playlist_age = fs::metadata(self.folder.join(PLAYLIST)).and_then(|metadata| metadata.modified()).ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        let reason = if exited {
+            "ffmpeg exited"
+        } else if is_stalled(playlist_age) {
+            "the router stopped sending"
+        } else if current.fetched.elapsed() > IDLE_TIMEOUT {
+            "nobody watches it anymore"
+        } else {
+            return;
+        };
+        warn!("stopping the restream of {}, {}", current.channel, reason);
+        let current = running.take().unwrap();
+        self.stop_running(current);
+    }
+
+    fn stop_running(&self, mut running: Running) {
+        let pid = running.child.id();
+        let _ = running.child.kill();
+        let _ = running.child.wait();
+        process::unregister(pid);
+        let _ = fs::remove_dir_all(&self.folder);
+        info!("stopped restreaming {}", running.channel);
+    }
+}
+
+// what ffmpeg writes, so nothing else in the folder or beyond it is served
+fn is_file_name(name: &str) -> bool {
+    name == PLAYLIST || name.strip_prefix("segment").and_then(|rest| rest.strip_suffix(".ts"))
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+fn is_stalled(playlist_age: Option<Duration>) -> bool {
+    playlist_age.is_none_or(|age| age > STALL_TIMEOUT)
+}
+
+pub fn content_type(name: &str) -> &'static str {
+    match Path::new(name).extension().and_then(|extension| extension.to_str()) {
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        _ => "video/mp2t",
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,64 @@
+use std::process::Command;
+use super::*;
+use crate::testing::TempFolder;
+
+fn running(restream: &HlsRestream, tuners: &Arc<Tuners>, fetched: Instant) {
+    fs::write(restream.folder.join(PLAYLIST), "#EXTM3U").unwrap();
+    let child = Command::new("sleep").arg("60").spawn().unwrap();
+    let tuner = tuners.acquire("cast", "ZDF HD").unwrap();
+    *restream.running.lock().unwrap() = Some(Running { channel: "ZDF HD".to_string(), child, fetched, _tuner: tuner });
+}
+
+#[test]
+fn only_the_files_ffmpeg_writes_are_served() {
+    assert!(is_file_name("index.m3u8"));
+    assert!(is_file_name("segment0.ts"));
+    assert!(is_file_name("segment123.ts"));
+    assert!(!is_file_name("segment.ts"));
+    assert!(!is_file_name("segment1.ts.tmp"));
+    assert!(!is_file_name("../index.m3u8"));
+    assert!(!is_file_name("segment-1.ts"));
+}
+
+#[test]
+fn nothing_is_served_without_a_restream() {
+    let folder = TempFolder::new();
+    let restream = HlsRestream::new(Tuners::new(1), folder.to_path_buf(), "http://htpc:23559".to_string());
+
+    assert_eq!(None, restream.file(PLAYLIST));
+}
+
+#[test]
+fn an_old_playlist_is_a_stalled_restream() {
+    assert!(!is_stalled(Some(Duration::from_secs(3))));
+    assert!(is_stalled(Some(STALL_TIMEOUT + Duration::from_secs(1))));
+    assert!(is_stalled(None));
+}
+
+#[test]
+fn a_watched_restream_keeps_its_tuner() {
+    let folder = TempFolder::new();
+    let tuners = Tuners::new(1);
+    let restream = HlsRestream::new(tuners.clone(), folder.to_path_buf(), "http://htpc:23559".to_string());
+    running(&restream, &tuners, Instant::now());
+
+    restream.stop_unused();
+
+    assert_eq!(Some(folder.join(PLAYLIST)), restream.file(PLAYLIST));
+    assert!(tuners.acquire("preview", "arte").is_err());
+    restream.stop();
+}
+
+#[test]
+fn an_unwatched_restream_gives_its_tuner_back() {
+    let folder = TempFolder::new();
+    let tuners = Tuners::new(1);
+    let restream = HlsRestream::new(tuners.clone(), folder.to_path_buf(), "http://htpc:23559".to_string());
+    running(&restream, &tuners, Instant::now() - IDLE_TIMEOUT - Duration::from_secs(1));
+
+    restream.stop_unused();
+
+    assert_eq!(None, restream.file(PLAYLIST));
+    assert!(tuners.acquire("preview", "arte").is_ok());
+    assert!(!folder.exists());
+}
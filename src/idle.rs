@@ -0,0 +1,129 @@
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use log::{error, info, warn};
+use serde::Serialize;
+use crate::cec;
+use crate::epg;
+use crate::events::Event;
+use crate::power;
+use crate::state::AppState;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+
+#[derive(Serialize)]
+pub struct IdleStatus {
+    action: Option<power::Action>,
+    idle_seconds: u64,
+    shutdown_in_seconds: Option<u64>,
+}
+
+// since when nothing happened on the box, cancelling a pending shutdown starts it over
+pub struct IdleShutdown {
+    action: Option<power::Action>,
+    timeout: Duration,
+    warning: Duration, // how long before the shutdown `idle.warning` is sent, so it can still be cancelled
+    since: Mutex<(Instant, bool)>, // and whether the warning went out
+}
+
+impl IdleShutdown {
+
+    pub fn from_env() -> Self {
+        let action = match env::var("IDLE_SHUTDOWN").as_deref() {
+            Ok("suspend")  => Some(power::Action::Suspend),
+            Ok("shutdown") => Some(power::Action::Shutdown),
+            Ok("") | Err(_) => None,
+            Ok(other) => { error!("unknown IDLE_SHUTDOWN {}, must be suspend or shutdown", other); None },
+        };
+        let number = |name: &str, default: u64| env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default);
+        Self::new(action, Duration::from_secs(60 * number("IDLE_SHUTDOWN_MINUTES", 60)), Duration::from_secs(number("IDLE_SHUTDOWN_WARNING_SECONDS", 120)))
+    }
+
+    pub fn new(action: Option<power::Action>, timeout: Duration, warning: Duration) -> Self {
+        Self { action, timeout, warning, since: Mutex::new((Instant::now(), false)) }
+    }
+
+    pub fn status(&self) -> IdleStatus {
+        let idle = self.since.lock().unwrap().0.elapsed();
+        IdleStatus {
+            action: self.action,
+            idle_seconds: idle.as_secs(),
+            shutdown_in_seconds: self.action.map(|_| self.timeout.saturating_sub(idle).as_secs()),
+        }
+    }
+
+    // returns whether a warning was out
+    pub fn reset(&self) -> bool {
+        let mut since = self.since.lock().unwrap();
+        let warned = since.1;
+        *since = (Instant::now(), false);
+        warned
+    }
+
+    // the time left if the warning is due now
+    fn warn(&self) -> Option<Duration> {
+        let mut since = self.since.lock().unwrap();
+        let left = self.timeout.saturating_sub(since.0.elapsed());
+        if since.1 || left > self.warning {
+            return None;
+        }
+        since.1 = true;
+        Some(left)
+    }
+
+    fn is_due(&self) -> bool {
+        self.since.lock().unwrap().0.elapsed() >= self.timeout
+    }
+}
+
+// whether anything is going on that a shutdown would interrupt, a recording in the next hour counts as well
+fn is_busy(state: &AppState) -> bool {
+    state.video_player.running().is_some()
+        || state.recorder.is_busy(epg::now())
+        || state.chat.running().is_some()
+        || state.download_manager.has_active()
+        || state.postprocessing.has_pending()
+}
+
+// with IDLE_SHUTDOWN, suspends or shuts down the host and turns off the TV after IDLE_SHUTDOWN_MINUTES without a player, chat, download or post processing
+// `idle.warning` is sent IDLE_SHUTDOWN_WARNING_SECONDS before, `DELETE /system/idle-shutdown` or anything starting cancels it
+pub async fn watch(state: web::Data<AppState>) {
+    let Some(action) = state.idle_shutdown.action else { return };
+    info!("{:?} after {} minutes idle", action, state.idle_shutdown.timeout.as_secs() / 60);
+    loop {
+        sleep(CHECK_INTERVAL).await;
+        if is_busy(&state) {
+            cancel(&state);
+        } else if state.idle_shutdown.is_due() {
+            state.idle_shutdown.reset();
+            let result = web::block(move || {
+                // off first, the host may not get to it afterwards
+                if let Err(error) = cec::set_power(false) {
+                    warn!("could not turn off TV: {}", error);
+                }
+                power::run(action)
+            }).await;
+            match result {
+                Ok(Ok(())) => {},
+                Ok(Err(error)) => error!("could not {:?} the idle host: {}", action, error),
+                Err(error) => error!("could not {:?} the idle host: {}", action, error),
+            }
+            // after a suspend the box is idle from the wakeup on
+            state.idle_shutdown.reset();
+        } else if let Some(left) = state.idle_shutdown.warn() {
+            info!("{:?} in {} seconds, nothing is going on", action, left.as_secs());
+            state.events.publish(Event::IdleShutdownWarning { action, seconds: left.as_secs() });
+        }
+    }
+}
+
+// starts the idle time over, `idle.cancelled` is sent if a shutdown was announced
+pub fn cancel(state: &AppState) {
+    if state.idle_shutdown.reset() {
+        info!("idle shutdown cancelled");
+        state.events.publish(Event::IdleShutdownCancelled);
+    }
+}
@@ -0,0 +1,50 @@
+use std::io;
+use std::process::Stdio;
+use log::info;
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+use crate::tools;
+
+pub const MAX_SCROLL: u32 = 50;
+
+// an event for the window that currently has the focus, usually the firefox chat
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum InputEvent {
+    Key(String),  // a keysym or combination as understood by xdotool, e.g. "Escape" or "ctrl+w"
+    Click(u8),    // 1 is the left, 2 the middle and 3 the right mouse button
+    Scroll(i32),  // positive scrolls down
+}
+
+pub fn is_key(key: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[A-Za-z0-9_]+(\+[A-Za-z0-9_]+)*$").unwrap();
+    }
+    key.len() <= 50 && RE.is_match(key)
+}
+
+fn xdotool(args: &[&str]) -> io::Result<()> {
+    let output = tools::command("xdotool")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("xdotool {} failed: {}", args.join(" "), stderr.trim())));
+    }
+    Ok(())
+}
+
+pub fn inject(event: &InputEvent) -> io::Result<()> {
+    info!("injecting {:?}", event);
+    match event {
+        InputEvent::Key(key) => xdotool(&["key", key]),
+        InputEvent::Click(button) => xdotool(&["click", &button.to_string()]),
+        InputEvent::Scroll(amount) => {
+            // X has no scroll events, the wheel is mouse button 4 (up) and 5 (down)
+            let button = if *amount < 0 { "4" } else { "5" };
+            xdotool(&["click", "--repeat", &amount.unsigned_abs().to_string(), button])
+        },
+    }
+}
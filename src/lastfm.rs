@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use log::{info, warn, debug};
+use reqwest::Client;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use crate::epg;
+use crate::progress;
+use crate::state::AppState;
+use crate::store::{Repository, Store};
+
+const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+const AUTH_URL: &str = "https://www.last.fm/api/auth/";
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+// the rules of Last.fm, a track counts once half of it or four minutes were played
+const MIN_DURATION: u64 = 30;
+const MAX_PLAYED: u64 = 4 * 60;
+// radio titles come without a duration
+const RADIO_PLAYED: u64 = 60;
+// the api errors for a token that wasn't authorized yet and for a session the user revoked
+const TOKEN_NOT_AUTHORIZED: u64 = 14;
+const INVALID_SESSION: u64 = 9;
+
+type Error = Box<dyn std::error::Error>;
+
+#[derive(Debug)]
+struct ApiError {
+    code: u64,
+    message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Last.fm error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Track {
+    pub artist: String,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>, // seconds
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Session {
+    name: String,
+    key: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub user: Option<String>,
+    pub pending: bool,  // a login waits for the user to allow it on last.fm
+    pub playing: Option<Track>,
+}
+
+// the login that was started, the user allows it on `url`
+#[derive(Serialize, Debug)]
+pub struct Login {
+    pub url: String,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Action {
+    NowPlaying(Track),
+    Scrobble(Track, u64), // with the time it started
+}
+
+// what plays and since when, decides when a track is scrobbled
+#[derive(Default)]
+pub struct Scrobbles {
+    playing: Option<(Track, u64)>,
+}
+
+impl Scrobbles {
+
+    // the calls for what the player plays now, the track that played before is scrobbled if it played long enough
+    pub fn observe(&mut self, track: Option<Track>, now: u64) -> Vec<Action> {
+        if self.playing.as_ref().map(|(playing, _)| playing) == track.as_ref() {
+            return Vec::new();
+        }
+        let mut actions = Vec::new();
+        if let Some((previous, started)) = self.playing.take() {
+            if counts(&previous, now.saturating_sub(started)) {
+                actions.push(Action::Scrobble(previous, started));
+            }
+        }
+        if let Some(track) = track {
+            actions.push(Action::NowPlaying(track.clone()));
+            self.playing = Some((track, now));
+        }
+        actions
+    }
+}
+
+fn counts(track: &Track, played: u64) -> bool {
+    match track.duration {
+        Some(duration) => duration > MIN_DURATION && (played >= duration / 2 || played >= MAX_PLAYED),
+        None => played >= RADIO_PLAYED,
+    }
+}
+
+// the track in the metadata mpv has, the tags of a music file or the "Artist - Title" a radio stream sends as icy-title
+pub fn track(metadata: &Value, duration: Option<f64>) -> Option<Track> {
+    let tags: BTreeMap<String, String> = metadata.as_object()?.iter()
+        .filter_map(|(key, value)| Some((key.to_lowercase(), value.as_str()?.trim().to_string())))
+        .filter(|(_, value)| !value.is_empty())
+        .collect();
+    if let (Some(artist), Some(title)) = (tags.get("artist"), tags.get("title")) {
+        return Some(Track { artist: artist.clone(), title: title.clone(), album: tags.get("album").cloned(), duration: duration.map(|duration| duration as u64) });
+    }
+    let (artist, title) = tags.get("icy-title")?.split_once(" - ")?;
+    let (artist, title) = (artist.trim(), title.trim());
+    if artist.is_empty() || title.is_empty() {
+        return None;
+    }
+    Some(Track { artist: artist.to_string(), title: title.to_string(), album: None, duration: None })
+}
+
+// scrobbles what the player plays to the Last.fm account that logged in, with LASTFM_API_KEY and LASTFM_API_SECRET
+pub struct LastFm {
+    client: Client,
+    url: String,
+    auth_url: String,
+    api_key: String,
+    secret: String,
+    session: Repository<Session>,
+    pending: Mutex<Option<String>>, // the token of the login
+    scrobbles: Mutex<Scrobbles>,
+}
+
+impl LastFm {
+
+    pub fn from_env(store: Arc<Store>) -> Option<Self> {
+        let (api_key, secret) = (env::var("LASTFM_API_KEY").ok()?, env::var("LASTFM_API_SECRET").ok()?);
+        Some(Self::new(API_URL.to_string(), AUTH_URL.to_string(), api_key, secret, store))
+    }
+
+    pub fn new(url: String, auth_url: String, api_key: String, secret: String, store: Arc<Store>) -> Self {
+        Self {
+            client: Client::builder().timeout(Duration::from_secs(10)).build().unwrap(),
+            url,
+            auth_url,
+            api_key,
+            secret,
+            session: Repository::new(store, "lastfm"),
+            pending: Mutex::default(),
+            scrobbles: Mutex::default(),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let playing = self.scrobbles.lock().unwrap().playing.as_ref().map(|(track, _)| track.clone());
+        Status { user: self.session.get("session").map(|session| session.name), pending: self.pending.lock().unwrap().is_some(), playing }
+    }
+
+    // starts a login, it is done once the user allowed it and `finish_login` was called
+    pub async fn start_login(&self) -> Result<Login, Error> {
+        let response = self.call("auth.getToken", &BTreeMap::new(), false).await?;
+        let token = response["token"].as_str().ok_or("Last.fm sent no token")?.to_string();
+        let url = format!("{}?api_key={}&token={}", self.auth_url, self.api_key, token);
+        *self.pending.lock().unwrap() = Some(token);
+        Ok(Login { url })
+    }
+
+    // the name of the user once the pending login was allowed, None while it wasn't yet
+    pub async fn finish_login(&self) -> Result<Option<String>, Error> {
+        let Some(token) = self.pending.lock().unwrap().clone() else {
+            return Ok(self.session.get("session").map(|session| session.name));
+        };
+        let response = match self.call("auth.getSession", &BTreeMap::from([("token", token)]), true).await {
+            Ok(response) => response,
+            Err(error) if error.downcast_ref::<ApiError>().is_some_and(|error| error.code == TOKEN_NOT_AUTHORIZED) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let session = Session {
+            name: response["session"]["name"].as_str().ok_or("Last.fm sent no user")?.to_string(),
+            key: response["session"]["key"].as_str().ok_or("Last.fm sent no session")?.to_string(),
+        };
+        info!("logged in to Last.fm as {}", session.name);
+        self.session.put("session", &session);
+        *self.pending.lock().unwrap() = None;
+        Ok(Some(session.name))
+    }
+
+    pub fn logout(&self) {
+        *self.pending.lock().unwrap() = None;
+        self.session.remove("session");
+    }
+
+    // tells Last.fm what plays now, and scrobbles the track before it
+    pub async fn observe(&self, track: Option<Track>, now: u64) {
+        let Some(session) = self.session.get("session") else { return };
+        let actions = self.scrobbles.lock().unwrap().observe(track, now);
+        for action in actions {
+            let (method, track, started) = match action {
+                Action::NowPlaying(track) => ("track.updateNowPlaying", track, None),
+                Action::Scrobble(track, started) => ("track.scrobble", track, Some(started)),
+            };
+            let mut params = BTreeMap::from([("artist", track.artist.clone()), ("track", track.title.clone()), ("sk", session.key.clone())]);
+            if let Some(album) = &track.album {
+                params.insert("album", album.clone());
+            }
+            if let Some(duration) = track.duration {
+                params.insert("duration", duration.to_string());
+            }
+            if let Some(started) = started {
+                params.insert("timestamp", started.to_string());
+            }
+            // a scrobble that fails is lost, the next track is still sent
+            match self.call(method, &params, true).await {
+                Ok(_) => debug!("{} {} - {}", method, track.artist, track.title),
+                Err(error) if error.downcast_ref::<ApiError>().is_some_and(|error| error.code == INVALID_SESSION) => {
+                    warn!("the Last.fm session of {} was revoked, logging out", session.name);
+                    self.session.remove("session");
+                    return;
+                },
+                Err(error) => warn!("could not send {} to Last.fm: {}", method, error),
+            }
+        }
+    }
+
+    async fn call(&self, method: &str, params: &BTreeMap<&str, String>, post: bool) -> Result<Value, Error> {
+        let mut params = params.clone();
+        params.insert("method", method.to_string());
+        params.insert("api_key", self.api_key.clone());
+        params.insert("api_sig", signature(&params, &self.secret));
+        params.insert("format", "json".to_string());
+        let request = match post {
+            true => self.client.post(&self.url).form(&params),
+            false => self.client.get(&self.url).query(&params),
+        };
+        // the errors come with a status as well, but the body tells them apart
+        let response: Value = request.send().await?.json().await?;
+        if let Some(code) = response["error"].as_u64() {
+            return Err(Box::new(ApiError { code, message: response["message"].as_str().unwrap_or_default().to_string() }));
+        }
+        Ok(response)
+    }
+}
+
+// the md5 of the parameters ordered by name, each name followed by its value, and the secret
+fn signature(params: &BTreeMap<&str, String>, secret: &str) -> String {
+    let mut text: String = params.iter().map(|(name, value)| format!("{}{}", name, value)).collect();
+    text.push_str(secret);
+    md5(text.as_bytes())
+}
+
+// only Last.fm still wants md5, none of the other crates has it
+fn md5(data: &[u8]) -> String {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let constants: Vec<u32> = (1..=64).map(|i| ((i as f64).sin().abs() * 4294967296.0) as u32).collect();
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64).wrapping_mul(8).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block.chunks(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(constants[i]).wrapping_add(words[g]);
+            (a, d, c) = (d, c, b);
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+    state.iter().flat_map(|word| word.to_le_bytes()).map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// looks at the metadata of the player every few seconds while someone is logged in
+pub async fn scrobble(state: web::Data<AppState>) {
+    if state.lastfm.is_none() {
+        return;
+    }
+    loop {
+        sleep(POLL_INTERVAL).await;
+        let Some(lastfm) = state.lastfm.as_ref().filter(|lastfm| lastfm.status().user.is_some()) else { continue };
+        let track = match state.video_player.running() {
+            Some(_) => web::block(|| {
+                let metadata = progress::mpv_command(json!(["get_property", "metadata"]))?;
+                let duration = progress::mpv_command(json!(["get_property", "duration"])).ok().and_then(|duration| duration.as_f64());
+                Ok::<_, std::io::Error>(track(&metadata, duration))
+            }).await.ok().and_then(Result::ok).flatten(),
+            None => None,
+        };
+        lastfm.observe(track, epg::now()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,121 @@
+use super::*;
+use crate::testing::{Fixture, MockServer, TempFolder};
+
+fn song(title: &str, duration: Option<u64>) -> Track {
+    Track { artist: "Daft Punk".to_string(), title: title.to_string(), album: None, duration }
+}
+
+fn lastfm(server: &MockServer, folder: &TempFolder) -> LastFm {
+    let url = server.serve("/2.0/", Fixture::Body(br#"{"scrobbles":{}}"#.to_vec()));
+    LastFm::new(url, "https://www.last.fm/api/auth/".to_string(), "key".to_string(), "secret".to_string(), Arc::new(Store::open(folder.join("store.json")).unwrap()))
+}
+
+#[test]
+fn hashes_like_md5() {
+    assert_eq!("d41d8cd98f00b204e9800998ecf8427e", md5(b""));
+    assert_eq!("9e107d9d372bb6826bd81d3542a419d6", md5(b"The quick brown fox jumps over the lazy dog"));
+    // longer than a block
+    assert_eq!("57edf4a22be3c955ac49da2e2107b67a", md5("1234567890".repeat(8).as_bytes()));
+}
+
+#[test]
+fn signs_the_parameters_ordered_by_name() {
+    let params = BTreeMap::from([("token", "t".to_string()), ("method", "auth.getSession".to_string()), ("api_key", "key".to_string())]);
+    assert_eq!(md5(b"api_keykeymethodauth.getSessiontokentsecret"), signature(&params, "secret"));
+}
+
+#[test]
+fn reads_the_track_from_the_tags_or_the_stream_title() {
+    let tags = json!({ "ARTIST": "Daft Punk", "Title": "One More Time", "album": "Discovery", "genre": "" });
+    assert_eq!(Some(Track { album: Some("Discovery".to_string()), ..song("One More Time", Some(320)) }), track(&tags, Some(320.4)));
+    assert_eq!(Some(song("Around the World", None)), track(&json!({ "icy-title": "Daft Punk - Around the World" }), None));
+    assert_eq!(None, track(&json!({ "icy-title": "Radio 1 - " }), None));
+    assert_eq!(None, track(&json!({ "title": "Only a title" }), Some(100.0)));
+}
+
+#[test]
+fn scrobbles_the_tracks_that_played_long_enough() {
+    let mut scrobbles = Scrobbles::default();
+    let (first, second, third) = (song("One More Time", Some(320)), song("Aerodynamic", Some(212)), song("Digital Love", Some(301)));
+
+    assert_eq!(vec![Action::NowPlaying(first.clone())], scrobbles.observe(Some(first.clone()), 1000));
+    assert!(scrobbles.observe(Some(first.clone()), 1010).is_empty());
+    // half of it played
+    assert_eq!(vec![Action::Scrobble(first, 1000), Action::NowPlaying(second.clone())], scrobbles.observe(Some(second.clone()), 1160));
+    // skipped
+    assert_eq!(vec![Action::NowPlaying(third.clone())], scrobbles.observe(Some(third.clone()), 1200));
+    assert_eq!(vec![Action::Scrobble(third, 1200)], scrobbles.observe(None, 1200 + MAX_PLAYED));
+    assert!(scrobbles.observe(None, 2000).is_empty());
+}
+
+#[test]
+fn scrobbles_radio_titles_after_a_minute() {
+    let mut scrobbles = Scrobbles::default();
+    let (first, second) = (song("Around the World", None), song("Veridis Quo", None));
+    scrobbles.observe(Some(first.clone()), 0);
+    assert_eq!(vec![Action::Scrobble(first, 0), Action::NowPlaying(second.clone())], scrobbles.observe(Some(second.clone()), RADIO_PLAYED));
+    assert_eq!(vec![Action::NowPlaying(song("Voyager", None))], scrobbles.observe(Some(song("Voyager", None)), RADIO_PLAYED + 10));
+}
+
+#[actix_web::test]
+async fn logs_in_with_a_signed_token() {
+    let server = MockServer::start();
+    let folder = TempFolder::new();
+    let lastfm = lastfm(&server, &folder);
+    let params = BTreeMap::from([("api_key", "key".to_string()), ("method", "auth.getToken".to_string())]);
+    let query = format!("/2.0/?api_key=key&api_sig={}&format=json&method=auth.getToken", signature(&params, "secret"));
+    server.serve(&query, Fixture::Body(br#"{"token":"abc"}"#.to_vec()));
+
+    let login = lastfm.start_login().await.unwrap();
+
+    assert_eq!("https://www.last.fm/api/auth/?api_key=key&token=abc", login.url);
+    assert!(lastfm.status().pending);
+    assert_eq!(None, lastfm.status().user);
+}
+
+#[actix_web::test]
+async fn waits_for_the_user_to_allow_the_login() {
+    let server = MockServer::start();
+    let folder = TempFolder::new();
+    let lastfm = lastfm(&server, &folder);
+    *lastfm.pending.lock().unwrap() = Some("abc".to_string());
+
+    server.serve("/2.0/", Fixture::Body(br#"{"error":14,"message":"This token has not been authorized"}"#.to_vec()));
+    assert_eq!(None, lastfm.finish_login().await.unwrap());
+    assert!(lastfm.status().pending);
+
+    server.serve("/2.0/", Fixture::Body(br#"{"session":{"name":"someone","key":"sk","subscriber":0}}"#.to_vec()));
+    assert_eq!(Some("someone".to_string()), lastfm.finish_login().await.unwrap());
+    assert!(!lastfm.status().pending);
+    assert_eq!("someone", lastfm.session.get("session").unwrap().name);
+}
+
+#[actix_web::test]
+async fn scrobbles_only_while_logged_in() {
+    let server = MockServer::start();
+    let folder = TempFolder::new();
+    let lastfm = lastfm(&server, &folder);
+
+    lastfm.observe(Some(song("One More Time", Some(320))), 1000).await;
+    assert_eq!(0, server.hits("/2.0/"));
+
+    lastfm.session.put("session", &Session { name: "someone".to_string(), key: "sk".to_string() });
+    lastfm.observe(Some(song("One More Time", Some(320))), 1000).await;
+    lastfm.observe(Some(song("Aerodynamic", Some(212))), 1200).await;
+    // now playing, the scrobble and the next now playing
+    assert_eq!(3, server.hits("/2.0/"));
+    assert_eq!(Some("application/x-www-form-urlencoded"), server.header("/2.0/", "content-type").as_deref());
+}
+
+#[actix_web::test]
+async fn logs_out_when_the_session_was_revoked() {
+    let server = MockServer::start();
+    let folder = TempFolder::new();
+    let lastfm = lastfm(&server, &folder);
+    lastfm.session.put("session", &Session { name: "someone".to_string(), key: "sk".to_string() });
+    server.serve("/2.0/", Fixture::Body(br#"{"error":9,"message":"Invalid session key"}"#.to_vec()));
+
+    lastfm.observe(Some(song("One More Time", Some(320))), 1000).await;
+
+    assert_eq!(None, lastfm.status().user);
+}
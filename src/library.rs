@@ -0,0 +1,228 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+use log::error;
+use reqwest::{Client, RequestBuilder};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use crate::tools;
+
+lazy_static! {
+    // mpv includes it for library items, the token goes in a header instead of the url or the arguments
+    pub static ref MPV_CONFIG: PathBuf = env::temp_dir().join("home_back-library-mpv.conf");
+}
+
+// a Jellyfin or Plex server whose libraries can be browsed and played
+pub enum Library {
+    Jellyfin { client: Client, url: String, token: String, user_id: String },
+    Plex { client: Client, url: String, token: String },
+}
+
+#[derive(Serialize, Debug)]
+pub struct LibraryItem {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub folder: bool, // has children that can be listed instead of being playable itself
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinItems {
+    items: Vec<JellyfinItem>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinItem {
+    id: String,
+    name: String,
+    #[serde(rename = "Type")]
+    kind: String,
+    #[serde(default)]
+    is_folder: bool,
+    media_type: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct PlexResponse {
+    media_container: PlexContainer,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct PlexContainer {
+    #[serde(default)]
+    directory: Vec<PlexDirectory>,
+    #[serde(default)]
+    metadata: Vec<PlexMetadata>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PlexDirectory {
+    key: String,
+    title: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PlexMetadata {
+    rating_key: String,
+    title: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default, rename = "Media")]
+    media: Vec<PlexMedia>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PlexMedia {
+    #[serde(rename = "Part")]
+    parts: Vec<PlexPart>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PlexPart {
+    key: String,
+}
+
+// plex libraries and items have separate id spaces, so the ids of libraries get a prefix
+const PLEX_SECTION_PREFIX: &str = "section-";
+
+impl PlexMetadata {
+    fn into_item(self) -> LibraryItem {
+        let folder = self.media.is_empty();
+        LibraryItem { id: self.rating_key, name: self.title, kind: self.kind, folder }
+    }
+}
+
+impl JellyfinItem {
+    fn into_item(self) -> LibraryItem {
+        LibraryItem { id: self.id, name: self.name, kind: self.kind, folder: self.is_folder }
+    }
+}
+
+impl Library {
+
+    // none without LIBRARY_SERVER, a broken configuration is logged and only disables the library
+    pub fn from_env() -> Option<Self> {
+        let server = env::var("LIBRARY_SERVER").ok()?;
+        let required = |name: &str| {
+            let value = env::var(name).ok().filter(|value| !value.is_empty());
+            if value.is_none() {
+                error!("{} not set, the library is disabled", name);
+            }
+            value
+        };
+        let url = required("LIBRARY_URL")?.trim_end_matches('/').to_string();
+        let token = required("LIBRARY_TOKEN")?;
+        let client = Client::builder().timeout(Duration::from_secs(10)).build().unwrap();
+        let library = match server.as_str() {
+            "jellyfin" => Library::Jellyfin { client, url, token, user_id: required("JELLYFIN_USER_ID")? },
+            "plex" => Library::Plex { client, url, token },
+            _ => { error!("LIBRARY_SERVER must be jellyfin or plex, not {}, the library is disabled", server); return None; },
+        };
+        // %length% quotes the value, whatever the token contains
+        let header = format!("{}: {}", library.token_header(), library.token());
+        if let Err(error) = tools::write_private(&MPV_CONFIG, &format!("http-header-fields=%{}%{}\n", header.len(), header)) {
+            error!("could not write {:?}, the library is disabled: {}", *MPV_CONFIG, error);
+            return None;
+        }
+        Some(library)
+    }
+
+    fn token_header(&self) -> &'static str {
+        match self {
+            Library::Jellyfin { .. } => "X-Emby-Token",
+            Library::Plex { .. } => "X-Plex-Token",
+        }
+    }
+
+    fn token(&self) -> &str {
+        match self {
+            Library::Jellyfin { token, .. } | Library::Plex { token, .. } => token,
+        }
+    }
+
+    // a stream url of get_direct_play with the token in it, for a Chromecast, which can't be given headers
+    pub fn with_token(&self, url: &str) -> String {
+        match self {
+            Library::Jellyfin { token, .. } => format!("{}&api_key={}", url, token),
+            Library::Plex { token, .. } => format!("{}?X-Plex-Token={}", url, token),
+        }
+    }
+
+    fn get(&self, path: &str) -> RequestBuilder {
+        match self {
+            Library::Jellyfin { client, url, .. } => client.get(format!("{}{}", url, path)).header(self.token_header(), self.token()),
+            Library::Plex { client, url, .. } => client.get(format!("{}{}", url, path)).header(self.token_header(), self.token()).header("Accept", "application/json"),
+        }
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, reqwest::Error> {
+        self.get(path).send().await?.error_for_status()?.json().await
+    }
+
+    pub async fn get_libraries(&self) -> Result<Vec<LibraryItem>, reqwest::Error> {
+        match self {
+            Library::Jellyfin { user_id, .. } => {
+                let views: JellyfinItems = self.get_json(&format!("/Users/{}/Views", user_id)).await?;
+                Ok(views.items.into_iter().map(JellyfinItem::into_item).collect())
+            },
+            Library::Plex { .. } => {
+                let sections: PlexResponse = self.get_json("/library/sections").await?;
+                Ok(sections.media_container.directory.into_iter()
+                    .map(|section| LibraryItem { id: format!("{}{}", PLEX_SECTION_PREFIX, section.key), name: section.title, kind: section.kind, folder: true })
+                    .collect())
+            },
+        }
+    }
+
+    pub async fn get_children(&self, id: &str) -> Result<Vec<LibraryItem>, reqwest::Error> {
+        match self {
+            Library::Jellyfin { user_id, .. } => {
+                let children: JellyfinItems = self.get_json(&format!("/Users/{}/Items?ParentId={}&SortBy=SortName", user_id, id)).await?;
+                Ok(children.items.into_iter().map(JellyfinItem::into_item).collect())
+            },
+            Library::Plex { .. } => {
+                let path = match id.strip_prefix(PLEX_SECTION_PREFIX) {
+                    Some(section) => format!("/library/sections/{}/all", section),
+                    None => format!("/library/metadata/{}/children", id),
+                };
+                let children: PlexResponse = self.get_json(&path).await?;
+                Ok(children.media_container.metadata.into_iter().map(PlexMetadata::into_item).collect())
+            },
+        }
+    }
+
+    // the name of a playable item and a url the player can stream it from directly with the headers of MPV_CONFIG, None if it is not playable
+    pub async fn get_direct_play(&self, id: &str) -> Result<Option<(String, String)>, reqwest::Error> {
+        match self {
+            Library::Jellyfin { url, user_id, .. } => {
+                let item: JellyfinItem = self.get_json(&format!("/Users/{}/Items/{}", user_id, id)).await?;
+                let stream = match item.media_type.as_deref() {
+                    Some("Video") => "Videos",
+                    Some("Audio") => "Audio",
+                    _ => return Ok(None),
+                };
+                Ok(Some((item.name, format!("{}/{}/{}/stream?static=true", url, stream, item.id))))
+            },
+            Library::Plex { url, .. } => {
+                let response: PlexResponse = self.get_json(&format!("/library/metadata/{}", id)).await?;
+                let item = match response.media_container.metadata.into_iter().next() {
+                    Some(item) => item,
+                    None => return Ok(None),
+                };
+                let part = match item.media.first().and_then(|media| media.parts.first()) {
+                    Some(part) => &part.key,
+                    None => return Ok(None),
+                };
+                Ok(Some((item.title.clone(), format!("{}{}", url, part))))
+            },
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use log::{info, warn};
+use crate::actions::{self, Action};
+use crate::state::AppState;
+
+const DEFAULT_SOCKET: &str = "/var/run/lirc/lircd";
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+// listens on the lircd socket and performs the actions mapped to the buttons in LIRC_BUTTONS
+pub fn listen(state: Arc<AppState>) {
+    let buttons = actions::parse_mapping("LIRC_BUTTONS", &env::var("LIRC_BUTTONS").unwrap_or_default());
+    if buttons.is_empty() {
+        return;
+    }
+    let socket = env::var("LIRC_SOCKET").unwrap_or(DEFAULT_SOCKET.to_string());
+
+    thread::spawn(move || loop {
+        match UnixStream::connect(&socket) {
+            Ok(stream) => {
+                info!("Listening for IR remote buttons on {}", socket);
+                handle_events(&state, &buttons, BufReader::new(stream));
+                warn!("Lost connection to lircd at {}", socket);
+            },
+            Err(error) => warn!("Could not connect to lircd at {}: {}", socket, error),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+}
+
+fn handle_events(state: &AppState, buttons: &HashMap<String, Action>, reader: impl BufRead) {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        // looks like "000000037ff07bee 00 KEY_VOLUMEUP myremote", the second field counts the repeats while a button is held
+        let mut words = line.split_whitespace();
+        let (Some(_code), Some(repeat), Some(button)) = (words.next(), words.next(), words.next()) else {
+            continue;
+        };
+        if let Some(&action) = buttons.get(button) {
+            if repeat == "00" || action.repeats() {
+                actions::perform(state, action);
+            }
+        }
+    }
+}
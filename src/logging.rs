@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use env_logger::{Target, WriteStyle};
+use env_logger::filter::{Builder as FilterBuilder, Filter};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_ROTATE_HOURS: u64 = 24;
+const DEFAULT_RETENTION: usize = 5;
+
+static LOGGER: OnceLock<&'static ReloadableLogger> = OnceLock::new();
+
+// the active log filter, a default level plus overrides for single modules
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogLevel {
+    pub level: String,
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+}
+
+impl LogLevel {
+    // the filter uses the env_logger syntax, e.g. "info,home_back::download=debug"
+    fn parse(spec: &str) -> Self {
+        let mut level = "info".to_string();
+        let mut modules = BTreeMap::new();
+        for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, module_level)) => { modules.insert(module.to_string(), module_level.to_string()); },
+                None => level = directive.to_string(),
+            }
+        }
+        Self { level, modules }
+    }
+
+    fn to_spec(&self) -> String {
+        std::iter::once(self.level.clone())
+            .chain(self.modules.iter().map(|(module, level)| format!("{}={}", module, level)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        std::iter::once(&self.level).chain(self.modules.values())
+            .try_for_each(|level| LevelFilter::from_str(level).map(|_| ()).map_err(|_| format!("invalid log level: {}", level)))
+    }
+}
+
+// wraps env_logger so the filter can be swapped while running, env_logger itself only does the formatting
+struct ReloadableLogger {
+    inner: env_logger::Logger,
+    filter: RwLock<(LogLevel, Filter)>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.read().unwrap().1.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.filter.read().unwrap().1.matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn build_filter(level: &LogLevel) -> Filter {
+    FilterBuilder::new().parse(&level.to_spec()).build()
+}
+
+// RUST_LOG sets the initial filter, so per module levels work like "info,home_back::download=debug"
+pub fn init() {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Trace);
+
+    match env::var("LOG_FILE") {
+        Ok(path) => {
+            let file = RotatingFile::from_env(PathBuf::from(path)).expect("could not open LOG_FILE");
+            builder.target(Target::Pipe(Box::new(file))).write_style(WriteStyle::Never);
+        },
+        Err(_) => { builder.write_style(WriteStyle::Always); },
+    }
+
+    let level = LogLevel::parse(&env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()));
+    let filter = build_filter(&level);
+    let max_level = filter.filter();
+    let logger: &'static ReloadableLogger = Box::leak(Box::new(ReloadableLogger { inner: builder.build(), filter: RwLock::new((level, filter)) }));
+
+    log::set_logger(logger).expect("logger already initialized");
+    log::set_max_level(max_level);
+    LOGGER.set(logger).ok();
+}
+
+pub fn get_level() -> Option<LogLevel> {
+    LOGGER.get().map(|logger| logger.filter.read().unwrap().0.clone())
+}
+
+pub fn set_level(level: LogLevel) -> Result<LogLevel, String> {
+    level.validate()?;
+    let logger = LOGGER.get().ok_or("logger not initialized")?;
+
+    let filter = build_filter(&level);
+    log::set_max_level(filter.filter());
+    *logger.filter.write().unwrap() = (level.clone(), filter);
+    log::info!("Changed log level to {}", level.to_spec());
+    Ok(level)
+}
+
+// log file that gets rotated once it grows past max_size or is older than max_age
+// rotated files are named like the log file with a number appended, .1 being the newest
+struct RotatingFile {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    opened_at: Instant,
+    max_size: u64,
+    max_age: Duration,
+    retention: usize,
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+
+    fn from_env(path: PathBuf) -> io::Result<Self> {
+        let max_size = env_or("LOG_MAX_SIZE", DEFAULT_MAX_SIZE);
+        let max_age = Duration::from_secs(env_or("LOG_ROTATE_HOURS", DEFAULT_ROTATE_HOURS) * 60 * 60);
+        let retention = env_or("LOG_RETENTION", DEFAULT_RETENTION);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size, opened_at: Instant::now(), max_size, max_age, retention })
+    }
+
+    fn needs_rotation(&self) -> bool {
+        self.size >= self.max_size || self.opened_at.elapsed() >= self.max_age
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.retention == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // shift every rotated file one number up, the oldest one falls off the end
+            let _ = fs::remove_file(numbered(&self.path, self.retention));
+            for n in (1..self.retention).rev() {
+                let from = numbered(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, numbered(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+
+        self.file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation() {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
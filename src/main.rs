@@ -2,105 +2,740 @@
 extern crate lazy_static;
 
 mod process;
+mod actions;
+mod arr;
+mod audio;
+mod auth;
+mod cec;
+mod chromecast;
+mod display;
+mod dlna;
 mod twitch;
+mod upload;
 mod download;
 mod dvbc;
 mod dvbc_preview;
+mod epg;
+mod events;
 mod files;
+mod health;
+mod hls;
+mod idle;
+mod input;
+mod lastfm;
+mod library;
+#[cfg(unix)]
+mod lirc;
+mod logging;
+mod media;
+mod mqtt;
+mod notifier;
+mod podcast;
+mod postprocess;
+mod power;
+mod probe;
+mod profiles;
+mod raids;
+mod relay;
+mod progress;
+mod radio;
+mod recorder;
+mod settings;
+mod speedtest;
+mod state;
+mod stats;
+mod storage;
+mod store;
+mod subtitles;
+mod teletext;
+#[cfg(test)]
+mod testing;
+mod tuners;
+mod tools;
+mod validation;
+mod viewing;
+mod webhooks;
+mod wol;
+mod xml;
 
-use dvbc_preview::ChannelPreview;
+use dvbc_preview::{ChannelPreview, Priority};
+use state::AppState;
+use validation::{Validate, Validator};
 
 use std::env;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use dotenv::dotenv;
-use env_logger::{Env, WriteStyle};
-use actix_web::{App, HttpResponse, HttpServer, Responder, get, put, post, delete, web, http};
+use actix_web::rt::{signal, spawn, System};
+use futures::StreamExt;
+use itertools::Itertools;
+use futures::channel::mpsc;
+use futures::future::{select, Either};
+use actix_web::{App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder, get, put, post, delete, web, http, middleware};
+use actix_web::http::header::{EntityTag, ETag, IfNoneMatch};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use log::{info, error};
 use process::*;
 
-lazy_static! {
-    static ref CHAT:             ProcessHandler<String, process::Chat>        = process::ProcessHandler::new(process::Chat{}, None);
-    static ref VIDEO_PLAYER:     ProcessHandler<VideoPlayerArgs, VideoPlayer> = ProcessHandler::new(process::VideoPlayer{}, Some(|args, _| if let VideoPlayerArgs::Twitch(_) = args {CHAT.stop().unwrap()}));
-    static ref TWITCH:           twitch::Twitch                               = twitch::Twitch::new();
-    static ref DOWNLOAD_MANAGER: download::DownloadManager                    = download::DownloadManager::new();
-    static ref DVBC:             dvbc::DvbC                                   = dvbc::DvbC::new();
-    static ref DVBC_PREVIEWS:    dvbc_preview::DvbCPreviews                   = dvbc_preview::DvbCPreviews::new();
-}
-
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type", content = "uri")]
 pub enum VideoPlayerSomthing {
     Twitch(String),
     DvbC(String),
+    Library(String),
+    Url(String),
+    Media(String),  // the path of a file in the media index
 }
 impl From<&VideoPlayerArgs> for VideoPlayerSomthing {
     fn from(args: &VideoPlayerArgs) -> Self {
-        return match args {
-            VideoPlayerArgs::Twitch(stream) => VideoPlayerSomthing::Twitch(stream.clone()),
+        match args {
+            VideoPlayerArgs::Twitch { stream, .. } => VideoPlayerSomthing::Twitch(stream.clone()),
             VideoPlayerArgs::DvbC(channel) => VideoPlayerSomthing::DvbC(channel.name.clone()),
+            VideoPlayerArgs::Library { id, .. } => VideoPlayerSomthing::Library(id.clone()),
+            VideoPlayerArgs::Url { url, .. } => VideoPlayerSomthing::Url(url.clone()),
+            VideoPlayerArgs::Media { path, .. } => VideoPlayerSomthing::Media(path.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+    commit: &'static str,
+    built_at: u64,
+}
+
+#[get("/version")]
+async fn get_version() -> impl Responder {
+    HttpResponse::Ok().json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("HOMEBACK_COMMIT"),
+        built_at: env!("HOMEBACK_BUILT_AT").parse().unwrap(),
+    })
+}
+
+#[get("/health")]
+async fn get_health() -> impl Responder {
+    HttpResponse::Ok().finish()
+}
+
+#[get("/ready")]
+async fn get_ready(state: web::Data<AppState>) -> impl Responder {
+    let readiness = state.health.check_readiness().await;
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+#[get("/status")]
+async fn get_status(state: web::Data<AppState>) -> impl Responder {
+    match web::block(move || state.health.system_status()).await {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/system/stats")]
+async fn get_system_stats(state: web::Data<AppState>) -> impl Responder {
+    match web::block(move || state.health.system_stats()).await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/network/speedtest")]
+async fn post_speedtest(state: web::Data<AppState>) -> impl Responder {
+    match state.speedtest.run().await {
+        Ok(test) => HttpResponse::Ok().json(test),
+        Err(speedtest::SpeedTestError::Running) => HttpResponse::Conflict().finish(),
+        Err(speedtest::SpeedTestError::Request(error)) => { error!("speed test failed: {}", error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+impl Validate for VideoPlayerSomthing {
+    fn validate(&self, validator: &mut Validator) {
+        match self {
+            VideoPlayerSomthing::Twitch(stream) => validator.check(validation::is_twitch_login(stream), "uri", "must be a twitch login name"),
+            VideoPlayerSomthing::DvbC(channel) => validator.check(validation::is_channel_name(channel), "uri", "must be a channel name"),
+            VideoPlayerSomthing::Library(id) => validator.check(validation::is_library_id(id), "uri", "must be a library item id"),
+            VideoPlayerSomthing::Url(url) => validator.check(validation::is_http_url(url), "uri", "must be a http(s) url"),
+            VideoPlayerSomthing::Media(path) => validator.check(!path.is_empty() && path.len() <= 4096, "uri", "must be the path of a media file"),
         };
     }
 }
 
 #[get("/videoplayer")]
-async fn get_videoplayer() -> impl Responder {
-    match VIDEO_PLAYER.running() {
-        Some(args) => HttpResponse::Ok().json(VideoPlayerSomthing::from(&*args)),
+async fn get_videoplayer(state: web::Data<AppState>) -> impl Responder {
+    match state.video_player.running() {
+        Some(args) => HttpResponse::Ok().json(StartVideoPlayer::from(&*args)),
         None => HttpResponse::NoContent().finish()
     }
 }
 
+#[derive(Serialize)]
+struct VideoPlayerSource {
+    #[serde(flatten)]
+    source: VideoPlayerSomthing,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chat: Option<String>,
+}
+
+// what the player opens, so another device on the LAN can play the same stream without going through the HTPC
+#[get("/videoplayer/source")]
+async fn get_videoplayer_source(state: web::Data<AppState>) -> impl Responder {
+    let args = match state.video_player.running() {
+        Some(args) => args,
+        None => return HttpResponse::NoContent().finish(),
+    };
+    let (url, chat) = match &*args {
+        VideoPlayerArgs::Twitch { stream, .. } => {
+            let name = stream.clone();
+            match web::block(move || chromecast::twitch_stream_url(&name)).await {
+                Ok(Ok(url)) => (Some(url), Some(format!("https://www.twitch.tv/popout/{}/chat", stream))),
+                Ok(Err(error)) => { error!("{}", error); return HttpResponse::BadGateway().finish() },
+                Err(_) => return HttpResponse::InternalServerError().finish(),
+            }
+        },
+        VideoPlayerArgs::DvbC(channel) => (Some(channel.url.clone()), None),
+        VideoPlayerArgs::Url { url, .. } => (Some(url.clone()), None),
+        // the stream url holds the LIBRARY_TOKEN, the other device has to log in itself and only gets the id
+        VideoPlayerArgs::Library { .. } => (None, None),
+        // a file on the disk of the HTPC
+        VideoPlayerArgs::Media { .. } => return HttpResponse::NotFound().finish(),
+    };
+    HttpResponse::Ok().json(VideoPlayerSource { source: VideoPlayerSomthing::from(&*args), url, chat })
+}
+
+#[derive(Serialize, Deserialize)]
+struct StartVideoPlayer {
+    #[serde(flatten)]
+    source: VideoPlayerSomthing,
+    // the name of a Chromecast to cast to instead of the local player
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    // streamlink's audio_only quality without a video window, only for Twitch
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    audio_only: bool,
+}
+
+impl From<&VideoPlayerArgs> for StartVideoPlayer {
+    fn from(args: &VideoPlayerArgs) -> Self {
+        StartVideoPlayer { source: VideoPlayerSomthing::from(args), target: None, audio_only: args.is_audio_only() }
+    }
+}
+
+impl Validate for StartVideoPlayer {
+    fn validate(&self, validator: &mut Validator) {
+        self.source.validate(validator);
+        if self.audio_only {
+            validator.check(matches!(self.source, VideoPlayerSomthing::Twitch(_)) && self.target.is_none(), "audio_only", "only for Twitch streams on the local player");
+        }
+        if let Some(target) = &self.target {
+            validator.check(chromecast::is_device_name(target), "target", "must be the name of a chromecast");
+        }
+    }
+}
+
+// the player waits out its startup window and its hooks call xset, so it is started, stopped and restarted on the blocking pool
+async fn play(state: &web::Data<AppState>, args: VideoPlayerArgs) -> HttpResponse {
+    let state = state.clone();
+    match web::block(move || state.video_player.start(args)).await {
+        Ok(started) => player_started(started),
+        Err(error) => { error!("could not start player: {}", error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
+async fn restart_player(state: &web::Data<AppState>) -> Result<(), HttpResponse> {
+    let state = state.clone();
+    match web::block(move || state.video_player.restart()).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(error)) => { error!("could not restart videoplayer: {}", error); Err(HttpResponse::InternalServerError().finish()) },
+        Err(_) => Err(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+// a DvbC channel gets its tuner before the player starts, so nothing else can take it in between
+async fn play_channel(state: &web::Data<AppState>, channel: dvbc::Channel) -> HttpResponse {
+    let (state, name) = (state.clone(), channel.name.clone());
+    match web::block(move || state.player_tuner.start(&name, || state.video_player.start(VideoPlayerArgs::DvbC(channel)))).await {
+        Ok(Ok(started)) => player_started(started),
+        Ok(Err(busy)) => tuners_busy(busy),
+        Err(error) => { error!("could not start player: {}", error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
+fn player_started(started: std::io::Result<Arc<VideoPlayerArgs>>) -> HttpResponse {
+    match started {
+        Ok(args) => HttpResponse::Ok().json(StartVideoPlayer::from(&*args)),
+        Err(error) => match error.get_ref().and_then(|inner| inner.downcast_ref::<StartupFailed>()) {
+            Some(failed) => HttpResponse::BadGateway().json(serde_json::json!({ "error": "player_failed", "message": failed.to_string(), "output": failed.output })),
+            None => { error!("could not start player: {}", error); HttpResponse::InternalServerError().finish() },
+        },
+    }
+}
+
 #[put("/videoplayer")]
-async fn start_videoplayer(web::Json(args): web::Json<VideoPlayerSomthing>) -> impl Responder {
-    return match args {
-        VideoPlayerSomthing::Twitch(stream) => HttpResponse::Ok().json(VideoPlayerSomthing::from(&*VIDEO_PLAYER.start(VideoPlayerArgs::Twitch(stream)).unwrap())),
+async fn start_videoplayer(state: web::Data<AppState>, web::Json(args): web::Json<StartVideoPlayer>, request: HttpRequest) -> impl Responder {
+    if let Err(response) = validation::validate(&args) {
+        return response;
+    }
+    let profile = match request_profile(&state, &request) {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    if let Some(target) = args.target {
+        return cast_videoplayer(state, args.source, target).await;
+    }
+    match args.source {
+        VideoPlayerSomthing::Twitch(stream) => play(&state, VideoPlayerArgs::Twitch { stream, audio_only: args.audio_only }).await,
         VideoPlayerSomthing::DvbC(channel_name) => {                
-            match DVBC.get_channels() {
+            match state.dvbc.get_channels() {
                 None => HttpResponse::InternalServerError().finish(), // TODO some return code / header that specifies we couldn't load channels
                 Some(channels) => {
-                    match channels.tv.iter().find(|channel| channel.name == channel_name) {
+                    // radio plays on the player too, e.g. for the radio relay
+                    match channels.tv.iter().chain(&channels.radio).find(|channel| channel.name == channel_name) {
                         None => HttpResponse::NotFound().finish(),
-                        Some(channel) => HttpResponse::Ok().json(VideoPlayerSomthing::from(&*VIDEO_PLAYER.start(VideoPlayerArgs::DvbC(channel.clone())).unwrap()))
+                        Some(channel) => play_channel(&state, channel.clone()).await,
                     }
                 }
             }
         }
+        VideoPlayerSomthing::Library(id) => {
+            let library = match &state.library {
+                Some(library) => library,
+                None => return HttpResponse::NotFound().finish(),
+            };
+            match library.get_direct_play(&id).await {
+                Ok(Some((name, url))) => play(&state, VideoPlayerArgs::Library { id, name, url, profile }).await,
+                Ok(None) => validation::bad_request("uri", format!("{} is not playable", id)),
+                Err(error) => { error!("could not get library item {}: {}", id, error); HttpResponse::BadGateway().finish() },
+            }
+        }
+        VideoPlayerSomthing::Url(url) => play(&state, VideoPlayerArgs::Url { name: url.clone(), url, profile }).await,
+        // only indexed files, so this can't be used to open anything on the disk
+        VideoPlayerSomthing::Media(path) => match state.media.get(&path) {
+            Some(file) => play(&state, VideoPlayerArgs::Media { name: file.name, path: file.path, profile }).await,
+            None => HttpResponse::NotFound().finish(),
+        },
     }
 }
 
-#[delete("/videoplayer")]
-async fn stop_videoplayer() -> impl Responder {
-    VIDEO_PLAYER.stop().unwrap();
+#[get("/library/server")]
+async fn get_libraries(state: web::Data<AppState>) -> impl Responder {
+    let library = match &state.library {
+        Some(library) => library,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    match library.get_libraries().await {
+        Ok(libraries) => HttpResponse::Ok().json(libraries),
+        Err(error) => { error!("could not get libraries: {}", error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+#[get("/library/server/{id}")]
+async fn get_library_items(state: web::Data<AppState>, id: web::Path<String>) -> impl Responder {
+    if !validation::is_library_id(&id) {
+        return validation::bad_request("id", "must be a library item id".to_string());
+    }
+    let library = match &state.library {
+        Some(library) => library,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    match library.get_children(&id).await {
+        Ok(items) => HttpResponse::Ok().json(items),
+        Err(error) => { error!("could not get library items of {}: {}", id, error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+#[derive(Deserialize)]
+struct Paging {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[get("/library")]
+async fn get_media(state: web::Data<AppState>, web::Query(paging): web::Query<Paging>, request: HttpRequest) -> impl Responder {
+    match request_profile(&state, &request) {
+        Ok(profile) => HttpResponse::Ok().json(state.media.get_page(profile.as_ref(), paging.offset, paging.limit.unwrap_or(50))),
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+struct MediaSearch {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[get("/library/search")]
+async fn search_media(state: web::Data<AppState>, web::Query(search): web::Query<MediaSearch>, request: HttpRequest) -> impl Responder {
+    if search.q.trim().is_empty() || search.q.len() > 200 {
+        return validation::bad_request("q", "must be between 1 and 200 characters".to_string());
+    }
+    match request_profile(&state, &request) {
+        Ok(profile) => HttpResponse::Ok().json(state.media.search(profile.as_ref(), &search.q, search.limit.unwrap_or(20))),
+        Err(response) => response,
+    }
+}
+
+#[get("/library/duplicates")]
+async fn get_media_duplicates(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.media.duplicates())
+}
+
+#[derive(Deserialize)]
+struct DeleteDuplicates {
+    paths: Vec<String>,
+}
+impl Validate for DeleteDuplicates {
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .check(!self.paths.is_empty(), "paths", "must not be empty")
+            .check(self.paths.len() <= 1000, "paths", "are too many");
+    }
+}
+
+#[delete("/library/duplicates")]
+async fn delete_media_duplicates(state: web::Data<AppState>, web::Json(delete): web::Json<DeleteDuplicates>) -> impl Responder {
+    if let Err(response) = validation::validate(&delete) {
+        return response;
+    }
+    let state = state.into_inner();
+    match web::block(move || state.media.delete_duplicates(&delete.paths)).await {
+        Ok(Ok(deleted)) => HttpResponse::Ok().json(deleted),
+        Ok(Err(error @ (media::DeleteError::NotADuplicate(_) | media::DeleteError::Outside(_)))) => validation::bad_request("paths", error.to_string()),
+        Ok(Err(error @ (media::DeleteError::LastCopy(_) | media::DeleteError::Changed(_)))) => HttpResponse::Conflict().body(error.to_string()),
+        Ok(Err(error)) => { error!("could not delete duplicates: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(error) => { error!("could not delete duplicates: {}", error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
+#[derive(Deserialize)]
+struct SubtitleSearch {
+    // comma separated, e.g. "en,de"
+    #[serde(default = "default_subtitle_languages")]
+    languages: String,
+}
+fn default_subtitle_languages() -> String {
+    env::var("SUBTITLE_LANGUAGES").unwrap_or("en".to_string())
+}
+
+#[get("/library/{id}/subtitles")]
+async fn search_subtitles(state: web::Data<AppState>, id: web::Path<String>, web::Query(search): web::Query<SubtitleSearch>) -> impl Responder {
+    let (subtitles, file) = match (&state.subtitles, state.media.get_by_id(&id)) {
+        (Some(subtitles), Some(file)) => (subtitles, file),
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    match subtitles.search(&file.path, &search.languages).await {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(error) => { error!("could not search subtitles for {:?}: {}", file.path, error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+#[derive(Deserialize)]
+struct SubtitleDownload {
+    file_id: u64,
+}
+
+#[post("/library/{id}/subtitles")]
+async fn download_subtitle(state: web::Data<AppState>, id: web::Path<String>, web::Json(download): web::Json<SubtitleDownload>) -> impl Responder {
+    let (subtitles, file) = match (&state.subtitles, state.media.get_by_id(&id)) {
+        (Some(subtitles), Some(file)) => (subtitles, file),
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    let subtitle = match subtitles.download(download.file_id, &file.path).await {
+        Ok(subtitle) => subtitle,
+        Err(error) => { error!("could not download subtitle {}: {}", download.file_id, error); return HttpResponse::BadGateway().finish() },
+    };
+
+    // mpv only looks for subtitles when it opens a file, so a running one has to be told
+    let playing = matches!(state.video_player.running().as_deref(), Some(VideoPlayerArgs::Media { path, .. }) if *path == file.path);
+    if playing {
+        let subtitle = subtitle.clone();
+        if let Ok(Err(error)) = web::block(move || progress::mpv_command(serde_json::json!(["sub-add", subtitle, "select"]))).await {
+            error!("could not add subtitle to the player: {}", error);
+        }
+    }
+    HttpResponse::Created().json(subtitle)
+}
+
+#[get("/lastfm")]
+async fn get_lastfm(state: web::Data<AppState>) -> impl Responder {
+    match &state.lastfm {
+        Some(lastfm) => HttpResponse::Ok().json(lastfm.status()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// the user allows the login at the url, then GET tells when it is done
+#[post("/lastfm/login")]
+async fn start_lastfm_login(state: web::Data<AppState>) -> impl Responder {
+    let lastfm = match &state.lastfm {
+        Some(lastfm) => lastfm,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    match lastfm.start_login().await {
+        Ok(login) => HttpResponse::Created().json(login),
+        Err(error) => { error!("could not start the Last.fm login: {}", error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+#[get("/lastfm/login")]
+async fn finish_lastfm_login(state: web::Data<AppState>) -> impl Responder {
+    let lastfm = match &state.lastfm {
+        Some(lastfm) => lastfm,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let status = lastfm.status();
+    if !status.pending {
+        return match status.user {
+            Some(user) => HttpResponse::Ok().json(serde_json::json!({ "user": user })),
+            None => HttpResponse::NotFound().finish(),
+        };
+    }
+    match lastfm.finish_login().await {
+        Ok(Some(user)) => HttpResponse::Ok().json(serde_json::json!({ "user": user })),
+        Ok(None) => HttpResponse::Accepted().finish(),
+        Err(error) => { error!("could not finish the Last.fm login: {}", error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+#[delete("/lastfm/login")]
+async fn lastfm_logout(state: web::Data<AppState>) -> impl Responder {
+    let lastfm = match &state.lastfm {
+        Some(lastfm) => lastfm,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    lastfm.logout();
     HttpResponse::NoContent().finish()
 }
 
+#[get("/media/{path:.*}")]
+async fn get_media_file(path: web::Path<String>, request: HttpRequest) -> impl Responder {
+    let path = match download::download_location(&path) {
+        Ok(path) => path,
+        Err(files::PathError::Io(error)) if error.kind() == std::io::ErrorKind::NotFound => return HttpResponse::NotFound().finish(),
+        Err(error @ files::PathError::Io(_)) => { error!("could not resolve {}: {}", path, error); return HttpResponse::InternalServerError().finish() },
+        Err(_) => return HttpResponse::NotFound().finish(),
+    };
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return HttpResponse::NotFound().finish(),
+        Err(error) => { error!("could not open {:?}: {}", path, error); return HttpResponse::InternalServerError().finish() },
+    };
+    let size = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(error) => { error!("could not read {:?}: {}", path, error); return HttpResponse::InternalServerError().finish() },
+    };
+
+    let content_type = files::content_type(&path);
+    let range = request.headers().get(http::header::RANGE).and_then(|value| value.to_str().ok());
+    let (mut response, start, length) = match range.map_or(files::ByteRange::Whole, |range| files::parse_range(range, size)) {
+        files::ByteRange::Whole => (HttpResponse::Ok(), 0, size),
+        files::ByteRange::Part(start, end) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header((http::header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)));
+            (response, start, end - start + 1)
+        },
+        files::ByteRange::Unsatisfiable => return HttpResponse::RangeNotSatisfiable().insert_header((http::header::CONTENT_RANGE, format!("bytes */{}", size))).finish(),
+    };
+    match files::stream(file, start, length).await {
+        Ok(body) => response
+            .content_type(content_type)
+            .insert_header((http::header::ACCEPT_RANGES, "bytes"))
+            .no_chunking(length)
+            .streaming(body),
+        Err(error) => { error!("could not read {:?}: {}", path, error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
+async fn cast_videoplayer(state: web::Data<AppState>, source: VideoPlayerSomthing, target: String) -> HttpResponse {
+    let (name, url) = match &source {
+        VideoPlayerSomthing::Twitch(stream) => {
+            let name = stream.clone();
+            match web::block(move || chromecast::twitch_stream_url(&name)).await {
+                Ok(Ok(url)) => (stream.clone(), url),
+                Ok(Err(error)) => { error!("{}", error); return HttpResponse::BadGateway().finish() },
+                Err(_) => return HttpResponse::InternalServerError().finish(),
+            }
+        },
+        // the Chromecast can't play the MPEG-TS of the router, it gets an HLS restream
+        VideoPlayerSomthing::DvbC(channel_name) => {
+            let channel = match state.dvbc.get_channels() {
+                None => return HttpResponse::InternalServerError().finish(),
+                Some(channels) => match channels.tv.iter().chain(&channels.radio).find(|channel| channel.name == *channel_name) {
+                    None => return HttpResponse::NotFound().finish(),
+                    Some(channel) => channel.clone(),
+                }
+            };
+            let hls = state.hls.clone();
+            let restreamed = channel.clone();
+            match web::block(move || hls.start(&restreamed)).await {
+                Ok(Ok(url)) => (channel.name, url),
+                Ok(Err(hls::HlsError::Busy(busy))) => return tuners_busy(busy),
+                Ok(Err(hls::HlsError::Io(error))) => { error!("could not restream {}: {}", channel.name, error); return HttpResponse::BadGateway().finish() },
+                Err(_) => return HttpResponse::InternalServerError().finish(),
+            }
+        },
+        VideoPlayerSomthing::Library(id) => {
+            let library = match &state.library {
+                Some(library) => library,
+                None => return HttpResponse::NotFound().finish(),
+            };
+            match library.get_direct_play(id).await {
+                Ok(Some((name, url))) => (name, library.with_token(&url)),
+                Ok(None) => return validation::bad_request("uri", format!("{} is not playable", id)),
+                Err(error) => { error!("could not get library item {}: {}", id, error); return HttpResponse::BadGateway().finish() },
+            }
+        },
+        VideoPlayerSomthing::Url(url) => (url.clone(), url.clone()),
+        // catt serves local files itself
+        VideoPlayerSomthing::Media(path) => match state.media.get(path) {
+            Some(file) => (file.name, file.path.to_string_lossy().into_owned()),
+            None => return HttpResponse::NotFound().finish(),
+        },
+    };
+
+    let device = target.clone();
+    match web::block(move || chromecast::cast(&device, &name, &url)).await {
+        Ok(Ok(())) => HttpResponse::Ok().json(StartVideoPlayer { source, target: Some(target), audio_only: false }),
+        Ok(Err(error)) => { error!("could not cast to {}: {}", target, error); HttpResponse::BadGateway().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/chromecast")]
+async fn get_chromecasts() -> impl Responder {
+    match web::block(chromecast::devices).await {
+        Ok(Ok(devices)) => HttpResponse::Ok().json(devices),
+        Ok(Err(error)) => { error!("could not scan for chromecasts: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[delete("/chromecast/{device}")]
+async fn stop_chromecast(state: web::Data<AppState>, device: web::Path<String>) -> impl Responder {
+    let device = device.into_inner();
+    if !chromecast::is_device_name(&device) {
+        return validation::bad_request("device", "must be the name of a chromecast".to_string());
+    }
+    state.hls.stop();
+    match web::block(move || chromecast::stop(&device)).await {
+        Ok(Ok(())) => HttpResponse::NoContent().finish(),
+        Ok(Err(error)) => { error!("could not stop chromecast: {}", error); HttpResponse::BadGateway().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[delete("/videoplayer")]
+async fn stop_videoplayer(state: web::Data<AppState>) -> impl Responder {
+    match web::block(move || state.video_player.stop()).await {
+        Ok(Ok(())) => HttpResponse::NoContent().finish(),
+        Ok(Err(error)) => { error!("could not stop videoplayer: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct NightMode {
+    enabled: bool,
+}
+
+#[get("/videoplayer/night-mode")]
+async fn get_night_mode(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(NightMode { enabled: state.night_mode.load(Ordering::Relaxed) })
+}
+
+#[put("/videoplayer/night-mode")]
+async fn put_night_mode(state: web::Data<AppState>, web::Json(NightMode { enabled }): web::Json<NightMode>) -> impl Responder {
+    if state.night_mode.swap(enabled, Ordering::Relaxed) != enabled {
+        info!("Night mode {}", if enabled { "enabled" } else { "disabled" });
+        // the filter is set when the player starts, so a running one has to start again
+        if let Err(response) = restart_player(&state).await {
+            return response;
+        }
+    }
+    HttpResponse::Ok().json(NightMode { enabled })
+}
+
+#[derive(Serialize)]
+struct SpotifyStatus {
+    running: bool,
+    name: String,
+}
+
+#[get("/spotify/status")]
+async fn get_spotify_status(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(SpotifyStatus { running: state.spotify.running().is_some(), name: state.spotify_name.clone() })
+}
+
+#[put("/spotify")]
+async fn start_spotify(state: web::Data<AppState>) -> impl Responder {
+    match state.spotify.start(state.spotify_name.clone()) {
+        Ok(_) => HttpResponse::Ok().json(SpotifyStatus { running: true, name: state.spotify_name.clone() }),
+        Err(error) => { error!("could not start librespot: {}", error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
+#[delete("/spotify")]
+async fn stop_spotify(state: web::Data<AppState>) -> impl Responder {
+    match state.spotify.stop() {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(error) => { error!("could not stop librespot: {}", error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
 #[get("/chat")]
-async fn get_chat() -> impl Responder {
-    match CHAT.running() {
+async fn get_chat(state: web::Data<AppState>) -> impl Responder {
+    match state.chat.running() {
         Some(stream) => HttpResponse::Ok().json(&*stream),
         None => HttpResponse::NoContent().finish(),
     }
 }
 
 #[put("/chat")]
-async fn open_chat(web::Json(stream): web::Json<String>) -> impl Responder {
-    HttpResponse::Ok().json(&*CHAT.start(stream).unwrap())
+async fn open_chat(state: web::Data<AppState>, web::Json(stream): web::Json<String>) -> impl Responder {
+    if !validation::is_twitch_login(&stream) {
+        return validation::bad_request("stream", "must be a twitch login name".to_string());
+    }
+    HttpResponse::Ok().json(&*state.chat.start(stream).unwrap())
 }
 
 #[delete("/chat")]
-async fn stop_chat() -> impl Responder {
-    CHAT.stop().unwrap();
+async fn stop_chat(state: web::Data<AppState>) -> impl Responder {
+    state.chat.stop().unwrap();
     HttpResponse::NoContent().finish()
 }
 
 #[put("/twitch/login")]
-async fn put_twitch_login() -> impl Responder {
-    HttpResponse::Ok().json(TWITCH.create_user_login().unwrap())
+async fn put_twitch_login(state: web::Data<AppState>, request: HttpRequest) -> impl Responder {
+    let profile = match request_profile(&state, &request) {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    let login = state.twitch.create_user_login().unwrap();
+    if let Some(profile) = profile {
+        state.profiles.add_twitch_login(&profile, login.id);
+    }
+    HttpResponse::Ok().json(login)
 }
 
 #[get("/twitch/login/{id}")]
-async fn get_twitch_login(id: web::Path<Uuid>) -> impl Responder {
-    if let Some(login) = TWITCH.get_user_login(*id) {
+async fn get_twitch_login(state: web::Data<AppState>, id: web::Path<Uuid>) -> impl Responder {
+    if let Some(login) = state.twitch.get_user_login(*id) {
         HttpResponse::Ok().json(login)
     } else {
         HttpResponse::NotFound().finish()
@@ -108,14 +743,138 @@ async fn get_twitch_login(id: web::Path<Uuid>) -> impl Responder {
 }
 
 #[get("/twitch/live/{id}")]
-async fn get_twitch_live(id: web::Path<Uuid>) -> impl Responder {
-    if let Some(streams) = TWITCH.get_online_following(*id).unwrap() {
-        HttpResponse::Ok().json(streams)
-    } else {
-        HttpResponse::NotFound().finish()
+async fn get_twitch_live(state: web::Data<AppState>, id: web::Path<Uuid>, request: HttpRequest) -> impl Responder {
+    let token = twitch::valid_access_token(&state, *id).await;
+    match state.twitch.get_online_following(token).await {
+        Ok(Some(streams)) => {
+            // fetched for every request, so the tag is derived from the content
+            let body = web::Bytes::from(serde_json::to_vec(&streams).unwrap());
+            json_with_etag(&request, &body, body.clone())
+        },
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(error) => { error!("could not check the followed streams of {}: {}", id, error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+#[derive(Deserialize)]
+struct Since {
+    #[serde(default)]
+    since: u64,
+}
+
+#[get("/twitch/live-by-game/{id}")]
+async fn get_twitch_live_by_game(state: web::Data<AppState>, id: web::Path<Uuid>) -> impl Responder {
+    let token = twitch::valid_access_token(&state, *id).await;
+    match state.twitch.get_live_by_game(token).await {
+        Ok(Some(games)) => HttpResponse::Ok().json(games),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(error) => { error!("could not check the followed streams of {}: {}", id, error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+// lets a frontend poll cheaply and only fetch the whole list when complete is false
+#[get("/twitch/live/{id}/changes")]
+async fn get_twitch_live_changes(state: web::Data<AppState>, id: web::Path<Uuid>, web::Query(Since { since }): web::Query<Since>) -> impl Responder {
+    match twitch::get_live_changes(&state, *id, since).await {
+        Ok(Some(changes)) => HttpResponse::Ok().json(changes),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(error) => { error!("could not check the followed streams of {}: {}", id, error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+const MAX_BOOKMARK_DESCRIPTION: usize = 140; // the limit of the stream markers
+
+#[derive(Deserialize)]
+struct NewBookmark {
+    // the Twitch login to look up the stream with, defaults to the first one of the profile
+    login: Option<Uuid>,
+    description: Option<String>,
+}
+
+#[post("/twitch/bookmark")]
+async fn post_twitch_bookmark(state: web::Data<AppState>, web::Json(bookmark): web::Json<NewBookmark>, request: HttpRequest) -> impl Responder {
+    if bookmark.description.as_ref().is_some_and(|description| description.chars().count() > MAX_BOOKMARK_DESCRIPTION) {
+        return validation::bad_request("description", "must not be longer than 140 characters".to_string());
+    }
+    let profile = match request_profile(&state, &request) {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    let stream = match state.video_player.running().as_deref() {
+        Some(VideoPlayerArgs::Twitch { stream, .. }) => stream.clone(),
+        _ => return HttpResponse::Conflict().body("no Twitch stream is playing"),
+    };
+    let login = bookmark.login.or_else(|| profile.and_then(|profile| state.profiles.get(&profile)?.twitch_logins.first().copied()));
+    let access_token = match login {
+        Some(login) => web::block({ let state = state.clone(); move || state.twitch.access_token(&login) }).await.ok().flatten(),
+        None => None,
+    };
+    let bookmark = state.twitch.bookmark(stream, bookmark.description, access_token).await;
+    HttpResponse::Created().json(bookmark)
+}
+
+#[derive(Deserialize)]
+struct NewClip {
+    // like for bookmarks, the login needs the clips:edit scope, which TWITCH_SCOPES has to add
+    login: Option<Uuid>,
+}
+
+#[post("/twitch/clip")]
+async fn post_twitch_clip(state: web::Data<AppState>, web::Json(clip): web::Json<NewClip>, request: HttpRequest) -> impl Responder {
+    let profile = match request_profile(&state, &request) {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    let stream = match state.video_player.running().as_deref() {
+        Some(VideoPlayerArgs::Twitch { stream, .. }) => stream.clone(),
+        _ => return HttpResponse::Conflict().body("no Twitch stream is playing"),
+    };
+    let login = match clip.login.or_else(|| profile.and_then(|profile| state.profiles.get(&profile)?.twitch_logins.first().copied())) {
+        Some(login) => login,
+        None => return validation::bad_request("login", "clips need a Twitch login".to_string()),
+    };
+    let access_token = match web::block({ let state = state.clone(); move || state.twitch.access_token_with_scopes(&login) }).await {
+        Ok(Some((_, scopes))) if !scopes.iter().any(|scope| scope == CLIP_SCOPE) => return missing_clip_scope(),
+        Ok(Some((access_token, _))) => access_token,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    match state.twitch.clip(&stream, &access_token).await {
+        Ok(Some(clip)) => {
+            state.events.publish(events::Event::TwitchClip { stream: stream.clone(), id: clip.id.clone(), edit_url: clip.edit_url.clone() });
+            HttpResponse::Created().json(clip)
+        },
+        Ok(None) => HttpResponse::Conflict().body(format!("{} is not live", stream)),
+        // the scope can also be revoked on Twitch after the token was validated
+        Err(error) if error.status().is_some_and(|status| status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN) => missing_clip_scope(),
+        Err(error) => { error!("could not clip {}: {}", stream, error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+const CLIP_SCOPE: &str = "clips:edit";
+
+fn missing_clip_scope() -> HttpResponse {
+    HttpResponse::Forbidden().body(format!("missing scope: the login needs {}, add it to TWITCH_SCOPES and log in again", CLIP_SCOPE))
+}
+
+#[get("/twitch/bookmark")]
+async fn get_twitch_bookmarks(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.twitch.bookmarks())
+}
+
+#[delete("/twitch/bookmark/{id}")]
+async fn delete_twitch_bookmark(state: web::Data<AppState>, id: web::Path<Uuid>) -> impl Responder {
+    match state.twitch.delete_bookmark(&id) {
+        true => HttpResponse::NoContent().finish(),
+        false => HttpResponse::NotFound().finish(),
     }
 }
 
+#[get("/process")]
+async fn get_processes() -> impl Responder {
+    HttpResponse::Ok().json(process::managed_processes())
+}
+
 #[get("/download/scan")]
 async fn get_scans() -> impl Responder {
     HttpResponse::Ok().json(download::read_scan_folder().unwrap())
@@ -123,104 +882,1378 @@ async fn get_scans() -> impl Responder {
 
 #[get("/download/scan/{file}")]
 async fn get_scan(file: web::Path<String>) -> impl Responder {
-    HttpResponse::Ok().json(download::read_scan_file(file.into_inner()).unwrap())
+    match download::read_scan_file(file.into_inner()) {
+        Ok(links) => HttpResponse::Ok().json(links),
+        Err(error) => file_error(error),
+    }
+}
+
+#[derive(Deserialize)]
+struct ScanBatch {
+    // like "{show}/Season {season}/{original_name}", see download::TEMPLATE_VARIABLES
+    #[serde(default = "default_template")]
+    template: String,
+    // only some of the links of the scan file
+    links: Option<Vec<String>>,
+    #[serde(flatten)]
+    headers: download::RequestHeaders,
+}
+
+fn default_template() -> String {
+    "{original_name}".to_string()
+}
+
+impl Validate for ScanBatch {
+    fn validate(&self, validator: &mut Validator) {
+        lazy_static! {
+            static ref VARIABLE: regex::Regex = regex::Regex::new(r"\{([^}]*)\}").unwrap();
+        }
+        validator
+            .check(!self.template.is_empty() && self.template.len() <= validation::MAX_PATH_LENGTH, "template", "must not be empty or too long")
+            .check(VARIABLE.captures_iter(&self.template).all(|captures| download::TEMPLATE_VARIABLES.contains(&&captures[1])), "template", "contains an unknown variable");
+        self.headers.validate(validator);
+    }
+}
+
+// downloads the links of a scan file at once, named by the template
+#[post("/download/scan/{file}")]
+async fn post_scan_batch(state: web::Data<AppState>, file: web::Path<String>, web::Json(batch): web::Json<ScanBatch>) -> impl Responder {
+    if let Err(response) = validation::validate(&batch) {
+        return response;
+    }
+    let scan = file.into_inner();
+    let links = match download::read_scan_file(scan.clone()) {
+        Ok(links) => links,
+        Err(error) => return file_error(error),
+    };
+    let links = match batch.links {
+        Some(selected) if selected.iter().any(|link| !links.contains(link)) => return validation::bad_request("links", format!("must be links of {}", scan)),
+        Some(selected) => selected,
+        None => links,
+    };
+
+    // all paths are checked first, so a bad template doesn't leave half of the batch queued
+    let paths: Vec<String> = links.iter().map(|link| download::templated_path(&batch.template, link, &scan)).collect();
+    for path in &paths {
+        match download::download_location(path) {
+            Ok(_) => {},
+            Err(error @ files::PathError::Io(_)) => { error!("could not resolve download path: {}", error); return HttpResponse::InternalServerError().finish() },
+            Err(error) => return validation::bad_request("template", error.to_string()),
+        }
+    }
+    let mut downloads = Vec::with_capacity(links.len());
+    for (link, path) in links.into_iter().zip(paths) {
+        match state.download_manager.trigger_download(link, path, batch.headers.clone()) {
+            Ok(download) => downloads.push(download),
+            Err(error) => { error!("could not queue download: {}", error); return HttpResponse::InternalServerError().json(downloads) },
+        }
+    }
+    HttpResponse::Created().json(downloads)
 }
 
 #[get("/download/files/{subfolder}")]
 async fn get_downloads_subfolder(subfolder: web::Path<String>) -> impl Responder {
-    HttpResponse::Ok().json(download::read_downloads_subfolder(subfolder.into_inner()).unwrap())
+    match download::read_downloads_subfolder(subfolder.into_inner()) {
+        Ok(files) => HttpResponse::Ok().json(files),
+        Err(error) => file_error(error),
+    }
+}
+
+// paths outside of the folders are reported like missing ones, so they can't be probed
+fn file_error(error: std::io::Error) -> HttpResponse {
+    match error.kind() {
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied => HttpResponse::NotFound().finish(),
+        _ => { error!("could not read files: {}", error); HttpResponse::InternalServerError().finish() },
+    }
 }
 
 
+#[post("/download/files/{subfolder}")]
+async fn upload_files(state: web::Data<AppState>, subfolder: web::Path<String>, request: HttpRequest, payload: web::Payload) -> impl Responder {
+    let boundary = match request.headers().get(http::header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).and_then(upload::boundary) {
+        Some(boundary) => boundary,
+        None => return validation::bad_request("body", "must be multipart/form-data".to_string()),
+    };
+    let too_large = request.headers().get(http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|length| length > state.uploads.max_size());
+    if too_large {
+        return HttpResponse::PayloadTooLarge().finish();
+    }
+
+    let folder = match download::download_location(&subfolder) {
+        Ok(folder) => folder,
+        Err(error @ files::PathError::Io(_)) => { error!("could not resolve {}: {}", subfolder, error); return HttpResponse::InternalServerError().finish() },
+        Err(error) => return validation::bad_request("subfolder", error.to_string()),
+    };
+    match state.uploads.receive(payload, &boundary, &folder).await {
+        Ok(files) => {
+            // so the files can be played right away, a scan that already runs does another one after it
+            let state = state.into_inner();
+            std::thread::spawn(move || state.media.scan());
+            HttpResponse::Created().json(files)
+        },
+        Err(upload::UploadError::TooLarge(_)) => HttpResponse::PayloadTooLarge().finish(),
+        Err(error @ upload::UploadError::Exists(_)) => HttpResponse::Conflict().body(error.to_string()),
+        Err(error @ upload::UploadError::Invalid(_)) => validation::bad_request("body", error.to_string()),
+        Err(error) => { error!("could not receive upload: {}", error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
 #[get("/download/{uuid}")]
-async fn get_download(uuid: web::Path<Uuid>) -> impl Responder {
-    match DOWNLOAD_MANAGER.get_download(uuid.into_inner()) {
+async fn get_download(state: web::Data<AppState>, uuid: web::Path<Uuid>) -> impl Responder {
+    match state.download_manager.get_download(uuid.into_inner()) {
         Some(download) => HttpResponse::Ok().json(download),
         None => HttpResponse::NoContent().finish(),
     }
 }
 #[get("/download")]
-async fn get_downloads() -> impl Responder {
-    HttpResponse::Ok().json(DOWNLOAD_MANAGER.get_downloads())
+async fn get_downloads(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.download_manager.get_downloads())
+}
+
+#[post("/postprocessing")]
+async fn post_postprocessing(state: web::Data<AppState>, web::Json(request): web::Json<postprocess::JobRequest>) -> impl Responder {
+    let mut validator = Validator::default();
+    validator
+        .check(!request.path.is_empty() && request.path.len() <= validation::MAX_PATH_LENGTH, "path", "must be a path in the DOWNLOAD_FOLDER")
+        .check(request.cut_start >= 0.0 && request.cut_end >= 0.0, "cut", "must not be negative");
+    if let Err(response) = validator.finish() {
+        return response;
+    }
+    match state.postprocessing.enqueue(request) {
+        Ok(job) => {
+            let location = format!("/postprocessing/{}", job.id);
+            HttpResponse::Accepted().append_header((http::header::LOCATION, &*location)).json(job)
+        },
+        Err(files::PathError::Io(error)) if error.kind() == std::io::ErrorKind::NotFound => HttpResponse::NotFound().finish(),
+        Err(files::PathError::Io(error)) if error.kind() == std::io::ErrorKind::AlreadyExists => HttpResponse::Conflict().body(error.to_string()),
+        Err(error @ files::PathError::Io(_)) => { error!("could not queue post processing: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(error) => validation::bad_request("path", error.to_string()),
+    }
+}
+
+#[get("/postprocessing")]
+async fn get_postprocessing_jobs(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.postprocessing.all())
+}
+
+#[get("/postprocessing/{id}")]
+async fn get_postprocessing_job(state: web::Data<AppState>, id: web::Path<Uuid>) -> impl Responder {
+    match state.postprocessing.get(*id) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().finish(),
+    }
 }
 
 #[derive(Deserialize)]
 struct Download {
     url: String,
     path: String,
+    #[serde(flatten)]
+    headers: download::RequestHeaders,
+}
+impl Validate for Download {
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .check(validation::is_http_url(&self.url), "url", "must be a http or https url")
+            .check(!self.path.is_empty(), "path", "must not be empty")
+            .check(self.path.len() <= validation::MAX_PATH_LENGTH, "path", "is too long");
+        self.headers.validate(validator);
+    }
+}
+
+impl Validate for download::RequestHeaders {
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .check(self.user_agent.as_deref().is_none_or(validation::is_header_value), "user_agent", "must be a short header value")
+            .check(self.referer.as_deref().is_none_or(validation::is_http_url), "referer", "must be a http or https url");
+    }
 }
+
 #[post("/download")]
-async fn post_download(web::Json(Download{url, path}): web::Json<Download>) -> impl Responder {
-    let download = DOWNLOAD_MANAGER.trigger_download(url, path);
+async fn post_download(state: web::Data<AppState>, web::Json(download): web::Json<Download>) -> impl Responder {
+    if let Err(response) = validation::validate(&download) {
+        return response;
+    }
+    let Download{url, path, headers} = download;
+    let download = match state.download_manager.trigger_download(url, path, headers) {
+        Ok(download) => download,
+        Err(error @ files::PathError::Io(_)) => { error!("could not resolve download path: {}", error); return HttpResponse::InternalServerError().finish() },
+        Err(error) => return validation::bad_request("path", error.to_string()),
+    };
     let location = format!("/download/{}", download.uuid);
     HttpResponse::Created().append_header((http::header::LOCATION, &*location)).json(download)
 }
 
 #[delete("/download/{uuid}")]
-async fn cancel_download(uuid: web::Path<Uuid>) -> impl Responder {
-    DOWNLOAD_MANAGER.cancel_download(uuid.into_inner());
+async fn cancel_download(state: web::Data<AppState>, uuid: web::Path<Uuid>) -> impl Responder {
+    state.download_manager.cancel_download(uuid.into_inner());
     HttpResponse::NoContent().finish()
 }
 
-#[get("/dvbc/tv")]
-async fn get_dvbc_tv() -> impl Responder {
-    match DVBC.get_channels() {
-        Some(channels) => { let response: Vec<&String> = channels.tv.iter().map(|c| &c.name).collect(); HttpResponse::Ok().json(response) }
-        None => HttpResponse::NoContent().finish(), // TODO some return code that specifies we couldn't load channels
+const PROFILE_HEADER: &str = "X-Profile";
+const MAX_FAVORITES: usize = 500;
+
+#[derive(Deserialize)]
+struct ProfileQuery {
+    profile: Option<String>,
+}
+
+// the profile from the X-Profile header or the profile query parameter, requests without one get what is shared by everyone
+fn request_profile(state: &AppState, request: &HttpRequest) -> Result<Option<Uuid>, HttpResponse> {
+    let id = match request.headers().get(PROFILE_HEADER) {
+        Some(header) => header.to_str().unwrap_or_default().to_string(),
+        None => match web::Query::<ProfileQuery>::from_query(request.query_string()).ok().and_then(|query| query.into_inner().profile) {
+            Some(id) => id,
+            None => return Ok(None),
+        },
+    };
+    match id.parse::<Uuid>().ok().filter(|id| state.profiles.get(id).is_some()) {
+        Some(id) => Ok(Some(id)),
+        None => Err(validation::bad_request("profile", "must be the id of a profile".to_string())),
     }
 }
 
-#[get("/dvbc/radio")]
-async fn get_dvbc_radio() -> impl Responder {
-    match DVBC.get_channels() {
-        Some(channels) => { let response: Vec<&String> = channels.radio.iter().map(|c| &c.name).collect(); HttpResponse::Ok().json(response) }
-        None => HttpResponse::NoContent().finish(), // TODO some return code that specifies we couldn't load channels
+#[get("/profiles")]
+async fn get_profiles(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.profiles.all())
+}
+
+#[derive(Deserialize)]
+struct NewProfile {
+    name: String,
+}
+impl Validate for NewProfile {
+    fn validate(&self, validator: &mut Validator) {
+        let name = self.name.trim();
+        validator.check(!name.is_empty() && name.chars().count() <= profiles::MAX_NAME_LENGTH, "name", "must be between 1 and 50 characters");
     }
 }
 
-#[post("/dvbc/tv/previews")] // it's a get with a body...
-async fn get_dvbc_tv_previews(web::Json(channel_names): web::Json<Vec<String>>) -> impl Responder {
-    match DVBC.get_channels() {
-        None => HttpResponse::InternalServerError().finish(), // TODO some return code / header that specifies we couldn't load channels
-        Some(channels) => {
-            let previews : Vec<Option<ChannelPreview>> = channel_names.iter()
-                .map(|name| channels.tv.iter()
-                    .find(|channel| &channel.name == name)
-                    .map(|channel| DVBC_PREVIEWS.get_preview(channel).unwrap())
-            ).collect();
-            HttpResponse::Ok().json(&previews)
-        }
+#[post("/profiles")]
+async fn post_profile(state: web::Data<AppState>, web::Json(profile): web::Json<NewProfile>) -> impl Responder {
+    if let Err(response) = validation::validate(&profile) {
+        return response;
     }
+    let profile = state.profiles.create(profile.name.trim().to_string());
+    let location = format!("/profiles/{}", profile.id);
+    HttpResponse::Created().append_header((http::header::LOCATION, &*location)).json(profile)
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    dotenv().ok();
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).write_style(WriteStyle::Always).init();
+#[get("/profiles/{id}")]
+async fn get_profile(state: web::Data<AppState>, id: web::Path<Uuid>) -> impl Responder {
+    match state.profiles.get(&id) {
+        Some(profile) => HttpResponse::Ok().json(profile),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
 
-    HttpServer::new(move || {
-        App::new()
-            .service(get_videoplayer)
-            .service(start_videoplayer)
-            .service(stop_videoplayer)
-            .service(get_chat)
-            .service(open_chat)
-            .service(stop_chat)
-            .service(put_twitch_login)
-            .service(get_twitch_login)
-            .service(get_twitch_live)
-            .service(get_scans)
-            .service(get_scan)
-            .service(get_downloads_subfolder)
-            .service(get_download)
-            .service(get_downloads)
-            .service(post_download)
-            .service(cancel_download)
-            .service(get_dvbc_tv)
-            .service(get_dvbc_radio)
-            .service(get_dvbc_tv_previews)
+#[put("/profiles/{id}/favorites")]
+async fn put_favorites(state: web::Data<AppState>, id: web::Path<Uuid>, web::Json(favorites): web::Json<Vec<VideoPlayerSomthing>>) -> impl Responder {
+    let mut validator = Validator::default();
+    validator.check(favorites.len() <= MAX_FAVORITES, "favorites", "must not be more than 500");
+    favorites.iter().for_each(|favorite| favorite.validate(&mut validator));
+    if let Err(response) = validator.finish() {
+        return response;
+    }
+    match state.profiles.set_favorites(&id, favorites) {
+        Some(profile) => HttpResponse::Ok().json(profile.favorites),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+#[get("/history")]
+async fn get_history(state: web::Data<AppState>, web::Query(query): web::Query<HistoryQuery>, request: HttpRequest) -> impl Responder {
+    match request_profile(&state, &request) {
+        Ok(profile) => HttpResponse::Ok().json(state.progress.history(profile.as_ref(), query.limit.unwrap_or(50).min(media::MAX_PAGE_SIZE))),
+        Err(response) => response,
+    }
+}
+
+#[get("/settings/{namespace}")]
+async fn get_settings(state: web::Data<AppState>, namespace: web::Path<String>) -> impl Responder {
+    if !validation::is_settings_namespace(&namespace) {
+        return validation::bad_request("namespace", "must be 1 to 64 lowercase letters, digits, - or _".to_string());
+    }
+    HttpResponse::Ok().json(state.settings.get(&namespace))
+}
+
+#[put("/settings/{namespace}")]
+async fn put_settings(state: web::Data<AppState>, namespace: web::Path<String>, web::Json(settings): web::Json<serde_json::Map<String, serde_json::Value>>) -> impl Responder {
+    if !validation::is_settings_namespace(&namespace) {
+        return validation::bad_request("namespace", "must be 1 to 64 lowercase letters, digits, - or _".to_string());
+    }
+    state.settings.put(&namespace, &settings);
+    HttpResponse::Ok().json(settings)
+}
+
+#[derive(Deserialize)]
+struct ViewingQuery {
+    period: Option<viewing::Period>,
+}
+
+#[get("/stats/viewing")]
+async fn get_viewing_stats(state: web::Data<AppState>, web::Query(query): web::Query<ViewingQuery>) -> impl Responder {
+    HttpResponse::Ok().json(state.viewing.stats(query.period.unwrap_or(viewing::Period::Week)))
+}
+
+#[get("/podcasts")]
+async fn get_podcasts(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.podcasts.get_podcasts())
+}
+
+#[derive(Deserialize)]
+struct PodcastSubscription {
+    url: String,
+}
+impl Validate for PodcastSubscription {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(validation::is_http_url(&self.url), "url", "must be a http or https url");
+    }
+}
+
+#[post("/podcasts")]
+async fn post_podcast(state: web::Data<AppState>, web::Json(subscription): web::Json<PodcastSubscription>) -> impl Responder {
+    if let Err(response) = validation::validate(&subscription) {
+        return response;
+    }
+    match state.podcasts.subscribe(subscription.url, &state.download_manager).await {
+        Ok(podcast) => {
+            let location = format!("/podcasts/{}", podcast.id);
+            HttpResponse::Created().append_header((http::header::LOCATION, &*location)).json(podcast)
+        },
+        Err(error) => { error!("could not subscribe to podcast: {}", error); HttpResponse::BadGateway().finish() },
+    }
+}
+
+#[get("/podcasts/{id}")]
+async fn get_podcast(state: web::Data<AppState>, id: web::Path<String>) -> impl Responder {
+    match state.podcasts.get_podcast(&id) {
+        Some(podcast) => HttpResponse::Ok().json(podcast),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[delete("/podcasts/{id}")]
+async fn delete_podcast(state: web::Data<AppState>, id: web::Path<String>) -> impl Responder {
+    match state.podcasts.unsubscribe(&id) {
+        true => HttpResponse::NoContent().finish(),
+        false => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[put("/podcasts/{id}/episodes/{episode}/play")]
+async fn play_podcast_episode(state: web::Data<AppState>, path: web::Path<(String, String)>, request: HttpRequest) -> impl Responder {
+    let (id, episode) = path.into_inner();
+    let profile = match request_profile(&state, &request) {
+        Ok(profile) => profile,
+        Err(response) => return response,
+    };
+    match state.podcasts.episode_source(&id, &episode, &state.download_manager) {
+        Some((name, url)) => {
+            play(&state, VideoPlayerArgs::Url { name, url, profile }).await
+        },
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// the frontend refreshes the listings often, a 304 saves sending the same json again
+fn json_with_etag(request: &HttpRequest, version: impl Hash, body: web::Bytes) -> HttpResponse {
+    let mut hasher = DefaultHasher::new();
+    version.hash(&mut hasher);
+    let etag = EntityTag::new_weak(format!("{:x}", hasher.finish()));
+    let cached = match request.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if cached {
+        return HttpResponse::NotModified().insert_header(ETag(etag)).finish();
+    }
+    HttpResponse::Ok().insert_header(ETag(etag)).content_type("application/json").body(body)
+}
+
+#[derive(Deserialize)]
+struct Freshness {
+    // fetches the channels from the router instead of answering from the cache
+    #[serde(default)]
+    fresh: bool,
+}
+
+async fn dvbc_channels(state: &AppState, fresh: bool) -> Result<Option<Arc<dvbc::Channels>>, HttpResponse> {
+    if !fresh {
+        return Ok(state.dvbc.get_channels());
+    }
+    match state.dvbc.fetch_fresh().await {
+        Ok(channels) => Ok(Some(channels)),
+        Err(error) => Err(HttpResponse::BadGateway().json(serde_json::json!({ "error": "router_unreachable", "message": error.to_string() }))),
+    }
+}
+
+#[get("/dvbc/tv")]
+async fn get_dvbc_tv(state: web::Data<AppState>, web::Query(query): web::Query<Freshness>, request: HttpRequest) -> impl Responder {
+    match dvbc_channels(&state, query.fresh).await {
+        Ok(Some(channels)) => json_with_etag(&request, ("tv", channels.version()), channels.tv_names()),
+        Ok(None) => no_channels(&state),
+        Err(response) => response,
+    }
+}
+
+#[get("/dvbc/radio")]
+async fn get_dvbc_radio(state: web::Data<AppState>, web::Query(query): web::Query<Freshness>, request: HttpRequest) -> impl Responder {
+    match dvbc_channels(&state, query.fresh).await {
+        Ok(Some(channels)) => json_with_etag(&request, ("radio", channels.version()), channels.radio_names()),
+        Ok(None) => no_channels(&state),
+        Err(response) => response,
+    }
+}
+
+// the channels were never loaded, a 503 if we know it is because of the router
+fn no_channels(state: &AppState) -> HttpResponse {
+    match state.health.router_reachable() {
+        Some(false) => HttpResponse::ServiceUnavailable().json(serde_json::json!({ "error": "router_unreachable", "message": "the router is not reachable" })),
+        _ => HttpResponse::NoContent().finish(),
+    }
+}
+
+#[get("/dvbc/epg.xml")]
+async fn get_dvbc_epg(state: web::Data<AppState>) -> impl Responder {
+    match state.dvbc.get_channels() {
+        Some(channels) => {
+            let channels: Vec<dvbc::Channel> = channels.tv.iter().chain(&channels.radio).cloned().collect();
+            HttpResponse::Ok().content_type("application/xml; charset=utf-8").body(state.epg.xmltv(&channels))
+        },
+        None => no_channels(&state),
+    }
+}
+
+#[get("/dvbc/now")]
+async fn get_dvbc_now(state: web::Data<AppState>) -> impl Responder {
+    match state.dvbc.get_channels() {
+        Some(channels) => HttpResponse::Ok().json(state.epg.now_on(&channels.tv, epg::now())),
+        None => no_channels(&state),
+    }
+}
+
+#[get("/dvbc/recordings")]
+async fn get_recordings(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.recorder.recordings())
+}
+
+#[delete("/dvbc/recordings/{id}")]
+async fn delete_recording(state: web::Data<AppState>, id: web::Path<Uuid>) -> impl Responder {
+    match state.recorder.cancel(&id) {
+        Some(true) => HttpResponse::NoContent().finish(),
+        Some(false) => HttpResponse::Conflict().body("the recording is over"),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/dvbc/series")]
+async fn get_series_rules(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.recorder.rules())
+}
+
+#[derive(Deserialize)]
+struct NewSeriesRule {
+    title: String,
+    channel: String,
+    #[serde(default)]
+    new_only: bool,
+}
+impl Validate for NewSeriesRule {
+    fn validate(&self, validator: &mut Validator) {
+        validator
+            .check(!self.title.trim().is_empty() && self.title.len() <= 200, "title", "must be between 1 and 200 characters")
+            .check(validation::is_channel_name(&self.channel), "channel", "must be a channel name");
+    }
+}
+
+#[post("/dvbc/series")]
+async fn post_series_rule(state: web::Data<AppState>, web::Json(rule): web::Json<NewSeriesRule>) -> impl Responder {
+    if let Err(response) = validation::validate(&rule) {
+        return response;
+    }
+    let known = state.dvbc.get_channels().is_some_and(|channels| channels.tv.iter().chain(&channels.radio).any(|channel| channel.name == rule.channel));
+    if !known {
+        return validation::bad_request("channel", format!("{} is not a channel of the router", rule.channel));
+    }
+    let rule = state.recorder.add_rule(rule.title.trim().to_string(), rule.channel, rule.new_only, &state.epg.programmes(), epg::now());
+    HttpResponse::Created().json(rule)
+}
+
+#[delete("/dvbc/series/{id}")]
+async fn delete_series_rule(state: web::Data<AppState>, id: web::Path<Uuid>) -> impl Responder {
+    match state.recorder.remove_rule(&id) {
+        true => HttpResponse::NoContent().finish(),
+        false => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/dvbc/{channel}/probe")]
+async fn get_dvbc_probe(state: web::Data<AppState>, channel_name: web::Path<String>) -> impl Responder {
+    let channel = match state.dvbc.get_channels().and_then(|channels| channels.tv.iter().chain(&channels.radio).find(|channel| channel.name == *channel_name).cloned()) {
+        Some(channel) => channel,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let tuner = match state.tuners.acquire("probe", &channel.name) {
+        Ok(tuner) => tuner,
+        Err(busy) => return tuners_busy(busy),
+    };
+    match web::block(move || { let _tuner = tuner; probe::probe(&channel) }).await {
+        Ok(Ok(probe)) => HttpResponse::Ok().json(probe),
+        Ok(Err(error)) => { error!("could not probe {}: {}", channel_name, error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+impl Validate for dvbc::ChannelOrder {
+    fn validate(&self, validator: &mut Validator) {
+        for (field, names) in [("tv", &self.tv), ("radio", &self.radio)] {
+            validator
+                .check(names.len() <= validation::MAX_ORDERED_CHANNELS, field, "too many channels")
+                .check(names.iter().all(|name| validation::is_channel_name(name)), field, "must all be channel names")
+                .check(names.iter().all_unique(), field, "must not list a channel twice");
+        }
+    }
+}
+
+#[get("/dvbc/order")]
+async fn get_dvbc_order(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.dvbc.get_order())
+}
+
+// channels that are not on the router (yet) keep their place, so a channel scan doesn't lose the order
+#[put("/dvbc/order")]
+async fn put_dvbc_order(state: web::Data<AppState>, web::Json(order): web::Json<dvbc::ChannelOrder>) -> impl Responder {
+    if let Err(response) = validation::validate(&order) {
+        return response;
+    }
+    state.dvbc.set_order(order);
+    HttpResponse::Ok().json(state.dvbc.get_order())
+}
+
+impl Validate for dvbc::ChannelSettings {
+    fn validate(&self, validator: &mut Validator) {
+        lazy_static! {
+            static ref ASPECT: regex::Regex = regex::Regex::new(r"^\d{1,2}(\.\d{1,3})?:\d{1,2}(\.\d{1,3})?$").unwrap();
+        }
+        validator
+            .check(self.audio_track.is_none_or(|track| (1..=16).contains(&track)), "audio_track", "must be between 1 and 16")
+            .check(self.volume_offset.is_none_or(|offset| (-20..=20).contains(&offset)), "volume_offset", "must be between -20 and 20 dB")
+            .check(self.aspect.as_deref().is_none_or(|aspect| ASPECT.is_match(aspect)), "aspect", "must be a ratio like 16:9");
+    }
+}
+
+#[get("/dvbc/{channel}/settings")]
+async fn get_dvbc_settings(state: web::Data<AppState>, channel_name: web::Path<String>) -> impl Responder {
+    if !validation::is_channel_name(&channel_name) {
+        return validation::bad_request("channel", "must be a channel name".to_string());
+    }
+    HttpResponse::Ok().json(state.dvbc.get_settings(&channel_name))
+}
+
+// the empty object removes the settings of the channel
+#[put("/dvbc/{channel}/settings")]
+async fn put_dvbc_settings(state: web::Data<AppState>, channel_name: web::Path<String>, web::Json(settings): web::Json<dvbc::ChannelSettings>) -> impl Responder {
+    if !state.dvbc.get_channels().is_some_and(|channels| channels.tv.iter().chain(&channels.radio).any(|channel| channel.name == *channel_name)) {
+        return HttpResponse::NotFound().finish();
+    }
+    if let Err(response) = validation::validate(&settings) {
+        return response;
+    }
+    state.dvbc.set_settings(&channel_name, &settings);
+    // the settings are passed to mpv when it starts, so a running one has to start again
+    if state.video_player.running().is_some_and(|args| matches!(&*args, VideoPlayerArgs::DvbC(playing) if playing.name == *channel_name)) {
+        if let Err(response) = restart_player(&state).await {
+            return response;
+        }
+    }
+    HttpResponse::Ok().json(settings)
+}
+
+// the player gets its channels from here with DVBC_RELAY, other clients can use it too
+#[get("/dvbc/relay/{channel}")]
+async fn get_dvbc_relay(state: web::Data<AppState>, channel_name: web::Path<String>, request: HttpRequest) -> impl Responder {
+    let channel = match state.dvbc.get_channels().and_then(|channels| channels.tv.iter().chain(&channels.radio).find(|channel| channel.name == *channel_name).cloned()) {
+        Some(channel) => channel,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    // the player already holds the tuner of the channel it plays, only its own stream shares it
+    let from_player = request.peer_addr().is_some_and(|addr| addr.ip().is_loopback()) && state.player_tuner.is_playing(&channel.name);
+    let lease = match from_player.then(relay::player_stream).flatten() {
+        Some(shared) => relay::StreamLease::Player(shared),
+        None => match state.tuners.acquire("relay", &channel.name) {
+            Ok(tuner) => relay::StreamLease::Tuner(tuner),
+            Err(busy) => return tuners_busy(busy),
+        },
+    };
+    HttpResponse::Ok().content_type("video/mp2t").streaming(state.relay.open(channel, lease))
+}
+
+// what a Chromecast plays of a DVB-C channel, it only loads HLS with CORS headers
+#[get("/dvbc/hls/{file}")]
+async fn get_dvbc_hls(state: web::Data<AppState>, file: web::Path<String>) -> impl Responder {
+    let path = match state.hls.file(&file) {
+        Some(path) => path,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    match tokio::fs::read(&path).await {
+        Ok(content) => HttpResponse::Ok()
+            .content_type(hls::content_type(&file))
+            .insert_header((http::header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
+            .insert_header((http::header::CACHE_CONTROL, "no-cache"))
+            .body(content),
+        // ffmpeg deleted the segment already
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => HttpResponse::NotFound().finish(),
+        Err(error) => { error!("could not read {:?}: {}", path, error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
+// for speakers in other rooms, the stream ends when the player stops playing radio
+#[get("/radio/relay")]
+async fn get_radio_relay(state: web::Data<AppState>) -> impl Responder {
+    if !state.radio_relay.is_enabled() {
+        return HttpResponse::NotFound().finish();
+    }
+    match state.radio_relay.listen() {
+        Some(stream) => HttpResponse::Ok().content_type("audio/mpeg").insert_header((http::header::CACHE_CONTROL, "no-cache")).streaming(stream),
+        None => HttpResponse::Conflict().json(serde_json::json!({ "error": "no_radio", "message": "the player plays no radio channel" })),
+    }
+}
+
+#[get("/dvbc/tuners")]
+async fn get_dvbc_tuners(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.tuners.status())
+}
+
+// lists what holds the tuners, so the client can offer to stop it
+fn tuners_busy(busy: tuners::TunersBusy) -> HttpResponse {
+    HttpResponse::Conflict().json(busy)
+}
+
+#[get("/dvbc/{channel}/teletext/{page}")]
+async fn get_dvbc_teletext(state: web::Data<AppState>, path: web::Path<(String, u16)>) -> impl Responder {
+    let (channel_name, page) = path.into_inner();
+    if !teletext::is_page(page) {
+        return validation::bad_request("page", "must be between 100 and 899".to_string());
+    }
+    let channel = match state.dvbc.get_channels().and_then(|channels| channels.tv.iter().find(|channel| channel.name == channel_name).cloned()) {
+        Some(channel) => channel,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let tuner = match state.tuners.acquire("teletext", &channel.name) {
+        Ok(tuner) => tuner,
+        Err(busy) => return tuners_busy(busy),
+    };
+    match web::block(move || { let _tuner = tuner; teletext::read_page(&channel, page) }).await {
+        Ok(Ok(Some(page))) => HttpResponse::Ok().json(page),
+        Ok(Ok(None)) => HttpResponse::NotFound().finish(),
+        Ok(Err(error)) => { error!("could not read teletext page {} of {}: {}", page, channel_name, error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct PreviewRequest {
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    inline: bool,
+}
+
+#[post("/dvbc/tv/previews")] // it's a get with a body...
+async fn get_dvbc_tv_previews(state: web::Data<AppState>, web::Json(channel_names): web::Json<Vec<String>>, web::Query(PreviewRequest { priority, inline }): web::Query<PreviewRequest>) -> impl Responder {
+    let mut validator = Validator::default();
+    validator
+        .check(channel_names.len() <= validation::MAX_PREVIEWS_PER_REQUEST, "channels", "too many channels in one request")
+        .check(channel_names.iter().all(|name| validation::is_channel_name(name)), "channels", "must all be channel names");
+    if let Err(response) = validator.finish() {
+        return response;
+    }
+    match state.dvbc.get_channels() {
+        None => HttpResponse::InternalServerError().finish(), // TODO some return code / header that specifies we couldn't load channels
+        // the previews are looked up on disk and read with inline=true
+        Some(channels) => match web::block(move || channel_names.iter()
+            .map(|name| channels.tv.iter()
+                .find(|channel| &channel.name == name)
+                .map(|channel| state.dvbc_previews.get_preview(channel, priority, inline).unwrap())
+            ).collect::<Vec<Option<ChannelPreview>>>()).await {
+            Ok(previews) => HttpResponse::Ok().json(&previews),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        }
+    }
+}
+
+// for a PREVIEW_FOLDER the web server doesn't know about
+#[get("/dvbc/tv/preview/{file}")]
+async fn get_dvbc_tv_preview_image(file: web::Path<String>) -> impl Responder {
+    let path = match dvbc_preview::served_preview(&file) {
+        Some(path) => path,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    match tokio::fs::read(&path).await {
+        Ok(image) => HttpResponse::Ok()
+            .content_type("image/jpeg")
+            .insert_header((http::header::CACHE_CONTROL, "no-cache"))
+            .body(image),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => HttpResponse::NotFound().finish(),
+        Err(error) => { error!("could not read {:?}: {}", path, error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Volume {
+    volume: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Mute {
+    muted: bool,
+}
+
+#[get("/system/volume")]
+async fn get_volume() -> impl Responder {
+    match web::block(audio::get_volume).await {
+        Ok(Ok(volume)) => HttpResponse::Ok().json(Volume { volume }),
+        Ok(Err(error)) => { error!("could not get volume: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[put("/system/volume")]
+async fn put_volume(web::Json(Volume { volume }): web::Json<Volume>) -> impl Responder {
+    if volume > audio::MAX_VOLUME {
+        return validation::bad_request("volume", format!("must be at most {}", audio::MAX_VOLUME));
+    }
+    match web::block(move || audio::set_volume(volume)).await {
+        Ok(Ok(volume)) => HttpResponse::Ok().json(Volume { volume }),
+        Ok(Err(error)) => { error!("could not set volume: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/system/mute")]
+async fn get_mute() -> impl Responder {
+    match web::block(audio::is_muted).await {
+        Ok(Ok(muted)) => HttpResponse::Ok().json(Mute { muted }),
+        Ok(Err(error)) => { error!("could not get mute state: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[put("/system/mute")]
+async fn put_mute(web::Json(Mute { muted }): web::Json<Mute>) -> impl Responder {
+    match web::block(move || audio::set_muted(muted)).await {
+        Ok(Ok(muted)) => HttpResponse::Ok().json(Mute { muted }),
+        Ok(Err(error)) => { error!("could not set mute state: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Display {
+    blanked: bool,
+}
+
+#[get("/display")]
+async fn get_display() -> impl Responder {
+    match web::block(display::is_blanked).await {
+        Ok(Ok(blanked)) => HttpResponse::Ok().json(Display { blanked }),
+        Ok(Err(error)) => { error!("could not get display state: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[put("/display")]
+async fn put_display(web::Json(Display { blanked }): web::Json<Display>) -> impl Responder {
+    match web::block(move || display::set_blanked(blanked)).await {
+        Ok(Ok(blanked)) => HttpResponse::Ok().json(Display { blanked }),
+        Ok(Err(error)) => { error!("could not set display state: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+impl Validate for input::InputEvent {
+    fn validate(&self, validator: &mut Validator) {
+        match self {
+            input::InputEvent::Key(key) => validator.check(input::is_key(key), "key", "must be a key name, e.g. Escape or ctrl+w"),
+            input::InputEvent::Click(button) => validator.check((1..=3).contains(button), "click", "must be 1, 2 or 3"),
+            input::InputEvent::Scroll(amount) => validator.check(*amount != 0 && amount.unsigned_abs() <= input::MAX_SCROLL, "scroll", &format!("must be between -{0} and {0} and not 0", input::MAX_SCROLL)),
+        };
+    }
+}
+
+#[post("/input/key")]
+async fn post_input(web::Json(event): web::Json<input::InputEvent>) -> impl Responder {
+    if let Err(response) = validation::validate(&event) {
+        return response;
+    }
+    match web::block(move || input::inject(&event)).await {
+        Ok(Ok(())) => HttpResponse::NoContent().finish(),
+        Ok(Err(error)) => { error!("could not inject input: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TvPower {
+    on: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TvInput {
+    input: cec::Input,
+}
+
+#[get("/tv/power")]
+async fn get_tv_power() -> impl Responder {
+    match web::block(cec::is_powered_on).await {
+        Ok(Ok(on)) => HttpResponse::Ok().json(TvPower { on }),
+        Ok(Err(error)) => { error!("could not get TV power state: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[put("/tv/power")]
+async fn put_tv_power(web::Json(TvPower { on }): web::Json<TvPower>) -> impl Responder {
+    match web::block(move || cec::set_power(on)).await {
+        Ok(Ok(())) => HttpResponse::Ok().json(TvPower { on }),
+        Ok(Err(error)) => { error!("could not set TV power state: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[put("/tv/input")]
+async fn put_tv_input(web::Json(TvInput { input }): web::Json<TvInput>) -> impl Responder {
+    if let cec::Input::Hdmi(port) = input {
+        if !(1..=cec::MAX_HDMI_PORT).contains(&port) {
+            return validation::bad_request("input.hdmi", format!("must be between 1 and {}", cec::MAX_HDMI_PORT));
+        }
+    }
+    match web::block(move || cec::set_input(&input).map(|_| input)).await {
+        Ok(Ok(input)) => HttpResponse::Ok().json(TvInput { input }),
+        Ok(Err(error)) => { error!("could not switch TV input: {}", error); HttpResponse::InternalServerError().finish() },
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+fn power_action(state: &AppState, request: &HttpRequest, action: power::Action) -> HttpResponse {
+    if !state.power.is_enabled() {
+        return HttpResponse::Forbidden().finish();
+    }
+    if !state.power.is_allowed(&state.auth, request) {
+        return auth::unauthorized();
+    }
+    match power::run(action) {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(error) => { error!("could not {:?} the host: {}", action, error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
+#[post("/system/shutdown")]
+async fn post_shutdown(state: web::Data<AppState>, request: HttpRequest) -> impl Responder {
+    power_action(&state, &request, power::Action::Shutdown)
+}
+
+#[post("/system/reboot")]
+async fn post_reboot(state: web::Data<AppState>, request: HttpRequest) -> impl Responder {
+    power_action(&state, &request, power::Action::Reboot)
+}
+
+#[post("/system/suspend")]
+async fn post_suspend(state: web::Data<AppState>, request: HttpRequest) -> impl Responder {
+    power_action(&state, &request, power::Action::Suspend)
+}
+
+#[get("/system/idle-shutdown")]
+async fn get_idle_shutdown(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.idle_shutdown.status())
+}
+
+#[delete("/system/idle-shutdown")]
+async fn delete_idle_shutdown(state: web::Data<AppState>) -> impl Responder {
+    idle::cancel(&state);
+    HttpResponse::NoContent().finish()
+}
+
+#[get("/wol")]
+async fn get_wol_devices(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.wol.devices())
+}
+
+#[post("/wol/{device}")]
+async fn post_wol(state: web::Data<AppState>, device: web::Path<String>) -> impl Responder {
+    match state.wol.wake(&device) {
+        Ok(true) => HttpResponse::Accepted().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(error) => { error!("could not wake {}: {}", device, error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct QuietMode {
+    enabled: bool,
+}
+
+#[get("/admin/quiet-mode")]
+async fn get_quiet_mode(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(QuietMode { enabled: state.quiet_mode.load(Ordering::Relaxed) })
+}
+
+// frees the line and the cpu, e.g. for a video call
+#[put("/admin/quiet-mode")]
+async fn put_quiet_mode(state: web::Data<AppState>, web::Json(QuietMode { enabled }): web::Json<QuietMode>, request: HttpRequest) -> impl Responder {
+    if !state.auth.is_allowed(&request) {
+        return auth::unauthorized();
+    }
+    if state.quiet_mode.swap(enabled, Ordering::Relaxed) != enabled {
+        info!("Quiet mode {}", if enabled { "enabled" } else { "disabled" });
+        state.download_manager.set_paused("quiet mode", enabled);
+        if enabled {
+            state.download_manager.pause_running();
+        }
+        state.dvbc_previews.set_paused(enabled);
+    }
+    HttpResponse::Ok().json(QuietMode { enabled })
+}
+
+#[get("/admin/loglevel")]
+async fn get_loglevel() -> impl Responder {
+    match logging::get_level() {
+        Some(level) => HttpResponse::Ok().json(level),
+        None => HttpResponse::NoContent().finish(),
+    }
+}
+
+#[put("/admin/loglevel")]
+async fn put_loglevel(state: web::Data<AppState>, web::Json(level): web::Json<logging::LogLevel>, request: HttpRequest) -> impl Responder {
+    if !state.auth.is_allowed(&request) {
+        return auth::unauthorized();
+    }
+    match logging::set_level(level) {
+        Ok(level) => HttpResponse::Ok().json(level),
+        Err(error) => validation::bad_request("level", error),
+    }
+}
+
+struct RestartRequests(mpsc::UnboundedSender<()>);
+
+#[post("/admin/restart")]
+async fn post_restart(state: web::Data<AppState>, restart: web::Data<RestartRequests>, request: HttpRequest) -> impl Responder {
+    if !state.auth.is_allowed(&request) {
+        return auth::unauthorized();
+    }
+    info!("Restart requested");
+    match restart.0.unbounded_send(()) {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(_) => HttpResponse::Conflict().finish(), // already shutting down
+    }
+}
+
+// the store holds the media index as well, so it can be a lot larger than the other json bodies
+const MAX_BACKUP_SIZE: usize = 64 * 1024 * 1024;
+
+// the Twitch logins are left out unless the request has the ADMIN_TOKEN
+#[get("/admin/backup")]
+async fn get_backup(state: web::Data<AppState>, request: HttpRequest) -> impl Responder {
+    match state.store.backup(state.auth.is_admin(&request)) {
+        Ok(backup) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header(http::header::ContentDisposition::attachment("home_back-backup.json"))
+            .body(backup),
+        Err(error) => { error!("could not create backup: {}", error); HttpResponse::InternalServerError().finish() },
+    }
+}
+
+// everything that was loaded from the store has to be loaded again, so HomeBack restarts afterwards
+#[post("/admin/restore")]
+async fn post_restore(state: web::Data<AppState>, restart: web::Data<RestartRequests>, request: HttpRequest, mut payload: web::Payload) -> impl Responder {
+    if !state.auth.is_allowed(&request) {
+        return auth::unauthorized();
+    }
+    let mut backup = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        match chunk {
+            Ok(chunk) if backup.len() + chunk.len() <= MAX_BACKUP_SIZE => backup.extend_from_slice(&chunk),
+            Ok(_) => return HttpResponse::PayloadTooLarge().finish(),
+            Err(error) => return validation::bad_request("body", error.to_string()),
+        }
+    }
+    match state.store.restore(&backup) {
+        Ok(()) => {},
+        Err(error) if error.kind() == std::io::ErrorKind::InvalidData => return validation::bad_request("body", format!("not a backup: {}", error)),
+        Err(error) => { error!("could not restore backup: {}", error); return HttpResponse::InternalServerError().finish() },
+    }
+    info!("Restart after restoring a backup");
+    let _ = restart.0.unbounded_send(());
+    HttpResponse::Accepted().finish()
+}
+
+#[derive(Deserialize)]
+struct ResetOptions {
+    #[serde(default)]
+    channels: bool,
+    #[serde(default)]
+    follows: bool,
+    #[serde(default)]
+    previews: bool,
+}
+
+#[post("/admin/reset")]
+async fn post_reset(state: web::Data<AppState>, web::Json(options): web::Json<ResetOptions>, request: HttpRequest) -> impl Responder {
+    if !state.auth.is_allowed(&request) {
+        return auth::unauthorized();
+    }
+    if options.channels {
+        state.dvbc.clear_cache();
+    }
+    if options.follows {
+        state.twitch.clear_follow_cache();
+    }
+    if options.previews {
+        if let Err(error) = state.dvbc_previews.clear() {
+            error!("could not clear previews: {}", error);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    HttpResponse::NoContent().finish()
+}
+
+#[get("/description.xml")]
+async fn get_dlna_description(state: web::Data<AppState>) -> impl Responder {
+    match &state.dlna {
+        Some(dlna) => HttpResponse::Ok().content_type("text/xml; charset=\"utf-8\"").body(dlna.description()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/{service}.xml")]
+async fn get_dlna_scpd(state: web::Data<AppState>, service: web::Path<String>) -> impl Responder {
+    match (&state.dlna, dlna::Service::from_name(&service)) {
+        (Some(dlna), Some(service)) => HttpResponse::Ok().content_type("text/xml; charset=\"utf-8\"").body(dlna.scpd(service)),
+        _ => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[post("/control/{service}")]
+async fn post_dlna_control(state: web::Data<AppState>, service: web::Path<String>, request: HttpRequest, body: String) -> impl Responder {
+    let service = match (&state.dlna, dlna::Service::from_name(&service)) {
+        (Some(_), Some(service)) => service,
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    let soap_action = request.headers().get("SOAPACTION").and_then(|value| value.to_str().ok()).unwrap_or_default().to_string();
+    // starting the player and talking to pactl blocks
+    let result = web::block(move || state.dlna.as_ref().unwrap().control(&state, service, &soap_action, &body)).await;
+    match result {
+        Ok(Ok(response)) => HttpResponse::Ok().content_type("text/xml; charset=\"utf-8\"").body(response),
+        Ok(Err(code)) => HttpResponse::InternalServerError().content_type("text/xml; charset=\"utf-8\"").body(dlna::soap_error(code)),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// SUBSCRIBE and UNSUBSCRIBE are not supported by the route macros
+async fn dlna_subscribe(state: web::Data<AppState>, service: web::Path<String>, request: HttpRequest) -> HttpResponse {
+    let (dlna, service) = match (&state.dlna, dlna::Service::from_name(&service)) {
+        (Some(dlna), Some(service)) => (dlna.clone(), service),
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    let header = |name| request.headers().get(name).and_then(|value| value.to_str().ok());
+    let requester = match request.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return HttpResponse::PreconditionFailed().finish(),
+    };
+    match dlna.subscribe(state.clone().into_inner(), service, requester, header("CALLBACK"), header("SID")) {
+        Some(sid) => HttpResponse::Ok()
+            .insert_header(("SID", sid))
+            .insert_header(("TIMEOUT", format!("Second-{}", dlna::SUBSCRIPTION_TIMEOUT)))
+            .finish(),
+        None => HttpResponse::PreconditionFailed().finish(),
+    }
+}
+
+async fn dlna_unsubscribe(state: web::Data<AppState>, request: HttpRequest) -> HttpResponse {
+    match (&state.dlna, request.headers().get("SID").and_then(|value| value.to_str().ok())) {
+        (Some(dlna), Some(sid)) => { dlna.unsubscribe(sid); HttpResponse::Ok().finish() },
+        (Some(_), None) => HttpResponse::PreconditionFailed().finish(),
+        _ => HttpResponse::NotFound().finish(),
+    }
+}
+
+fn configure_dlna(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(get_dlna_description)
+        .service(get_dlna_scpd)
+        .service(post_dlna_control)
+        .service(web::resource("/event/{service}")
+            .route(web::method(http::Method::from_bytes(b"SUBSCRIBE").unwrap()).to(dlna_subscribe))
+            .route(web::method(http::Method::from_bytes(b"UNSUBSCRIBE").unwrap()).to(dlna_unsubscribe)));
+}
+
+fn configure_api(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(get_health)
+        .service(get_ready)
+        .service(get_status)
+        .service(get_system_stats)
+        .service(post_speedtest)
+        .service(get_videoplayer)
+        .service(get_videoplayer_source)
+        .service(start_videoplayer)
+        .service(stop_videoplayer)
+        .service(get_chromecasts)
+        .service(stop_chromecast)
+        .service(get_night_mode)
+        .service(put_night_mode)
+        .service(get_libraries)
+        .service(get_library_items)
+        .service(get_media)
+        .service(search_media)
+        .service(get_media_duplicates)
+        .service(delete_media_duplicates)
+        .service(search_subtitles)
+        .service(download_subtitle)
+        .service(get_lastfm)
+        .service(start_lastfm_login)
+        .service(finish_lastfm_login)
+        .service(lastfm_logout)
+        .service(get_media_file)
+        .service(get_spotify_status)
+        .service(start_spotify)
+        .service(stop_spotify)
+        .service(get_chat)
+        .service(open_chat)
+        .service(stop_chat)
+        .service(put_twitch_login)
+        .service(get_twitch_login)
+        .service(get_twitch_live)
+        .service(get_twitch_live_changes)
+        .service(get_twitch_live_by_game)
+        .service(post_twitch_bookmark)
+        .service(post_twitch_clip)
+        .service(get_twitch_bookmarks)
+        .service(delete_twitch_bookmark)
+        .service(get_processes)
+        .service(get_scans)
+        .service(get_scan)
+        .service(post_scan_batch)
+        .service(get_downloads_subfolder)
+        .service(upload_files)
+        .service(get_download)
+        .service(get_downloads)
+        .service(post_download)
+        .service(post_postprocessing)
+        .service(get_postprocessing_jobs)
+        .service(get_postprocessing_job)
+        .service(cancel_download)
+        .service(get_profiles)
+        .service(post_profile)
+        .service(get_profile)
+        .service(put_favorites)
+        .service(get_history)
+        .service(get_settings)
+        .service(put_settings)
+        .service(get_viewing_stats)
+        .service(get_podcasts)
+        .service(post_podcast)
+        .service(get_podcast)
+        .service(delete_podcast)
+        .service(play_podcast_episode)
+        .service(get_dvbc_tv)
+        .service(get_dvbc_radio)
+        .service(get_dvbc_epg)
+        .service(get_dvbc_now)
+        .service(get_recordings)
+        .service(delete_recording)
+        .service(get_series_rules)
+        .service(post_series_rule)
+        .service(delete_series_rule)
+        .service(get_dvbc_order)
+        .service(put_dvbc_order)
+        .service(get_dvbc_settings)
+        .service(put_dvbc_settings)
+        .service(get_dvbc_relay)
+        .service(get_dvbc_hls)
+        .service(get_radio_relay)
+        .service(get_dvbc_tuners)
+        .service(get_dvbc_probe)
+        .service(get_dvbc_teletext)
+        .service(get_dvbc_tv_previews)
+        .service(get_dvbc_tv_preview_image)
+        .service(get_volume)
+        .service(put_volume)
+        .service(get_mute)
+        .service(put_mute)
+        .service(post_shutdown)
+        .service(post_reboot)
+        .service(post_suspend)
+        .service(get_idle_shutdown)
+        .service(delete_idle_shutdown)
+        .service(get_wol_devices)
+        .service(post_wol)
+        .service(get_display)
+        .service(put_display)
+        .service(post_input)
+        .service(get_tv_power)
+        .service(put_tv_power)
+        .service(put_tv_input)
+        .service(get_quiet_mode)
+        .service(put_quiet_mode)
+        .service(get_loglevel)
+        .service(put_loglevel)
+        .service(post_restart)
+        .service(post_reset)
+        .service(get_backup)
+        .service(post_restore);
+}
+
+fn main() -> std::io::Result<()> {
+    dotenv().ok();
+    logging::init();
+    process::kill_orphans();
+
+    // the blocking reqwest clients can't be created from within the async runtime
+    let state = match AppState::from_env() {
+        Ok(state) => web::Data::new(state),
+        Err(error) => { error!("could not start HomeBack: {}", error); return Err(error) },
+    };
+    health::validate_binaries();
+    state.health.log_system_status();
+    if env::var("SPOTIFY_CONNECT").is_ok_and(|value| value == "true") {
+        if let Err(error) = state.spotify.start(state.spotify_name.clone()) {
+            error!("could not start librespot: {}", error);
+        }
+    }
+    #[cfg(unix)]
+    lirc::listen(state.clone().into_inner());
+    cec::listen(state.clone().into_inner());
+    raids::follow(state.clone().into_inner());
+    mqtt::connect(state.clone().into_inner());
+    webhooks::start(&state.events);
+    notifier::start(&state.events);
+    arr::start(&state.events);
+    postprocess::start(&state.events, state.postprocessing.clone());
+    media::start(state.clone().into_inner());
+    recorder::start(state.clone().into_inner());
+    download::warm_scan_cache();
+    progress::track(state.clone().into_inner());
+    dlna::start(state.clone().into_inner());
+    process::watch_resources();
+    let restart = System::new().block_on(run(state))?;
+
+    if restart {
+        info!("Restarting");
+        #[cfg(unix)]
+        return Err(Command::new(env::current_exe()?).args(env::args_os().skip(1)).exec());
+        // Windows can't replace the running process, so the new one takes over once this one exits
+        #[cfg(windows)]
+        Command::new(env::current_exe()?).args(env::args_os().skip(1)).spawn()?;
+    }
+    Ok(())
+}
+
+// returns whether a restart was requested
+async fn run(state: web::Data<AppState>) -> std::io::Result<bool> {
+    state.download_manager.resume_persisted();
+    spawn(state.dvbc.clone().keep_updated());
+    state.dvbc_previews.start();
+    state.radio_relay.start();
+    state.hls.start_watching();
+    spawn(podcast::poll(state.clone()));
+    spawn(epg::poll(state.clone()));
+    spawn(lastfm::scrobble(state.clone()));
+    spawn(twitch::watch_live(state.clone()));
+    spawn(health::watch_router(state.clone()));
+    spawn(storage::watch(state.clone()));
+    spawn(idle::watch(state.clone()));
+    let app_state = state.clone();
+    let (restart_sender, mut restart_receiver) = mpsc::unbounded();
+    let restart_requests = web::Data::new(RestartRequests(restart_sender));
+
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .app_data(validation::json_config())
+            .app_data(app_state.clone())
+            .app_data(restart_requests.clone())
+            .service(web::scope("/api/v1")
+                .service(get_version)
+                .configure(configure_api))
+            // control points expect the paths from the device description, so this is not versioned
+            .service(web::scope("/dlna")
+                .configure(configure_dlna))
+            // the unversioned paths are kept for older frontends
+            .service(web::scope("")
+                .wrap(middleware::DefaultHeaders::new().add(("Deprecation", "true")))
+                .configure(configure_api))
     })
-        .bind(env::var("ADDR").unwrap_or("127.0.0.1:23559".to_string()))?
-        .run()
-        .await
+        .disable_signals(); // we stop the server ourselves, after the downloads had a chance to clean up
+
+    // ADDR can be a comma separated list, e.g. "0.0.0.0:23559,[::]:23559"
+    for addr in env::var("ADDR").unwrap_or("127.0.0.1:23559".to_string()).split(',').map(str::trim).filter(|addr| !addr.is_empty()) {
+        info!("Listening on {}", addr);
+        server = server.bind(addr)?;
+    }
+    #[cfg(unix)]
+    if let Ok(path) = env::var("UNIX_SOCKET") {
+        // a socket file left behind by a previous run would make the bind fail
+        let _ = fs::remove_file(&path);
+        info!("Listening on {}", path);
+        server = server.bind_uds(path)?;
+    }
+    let server = server.run();
+
+    let handle = server.handle();
+    let shutdown = spawn(async move {
+        let restart = match select(Box::pin(shutdown_signal()), restart_receiver.next()).await {
+            Either::Left(_) => false,
+            Either::Right(_) => true,
+        };
+        shutdown(state).await;
+        handle.stop(true).await;
+        restart
+    });
+
+    server.await?;
+    Ok(shutdown.await.unwrap_or(false))
+}
+
+#[cfg(windows)]
+async fn shutdown_signal() {
+    let _ = signal::ctrl_c().await;
 }
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate()).unwrap();
+    let ctrl_c = Box::pin(signal::ctrl_c());
+    let terminate = Box::pin(terminate.recv());
+    select(ctrl_c, terminate).await;
+}
+
+async fn shutdown(state: web::Data<AppState>) {
+    if let Some(dlna) = &state.dlna {
+        dlna.announce_byebye();
+    }
+    info!("Shutting down, stopping all child processes");
+    if let Err(error) = state.video_player.stop() {
+        error!("could not stop video player: {}", error);
+    }
+    state.progress.flush();
+    if let Err(error) = state.chat.stop() {
+        error!("could not stop chat: {}", error);
+    }
+    if let Err(error) = state.spotify.stop() {
+        error!("could not stop librespot: {}", error);
+    }
+    state.dvbc_previews.shutdown().await;
+    state.download_manager.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests;
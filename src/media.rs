@@ -0,0 +1,485 @@
+use std::env;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use itertools::Itertools;
+use log::{info, debug, error, warn};
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use uuid::Uuid;
+use crate::files::{self, PathError, Root};
+use crate::process;
+use crate::progress::{self, Progress, WatchState};
+use crate::state::AppState;
+use crate::store::{Repository, Store};
+use crate::tools;
+
+const EXTENSIONS: [&str; 14] = ["mkv", "mp4", "m4v", "avi", "mov", "webm", "ts", "mpg", "mp3", "m4a", "flac", "ogg", "opus", "wav"];
+pub const MAX_PAGE_SIZE: usize = 500;
+// a file on a stalled network share would hang the scan
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DEFAULT_SCAN_MINUTES: u64 = 15;
+
+// a video or audio file in one of the media folders
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MediaFile {
+    pub path: PathBuf,
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,  // seconds since the epoch
+    pub duration: Option<f64>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    // sha1 of the content, only files that have the same size as another one are hashed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct MediaItem {
+    pub id: String,
+    #[serde(flatten)]
+    pub file: MediaFile,
+    #[serde(flatten)]
+    pub watch_state: WatchState,
+}
+
+#[derive(Serialize)]
+pub struct Page {
+    pub total: usize,
+    pub items: Vec<MediaItem>,
+}
+
+// what the name of a file tells about its content, e.g. "The.Show.S01E02.1080p"
+#[derive(Serialize)]
+pub struct ParsedName {
+    pub title: String,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub parsed: ParsedName,
+    #[serde(flatten)]
+    pub item: MediaItem,
+}
+
+// files with the same content, the space of all but one of them could be freed
+#[derive(Serialize)]
+pub struct Duplicates {
+    pub hash: String,
+    pub size: u64,
+    pub files: Vec<MediaFile>,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct Deleted {
+    pub deleted: Vec<String>,
+    pub skipped: Vec<String>, // the kept copy changed or is gone
+    pub failed: Vec<String>,  // could not be removed, the others are deleted anyway
+}
+
+#[derive(Debug)]
+pub enum DeleteError {
+    NotADuplicate(String),
+    LastCopy(String),
+    Changed(String),  // since it was hashed, so it might not be a duplicate anymore
+    Outside(String),  // of the media folders, e.g. through a folder replaced by a symlink
+    Io(io::Error),
+}
+
+impl fmt::Display for DeleteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeleteError::NotADuplicate(path) => write!(f, "{} is not a known duplicate", path),
+            DeleteError::LastCopy(path)      => write!(f, "{} is the last copy of its content", path),
+            DeleteError::Changed(path)       => write!(f, "{} changed since it was hashed", path),
+            DeleteError::Outside(path)       => write!(f, "{} is outside of the media folders", path),
+            DeleteError::Io(error)           => write!(f, "{}", error),
+        }
+    }
+}
+
+pub struct MediaIndex {
+    roots: Vec<PathBuf>,
+    scan_interval: Duration,
+    index: Repository<MediaFile>,
+    progress: Arc<Progress>,
+    scanning: AtomicBool,
+    rescan: AtomicBool,
+}
+
+impl MediaIndex {
+
+    pub fn from_env(store: Arc<Store>, progress: Arc<Progress>) -> Self {
+        let mut roots = vec![Root::Download.folder().to_path_buf()];
+        roots.extend(env::var("MEDIA_FOLDERS").unwrap_or_default().split(',').map(str::trim).filter(|folder| !folder.is_empty()).map(PathBuf::from));
+        let minutes = env::var("MEDIA_SCAN_MINUTES").ok().and_then(|minutes| minutes.parse().ok()).unwrap_or(DEFAULT_SCAN_MINUTES);
+        Self::new(roots, Duration::from_secs(60 * minutes), store, progress)
+    }
+
+    pub fn new(roots: Vec<PathBuf>, scan_interval: Duration, store: Arc<Store>, progress: Arc<Progress>) -> Self {
+        Self { roots, scan_interval, index: Repository::new(store, "media"), progress, scanning: AtomicBool::new(false), rescan: AtomicBool::new(false) }
+    }
+
+    pub fn get(&self, path: &str) -> Option<MediaFile> {
+        self.index.get(path)
+    }
+
+    fn item(&self, profile: Option<&Uuid>, file: MediaFile) -> MediaItem {
+        let watch_state = self.progress.get(&progress::profile_key(profile, file.path.to_string_lossy().into_owned()));
+        MediaItem { id: id(&file.path), file, watch_state }
+    }
+
+    pub fn get_by_id(&self, id: &str) -> Option<MediaFile> {
+        self.index.all().into_iter().map(|(_, file)| file).find(|file| self::id(&file.path) == id)
+    }
+
+    // the indexed files, newest first, with how far the profile watched them
+    pub fn get_page(&self, profile: Option<&Uuid>, offset: usize, limit: usize) -> Page {
+        let mut files: Vec<MediaFile> = self.index.all().into_iter().map(|(_, file)| file).collect();
+        files.sort_by(|a, b| b.modified.cmp(&a.modified).then_with(|| a.path.cmp(&b.path)));
+        Page { total: files.len(), items: files.into_iter().skip(offset).take(limit.min(MAX_PAGE_SIZE)).map(|file| self.item(profile, file)).collect() }
+    }
+
+    // the files that match every word of the query, best matches first
+    pub fn search(&self, profile: Option<&Uuid>, query: &str, limit: usize) -> Vec<SearchResult> {
+        let words = normalize(query);
+        let words: Vec<&str> = words.split_whitespace().collect();
+        let mut results: Vec<(u32, ParsedName, MediaFile)> = self.index.all().into_iter()
+            .filter_map(|(_, file)| {
+                let parsed = parse_name(&file.name);
+                let score = score(&words, &file, &parsed)?;
+                Some((score, parsed, file))
+            })
+            .collect();
+        results.sort_by(|(a_score, _, a), (b_score, _, b)| b_score.cmp(a_score).then_with(|| b.modified.cmp(&a.modified)));
+        results.into_iter().take(limit.min(MAX_PAGE_SIZE)).map(|(_, parsed, file)| SearchResult { parsed, item: self.item(profile, file) }).collect()
+    }
+
+    // walks the media folders, only new or changed files are probed again
+    // only one scan runs at a time, a scan requested while one runs is done once it is finished
+    pub fn scan(&self) {
+        self.rescan.store(true, Ordering::SeqCst);
+        // the running scan could have finished right after it looked for requests
+        while self.rescan.load(Ordering::SeqCst) && !self.scanning.swap(true, Ordering::SeqCst) {
+            while self.rescan.swap(false, Ordering::SeqCst) {
+                self.scan_folders();
+            }
+            self.scanning.store(false, Ordering::SeqCst);
+        }
+    }
+
+    fn scan_folders(&self) {
+        let known: HashMap<String, MediaFile> = self.index.all().into_iter().collect();
+        let mut paths = Vec::new();
+        for root in &self.roots {
+            if let Err(error) = walk(root, &mut paths) {
+                error!("could not scan {:?}: {}", root, error);
+            }
+        }
+
+        let mut probed = 0;
+        let files: Vec<(String, MediaFile)> = paths.into_iter()
+            .filter_map(|path| {
+                let metadata = fs::metadata(&path).ok()?;
+                let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+                let key = path.to_string_lossy().into_owned();
+                if let Some(file) = known.get(&key).filter(|file| file.size == metadata.len() && file.modified == modified) {
+                    return Some((key, file.clone()));
+                }
+                probed += 1;
+                Some((key, probe(path, metadata.len(), modified)))
+            })
+            .collect();
+
+        info!("indexed {} media files, probed {}", files.len(), probed);
+        self.index.replace_all(files);
+    }
+
+    // hashes the files that could be duplicates, which are the ones with the same size as another file
+    pub fn hash_candidates(&self) {
+        let mut by_size: HashMap<u64, Vec<(String, MediaFile)>> = HashMap::new();
+        for (key, file) in self.index.all().into_iter().filter(|(_, file)| file.size > 0) {
+            by_size.entry(file.size).or_default().push((key, file));
+        }
+
+        let mut hashed = 0;
+        for (key, mut file) in by_size.into_values().filter(|files| files.len() > 1).flatten().filter(|(_, file)| file.hash.is_none()) {
+            match hash_file(&file.path) {
+                Ok(hash) => {
+                    // a scan could have replaced or removed it in the meantime
+                    if self.index.get(&key).is_some_and(|current| current.size == file.size && current.modified == file.modified) {
+                        file.hash = Some(hash);
+                        self.index.put(&key, &file);
+                        hashed += 1;
+                    }
+                },
+                Err(error) => error!("could not hash {:?}: {}", file.path, error),
+            }
+        }
+        if hashed > 0 {
+            info!("hashed {} media files", hashed);
+        }
+    }
+
+    // the groups of files with the same content, the ones wasting the most space first
+    pub fn duplicates(&self) -> Vec<Duplicates> {
+        let mut by_hash: HashMap<String, Vec<MediaFile>> = HashMap::new();
+        for (_, file) in self.index.all() {
+            if let Some(hash) = file.hash.clone() {
+                by_hash.entry(hash).or_default().push(file);
+            }
+        }
+        let mut duplicates: Vec<Duplicates> = by_hash.into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .map(|(hash, mut files)| {
+                files.sort_by(|a, b| a.path.cmp(&b.path));
+                Duplicates { hash, size: files[0].size, files }
+            })
+            .collect();
+        duplicates.sort_by_key(|duplicates| std::cmp::Reverse(duplicates.size * (duplicates.files.len() as u64 - 1)));
+        duplicates
+    }
+
+    // deletes duplicates, but never all copies of a content. Every path and kept copy is checked before the first file is deleted,
+    // so nothing is deleted if one of the paths can't be. A content is skipped if none of the copies that would be kept is still on disk
+    // with that content, the index can be older than the files. A file that can't be removed is listed as failed, the others are still deleted
+    pub fn delete_duplicates(&self, paths: &[String]) -> Result<Deleted, DeleteError> {
+        let paths: Vec<&String> = paths.iter().unique().collect();
+        let files: HashMap<String, MediaFile> = self.index.all().into_iter().collect();
+        for &path in &paths {
+            let hash = files.get(path).and_then(|file| file.hash.as_ref()).ok_or_else(|| DeleteError::NotADuplicate(path.clone()))?;
+            let kept = files.iter().any(|(other, file)| file.hash.as_ref() == Some(hash) && !paths.contains(&other));
+            if !kept {
+                return Err(DeleteError::LastCopy(path.clone()));
+            }
+        }
+        let mut resolved = HashMap::new();
+        for &path in &paths {
+            resolved.insert(path, self.resolve(path)?);
+            if !is_unchanged(&files[path]) {
+                return Err(DeleteError::Changed(path.clone()));
+            }
+        }
+
+        let mut deleted = Deleted::default();
+        let mut to_delete = Vec::new();
+        for (hash, group) in paths.into_iter().into_group_map_by(|&path| files[path].hash.clone().unwrap()) {
+            let kept = files.iter()
+                .filter(|(other, file)| file.hash.as_ref() == Some(&hash) && !group.contains(other))
+                .find(|(_, file)| is_unchanged(file) && hash_file(&file.path).is_ok_and(|current| current == hash));
+            if kept.is_some() {
+                to_delete.extend(group);
+            } else {
+                warn!("not deleting {} copies of {}, no other copy is left on disk", group.len(), hash);
+                deleted.skipped.extend(group.into_iter().cloned());
+            }
+        }
+
+        for path in to_delete {
+            info!("deleting duplicate {}", path);
+            match fs::remove_file(&resolved[path]) {
+                Ok(()) => {
+                    self.index.remove(path);
+                    deleted.deleted.push(path.clone());
+                },
+                Err(error) => {
+                    error!("could not delete {}: {}", path, error);
+                    deleted.failed.push(path.clone());
+                },
+            }
+        }
+        Ok(deleted)
+    }
+
+    // an indexed path through the path jail of the media folder it was found in
+    fn resolve(&self, path: &str) -> Result<PathBuf, DeleteError> {
+        let (root, relative) = self.roots.iter()
+            .find_map(|root| Some((root, Path::new(path).strip_prefix(root).ok()?)))
+            .ok_or_else(|| DeleteError::Outside(path.to_string()))?;
+        match files::resolve_in(root, relative) {
+            Ok(resolved) => Ok(resolved),
+            Err(PathError::Io(error)) => Err(DeleteError::Io(error)),
+            Err(_) => Err(DeleteError::Outside(path.to_string())),
+        }
+    }
+}
+
+pub fn parse_name(name: &str) -> ParsedName {
+    lazy_static! {
+        static ref EPISODE: Regex = Regex::new(r"(?i)\bs(\d{1,2})\s*e(\d{1,3})\b|\b(\d{1,2})x(\d{2,3})\b").unwrap();
+        // everything after these is release info like "1080p.WEB.x264"
+        static ref RELEASE: Regex = Regex::new(r"(?i)\b((19|20)\d{2}|\d{3,4}p|web|bluray|hdtv|x264|x265|h264|hevc)\b").unwrap();
+    }
+    let spaced = name.replace(['.', '_'], " ");
+    let (title, season, episode) = match EPISODE.captures(&spaced) {
+        Some(capture) => {
+            let number = |a: usize, b: usize| capture.get(a).or(capture.get(b)).and_then(|number| number.as_str().parse().ok());
+            (&spaced[..capture.get(0).unwrap().start()], number(1, 3), number(2, 4))
+        },
+        None => (&spaced[..], None, None),
+    };
+    let title = match RELEASE.find(title) {
+        Some(release) if release.start() > 0 => &title[..release.start()],
+        _ => title,
+    };
+    let title = title.trim_matches(|c: char| c.is_whitespace() || c == '-' || c == '(' || c == '[').to_string();
+    ParsedName { title: if title.is_empty() { name.to_string() } else { title }, season, episode }
+}
+
+fn normalize(text: &str) -> String {
+    text.chars().map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { ' ' }).collect()
+}
+
+// None if a word doesn't match, the parent folders count as well, as they often hold the name of the show
+fn score(words: &[&str], file: &MediaFile, parsed: &ParsedName) -> Option<u32> {
+    lazy_static! {
+        static ref NUMBERS: Regex = Regex::new(r"^(?:s(\d+))?(?:e(\d+))?$").unwrap();
+    }
+    let folders: Vec<String> = file.path.iter().rev().skip(1).take(2).map(|folder| folder.to_string_lossy().into_owned()).collect();
+    let haystack = normalize(&format!("{} {} {}", parsed.title, file.name, folders.join(" ")));
+    let haystack_words: Vec<&str> = haystack.split_whitespace().collect();
+    let squashed: String = haystack_words.concat();
+
+    let mut score = 0;
+    for word in words {
+        if let Some(capture) = NUMBERS.captures(word).filter(|capture| capture.get(1).is_some() || capture.get(2).is_some()) {
+            let number = |i: usize| capture.get(i).and_then(|number| number.as_str().parse::<u32>().ok());
+            if number(1).is_some_and(|season| Some(season) != parsed.season) || number(2).is_some_and(|episode| Some(episode) != parsed.episode) {
+                return None;
+            }
+            score += 3;
+        } else if haystack_words.contains(word) {
+            score += 4;
+        } else if haystack_words.iter().any(|haystack_word| haystack_word.starts_with(word)) {
+            score += 3;
+        } else if squashed.contains(word) {
+            score += 2;
+        } else if word.len() >= 3 && is_subsequence(word, &squashed) {
+            // typos and missing letters, "brkng bad"
+            score += 1;
+        } else {
+            return None;
+        }
+    }
+    Some(score)
+}
+
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars();
+    needle.chars().all(|c| haystack.any(|h| h == c))
+}
+
+// only a root that can't be read fails, a folder below it that can't is skipped and the rest is still indexed
+fn walk(folder: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(folder)? {
+        let (path, file_type) = match entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))) {
+            Ok(entry) => entry,
+            Err(error) => { warn!("skipping an entry of {:?}: {}", folder, error); continue; },
+        };
+        if file_type.is_dir() {
+            if let Err(error) = walk(&path, paths) {
+                warn!("skipping {:?}: {}", path, error);
+            }
+        } else if file_type.is_file() && path.extension().and_then(|extension| extension.to_str()).is_some_and(|extension| EXTENSIONS.contains(&extension.to_lowercase().as_str())) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn probe(path: PathBuf, size: u64, modified: u64) -> MediaFile {
+    let name = path.file_stem().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut file = MediaFile { path, name, size, modified, duration: None, width: None, height: None, video_codec: None, audio_codec: None, hash: None };
+    match ffprobe(&file.path) {
+        Ok(info) => {
+            file.duration = info["format"]["duration"].as_str().and_then(|duration| duration.parse().ok());
+            let streams = info["streams"].as_array().cloned().unwrap_or_default();
+            let stream = |codec_type: &str| streams.iter().find(|stream| stream["codec_type"] == codec_type);
+            if let Some(video) = stream("video") {
+                file.width = video["width"].as_u64();
+                file.height = video["height"].as_u64();
+                file.video_codec = video["codec_name"].as_str().map(str::to_string);
+            }
+            file.audio_codec = stream("audio").and_then(|audio| audio["codec_name"].as_str().map(str::to_string));
+        },
+        // e.g. a download that is still running, it is probed again once its size changed
+        Err(error) => debug!("could not probe {:?}: {}", file.path, error),
+    }
+    file
+}
+
+pub fn ffprobe(path: &Path) -> io::Result<Value> {
+    let child = tools::command("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-print_format").arg("json")
+        .arg("-show_format")
+        .arg("-show_streams")
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let output = process::output_within(child, PROBE_TIMEOUT)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("ffprobe failed: {}", stderr.trim())));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+// stable as long as the file isn't moved, and unlike the path it fits into an url
+pub fn id(path: &Path) -> String {
+    format!("{:x}", Sha1::digest(path.as_os_str().as_encoded_bytes()))[..16].to_string()
+}
+
+// whether the size and modification time on disk are still the ones that were indexed
+fn is_unchanged(file: &MediaFile) -> bool {
+    fs::symlink_metadata(&file.path).ok()
+        .filter(|metadata| metadata.is_file() && metadata.len() == file.size)
+        .and_then(|metadata| metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok())
+        .is_some_and(|modified| modified.as_secs() == file.modified)
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(format!("{:x}", hasher.finalize())),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+// rescans the media folders every MEDIA_SCAN_MINUTES
+pub fn start(state: Arc<AppState>) {
+    thread::spawn(move || loop {
+        // probing and hashing take the cpu
+        if !state.quiet_mode.load(Ordering::Relaxed) {
+            state.media.scan();
+            state.media.hash_candidates();
+        }
+        thread::sleep(state.media.scan_interval);
+    });
+}
+
+#[cfg(test)]
+mod tests;
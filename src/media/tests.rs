@@ -0,0 +1,162 @@
+use std::fs;
+use std::time::SystemTime;
+use super::*;
+use crate::testing::{body, TempFolder};
+
+struct Harness {
+    folder: TempFolder, // holds the files and the store
+    media: MediaIndex,
+}
+
+impl Harness {
+
+    fn new() -> Self {
+        let folder = TempFolder::new();
+        let store = Arc::new(Store::open(folder.join("store.json")).unwrap());
+        let media = MediaIndex::new(vec![folder.to_path_buf()], Duration::from_secs(60), store.clone(), Arc::new(Progress::new(store)));
+        Self { folder, media }
+    }
+
+    // written and indexed with its hash, like after a scan
+    fn add(&self, name: &str, content: &[u8]) -> String {
+        let path = self.folder.join(name);
+        fs::write(&path, content).unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let key = path.to_string_lossy().into_owned();
+        let mut file = MediaFile { path: path.clone(), name: name.to_string(), size: content.len() as u64, modified, duration: None, width: None, height: None, video_codec: None, audio_codec: None, hash: None };
+        file.hash = Some(hash_file(&path).unwrap());
+        self.media.index.put(&key, &file);
+        key
+    }
+}
+
+// the same size and modification time, so only the hash can tell
+fn overwrite_unnoticed(path: &str, content: &[u8]) {
+    let modified = fs::metadata(path).unwrap().modified().unwrap();
+    fs::write(path, content).unwrap();
+    fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+}
+
+#[test]
+fn deletes_the_chosen_copies() {
+    let harness = Harness::new();
+    let kept = harness.add("a.mkv", &body(1000, 1));
+    let deleted = harness.add("b.mkv", &body(1000, 1));
+
+    let result = harness.media.delete_duplicates(std::slice::from_ref(&deleted)).unwrap();
+
+    assert_eq!(vec![deleted.clone()], result.deleted);
+    assert!(result.skipped.is_empty());
+    assert!(!Path::new(&deleted).exists());
+    assert!(Path::new(&kept).exists());
+    assert!(harness.media.get(&deleted).is_none());
+}
+
+#[test]
+fn skips_a_content_whose_kept_copy_changed() {
+    let harness = Harness::new();
+    let kept = harness.add("a.mkv", &body(1000, 1));
+    let duplicate = harness.add("b.mkv", &body(1000, 1));
+    overwrite_unnoticed(&kept, &body(1000, 2));
+
+    let result = harness.media.delete_duplicates(std::slice::from_ref(&duplicate)).unwrap();
+
+    assert!(result.deleted.is_empty());
+    assert_eq!(vec![duplicate.clone()], result.skipped);
+    assert!(Path::new(&duplicate).exists());
+    assert!(harness.media.get(&duplicate).is_some());
+}
+
+#[test]
+fn skips_a_content_whose_kept_copy_is_gone() {
+    let harness = Harness::new();
+    let kept = harness.add("a.mkv", &body(1000, 1));
+    let duplicate = harness.add("b.mkv", &body(1000, 1));
+    fs::remove_file(&kept).unwrap();
+
+    let result = harness.media.delete_duplicates(std::slice::from_ref(&duplicate)).unwrap();
+
+    assert_eq!(vec![duplicate.clone()], result.skipped);
+    assert!(Path::new(&duplicate).exists());
+}
+
+#[test]
+fn keeps_any_copy_that_still_matches() {
+    let harness = Harness::new();
+    let changed = harness.add("a.mkv", &body(1000, 1));
+    let kept = harness.add("b.mkv", &body(1000, 1));
+    let deleted = harness.add("c.mkv", &body(1000, 1));
+    // another content is deleted in the same request
+    let other_kept = harness.add("d.mkv", &body(500, 3));
+    let other_deleted = harness.add("e.mkv", &body(500, 3));
+    overwrite_unnoticed(&changed, &body(1000, 2));
+    fs::remove_file(&other_kept).unwrap();
+
+    let result = harness.media.delete_duplicates(&[deleted.clone(), other_deleted.clone()]).unwrap();
+
+    assert_eq!(vec![deleted.clone()], result.deleted);
+    assert_eq!(vec![other_deleted.clone()], result.skipped);
+    assert!(Path::new(&kept).exists());
+    assert!(Path::new(&other_deleted).exists());
+}
+
+#[test]
+fn refuses_a_changed_copy_to_delete() {
+    let harness = Harness::new();
+    harness.add("a.mkv", &body(1000, 1));
+    let duplicate = harness.add("b.mkv", &body(1000, 1));
+    fs::File::options().write(true).open(&duplicate).unwrap().set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+
+    assert!(matches!(harness.media.delete_duplicates(std::slice::from_ref(&duplicate)), Err(DeleteError::Changed(_))));
+    assert!(Path::new(&duplicate).exists());
+}
+
+#[test]
+fn deletes_nothing_if_one_of_the_paths_is_refused() {
+    let harness = Harness::new();
+    harness.add("a.mkv", &body(1000, 1));
+    let duplicate = harness.add("b.mkv", &body(1000, 1));
+    let unknown = harness.folder.join("c.mkv").to_string_lossy().into_owned();
+
+    assert!(matches!(harness.media.delete_duplicates(&[duplicate.clone(), unknown]), Err(DeleteError::NotADuplicate(_))));
+    assert!(Path::new(&duplicate).exists());
+}
+
+#[test]
+fn refuses_a_copy_that_moved_out_of_the_media_folders() {
+    let harness = Harness::new();
+    let outside = TempFolder::new();
+    fs::create_dir(harness.folder.join("show")).unwrap();
+    harness.add("a.mkv", &body(1000, 1));
+    let duplicate = harness.add("show/b.mkv", &body(1000, 1));
+    fs::rename(harness.folder.join("show"), outside.join("show")).unwrap();
+    std::os::unix::fs::symlink(outside.join("show"), harness.folder.join("show")).unwrap();
+
+    assert!(matches!(harness.media.delete_duplicates(std::slice::from_ref(&duplicate)), Err(DeleteError::Outside(_))));
+    assert!(outside.join("show/b.mkv").exists());
+}
+
+#[test]
+fn finds_a_file_by_the_id_of_its_page_item() {
+    let harness = Harness::new();
+    let path = harness.add("a.mkv", &body(1000, 1));
+    harness.add("b.mkv", &body(500, 2));
+
+    let item = harness.media.get_page(None, 0, 10).items.into_iter().find(|item| item.file.path == Path::new(&path)).unwrap();
+
+    assert_eq!(16, item.id.len());
+    assert_eq!(Some(PathBuf::from(&path)), harness.media.get_by_id(&item.id).map(|file| file.path));
+    assert!(harness.media.get_by_id("0000000000000000").is_none());
+}
+
+#[test]
+fn a_scan_requested_during_a_scan_is_left_to_it() {
+    let harness = Harness::new();
+    fs::write(harness.folder.join("a.mkv"), body(100, 1)).unwrap();
+    harness.media.scanning.store(true, Ordering::SeqCst);
+
+    harness.media.scan();
+
+    assert!(harness.media.get_page(None, 0, 10).items.is_empty());
+    assert!(harness.media.rescan.load(Ordering::SeqCst));
+}
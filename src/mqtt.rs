@@ -0,0 +1,304 @@
+use std::env;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+use log::{info, warn, error};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::actions::{self, Action};
+use crate::process;
+use crate::audio;
+use crate::process::VideoPlayerArgs;
+use crate::state::AppState;
+use crate::validation;
+use crate::VideoPlayerSomthing;
+use crate::tools;
+
+// the state is also published in this interval, to catch players that exited on their own
+const STATE_INTERVAL: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+// connection settings for the broker, the clients from mosquitto do the actual talking
+#[derive(Clone)]
+pub struct Broker {
+    host: String,
+    port: String,
+    user: Option<String>,
+    // the password goes to the clients in a file only this user can read, anyone can see the arguments
+    options_file: Option<PathBuf>,
+    pub prefix: String,
+}
+
+impl Broker {
+
+    pub fn from_env() -> Option<Self> {
+        let host = env::var("MQTT_HOST").ok()?;
+        let options_file = match env::var("MQTT_PASSWORD") {
+            Ok(password) => match write_options_file(&password) {
+                Ok(path) => Some(path),
+                Err(error) => { error!("could not store MQTT_PASSWORD for the mosquitto clients, not using MQTT: {}", error); return None; },
+            },
+            Err(_) => None,
+        };
+        Some(Self {
+            host,
+            port:   env::var("MQTT_PORT").unwrap_or("1883".to_string()),
+            user:   env::var("MQTT_USER").ok(),
+            options_file,
+            prefix: env::var("MQTT_TOPIC").unwrap_or("home_back".to_string()),
+        })
+    }
+
+    fn command(&self, binary: &str) -> Command {
+        let mut command = tools::command(binary);
+        command.arg("-h").arg(&self.host).arg("-p").arg(&self.port);
+        if let Some(user) = &self.user {
+            command.arg("-u").arg(user);
+        }
+        if let Some(options_file) = &self.options_file {
+            command.arg("-o").arg(options_file);
+        }
+        command
+    }
+
+    pub fn publish(&self, topic: &str, payload: &str, retain: bool) -> io::Result<()> {
+        self.publish_absolute(&format!("{}/{}", self.prefix, topic), payload, retain)
+    }
+
+    // for topics outside of the prefix
+    pub fn publish_absolute(&self, topic: &str, payload: &str, retain: bool) -> io::Result<()> {
+        let mut command = self.command("mosquitto_pub");
+        command.arg("-t").arg(topic).arg("-m").arg(payload);
+        if retain {
+            command.arg("-r");
+        }
+        let output = command.stdin(Stdio::null()).output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::other(format!("mosquitto_pub to {} failed: {}", topic, stderr.trim())));
+        }
+        Ok(())
+    }
+
+    pub fn publish_json(&self, topic: &str, payload: &impl Serialize, retain: bool) {
+        let result = serde_json::to_string(payload).map_err(io::Error::other)
+            .and_then(|payload| self.publish(topic, &payload, retain));
+        if let Err(error) = result {
+            error!("could not publish to MQTT: {}", error);
+        }
+    }
+}
+
+// one option per line, the rest of the line after `-P ` is the password
+fn write_options_file(password: &str) -> io::Result<PathBuf> {
+    if password.contains(['\n', '\r']) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the password must be a single line"));
+    }
+    let path = env::temp_dir().join("home_back-mqtt.conf");
+    tools::write_private(&path, &format!("-P {}\n", password))?;
+    Ok(path)
+}
+
+// publishes the player state, a download summary and all events to MQTT_HOST and listens for commands
+pub fn connect(state: Arc<AppState>) {
+    let broker = match Broker::from_env() {
+        Some(broker) => broker,
+        None => return,
+    };
+    info!("Publishing to MQTT broker {}:{} under {}", broker.host, broker.port, broker.prefix);
+    if env::var("HA_DISCOVERY").is_ok_and(|value| value == "true") {
+        let discovery_broker = broker.clone();
+        thread::spawn(move || publish_discovery(&discovery_broker, &env::var("HA_DISCOVERY_PREFIX").unwrap_or("homeassistant".to_string())));
+    }
+
+    let events = state.events.subscribe();
+    let publisher_state = state.clone();
+    let publisher_broker = broker.clone();
+    thread::spawn(move || {
+        let (state, broker) = (publisher_state, publisher_broker);
+        let (mut player, mut downloads, mut media_player) = (Value::Null, Value::Null, Value::Null);
+        loop {
+            match events.recv_timeout(STATE_INTERVAL) {
+                Ok(event) => broker.publish_json(&format!("events/{}", event.name()), &*event, false),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            // only publish when something changed, the retained messages keep the last state for new subscribers
+            publish_changed(&broker, "player", &mut player, player_state(&state));
+            publish_changed(&broker, "downloads", &mut downloads, json!(state.download_manager.get_summary()));
+            publish_media_player(&broker, &mut media_player, media_player_state(&state));
+        }
+    });
+
+    thread::spawn(move || loop {
+        let topic = format!("{}/command/#", broker.prefix);
+        match broker.command("mosquitto_sub").arg("-v").arg("-t").arg(&topic).stdin(Stdio::null()).stdout(Stdio::piped()).spawn() {
+            Ok(mut process) => {
+                process::register("mosquitto_sub", process.id());
+                let stdout = process.stdout.take().unwrap();
+                for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                    // -v prints the topic in front of the payload
+                    let (topic, payload) = line.split_once(' ').unwrap_or((&line, ""));
+                    let command = topic.rsplit('/').next().unwrap_or_default();
+                    if let Err(error) = handle_command(&state, command, payload) {
+                        warn!("could not handle MQTT command {}: {}", command, error);
+                    }
+                }
+                let _ = process.wait();
+                process::unregister(process.id());
+                warn!("mosquitto_sub exited, reconnecting");
+            },
+            Err(error) => error!("could not start mosquitto_sub: {}", error),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+}
+
+// Home Assistant has no MQTT media player of its own, the media_player is for the MQTT Media Player custom integration
+// and the sensors and controls of the device work without it
+fn publish_discovery(broker: &Broker, discovery_prefix: &str) {
+    let device = json!({ "identifiers": [broker.prefix], "name": "HomeBack", "sw_version": env!("CARGO_PKG_VERSION") });
+    let topic = |suffix: &str| format!("{}/{}", broker.prefix, suffix);
+    let entities = [
+        ("media_player", "player", json!({
+            "name": "Player",
+            "state_state_topic": topic("media_player/state"),
+            "state_title_topic": topic("media_player/title"),
+            "state_volume_topic": topic("media_player/volume"),
+            "command_volume_topic": topic("command/media_volume"),
+            // the player can't pause, pausing stops it
+            "command_pause_topic": topic("command/stop"),
+            "command_pause_payload": "",
+            "command_next_topic": topic("command/zap_next"),
+            "command_next_payload": "",
+            "command_previous_topic": topic("command/zap_previous"),
+            "command_previous_payload": "",
+            "command_playmedia_topic": topic("command/play_media"),
+        })),
+        ("sensor", "player", json!({ "name": "Player", "state_topic": topic("player"), "value_template": "{{ value_json.state }}", "icon": "mdi:television-play" })),
+        ("sensor", "channel", json!({ "name": "Channel playing", "state_topic": topic("player"), "value_template": "{{ value_json.uri | default('') }}", "icon": "mdi:television-classic" })),
+        ("sensor", "downloads_active", json!({ "name": "Downloads active", "state_topic": topic("downloads"), "value_template": "{{ value_json.active }}", "icon": "mdi:download" })),
+        ("sensor", "downloads_queued", json!({ "name": "Downloads queued", "state_topic": topic("downloads"), "value_template": "{{ value_json.queued }}", "icon": "mdi:tray-full" })),
+        ("button", "stop", json!({ "name": "Stop", "command_topic": topic("command/stop"), "payload_press": "", "icon": "mdi:stop" })),
+        ("number", "volume", json!({ "name": "Volume", "command_topic": topic("command/volume"), "min": 0, "max": audio::MAX_VOLUME, "unit_of_measurement": "%", "optimistic": true, "icon": "mdi:volume-high" })),
+    ];
+
+    info!("Publishing Home Assistant discovery to {}", discovery_prefix);
+    for (component, object_id, mut config) in entities {
+        config["unique_id"] = json!(format!("{}_{}", broker.prefix, object_id));
+        config["device"] = device.clone();
+        let config_topic = format!("{}/{}/{}/{}/config", discovery_prefix, component, broker.prefix, object_id);
+        let result = serde_json::to_string(&config).map_err(io::Error::other)
+            .and_then(|payload| broker.publish_absolute(&config_topic, &payload, true));
+        if let Err(error) = result {
+            error!("could not publish Home Assistant discovery: {}", error);
+        }
+    }
+}
+
+fn publish_changed(broker: &Broker, topic: &str, published: &mut Value, current: Value) {
+    if *published != current {
+        broker.publish_json(topic, &current, true);
+        *published = current;
+    }
+}
+
+// the custom integration wants a plain value on each topic
+fn publish_media_player(broker: &Broker, published: &mut Value, current: Value) {
+    for field in ["state", "title", "volume"] {
+        if published[field] != current[field] {
+            let payload = match &current[field] {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            if let Err(error) = broker.publish(&format!("media_player/{}", field), &payload, true) {
+                error!("could not publish to MQTT: {}", error);
+            }
+        }
+    }
+    *published = current;
+}
+
+fn media_player_state(state: &AppState) -> Value {
+    let (player_state, title) = match state.video_player.running() {
+        Some(args) => ("playing", args.source_and_name().1.to_string()),
+        None => ("idle", String::new()),
+    };
+    // Home Assistant has the volume between 0 and 1
+    let volume = audio::get_volume().map(|volume| f64::from(volume.min(100)) / 100.0).ok();
+    json!({ "state": player_state, "title": title, "volume": volume })
+}
+
+fn player_state(state: &AppState) -> Value {
+    match state.video_player.running() {
+        Some(args) => {
+            let mut player = json!(VideoPlayerSomthing::from(&*args));
+            player["state"] = json!("playing");
+            player
+        },
+        None => json!({ "state": "idle" }),
+    }
+}
+
+fn handle_command(state: &AppState, command: &str, payload: &str) -> io::Result<()> {
+    info!("Received MQTT command {}: {}", command, payload);
+    match command {
+        "play" => {
+            let request: VideoPlayerSomthing = serde_json::from_str(payload)?;
+            if validation::validate(&request).is_err() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid play command: {}", payload)));
+            }
+            match request {
+                VideoPlayerSomthing::Twitch(stream) => state.video_player.start(VideoPlayerArgs::Twitch { stream, audio_only: false }).map(|_| ()),
+                VideoPlayerSomthing::DvbC(channel_name) => actions::play_dvbc(state, &channel_name),
+                VideoPlayerSomthing::Url(url) => state.video_player.start(VideoPlayerArgs::Url { name: url.clone(), url, profile: None }).map(|_| ()),
+                VideoPlayerSomthing::Media(path) => match state.media.get(&path) {
+                    Some(file) => state.video_player.start(VideoPlayerArgs::Media { name: file.name, path: file.path, profile: None }).map(|_| ()),
+                    None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the media index", path))),
+                },
+                // the library client is async and this runs outside of the runtime
+                VideoPlayerSomthing::Library(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "library items can only be played through the API")),
+            }
+        },
+        // what the media player of Home Assistant sends, the media id is a play command or the name of a DvbC channel
+        "play_media" => {
+            let media: PlayMedia = serde_json::from_str(payload)?;
+            match serde_json::from_str::<VideoPlayerSomthing>(&media.media_id) {
+                Ok(_) => handle_command(state, "play", &media.media_id),
+                Err(_) => actions::play_dvbc(state, &media.media_id),
+            }
+        },
+        "stop" => state.video_player.stop(),
+        "zap_next" => { actions::perform(state, Action::ZapNext); Ok(()) },
+        "zap_previous" => { actions::perform(state, Action::ZapPrevious); Ok(()) },
+        "volume" => {
+            let volume = payload.trim().parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid volume: {}", payload)))?;
+            audio::set_volume(volume).map(|_| ())
+        },
+        "media_volume" => audio::set_volume(media_volume(payload)?).map(|_| ()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown command")),
+    }
+}
+
+#[derive(Deserialize)]
+struct PlayMedia {
+    media_id: String,
+}
+
+// from the 0 to 1 of Home Assistant to percent
+fn media_volume(payload: &str) -> io::Result<u32> {
+    match payload.trim().parse::<f64>() {
+        Ok(volume) if (0.0..=1.0).contains(&volume) => Ok((volume * 100.0).round() as u32),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid volume: {}", payload))),
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,22 @@
+use super::*;
+
+#[test]
+fn the_media_volume_is_converted_to_percent() {
+    assert_eq!(45, media_volume("0.45").unwrap());
+    assert_eq!(0, media_volume("0").unwrap());
+    assert_eq!(100, media_volume("1.0\n").unwrap());
+}
+
+#[test]
+fn a_media_volume_outside_of_0_to_1_is_rejected() {
+    assert!(media_volume("45").is_err());
+    assert!(media_volume("-0.1").is_err());
+    assert!(media_volume("loud").is_err());
+}
+
+#[test]
+fn play_media_carries_the_media_id() {
+    let media: PlayMedia = serde_json::from_str(r#"{"media_type": "channel", "media_id": "ZDF HD"}"#).unwrap();
+
+    assert_eq!("ZDF HD", media.media_id);
+}
@@ -0,0 +1,101 @@
+use std::env;
+use std::thread;
+use std::time::Duration;
+use log::{info, error};
+use reqwest::blocking::Client;
+use serde_json::json;
+use crate::events::{Event, Events};
+
+const DEFAULT_EVENTS: &str = "twitch.live,download.failed,disk.low";
+const MB: u64 = 1024 * 1024;
+
+enum Target {
+    Telegram { token: String, chat_id: String },
+    Discord { webhook_url: String },
+}
+
+impl Target {
+
+    fn from_env() -> Vec<Target> {
+        let mut targets = Vec::new();
+        if let (Ok(token), Ok(chat_id)) = (env::var("TELEGRAM_BOT_TOKEN"), env::var("TELEGRAM_CHAT_ID")) {
+            targets.push(Target::Telegram { token, chat_id });
+        }
+        if let Ok(webhook_url) = env::var("DISCORD_WEBHOOK_URL") {
+            targets.push(Target::Discord { webhook_url });
+        }
+        targets
+    }
+
+    fn send(&self, client: &Client, message: &str) -> Result<(), reqwest::Error> {
+        let request = match self {
+            Target::Telegram { token, chat_id } => client.post(format!("https://api.telegram.org/bot{}/sendMessage", token))
+                .json(&json!({ "chat_id": chat_id, "text": message })),
+            Target::Discord { webhook_url } => client.post(webhook_url)
+                .json(&json!({ "content": message })),
+        };
+        request.send()?.error_for_status()?;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Target::Telegram { .. } => "Telegram",
+            Target::Discord { .. } => "Discord",
+        }
+    }
+}
+
+fn message(event: &Event) -> String {
+    match event {
+        Event::PlayerStarted { source, name } => format!("Playing {} ({})", name, source),
+        Event::PlayerStopped => "Player stopped".to_string(),
+        Event::DownloadFinished { path, .. } => format!("Download finished: {}", path),
+        Event::DownloadFailed { path, error, .. } => format!("Download failed: {}\n{}", path, error),
+        Event::TwitchLive { channel, title, game } => format!("{} is live with {}: {}\nhttps://twitch.tv/{}", channel, game, title, channel),
+        Event::TwitchRaid { from, to } => format!("{} raided {}, following along\nhttps://twitch.tv/{}", from, to, to),
+        Event::TwitchClip { stream, edit_url, .. } => format!("Clipped {}\n{}", stream, edit_url),
+        Event::ProcessStarted { kind, pid } => format!("{} started ({})", kind, pid),
+        Event::ProcessStopped { kind, pid } => format!("{} stopped ({})", kind, pid),
+        Event::ProcessExited { kind, code: Some(code) } => format!("{} exited with {}", kind, code),
+        Event::ProcessExited { kind, code: None } => format!("{} was killed", kind),
+        Event::RouterUnreachable { error } => format!("The router is not reachable, DvbC won't work: {}", error),
+        Event::RouterReachable => "The router is reachable again".to_string(),
+        Event::PostProcessingFinished { path, .. } => format!("Post processing finished: {}", path),
+        Event::PostProcessingFailed { path, error, .. } => format!("Post processing failed: {}\n{}", path, error),
+        Event::DiskSpaceLow { folder, available } => format!("Only {} MB left for {}, new downloads are paused", available / MB, folder),
+        Event::DiskSpaceRecovered { folder, available } => format!("{} MB free for {} again, downloads continue", available / MB, folder),
+        Event::IdleShutdownWarning { action, seconds } => format!("Nothing is going on, {:?} in {} seconds", action, seconds),
+        Event::IdleShutdownCancelled => "Idle shutdown cancelled".to_string(),
+    }
+}
+
+// sends a chat message to Telegram and/or Discord for the events listed in NOTIFY_EVENTS
+pub fn start(events: &Events) {
+    let targets = Target::from_env();
+    if targets.is_empty() {
+        return;
+    }
+    let filters: Vec<String> = env::var("NOTIFY_EVENTS").unwrap_or(DEFAULT_EVENTS.to_string())
+        .split(',')
+        .map(|filter| filter.trim().to_string())
+        .filter(|filter| !filter.is_empty())
+        .collect();
+    info!("Sending notifications for {} to {}", filters.join(", "), targets.iter().map(Target::name).collect::<Vec<_>>().join(" and "));
+
+    let receiver = events.subscribe();
+    thread::spawn(move || {
+        let client = Client::builder().timeout(Duration::from_secs(10)).build().unwrap();
+        for event in receiver {
+            if !filters.iter().any(|filter| event.matches(filter)) {
+                continue;
+            }
+            let message = message(&event);
+            for target in &targets {
+                if let Err(error) = target.send(&client, &message) {
+                    error!("Could not send {} notification to {}: {}", event.name(), target.name(), error);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,197 @@
+use std::env;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use log::{info, error};
+use reqwest::{Client, Url};
+use serde::{Serialize, Deserialize};
+use sha1::{Digest, Sha1};
+use uuid::Uuid;
+use crate::download::{self, DownloadManager, RequestHeaders};
+use crate::state::AppState;
+use crate::store::{Repository, Store};
+use crate::xml::{xml_attribute, xml_element, xml_elements, xml_text};
+
+// feeds of long running shows list hundreds of episodes, older ones are forgotten
+const MAX_EPISODES: usize = 100;
+const FOLDER: &str = "podcasts";
+
+const DEFAULT_POLL_MINUTES: u64 = 60;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Podcast {
+    pub id: String,
+    pub url: String,
+    pub title: String,
+    pub episodes: Vec<Episode>,
+}
+
+#[derive(Serialize)]
+pub struct PodcastSummary {
+    id: String,
+    url: String,
+    title: String,
+    episodes: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Episode {
+    pub id: String,
+    pub title: String,
+    pub published: Option<String>,
+    pub url: String,
+    path: Option<String>,     // relative to the DOWNLOAD_FOLDER, once it was downloaded
+    download: Option<Uuid>,
+}
+
+pub struct Podcasts {
+    client: Client,
+    poll_interval: Duration,
+    subscriptions: Repository<Podcast>,
+}
+
+impl Podcasts {
+
+    pub fn from_env(store: Arc<Store>) -> Self {
+        let minutes = env::var("PODCAST_POLL_MINUTES").ok().and_then(|minutes| minutes.parse().ok()).unwrap_or(DEFAULT_POLL_MINUTES);
+        Self::new(Duration::from_secs(60 * minutes), store)
+    }
+
+    pub fn new(poll_interval: Duration, store: Arc<Store>) -> Self {
+        Self { client: Client::new(), poll_interval, subscriptions: Repository::new(store, "podcasts") }
+    }
+
+    pub fn get_podcasts(&self) -> Vec<PodcastSummary> {
+        self.subscriptions.all().into_iter()
+            .map(|(_, podcast)| PodcastSummary { id: podcast.id, url: podcast.url, title: podcast.title, episodes: podcast.episodes.len() })
+            .collect()
+    }
+
+    pub fn get_podcast(&self, id: &str) -> Option<Podcast> {
+        self.subscriptions.get(id)
+    }
+
+    // subscribes to the feed and downloads its latest episode, the older ones can only be streamed
+    pub async fn subscribe(&self, url: String, download_manager: &DownloadManager) -> Result<Podcast, Box<dyn std::error::Error>> {
+        let id = hash(&url);
+        if let Some(podcast) = self.subscriptions.get(&id) {
+            return Ok(podcast);
+        }
+        let (title, mut episodes) = self.fetch(&url).await?;
+        info!("subscribed to podcast {} with {} episodes", title, episodes.len());
+        if let Some(latest) = episodes.first_mut() {
+            enqueue(&title, latest, download_manager);
+        }
+        let podcast = Podcast { id: id.clone(), url, title, episodes };
+        self.subscriptions.put(&id, &podcast);
+        Ok(podcast)
+    }
+
+    pub fn unsubscribe(&self, id: &str) -> bool {
+        let known = self.subscriptions.get(id).is_some();
+        self.subscriptions.remove(id);
+        known
+    }
+
+    // checks all feeds and downloads the episodes that are new since the last check
+    pub async fn refresh(&self, download_manager: &DownloadManager) {
+        for (id, mut podcast) in self.subscriptions.all() {
+            let (_, episodes) = match self.fetch(&podcast.url).await {
+                Ok(feed) => feed,
+                Err(error) => { error!("could not refresh podcast {}: {}", podcast.title, error); continue },
+            };
+            // episodes from before the ids were sha1 are only known by their url
+            let mut new_episodes: Vec<Episode> = episodes.into_iter()
+                .filter(|episode| podcast.episodes.iter().all(|known| known.id != episode.id && known.url != episode.url))
+                .collect();
+            if new_episodes.is_empty() {
+                continue;
+            }
+            info!("found {} new episodes of {}", new_episodes.len(), podcast.title);
+            for episode in new_episodes.iter_mut() {
+                enqueue(&podcast.title, episode, download_manager);
+            }
+            new_episodes.append(&mut podcast.episodes);
+            new_episodes.truncate(MAX_EPISODES);
+            podcast.episodes = new_episodes;
+            self.subscriptions.put(&id, &podcast);
+        }
+    }
+
+    // what the player should open for an episode, the download if it is finished and the feed otherwise
+    pub fn episode_source(&self, id: &str, episode_id: &str, download_manager: &DownloadManager) -> Option<(String, String)> {
+        let podcast = self.subscriptions.get(id)?;
+        let episode = podcast.episodes.into_iter().find(|episode| episode.id == episode_id)?;
+        let downloading = episode.download.is_some_and(|uuid| download_manager.get_download(uuid).is_some());
+        let local = episode.path.as_deref().and_then(|path| download::download_location(path).ok()).filter(|path| !downloading && path.is_file());
+        let url = match local {
+            Some(path) => path.to_string_lossy().into_owned(),
+            None => episode.url,
+        };
+        Some((format!("{} - {}", podcast.title, episode.title), url))
+    }
+
+    async fn fetch(&self, url: &str) -> Result<(String, Vec<Episode>), Box<dyn std::error::Error>> {
+        let feed = self.client.get(url).send().await?.error_for_status()?.text().await?;
+        let channel = xml_element(&feed, "channel").ok_or("not a rss feed")?;
+        let title = xml_element(&channel, "title").map(|title| xml_text(&title)).unwrap_or(url.to_string());
+        let mut episodes: Vec<Episode> = xml_elements(&channel, "item").into_iter()
+            .filter_map(|item| {
+                let url = xml_attribute(item, "enclosure", "url")?;
+                let guid = xml_element(item, "guid").map(|guid| xml_text(&guid)).unwrap_or_else(|| url.clone());
+                Some(Episode {
+                    id: hash(&guid),
+                    title: xml_element(item, "title").map(|title| xml_text(&title)).unwrap_or_else(|| guid.clone()),
+                    published: xml_element(item, "pubDate").map(|date| xml_text(&date)),
+                    url,
+                    path: None,
+                    download: None,
+                })
+            })
+            .collect();
+        episodes.truncate(MAX_EPISODES);
+        Ok((title, episodes))
+    }
+}
+
+fn enqueue(podcast: &str, episode: &mut Episode, download_manager: &DownloadManager) {
+    let extension = Url::parse(&episode.url).ok()
+        .and_then(|url| url.path().rsplit_once('.').map(|(_, extension)| extension.to_string()))
+        .filter(|extension| extension.len() <= 4 && extension.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or("mp3".to_string());
+    // episodes of a show often share a title like "Trailer" or "Bonus", the id keeps them apart
+    let path = format!("{}/{}/{} [{}].{}", FOLDER, file_name(podcast), file_name(&episode.title), &episode.id[..8], extension);
+    match download_manager.trigger_download(episode.url.clone(), path.clone(), RequestHeaders::default()) {
+        Ok(download) => {
+            episode.path = Some(path);
+            episode.download = Some(download.uuid);
+        },
+        Err(error) => error!("could not download episode {} of {}: {}", episode.title, podcast, error),
+    }
+}
+
+// titles can contain anything, but they end up as files for the frontend
+pub fn file_name(title: &str) -> String {
+    let name: String = title.chars()
+        .map(|c| if c.is_alphanumeric() || " -_.,()".contains(c) { c } else { '_' })
+        .take(100)
+        .collect();
+    name.trim_matches(|c: char| c == '.' || c.is_whitespace()).to_string()
+}
+
+// stable ids for urls and guids, which don't fit into a path
+pub fn hash(text: &str) -> String {
+    format!("{:x}", Sha1::digest(text.as_bytes()))
+}
+
+// refreshes all feeds every PODCAST_POLL_MINUTES, this has to run on the runtime as the downloads do
+pub async fn poll(state: web::Data<AppState>) {
+    loop {
+        if !state.quiet_mode.load(Ordering::Relaxed) {
+            state.podcasts.refresh(&state.download_manager).await;
+        }
+        sleep(state.podcasts.poll_interval).await;
+    }
+}
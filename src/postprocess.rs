@@ -0,0 +1,286 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use log::{info, error, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::events::{Event, Events};
+use crate::files::{self, PathError, Root};
+use crate::media;
+use crate::process;
+
+// finished jobs are kept for the frontend until there are more than this
+const MAX_FINISHED_JOBS: usize = 50;
+
+const H264_ARGS: &str = "-c:v libx264 -preset veryfast -crf 21 -c:a copy -c:s copy";
+const HEVC_ARGS: &str = "-c:v libx265 -preset fast -crf 24 -c:a copy -c:s copy";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Remux, // TS to MKV, without touching the streams
+    H264,
+    Hevc,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JobRequest {
+    pub path: String, // in the DOWNLOAD_FOLDER
+    pub profile: Profile,
+    // seconds of padding to cut from the start and the end
+    #[serde(default)]
+    pub cut_start: f64,
+    #[serde(default)]
+    pub cut_end: f64,
+    // deletes the original once the new file is complete
+    #[serde(default)]
+    pub replace: bool,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Job {
+    pub id: Uuid,
+    pub path: String,
+    pub output: String,
+    pub profile: Profile,
+    cut_start: f64,
+    cut_end: f64,
+    replace: bool,
+    pub status: JobStatus,
+    progress: f64, // 0 to 1, of the duration after cutting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// remuxes, cuts or transcodes files of the DOWNLOAD_FOLDER with ffmpeg, one at a time as they take the cpu
+// meant for recordings, the ones of the recorder as well as .ts files that were downloaded, e.g. recorded streams
+pub struct PostProcessing {
+    jobs: Arc<Mutex<Vec<Job>>>,
+    queued: Mutex<mpsc::Sender<Uuid>>,
+    ts_profile: Option<Profile>, // the profile finished .ts downloads get, e.g. recorded streams
+}
+
+// the ffmpeg arguments of the profiles that transcode
+#[derive(Clone, Debug)]
+pub struct Encoders {
+    pub h264: String,
+    pub hevc: String,
+}
+
+impl PostProcessing {
+
+    pub fn from_env(events: Arc<Events>) -> Self {
+        let encoders = Encoders {
+            h264: env::var("TRANSCODE_H264").unwrap_or(H264_ARGS.to_string()),
+            hevc: env::var("TRANSCODE_HEVC").unwrap_or(HEVC_ARGS.to_string()),
+        };
+        let ts_profile = env::var("POSTPROCESS_TS").ok().and_then(|profile| match profile.as_str() {
+            "remux" => Some(Profile::Remux),
+            "h264"  => Some(Profile::H264),
+            "hevc"  => Some(Profile::Hevc),
+            _ => { warn!("POSTPROCESS_TS must be remux, h264 or hevc, not {}", profile); None },
+        });
+        Self::new(events, encoders, ts_profile)
+    }
+
+    pub fn new(events: Arc<Events>, encoders: Encoders, ts_profile: Option<Profile>) -> Self {
+        let jobs: Arc<Mutex<Vec<Job>>> = Arc::default();
+        let (queued, receiver) = mpsc::channel();
+        let worked = jobs.clone();
+        thread::spawn(move || for id in receiver {
+            run(&worked, &events, &encoders, id);
+        });
+        Self { jobs, queued: Mutex::new(queued), ts_profile }
+    }
+
+    pub fn enqueue(&self, request: JobRequest) -> Result<Job, PathError> {
+        let input = files::resolve(Root::Download, &request.path)?;
+        if !input.is_file() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not a file", request.path)).into());
+        }
+        let output = output_path(&request.path, request.profile);
+        if files::resolve(Root::Download, &output)?.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", output)).into());
+        }
+
+        let job = Job {
+            id: Uuid::new_v4(),
+            path: request.path,
+            output,
+            profile: request.profile,
+            cut_start: request.cut_start,
+            cut_end: request.cut_end,
+            replace: request.replace,
+            status: JobStatus::Queued,
+            progress: 0.0,
+            error: None,
+        };
+        info!("queueing {:?} of {}", job.profile, job.path);
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.push(job.clone());
+            let finished = jobs.iter().filter(|job| matches!(job.status, JobStatus::Finished | JobStatus::Failed)).count();
+            if finished > MAX_FINISHED_JOBS {
+                if let Some(oldest) = jobs.iter().position(|job| matches!(job.status, JobStatus::Finished | JobStatus::Failed)) {
+                    jobs.remove(oldest);
+                }
+            }
+        }
+        self.queued.lock().unwrap().send(job.id).expect("the post processing worker is gone");
+        Ok(job)
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Job> {
+        self.jobs.lock().unwrap().iter().find(|job| job.id == id).cloned()
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.jobs.lock().unwrap().iter().any(|job| matches!(job.status, JobStatus::Queued | JobStatus::Running))
+    }
+
+    pub fn all(&self) -> Vec<Job> {
+        self.jobs.lock().unwrap().clone()
+    }
+}
+
+// next to the original, as mkv, with the profile in the name if that is the original's name already
+fn output_path(path: &str, profile: Profile) -> String {
+    let path = Path::new(path);
+    let mut output = path.with_extension("mkv");
+    if output == path {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        output = path.with_file_name(format!("{}.{}.mkv", stem, profile_name(profile)));
+    }
+    output.to_string_lossy().into_owned()
+}
+
+fn profile_name(profile: Profile) -> &'static str {
+    match profile {
+        Profile::Remux => "remux",
+        Profile::H264  => "h264",
+        Profile::Hevc  => "hevc",
+    }
+}
+
+fn run(jobs: &Mutex<Vec<Job>>, events: &Events, encoders: &Encoders, id: Uuid) {
+    let update = |change: &dyn Fn(&mut Job)| if let Some(job) = jobs.lock().unwrap().iter_mut().find(|job| job.id == id) {
+        change(job);
+    };
+    let Some(job) = jobs.lock().unwrap().iter().find(|job| job.id == id).cloned() else {
+        return;
+    };
+    update(&|job| job.status = JobStatus::Running);
+
+    match transcode(&job, encoders, |progress| update(&|job| job.progress = progress)) {
+        Ok(()) => {
+            info!("{:?} of {} finished as {}", job.profile, job.path, job.output);
+            update(&|job| { job.status = JobStatus::Finished; job.progress = 1.0 });
+            events.publish(Event::PostProcessingFinished { id, path: job.output.clone() });
+        },
+        Err(error) => {
+            error!("could not post process {}: {}", job.path, error);
+            let message = error.to_string();
+            update(&|job| { job.status = JobStatus::Failed; job.error = Some(message.clone()) });
+            events.publish(Event::PostProcessingFailed { id, path: job.path.clone(), error: message.clone() });
+        },
+    }
+}
+
+fn transcode(job: &Job, encoders: &Encoders, progress: impl Fn(f64)) -> io::Result<()> {
+    let input = files::resolve(Root::Download, &job.path)?;
+    let output = files::resolve(Root::Download, &job.output)?;
+    let info = media::ffprobe(&input)?;
+    let duration = info["format"]["duration"].as_str().and_then(|duration| duration.parse::<f64>().ok());
+    let length = duration.map(|duration| duration - job.cut_start - job.cut_end);
+    if length.is_some_and(|length| length <= 0.0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "nothing is left after cutting"));
+    }
+
+    let mut command = process::scoped_command("postprocess", "ffmpeg");
+    command.arg("-hide_banner").arg("-loglevel").arg("error").arg("-nostats").arg("-progress").arg("pipe:1");
+    if job.cut_start > 0.0 {
+        command.arg("-ss").arg(job.cut_start.to_string());
+    }
+    command.arg("-i").arg(&input);
+    if let Some(length) = length.filter(|_| job.cut_end > 0.0) {
+        command.arg("-t").arg(length.to_string());
+    }
+    // the video, audio and subtitles, but neither data streams nor teletext, which MKV can't hold
+    command.arg("-map").arg("0:v?").arg("-map").arg("0:a?").arg("-map").arg("0:s?");
+    for stream in info["streams"].as_array().into_iter().flatten().filter(|stream| stream["codec_name"] == "dvb_teletext") {
+        if let Some(index) = stream["index"].as_u64() {
+            command.arg("-map").arg(format!("-0:{}", index));
+        }
+    }
+    match job.profile {
+        Profile::Remux => { command.arg("-c").arg("copy"); },
+        Profile::H264  => { command.args(encoders.h264.split_whitespace()); },
+        Profile::Hevc  => { command.args(encoders.hevc.split_whitespace()); },
+    }
+    let mut child = command.arg("-n").arg(&output)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let pid = child.id();
+    process::register("postprocess", pid);
+
+    // read alongside, a full stderr pipe would block ffmpeg while the progress is read
+    let mut stderr = child.stderr.take().unwrap();
+    let errors = thread::spawn(move || {
+        let mut errors = String::new();
+        let _ = stderr.read_to_string(&mut errors);
+        errors
+    });
+    // key=value lines, a block per update
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        let line = line?;
+        if let (Some(out_time), Some(length)) = (line.strip_prefix("out_time_us=").and_then(|micros| micros.parse::<f64>().ok()), length) {
+            progress((out_time / 1_000_000.0 / length).clamp(0.0, 1.0));
+        }
+    }
+    let status = child.wait();
+    process::unregister(pid);
+    let status = status?;
+    if !status.success() {
+        let _ = fs::remove_file(&output);
+        let errors = errors.join().unwrap_or_default();
+        return Err(io::Error::other(format!("ffmpeg exited with {}: {}", status, errors.lines().last().unwrap_or_default())));
+    }
+    if job.replace {
+        fs::remove_file(&input)?;
+    }
+    Ok(())
+}
+
+// with POSTPROCESS_TS, finished .ts downloads are post processed with that profile and replaced
+pub fn start(events: &Events, state: Arc<PostProcessing>) {
+    let Some(profile) = state.ts_profile else {
+        return;
+    };
+    let receiver = events.subscribe();
+    thread::spawn(move || for event in receiver {
+        if let Event::DownloadFinished { path, .. } = &*event {
+            if !PathBuf::from(path).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("ts")) {
+                continue;
+            }
+            let request = JobRequest { path: path.clone(), profile, cut_start: 0.0, cut_end: 0.0, replace: true };
+            if let Err(error) = state.enqueue(request) {
+                error!("could not post process {}: {}", path, error);
+            }
+        }
+    });
+}
@@ -0,0 +1,80 @@
+use std::env;
+use std::io;
+use std::process::Stdio;
+use actix_web::{http, HttpRequest};
+use log::info;
+use serde::Serialize;
+use crate::auth::Auth;
+use crate::tools;
+
+// whether the host may be shut down over the API, and by whom
+pub struct PowerControl {
+    enabled: bool, // anyone on the network could turn off the box otherwise
+    allowed_origins: Vec<String>, // the frontends that may ask without the token, e.g. `http://htpc.local`
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Shutdown,
+    Reboot,
+    Suspend,
+}
+
+impl Action {
+    fn systemctl_command(&self) -> &'static str {
+        match self {
+            Action::Shutdown => "poweroff",
+            Action::Reboot   => "reboot",
+            Action::Suspend  => "suspend",
+        }
+    }
+}
+
+impl PowerControl {
+
+    pub fn from_env() -> Self {
+        let allowed_origins = env::var("POWER_ALLOWED_ORIGINS").unwrap_or_default()
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        Self::new(env::var("POWER_CONTROL").is_ok_and(|value| value == "true"), allowed_origins)
+    }
+
+    pub fn new(enabled: bool, allowed_origins: Vec<String>) -> Self {
+        Self { enabled, allowed_origins }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // whether the request has the ADMIN_TOKEN or comes from one of the POWER_ALLOWED_ORIGINS, without either nobody may
+    pub fn is_allowed(&self, auth: &Auth, request: &HttpRequest) -> bool {
+        let origin = request.headers().get(http::header::ORIGIN).and_then(|value| value.to_str().ok());
+        auth.is_admin(request) || is_allowed_origin(origin, &self.allowed_origins)
+    }
+}
+
+// the browser sets the Origin, so another page opened on the network can't ask in the name of the frontend
+fn is_allowed_origin(origin: Option<&str>, allowed: &[String]) -> bool {
+    origin.is_some_and(|origin| allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin.trim_end_matches('/'))))
+}
+
+pub fn run(action: Action) -> io::Result<()> {
+    info!("{:?} of the host requested", action);
+    let output = tools::command("systemctl")
+        .arg(action.systemctl_command())
+        .stdin(Stdio::null())
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("systemctl {} failed: {}", action.systemctl_command(), stderr.trim())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,19 @@
+use super::*;
+
+fn allowed() -> Vec<String> {
+    vec!["http://htpc.local:8080".to_string()]
+}
+
+#[test]
+fn the_frontend_may_ask_from_its_origin() {
+    assert!(is_allowed_origin(Some("http://htpc.local:8080"), &allowed()));
+    assert!(is_allowed_origin(Some("http://HTPC.local:8080/"), &allowed()));
+}
+
+#[test]
+fn other_origins_are_not_allowed() {
+    assert!(!is_allowed_origin(Some("http://htpc.local"), &allowed()));
+    assert!(!is_allowed_origin(Some("http://evil.example"), &allowed()));
+    assert!(!is_allowed_origin(None, &allowed()));
+    assert!(!is_allowed_origin(Some("http://htpc.local:8080"), &[]));
+}
@@ -0,0 +1,91 @@
+use std::io;
+use std::process::Stdio;
+use log::info;
+use serde::Serialize;
+use serde_json::Value;
+use crate::dvbc::Channel;
+use crate::process;
+
+// long enough for the first keyframe, channels send one every few seconds
+const READ_SECONDS: u32 = 3;
+
+#[derive(Serialize)]
+pub struct ChannelProbe {
+    pub channel: String,
+    pub decodable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video: Option<VideoStream>,
+    pub audio: Vec<AudioStream>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct VideoStream {
+    pub codec: Option<String>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub frames: u64,
+}
+
+#[derive(Serialize)]
+pub struct AudioStream {
+    pub codec: Option<String>,
+    pub language: Option<String>,
+    pub frames: u64,
+}
+
+// reads a few seconds of the stream and decodes it, so a black screen can be told apart from a player problem
+// an unreachable or encrypted channel is a result, only a missing ffprobe is an error
+pub fn probe(channel: &Channel) -> io::Result<ChannelProbe> {
+    info!("probing {}", channel.name);
+    let child = process::scoped_command("probe", "ffprobe")
+        .arg("-v").arg("error")
+        .arg("-print_format").arg("json")
+        .arg("-show_streams")
+        .arg("-count_frames")
+        .arg("-read_intervals").arg(format!("%+{}", READ_SECONDS))
+        .arg(&channel.url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let pid = child.id();
+    process::register("probe", pid);
+    let output = child.wait_with_output();
+    process::unregister(pid);
+    let output = output?;
+
+    let mut probe = ChannelProbe { channel: channel.name.clone(), decodable: false, video: None, audio: Vec::new(), error: None };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        probe.error = Some(stderr.lines().last().unwrap_or("ffprobe failed").to_string());
+        return Ok(probe);
+    }
+
+    let info: Value = serde_json::from_slice(&output.stdout)?;
+    let streams = info["streams"].as_array().cloned().unwrap_or_default();
+    // the decoder reads no frames from a scrambled stream, even though the codecs are listed
+    let frames = |stream: &Value| stream["nb_read_frames"].as_str().and_then(|frames| frames.parse().ok()).unwrap_or(0);
+    let codec = |stream: &Value| stream["codec_name"].as_str().map(str::to_string);
+    probe.video = streams.iter().find(|stream| stream["codec_type"] == "video").map(|video| VideoStream {
+        codec:  codec(video),
+        width:  video["width"].as_u64().filter(|width| *width > 0),
+        height: video["height"].as_u64().filter(|height| *height > 0),
+        frames: frames(video),
+    });
+    probe.audio = streams.iter().filter(|stream| stream["codec_type"] == "audio").map(|audio| AudioStream {
+        codec:    codec(audio),
+        language: audio["tags"]["language"].as_str().map(str::to_string),
+        frames:   frames(audio),
+    }).collect();
+    // radio channels have no video, there the audio has to decode
+    probe.decodable = match &probe.video {
+        Some(video) => video.frames > 0,
+        None => probe.audio.iter().any(|audio| audio.frames > 0),
+    };
+    if !probe.decodable {
+        probe.error = Some(format!("no frames could be decoded in {} seconds", READ_SECONDS));
+    }
+    Ok(probe)
+}
@@ -1,23 +1,106 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fmt;
+use std::ffi::OsStr;
+use std::fs;
 use std::io;
-use std::process::{Command, Child, Stdio};
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::str;
+use std::thread;
+use std::time::{Duration, Instant};
+use futures::channel::oneshot;
+use futures::future::{join_all, select, Either};
 use log::info;
-use log::error;
+use log::{debug, warn, error};
+use serde::Serialize;
+use tokio::runtime::{self, Runtime};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::time::timeout;
+use uuid::Uuid;
 
-use super::dvbc::Channel;
+use super::events::{Event, Events};
+use super::dvbc::{Channel, ChannelSettings};
+use super::library;
+use super::progress::{self, Progress, MPV_SOCKET};
+use super::relay::Relay;
+use super::store::Repository;
+use super::tools;
 
-pub trait ProcessStarter<Args> {
-    fn start_process(&self, args: &Args) -> io::Result<Child>;
-    fn on_stop(&self, _args: &Args, _process: &Child) {}
+pub trait ProcessStarter<Args>: Send + Sync {
+    // what the process is listed as in GET /process
+    fn kind(&self) -> &'static str;
+    fn command(&self, args: &Args) -> io::Result<Command>;
+    fn on_start(&self, _args: &Args, _pid: u32) {}
+    fn on_stop(&self, _args: &Args, _pid: u32) {}
+    // a process that exits this soon after being started failed to start, e.g. an offline stream
+    fn startup_window(&self, _args: &Args) -> Duration { Duration::ZERO }
 }
 
+// the state takes the starters as they are given, real or fake
+impl <Args> ProcessStarter<Args> for Box<dyn ProcessStarter<Args>> {
+    fn kind(&self) -> &'static str { (**self).kind() }
+    fn command(&self, args: &Args) -> io::Result<Command> { (**self).command(args) }
+    fn on_start(&self, args: &Args, pid: u32) { (**self).on_start(args, pid) }
+    fn on_stop(&self, args: &Args, pid: u32) { (**self).on_stop(args, pid) }
+    fn startup_window(&self, args: &Args) -> Duration { (**self).startup_window(args) }
+}
+
+// stands in for the real process until it is stopped
+fn dry_run(kind: &str, command: &Command) -> Command {
+    info!("dry run, not starting {}: {:?}", kind, command);
+    let mut fake = sleep();
+    fake.stdin(Stdio::null());
+    fake
+}
+
+#[cfg(unix)]
+fn sleep() -> Command {
+    let mut command = Command::new("sleep");
+    command.arg(i32::MAX.to_string());
+    command
+}
+
+#[cfg(windows)]
+fn sleep() -> Command {
+    let mut command = Command::new("powershell");
+    command.arg("-NoProfile").arg("-Command").arg("Start-Sleep -Seconds 2147483");
+    command
+}
+
+// asks the process to exit, on Windows this takes its children along
+#[cfg(unix)]
+fn terminate(pid: u32) -> io::Result<ExitStatus> {
+    tools::command("kill").arg(pid.to_string()).status()
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) -> io::Result<ExitStatus> {
+    tools::command("taskkill").arg("/T").arg("/F").arg("/PID").arg(pid.to_string()).status()
+}
+
+// taskkill ends the whole tree, so the pid of mpv isn't needed
+#[cfg(windows)]
 fn kill_mpv(parent_process_id: u32) {
+    match terminate(parent_process_id) {
+        Ok(status) => info!("killed {} and its children with status: {}", parent_process_id, status),
+        Err(error) => error!("kill of {} failed: {}", parent_process_id, error),
+    }
+}
+
+#[cfg(unix)]
+fn kill_mpv(parent_process_id: u32) {
+    let mpv = PathBuf::from(tools::program("mpv"));
+    let mpv_name = mpv.file_name().unwrap_or(mpv.as_os_str());
     // find the process id by calling ps
-    let output = match Command::new("ps")
+    let output = match tools::command("ps")
         .arg("h")                      // don't show a header
         .arg("-o").arg("pid,ppid")     // only show the process id and the parent process id
-        .arg("-C").arg("mpv").output() // only show processes with the command mpv
+        .arg("-C").arg(mpv_name).output() // only show processes with the command mpv
     {
         Ok(output) => output,
         Err(error) => {error!("could not find pid of mpv: {}", error); return},
@@ -28,14 +111,13 @@ fn kill_mpv(parent_process_id: u32) {
     // every line of the output is a mpv process, find the one with the right parent process id
     for line in output_as_str.lines() {
         // parse the words on the line as u32, as we expect them to be process ids
-        let words: Vec<u32> = line.trim()
-            .split_whitespace()
+        let words: Vec<u32> = line.split_whitespace()
             .filter_map(|word| word.parse::<u32>().ok())
             .collect();
 
         if let [pid, ppid] = words[0..2] {
             if ppid == parent_process_id {                        
-                if let Ok(status) = Command::new("kill").arg(pid.to_string()).status() {
+                if let Ok(status) = terminate(pid) {
                     info!("killed {} with status: {}", pid, status);
                 } else {
                     error!("kill of {} failed", pid);
@@ -49,78 +131,318 @@ fn kill_mpv(parent_process_id: u32) {
 
 pub struct Chat {}
 impl ProcessStarter<String> for Chat {
-    fn start_process(&self, args: &String) -> io::Result<Child> {
+    fn kind(&self) -> &'static str { "chat" }
+
+    fn command(&self, args: &String) -> io::Result<Command> {
         info!("opening chat: {}", &args);
         let path = format!("file:///opt/home_back/chat.html?channel={}", args);
-        Command::new("firefox")
+        let mut command = scoped_command(self.kind(), "firefox");
+        command
             .arg("-kiosk")
             .arg("-private-window")
             .arg(path)
             .stdin(Stdio::null())
             .stdout(Stdio::null()) // TODO write to log file
-            .stderr(Stdio::null()) // TODO write to log file
-            .spawn()
+            .stderr(Stdio::null()); // TODO write to log file
+        Ok(command)
+    }
+}
+
+// makes the HTPC show up as a Spotify Connect speaker, the args are the name of the speaker
+pub struct Librespot {}
+impl ProcessStarter<String> for Librespot {
+    fn kind(&self) -> &'static str { "spotify" }
+
+    fn command(&self, args: &String) -> io::Result<Command> {
+        info!("starting Spotify Connect as {}", &args);
+        let mut command = scoped_command(self.kind(), "librespot");
+        command
+            .arg("--name").arg(args)
+            .arg("--device-type").arg("tv")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        Ok(command)
     }
 }
 
 #[derive(PartialEq)]
 pub enum VideoPlayerArgs {
-    Twitch(String),
+    // audio only keeps the TV free, e.g. to listen to a stream while it shows something else
+    Twitch { stream: String, audio_only: bool },
     DvbC(Channel),
+    // the profile that started a file, url or library item, their position is remembered separately
+    Library { id: String, name: String, url: String, profile: Option<Uuid> },
+    Url { name: String, url: String, profile: Option<Uuid> },
+    Media { name: String, path: PathBuf, profile: Option<Uuid> },
+}
+
+impl VideoPlayerArgs {
+
+    // what the playback position is remembered under, live sources can't be resumed
+    pub fn progress_key(&self) -> Option<String> {
+        match self {
+            VideoPlayerArgs::Twitch { .. } | VideoPlayerArgs::DvbC(_) => None,
+            VideoPlayerArgs::Library { id, profile, .. } => Some(progress::profile_key(profile.as_ref(), format!("library/{}", id))),
+            VideoPlayerArgs::Url { url, profile, .. } => Some(progress::profile_key(profile.as_ref(), url.clone())),
+            VideoPlayerArgs::Media { path, profile, .. } => Some(progress::profile_key(profile.as_ref(), path.to_string_lossy().into_owned())),
+        }
+    }
+
+    // what is played, for the events and the statistics
+    pub fn source_and_name(&self) -> (&'static str, &str) {
+        match self {
+            VideoPlayerArgs::Twitch { stream, .. } => ("twitch", stream),
+            VideoPlayerArgs::DvbC(channel) => ("dvbc", &channel.name),
+            VideoPlayerArgs::Library { name, .. } => ("library", name),
+            VideoPlayerArgs::Url { name, .. } => ("url", name),
+            VideoPlayerArgs::Media { name, .. } => ("media", name),
+        }
+    }
+
+    pub fn is_audio_only(&self) -> bool {
+        matches!(self, VideoPlayerArgs::Twitch { audio_only: true, .. })
+    }
+
+    pub fn started_event(&self) -> Event {
+        let (source, name) = self.source_and_name();
+        Event::PlayerStarted { source, name: name.to_string() }
+    }
+}
+
+lazy_static! {
+    // only logs the commands and starts a sleep instead, to work on the API and frontend without streamlink, mpv or a TV
+    static ref DRY_RUN: bool = env::var("DRY_RUN").is_ok_and(|value| value == "true");
 }
 
-pub struct VideoPlayer{}
+const DEFAULT_STARTUP_SECONDS: u64 = 3;
+
+// compresses the dynamic range, so quiet dialogue and loud explosions end up at a similar volume
+const NIGHT_MODE_FILTER: &str = "dynaudnorm=f=150:g=15,acompressor=threshold=-20dB:ratio=4";
+
+pub struct VideoPlayer {
+    pub night_mode: Arc<AtomicBool>,
+    pub progress: Arc<Progress>,
+    pub channel_settings: Repository<ChannelSettings>,
+    pub relay: Arc<Relay>,
+    pub mpris_plugin: Option<String>, // path to mpris.so from mpv-mpris
+    pub startup_window: Duration,
+}
+
+impl VideoPlayer {
+
+    pub fn from_env(night_mode: Arc<AtomicBool>, progress: Arc<Progress>, channel_settings: Repository<ChannelSettings>, relay: Arc<Relay>) -> Self {
+        let startup_seconds = env::var("PLAYER_STARTUP_SECONDS").ok().and_then(|seconds| seconds.parse().ok()).unwrap_or(DEFAULT_STARTUP_SECONDS);
+        Self { night_mode, progress, channel_settings, relay, mpris_plugin: env::var("MPV_MPRIS_PLUGIN").ok(), startup_window: Duration::from_secs(startup_seconds) }
+    }
+
+    fn mpv_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.night_mode.load(Ordering::Relaxed) {
+            args.push(format!("--af=lavfi=[{}]", NIGHT_MODE_FILTER));
+        }
+        if let Some(plugin) = &self.mpris_plugin {
+            // lets desktop widgets, KDE Connect and bluetooth remotes see and control mpv, whatever source it plays
+            args.push(format!("--script={}", plugin));
+        }
+        args
+    }
+
+    // everything but Twitch, the ones with a progress key are resumed where they were stopped
+    fn open_mpv(&self, args: &VideoPlayerArgs, name: &str, target: &OsStr, extra_args: &[String]) -> io::Result<Command> {
+        info!("opening {}", name);
+        let mut command = scoped_command(self.kind(), "mpv");
+        command
+            .args(self.mpv_args())
+            // lets the progress tracking ask for the position
+            .arg(format!("--input-ipc-server={}", MPV_SOCKET.display()))
+            .arg(format!("--force-media-title={}", name));
+        if let Some(position) = args.progress_key().and_then(|key| self.progress.get(&key).resume_at) {
+            info!("resuming at {:.0}s", position);
+            command.arg(format!("--start={}", position));
+        }
+        command
+            .args(extra_args)
+            // a target starting with - is not taken for an option
+            .arg("--")
+            .arg(target)
+            .stdin(Stdio::null());
+        Ok(command)
+    }
+}
 impl ProcessStarter<VideoPlayerArgs> for VideoPlayer {
+    fn kind(&self) -> &'static str { "videoplayer" }
 
-    fn start_process(&self, args: &VideoPlayerArgs) -> io::Result<Child> {
-        return match args {
-            VideoPlayerArgs::Twitch(stream) => {                
+    fn command(&self, args: &VideoPlayerArgs) -> io::Result<Command> {
+        match args {
+            VideoPlayerArgs::Twitch { stream, audio_only } => {
                 info!("opening Twitch Stream: {}", &stream);
-                Command::new("streamlink")
+                let mut command = scoped_command(self.kind(), "streamlink");
+                command
                     //.arg("-v")
-                    .arg("--player-passthrough").arg("hls,http")
-                    .arg(stream)
-                    .stdin(Stdio::null())
-                    .spawn()
+                    .arg("--player-passthrough").arg("hls,http");
+                let mut player_args = self.mpv_args();
+                // what MPRIS shows, like the other sources, a Twitch login has no spaces that would split the args
+                player_args.push(format!("--force-media-title={}", stream));
+                if *audio_only {
+                    player_args.push("--no-video".to_string());
+                }
+                if tools::is_configured("mpv") {
+                    command.arg(format!("--player={}", tools::program("mpv").to_string_lossy()));
+                    player_args.splice(0..0, tools::extra_args("mpv"));
+                }
+                if !player_args.is_empty() {
+                    command.arg(format!("--player-args={}", player_args.join(" ")));
+                }
+                command.arg(stream);
+                if *audio_only {
+                    command.arg("audio_only");
+                }
+                command.stdin(Stdio::null());
+                Ok(command)
             },
+            // in mpv as well, so the previews can take a screenshot instead of tuning the channel a second time
             VideoPlayerArgs::DvbC(channel) => {
-                info!("opening DvbC Channel: {}", &channel.name);
-                Command::new("ffplay")
-                    .arg("-sn")
-                    .arg(&channel.url)
-                    .stdin(Stdio::null())
-                    .spawn()
+                let url = self.relay.player_url(channel).unwrap_or_else(|| channel.url.clone());
+                let mut extra_args = vec!["--sid=no".to_string()]; // the teletext subtitles
+                if let Some(settings) = self.channel_settings.get(&channel.name) {
+                    extra_args.extend(settings.mpv_args());
+                }
+                self.open_mpv(args, &channel.name, OsStr::new(&url), &extra_args)
             },
-        };
+            VideoPlayerArgs::Library { name, url, .. } => self.open_mpv(args, name, OsStr::new(url), &[format!("--include={}", library::MPV_CONFIG.display())]),
+            VideoPlayerArgs::Url { name, url, .. } => self.open_mpv(args, name, OsStr::new(url), &[]),
+            VideoPlayerArgs::Media { name, path, .. } => self.open_mpv(args, name, path.as_os_str(), &[]),
+        }
     }
 
-    fn on_stop(&self, args: &VideoPlayerArgs, process: &Child) {
-        if let VideoPlayerArgs::Twitch(_) = args {
-            kill_mpv(process.id());
+    fn startup_window(&self, _args: &VideoPlayerArgs) -> Duration {
+        self.startup_window
+    }
+
+    fn on_stop(&self, args: &VideoPlayerArgs, pid: u32) {
+        if let VideoPlayerArgs::Twitch { .. } = args {
+            kill_mpv(pid);
         }
     }
     
 }
 
-pub struct ProcessHandler<Args: PartialEq, T: ProcessStarter<Args> + 'static> {
-    open_process: Mutex<Option<(Arc<Args>, Child)>>,
-    t: T,
-    on_stop: Option<fn(&Args, &Child)>,
+pub type Hook<Args> = Arc<dyn Fn(&Args, u32) + Send + Sync>;
+pub type ExitHook<Args> = Arc<dyn Fn(&Args, Option<i32>) + Send + Sync>;
+
+// what other features do when a process starts or stops, the starter only knows how to run the process itself
+struct Hooks<Args> {
+    on_start: Vec<Hook<Args>>,
+    on_stop: Vec<Hook<Args>>,
+    on_unexpected_exit: Vec<ExitHook<Args>>,
+}
+
+impl <Args> Default for Hooks<Args> {
+    fn default() -> Self {
+        Hooks { on_start: Vec::new(), on_stop: Vec::new(), on_unexpected_exit: Vec::new() }
+    }
 }
 
-impl <Args: PartialEq, T: ProcessStarter<Args>> ProcessHandler<Args, T> {
+type KillRequest = oneshot::Sender<mpsc::Sender<io::Result<()>>>;
+// the last lines a process printed
+type Output = Arc<Mutex<VecDeque<String>>>;
 
-    pub fn new(t: T, on_stop: Option<fn(&Args, &Child)>) -> ProcessHandler<Args, T> {
-        ProcessHandler {open_process: Mutex::from(None), t, on_stop}
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+const OUTPUT_LINES: usize = 20;
+const MAX_LINE_LENGTH: usize = 1000;
+
+// the process exited within its startup window, so it most likely didn't start at all
+#[derive(Debug)]
+pub struct StartupFailed {
+    pub kind: &'static str,
+    pub code: Option<i32>,
+    pub output: Vec<String>,
+}
+
+impl fmt::Display for StartupFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{} exited with {} right after starting", self.kind, code),
+            None => write!(f, "{} was killed right after starting", self.kind),
+        }
     }
+}
 
-    pub fn running(&self) -> Option<Arc<Args>> {
-        self.check_process();
+impl std::error::Error for StartupFailed {}
 
-        return match &*self.open_process.lock().unwrap() {
-            Some((stream, _)) => Some(stream.clone()),
-            None              => None
-        };
+// what start waits for during the startup window
+struct Startup {
+    exited: mpsc::Receiver<Option<i32>>,
+    output: Output,
+}
+
+lazy_static! {
+    // the children are awaited here, so they are noticed no matter which thread or runtime started them
+    static ref SUPERVISOR: Runtime = runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("process-supervisor")
+        .enable_all()
+        .build()
+        .expect("could not start the runtime supervising the child processes");
+}
+
+struct Running<Args> {
+    args: Arc<Args>,
+    pid: u32,
+    generation: u64,  // tells the supervisor whether the process it awaits is still the current one
+    kill: KillRequest,
+}
+
+impl <Args> Running<Args> {
+    // the supervisor owns the child, so it does the killing
+    fn kill(self) -> io::Result<()> {
+        let (done, result) = mpsc::channel();
+        if self.kill.send(done).is_err() {
+            return Ok(()); // it exited on its own in the meantime
+        }
+        match result.recv_timeout(KILL_TIMEOUT) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Disconnected) => Ok(()),
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} did not exit after being killed", self.pid))),
+        }
+    }
+}
+
+struct Supervised<Args> {
+    open_process: Mutex<Option<Running<Args>>>,
+    starter: Box<dyn ProcessStarter<Args>>,
+    hooks: RwLock<Hooks<Args>>,
+    events: Arc<Events>,
+    generation: AtomicU64,
+}
+
+pub struct ProcessHandler<Args: PartialEq> {
+    inner: Arc<Supervised<Args>>,
+}
+
+impl <Args: PartialEq + Send + Sync + 'static> ProcessHandler<Args> {
+
+    pub fn new(starter: impl ProcessStarter<Args> + 'static, events: Arc<Events>) -> ProcessHandler<Args> {
+        ProcessHandler { inner: Arc::new(Supervised { open_process: Mutex::from(None), starter: Box::new(starter), hooks: RwLock::default(), events, generation: AtomicU64::new(0) }) }
+    }
+
+    // called after the process was started. Like on_stop, this is not called for a restart and must not use this handler
+    pub fn on_start(&self, hook: impl Fn(&Args, u32) + Send + Sync + 'static) {
+        self.inner.hooks.write().unwrap().on_start.push(Arc::new(hook));
+    }
+
+    // called when the process is stopped or exits on its own
+    pub fn on_stop(&self, hook: impl Fn(&Args, u32) + Send + Sync + 'static) {
+        self.inner.hooks.write().unwrap().on_stop.push(Arc::new(hook));
+    }
+
+    // called after the on_stop hooks when the process exited on its own, with its exit code. This may use the handler, e.g. to start it again
+    pub fn on_unexpected_exit(&self, hook: impl Fn(&Args, Option<i32>) + Send + Sync + 'static) {
+        self.inner.hooks.write().unwrap().on_unexpected_exit.push(Arc::new(hook));
+    }
+
+    pub fn running(&self) -> Option<Arc<Args>> {
+        self.inner.open_process.lock().unwrap().as_ref().map(|running| running.args.clone())
     }
 
     pub fn start(&self, args: Args) -> io::Result<Arc<Args>> {
@@ -131,47 +453,480 @@ impl <Args: PartialEq, T: ProcessStarter<Args>> ProcessHandler<Args, T> {
             }
         }
 
-        let mut open_stream = self.open_process.lock().unwrap(); 
-        self.stop_impl(&mut *open_stream)?;
-        
-        let process = self.t.start_process(&args)?;
+        let mut open_stream = self.inner.open_process.lock().unwrap();
+        self.stop_impl(&mut open_stream)?;
 
         let arc = Arc::new(args);
-        *open_stream = Some((arc.clone(), process));
-        return Ok(arc.clone());
+        let window = self.inner.starter.startup_window(&arc);
+        let (running, startup) = self.spawn(arc.clone(), !window.is_zero())?;
+        register(self.inner.starter.kind(), running.pid);
+        self.inner.started(&arc, running.pid);
+        *open_stream = Some(running);
+        drop(open_stream);
+
+        // the supervisor cleans up if it exits, this only has to tell the caller
+        if let Ok(code) = startup.exited.recv_timeout(window) {
+            let output = startup.output.lock().unwrap().iter().cloned().collect();
+            return Err(io::Error::other(StartupFailed { kind: self.inner.starter.kind(), code, output }));
+        }
+        Ok(arc)
+    }
+
+    // starts the running process again with the same args, e.g. to pick up changed settings
+    // the hooks are not called, as from the outside it keeps running
+    pub fn restart(&self) -> io::Result<()> {
+        let mut open_stream = self.inner.open_process.lock().unwrap();
+        if let Some(running) = open_stream.take() {
+            let args = running.args.clone();
+            let pid = running.pid;
+            self.inner.starter.on_stop(&args, pid);
+            running.kill()?;
+
+            let (new_process, _) = self.spawn(args.clone(), false)?;
+            register_restart(self.inner.starter.kind(), new_process.pid, pid);
+            self.inner.starter.on_start(&args, new_process.pid);
+            *open_stream = Some(new_process);
+        }
+        Ok(())
     }
 
     pub fn stop(&self) -> io::Result<()> {
-        let mut open_stream = self.open_process.lock().unwrap();
-        self.stop_impl(&mut *open_stream)?;
-        *open_stream = None;
-        return Ok(());
+        let mut open_stream = self.inner.open_process.lock().unwrap();
+        self.stop_impl(&mut open_stream)
     }
 
-    fn stop_impl(&self, open_stream: &mut Option<(Arc<Args>, Child)>) -> io::Result<()> {
-        if let Some((args, process)) = open_stream {
-            self.handle_callbacks(&args, &process);
-            process.kill()?;
-            process.wait()?;
+    fn stop_impl(&self, open_stream: &mut Option<Running<Args>>) -> io::Result<()> {
+        if let Some(running) = open_stream.take() {
+            self.inner.stopped(&running.args, running.pid);
+            self.inner.events.publish(Event::ProcessStopped { kind: self.inner.starter.kind(), pid: running.pid });
+            unregister(running.pid);
+            running.kill()?;
         }
-        return Ok(());
+        Ok(())
     }
 
-    fn handle_callbacks(&self, args: &Args, process: &Child) {       // TODO check if mut  
-        self.t.on_stop(args, process);
-        if let Some(callback) = self.on_stop {
-            callback(args, process);
+    // has to be called with the lock held, so the supervisor can't see the exit before the process is stored
+    fn spawn(&self, args: Arc<Args>, capture_output: bool) -> io::Result<(Running<Args>, Startup)> {
+        let kind = self.inner.starter.kind();
+        let mut command = self.inner.starter.command(&args)?;
+        if *DRY_RUN {
+            command = dry_run(kind, &command);
+        }
+        if capture_output {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
         }
+        let _runtime = SUPERVISOR.enter();
+        let mut child = tokio::process::Command::from(command).spawn()?;
+        let pid = child.id().expect("the child was not awaited yet");
+        let output = Output::default();
+        let mut readers = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            readers.push(SUPERVISOR.spawn(capture(kind, stdout, output.clone())));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            readers.push(SUPERVISOR.spawn(capture(kind, stderr, output.clone())));
+        }
+
+        let generation = self.inner.generation.fetch_add(1, Ordering::Relaxed);
+        let (kill, kill_requests) = oneshot::channel();
+        let (exit_notification, exited) = mpsc::channel();
+        SUPERVISOR.spawn(supervise(self.inner.clone(), child, generation, kill_requests, readers, exit_notification));
+        Ok((Running { args, pid, generation, kill }, Startup { exited, output }))
+    }
+}
+
+impl <Args: Send + Sync + 'static> Supervised<Args> {
+
+    fn started(&self, args: &Args, pid: u32) {
+        self.starter.on_start(args, pid);
+        // cloned, so a hook can register more hooks
+        let hooks = self.hooks.read().unwrap().on_start.clone();
+        hooks.iter().for_each(|hook| hook(args, pid));
+        self.events.publish(Event::ProcessStarted { kind: self.starter.kind(), pid });
+    }
+
+    fn stopped(&self, args: &Args, pid: u32) {
+        self.starter.on_stop(args, pid);
+        let hooks = self.hooks.read().unwrap().on_stop.clone();
+        hooks.iter().for_each(|hook| hook(args, pid));
     }
 
-    fn check_process(&self) { 
+    fn exited(&self, generation: u64, status: io::Result<ExitStatus>) {
         let mut open_stream = self.open_process.lock().unwrap();
-        if let Some((args, process)) = &mut *open_stream {
-            if process.try_wait().unwrap().is_some() {
-                self.handle_callbacks(args, process);
-                *open_stream = None
+        if open_stream.as_ref().is_none_or(|running| running.generation != generation) {
+            return; // it was stopped, so the callbacks already ran
+        }
+        let running = open_stream.take().unwrap();
+        let kind = self.starter.kind();
+        match &status {
+            Ok(status) if status.success() => info!("{} ({}) exited", kind, running.pid),
+            Ok(status) => warn!("{} ({}) exited with {}", kind, running.pid, status),
+            Err(error) => error!("could not wait for {} ({}): {}", kind, running.pid, error),
+        }
+        unregister(running.pid);
+        self.stopped(&running.args, running.pid);
+        // without the lock, so these can start it again
+        drop(open_stream);
+        let code = status.ok().and_then(|status| status.code());
+        self.events.publish(Event::ProcessExited { kind, code });
+        let hooks = self.hooks.read().unwrap().on_unexpected_exit.clone();
+        hooks.iter().for_each(|hook| hook(&running.args, code));
+    }
+}
+
+async fn supervise<Args: Send + Sync + 'static>(
+    inner: Arc<Supervised<Args>>,
+    mut child: tokio::process::Child,
+    generation: u64,
+    kill_requests: oneshot::Receiver<mpsc::Sender<io::Result<()>>>,
+    readers: Vec<JoinHandle<()>>,
+    exit_notification: mpsc::Sender<Option<i32>>,
+) {
+    let kill_request = match select(Box::pin(child.wait()), kill_requests).await {
+        Either::Left((status, _)) => Err(status),
+        Either::Right((Ok(done), _)) => Ok(done),
+        // the handler is gone, but the process should still be noticed
+        Either::Right((Err(_), wait)) => Err(wait.await),
+    };
+    let status = match kill_request {
+        Ok(done) => {
+            let _ = done.send(child.kill().await);
+            return;
+        },
+        Err(status) => status,
+    };
+    // so the output is complete, but children of the process could keep the pipes open
+    let _ = timeout(Duration::from_millis(500), join_all(readers)).await;
+    let _ = exit_notification.send(status.as_ref().ok().and_then(|status| status.code()));
+    // the callbacks call other programs, which shouldn't block the supervisor
+    let _ = spawn_blocking(move || inner.exited(generation, status)).await;
+}
+
+// progress output like the status line of mpv ends with \r, so that ends a line as well
+async fn capture(kind: &'static str, mut pipe: impl AsyncRead + Unpin, output: Output) {
+    let mut buffer = [0; 4096];
+    let mut line = Vec::new();
+    loop {
+        let read = match pipe.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        for &byte in &buffer[..read] {
+            match byte {
+                b'\n' | b'\r' => push_line(kind, &mut line, &output),
+                _ if line.len() < MAX_LINE_LENGTH => line.push(byte),
+                _ => {},
+            }
+        }
+    }
+    push_line(kind, &mut line, &output);
+}
+
+fn push_line(kind: &str, line: &mut Vec<u8>, output: &Output) {
+    if line.is_empty() {
+        return;
+    }
+    let text = String::from_utf8_lossy(line).into_owned();
+    debug!("{}: {}", kind, text);
+    let mut output = output.lock().unwrap();
+    if output.len() == OUTPUT_LINES {
+        output.pop_front();
+    }
+    output.push_back(text);
+    line.clear();
+}
+
+// a child HomeBack keeps running, so misbehaving ones can be found
+#[derive(Serialize)]
+pub struct ManagedProcess {
+    pub kind: &'static str,
+    pub pid: u32,
+    pub args: String,  // the command line, shortened
+    pub uptime: u64,   // seconds
+    pub restarts: u32,
+    // of the process and everything it started, like the content processes of firefox, as of the last sample
+    pub cpu: Option<f64>,     // percent of one core
+    pub memory: Option<u64>,  // resident bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_limit: Option<u64>,
+}
+
+struct Registered {
+    kind: &'static str,
+    started: Instant,
+    restarts: u32,
+    sample: Option<Sample>,
+    cpu: Option<f64>,
+}
+
+struct Sample {
+    taken: Instant,
+    cpu_ticks: u64,
+    memory: u64,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<HashMap<u32, Registered>> = Mutex::new(HashMap::new());
+    // the registered children, so the next start can kill them if HomeBack crashed
+    static ref PID_FILE: PathBuf = env::var("PID_FILE").map(PathBuf::from).unwrap_or_else(|_| runtime_folder().join("home_back.pids"));
+    // in MiB per kind, e.g. "chat=2048,videoplayer=4096"
+    static ref MEMORY_LIMITS: HashMap<String, u64> = parse_limits("PROCESS_MEMORY_LIMITS").into_iter().map(|(kind, limit)| (kind, limit * 1024 * 1024)).collect();
+    // in percent of one core per kind, e.g. "preview=50", only with SYSTEMD_SCOPE
+    static ref CPU_LIMITS: HashMap<String, u64> = parse_limits("PROCESS_CPU_LIMITS");
+    // from 1 to 10000 per kind, the default is 100, only with SYSTEMD_SCOPE
+    static ref IO_WEIGHTS: HashMap<String, u64> = parse_limits("PROCESS_IO_WEIGHTS");
+    // "user" or "system", the systemd instance that gets a scope for every child
+    static ref SYSTEMD_SCOPE: Option<String> = env::var("SYSTEMD_SCOPE").ok().filter(|manager| {
+        let valid = manager == "user" || manager == "system";
+        if !valid {
+            error!("SYSTEMD_SCOPE must be user or system, not {}", manager);
+        }
+        valid
+    });
+}
+
+const MAX_ARGS_LENGTH: usize = 200;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0; // USER_HZ, which is 100 on everything Linux runs on
+
+fn parse_limits(variable: &str) -> HashMap<String, u64> {
+    env::var(variable).unwrap_or_default().split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let parsed = entry.split_once('=').and_then(|(kind, limit)| Some((kind.trim().to_string(), limit.trim().parse::<u64>().ok()?)));
+            if parsed.is_none() {
+                error!("could not parse {} entry: {}", variable, entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+// a command for the tool that runs in its own systemd scope if SYSTEMD_SCOPE is set, with the limits of its kind
+// systemd-run execs the tool, so the pid stays the same, and the scope is cleaned up once the process and its children are gone
+pub fn scoped_command(kind: &str, name: &str) -> Command {
+    let Some(manager) = &*SYSTEMD_SCOPE else { return tools::command(name) };
+    let mut command = tools::command("systemd-run");
+    command
+        .arg(format!("--{}", manager))
+        .arg("--scope")
+        .arg("--quiet")
+        .arg("--collect")
+        .arg(format!("--description=HomeBack {}", kind));
+    if let Some(limit) = MEMORY_LIMITS.get(kind) {
+        command.arg("-p").arg(format!("MemoryMax={}", limit));
+    }
+    if let Some(limit) = CPU_LIMITS.get(kind) {
+        command.arg("-p").arg(format!("CPUQuota={}%", limit));
+    }
+    if let Some(weight) = IO_WEIGHTS.get(kind) {
+        command.arg("-p").arg(format!("IOWeight={}", weight));
+    }
+    command.arg("--").arg(tools::program(name)).args(tools::extra_args(name));
+    command
+}
+
+pub fn register(kind: &'static str, pid: u32) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.insert(pid, Registered { kind, started: Instant::now(), restarts: 0, sample: None, cpu: None });
+    save_pids(&registry);
+}
+
+// the restarted process takes over the count of the one it replaces
+fn register_restart(kind: &'static str, pid: u32, replaced: u32) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let restarts = registry.remove(&replaced).map_or(0, |registered| registered.restarts) + 1;
+    registry.insert(pid, Registered { kind, started: Instant::now(), restarts, sample: None, cpu: None });
+    save_pids(&registry);
+}
+
+// cleared by a reboot, just like the pids in it become meaningless
+#[cfg(not(test))]
+fn runtime_folder() -> PathBuf {
+    env::var("XDG_RUNTIME_DIR").map(PathBuf::from).unwrap_or_else(|_| env::temp_dir())
+}
+
+#[cfg(test)]
+fn runtime_folder() -> PathBuf {
+    crate::testing::RUNTIME_FOLDER.to_path_buf()
+}
+
+pub fn unregister(pid: u32) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.remove(&pid);
+    save_pids(&registry);
+}
+
+// like wait_with_output, but the child is killed once it runs longer than the timeout, e.g. an ffprobe waiting for a router that doesn't answer
+pub fn output_within(child: Child, timeout: Duration) -> io::Result<std::process::Output> {
+    let pid = child.id();
+    let (sender, output) = mpsc::channel();
+    // the thread reaps the child after the kill
+    thread::spawn(move || { let _ = sender.send(child.wait_with_output()); });
+    match output.recv_timeout(timeout) {
+        Ok(output) => output,
+        Err(_) => {
+            warn!("killing {}, it took longer than {} seconds", pid, timeout.as_secs());
+            if let Err(error) = terminate(pid) {
+                error!("kill of {} failed: {}", pid, error);
             }
+            Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} took longer than {} seconds", pid, timeout.as_secs())))
+        },
+    }
+}
+
+// with the start time of each process, a pid that was reused by now doesn't match anymore
+fn save_pids(registry: &HashMap<u32, Registered>) {
+    let pids: String = registry.iter()
+        .filter_map(|(&pid, registered)| Some(format!("{} {} {}\n", pid, read_usage(pid)?.started, registered.kind)))
+        .collect();
+    if let Err(error) = fs::write(&*PID_FILE, pids) {
+        error!("could not write the PID_FILE {:?}: {}", *PID_FILE, error);
+    }
+}
+
+// kills the children a crashed run left behind, they would keep the tuner and the audio device busy
+// only on Linux, elsewhere the start times can't be checked
+pub fn kill_orphans() {
+    let pids = match fs::read_to_string(&*PID_FILE) {
+        Ok(pids) => pids,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return,
+        Err(error) => { error!("could not read the PID_FILE {:?}: {}", *PID_FILE, error); return },
+    };
+    let usages = all_usages();
+    for line in pids.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [pid, started, kind] = fields[..] else { continue };
+        let (Ok(pid), Ok(started)) = (pid.parse::<u32>(), started.parse::<u64>()) else { continue };
+        if usages.get(&pid).is_none_or(|usage| usage.started != started) {
+            continue;
         }
+        // e.g. the mpv of streamlink, which stays when only the parent is killed
+        for pid in tree(pid, &usages) {
+            warn!("killing {} ({}), it was left over from the last run", kind, pid);
+            if let Err(error) = terminate(pid) {
+                error!("could not kill {}: {}", pid, error);
+            }
+        }
+    }
+    if let Err(error) = fs::write(&*PID_FILE, "") {
+        error!("could not clear the PID_FILE {:?}: {}", *PID_FILE, error);
     }
+}
 
+pub fn managed_processes() -> Vec<ManagedProcess> {
+    let registry = REGISTRY.lock().unwrap();
+    let mut processes: Vec<ManagedProcess> = registry.iter()
+        .map(|(&pid, registered)| ManagedProcess {
+            kind: registered.kind,
+            pid,
+            args: command_line(pid),
+            uptime: registered.started.elapsed().as_secs(),
+            restarts: registered.restarts,
+            cpu: registered.cpu,
+            memory: registered.sample.as_ref().map(|sample| sample.memory),
+            memory_limit: MEMORY_LIMITS.get(registered.kind).copied(),
+        })
+        .collect();
+    processes.sort_by(|a, b| a.kind.cmp(b.kind).then(a.pid.cmp(&b.pid)));
+    processes
 }
+
+// the arguments are separated by null bytes in /proc
+fn command_line(pid: u32) -> String {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let args = String::from_utf8_lossy(&cmdline).split('\0').filter(|arg| !arg.is_empty()).collect::<Vec<_>>().join(" ");
+    match args.char_indices().nth(MAX_ARGS_LENGTH) {
+        Some((end, _)) => format!("{}...", &args[..end]),
+        None => args,
+    }
+}
+
+// what /proc knows about a single process
+struct Usage {
+    parent: u32,
+    started: u64,  // clock ticks after boot
+    cpu_ticks: u64,
+    memory: u64,
+}
+
+fn read_usage(pid: u32) -> Option<Usage> {
+    // the name in parentheses can contain spaces, the fields after it are "state ppid ... utime stime ... starttime"
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let parent = fields.get(1)?.parse().ok()?;
+    let cpu_ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    let started = fields.get(19)?.parse().ok()?;
+    // kernel threads and zombies have no memory
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let memory = status.lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map_or(0, |kb| kb * 1024);
+    Some(Usage { parent, started, cpu_ticks, memory })
+}
+
+fn all_usages() -> HashMap<u32, Usage> {
+    fs::read_dir("/proc").into_iter().flatten()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+        .filter_map(|pid| Some((pid, read_usage(pid)?)))
+        .collect()
+}
+
+// the process and all of its descendants
+fn tree(pid: u32, usages: &HashMap<u32, Usage>) -> Vec<u32> {
+    let mut tree = Vec::new();
+    let mut pending = vec![pid];
+    while let Some(pid) = pending.pop() {
+        tree.push(pid);
+        pending.extend(usages.iter().filter(|(_, usage)| usage.parent == pid).map(|(&child, _)| child));
+    }
+    tree
+}
+
+// sums up the process and all of its descendants
+fn tree_usage(pid: u32, usages: &HashMap<u32, Usage>) -> Option<(u64, u64)> {
+    usages.get(&pid)?;
+    Some(tree(pid, usages).iter()
+        .filter_map(|pid| usages.get(pid))
+        .fold((0, 0), |total, usage| (total.0 + usage.cpu_ticks, total.1 + usage.memory)))
+}
+
+fn sample_resources() {
+    let usages = all_usages();
+    let mut over_limit = Vec::new();
+    {
+        let mut registry = REGISTRY.lock().unwrap();
+        for (&pid, registered) in registry.iter_mut() {
+            let Some((cpu_ticks, memory)) = tree_usage(pid, &usages) else { continue };
+            let now = Instant::now();
+            registered.cpu = registered.sample.as_ref().map(|sample| {
+                let seconds = now.duration_since(sample.taken).as_secs_f64().max(0.001);
+                cpu_ticks.saturating_sub(sample.cpu_ticks) as f64 / CLOCK_TICKS_PER_SECOND / seconds * 100.0
+            });
+            registered.sample = Some(Sample { taken: now, cpu_ticks, memory });
+            if let Some(&limit) = MEMORY_LIMITS.get(registered.kind).filter(|&&limit| memory > limit) {
+                over_limit.push((pid, registered.kind, memory, limit));
+            }
+        }
+    }
+
+    // the supervisor notices the exit, so for a handled process this is like a crash
+    for (pid, kind, memory, limit) in over_limit {
+        warn!("{} ({}) uses {} MiB, more than its limit of {} MiB, killing it", kind, pid, memory / 1024 / 1024, limit / 1024 / 1024);
+        if let Err(error) = terminate(pid) {
+            error!("could not kill {} ({}): {}", kind, pid, error);
+        }
+    }
+}
+
+// samples cpu and memory of the managed processes every few seconds and enforces PROCESS_MEMORY_LIMITS
+pub fn watch_resources() {
+    thread::spawn(|| loop {
+        sample_resources();
+        thread::sleep(SAMPLE_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests;
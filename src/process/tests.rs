@@ -0,0 +1,22 @@
+use super::*;
+
+#[test]
+fn a_child_that_finishes_in_time_gives_its_output() {
+    let child = Command::new("echo").arg("teletext").stdout(Stdio::piped()).spawn().unwrap();
+
+    let output = output_within(child, Duration::from_secs(5)).unwrap();
+
+    assert_eq!("teletext\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn a_child_that_takes_too_long_is_killed() {
+    let child = Command::new("sleep").arg("60").spawn().unwrap();
+    let pid = child.id();
+
+    let error = output_within(child, Duration::from_millis(100)).unwrap_err();
+
+    assert_eq!(io::ErrorKind::TimedOut, error.kind());
+    thread::sleep(Duration::from_millis(200));
+    assert!(!PathBuf::from(format!("/proc/{}", pid)).exists());
+}
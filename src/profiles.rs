@@ -0,0 +1,65 @@
+use std::sync::Arc;
+use log::info;
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use crate::VideoPlayerSomthing;
+use crate::store::{Repository, Store};
+
+pub const MAX_NAME_LENGTH: usize = 50;
+
+// groups the favorites, positions and Twitch logins of one person, there are no passwords, anyone can pick any profile
+#[derive(Serialize, Deserialize)]
+pub struct Profile {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(default)]
+    pub favorites: Vec<VideoPlayerSomthing>,
+    // the ids of their logins, so another device of the same person can use them
+    #[serde(default)]
+    pub twitch_logins: Vec<Uuid>,
+}
+
+pub struct Profiles {
+    profiles: Repository<Profile>,
+}
+
+impl Profiles {
+
+    pub fn new(store: Arc<Store>) -> Self {
+        Self { profiles: Repository::new(store, "profiles") }
+    }
+
+    pub fn all(&self) -> Vec<Profile> {
+        let mut profiles: Vec<Profile> = self.profiles.all().into_iter().map(|(_, profile)| profile).collect();
+        profiles.sort_by_key(|profile| profile.name.to_lowercase());
+        profiles
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Profile> {
+        self.profiles.get(&id.to_string())
+    }
+
+    pub fn create(&self, name: String) -> Profile {
+        let profile = Profile { id: Uuid::new_v4(), name, favorites: Vec::new(), twitch_logins: Vec::new() };
+        info!("created profile {} for {}", profile.id, profile.name);
+        self.profiles.put(&profile.id.to_string(), &profile);
+        profile
+    }
+
+    pub fn set_favorites(&self, id: &Uuid, favorites: Vec<VideoPlayerSomthing>) -> Option<Profile> {
+        self.update(id, |profile| profile.favorites = favorites)
+    }
+
+    pub fn add_twitch_login(&self, id: &Uuid, login: Uuid) -> Option<Profile> {
+        self.update(id, |profile| if !profile.twitch_logins.contains(&login) {
+            profile.twitch_logins.push(login);
+        })
+    }
+
+    fn update(&self, id: &Uuid, f: impl FnOnce(&mut Profile)) -> Option<Profile> {
+        let mut profile = self.get(id)?;
+        f(&mut profile);
+        self.profiles.put(&id.to_string(), &profile);
+        Some(profile)
+    }
+}
@@ -0,0 +1,197 @@
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::debug;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+use crate::state::AppState;
+use crate::store::{Repository, Store};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+// resuming the first seconds or the credits is more annoying than starting over
+const MIN_RESUME_POSITION: f64 = 30.0;
+const WATCHED_RATIO: f64 = 0.95;
+// every save writes the whole store, the position of a playing file is only saved this often
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+// less than this is a paused player, that is not saved again
+const MIN_POSITION_CHANGE: f64 = 1.0;
+
+lazy_static! {
+    pub static ref MPV_SOCKET: PathBuf = socket_path();
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Position {
+    position: f64,
+    duration: Option<f64>,
+    updated: u64,
+}
+
+// how far a file was watched, for the listings
+#[derive(Serialize, Default)]
+pub struct WatchState {
+    pub watched: bool,
+    pub resume_at: Option<f64>,
+}
+
+// a file, url or library item that was played, for the history
+#[derive(Serialize)]
+pub struct Watched {
+    pub key: String,
+    pub position: f64,
+    pub duration: Option<f64>,
+    pub updated: u64,
+}
+
+// the playback positions, keyed by the file, url or library item and prefixed with the profile that played it
+pub struct Progress {
+    positions: Repository<Position>,
+    // the latest position of the playing file, until it is saved
+    pending: Mutex<Option<(String, Position)>>,
+    saved: Mutex<Option<(String, Instant)>>,
+}
+
+impl Progress {
+
+    pub fn new(store: Arc<Store>) -> Self {
+        Self { positions: Repository::new(store, "progress"), pending: Mutex::default(), saved: Mutex::default() }
+    }
+
+    pub fn get(&self, key: &str) -> WatchState {
+        match self.position(key) {
+            None => WatchState::default(),
+            Some(position) => {
+                let watched = position.duration.is_some_and(|duration| position.position >= duration * WATCHED_RATIO);
+                let resume_at = Some(position.position).filter(|&position| !watched && position >= MIN_RESUME_POSITION);
+                WatchState { watched, resume_at }
+            },
+        }
+    }
+
+    // what the profile (or everyone without one) played, the most recent first
+    pub fn history(&self, profile: Option<&Uuid>, limit: usize) -> Vec<Watched> {
+        let mut history: Vec<Watched> = self.positions.all().into_iter()
+            .filter_map(|(key, position)| {
+                let (owner, key) = match key.split_once('/').and_then(|(prefix, rest)| Some((prefix.parse::<Uuid>().ok()?, rest.to_string()))) {
+                    Some((owner, key)) => (Some(owner), key),
+                    None => (None, key),
+                };
+                Some(Watched { key, position: position.position, duration: position.duration, updated: position.updated })
+                    .filter(|_| owner.as_ref() == profile)
+            })
+            .collect();
+        history.sort_by_key(|watched| std::cmp::Reverse(watched.updated));
+        history.truncate(limit);
+        history
+    }
+
+    fn set(&self, key: &str, position: f64, duration: Option<f64>) {
+        if self.position(key).is_some_and(|known| (known.position - position).abs() < MIN_POSITION_CHANGE && known.duration == duration) {
+            return;
+        }
+        let updated = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        let previous = self.pending.lock().unwrap().replace((key.to_string(), Position { position, duration, updated }));
+        // the player switched the file before the last position of the other one was saved
+        if let Some((previous, position)) = previous.filter(|(previous, _)| previous != key) {
+            self.positions.put(&previous, &position);
+        }
+        // a file that just started is saved right away, so it shows up in the history
+        let due = self.saved.lock().unwrap().as_ref().is_none_or(|(saved, at)| saved != key || at.elapsed() >= SAVE_INTERVAL);
+        if due {
+            self.flush();
+        }
+    }
+
+    // saves the latest position, once the player stopped or HomeBack shuts down
+    pub fn flush(&self) {
+        if let Some((key, position)) = self.pending.lock().unwrap().take() {
+            self.positions.put(&key, &position);
+            *self.saved.lock().unwrap() = Some((key, Instant::now()));
+        }
+    }
+
+    fn position(&self, key: &str) -> Option<Position> {
+        match &*self.pending.lock().unwrap() {
+            Some((pending, position)) if pending == key => Some(position.clone()),
+            _ => self.positions.get(key),
+        }
+    }
+}
+
+// the key of a position for a profile, the positions without one are shared
+pub fn profile_key(profile: Option<&Uuid>, key: String) -> String {
+    match profile {
+        Some(profile) => format!("{}/{}", profile, key),
+        None => key,
+    }
+}
+
+#[cfg(unix)]
+fn socket_path() -> PathBuf {
+    env::temp_dir().join("home_back-mpv.sock")
+}
+
+// mpv uses a named pipe on Windows
+#[cfg(windows)]
+fn socket_path() -> PathBuf {
+    PathBuf::from(r"\\.\pipe\home_back-mpv")
+}
+
+#[cfg(unix)]
+fn connect() -> io::Result<UnixStream> {
+    let stream = UnixStream::connect(&*MPV_SOCKET)?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    Ok(stream)
+}
+
+#[cfg(windows)]
+fn connect() -> io::Result<std::fs::File> {
+    std::fs::OpenOptions::new().read(true).write(true).open(&*MPV_SOCKET)
+}
+
+// sends a command to the json ipc of the running mpv and returns the data of the response
+pub fn mpv_command(command: Value) -> io::Result<Value> {
+    let mut stream = connect()?;
+    writeln!(stream, "{}", json!({ "command": command, "request_id": 1 }))?;
+
+    // mpv also writes events to the socket, the response is the line with our request id
+    for line in BufReader::new(stream).lines() {
+        let response: Value = serde_json::from_str(&line?)?;
+        if response["request_id"] == 1 {
+            if response["error"] != "success" {
+                return Err(io::Error::other(format!("mpv: {}", response["error"])));
+            }
+            return Ok(response["data"].clone());
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "mpv closed the ipc socket"))
+}
+
+fn get_property(name: &str) -> io::Result<Option<f64>> {
+    Ok(mpv_command(json!(["get_property", name]))?.as_f64())
+}
+
+// asks the player for its position every few seconds and remembers it for the files that can be resumed
+pub fn track(state: Arc<AppState>) {
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        let key = match state.video_player.running().and_then(|args| args.progress_key()) {
+            Some(key) => key,
+            None => { state.progress.flush(); continue; },
+        };
+        match get_property("time-pos") {
+            Ok(Some(position)) => state.progress.set(&key, position, get_property("duration").ok().flatten()),
+            Ok(None) => {},
+            Err(error) => debug!("could not get position from mpv: {}", error),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests;
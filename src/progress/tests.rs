@@ -0,0 +1,69 @@
+use super::*;
+use crate::testing::TempFolder;
+
+fn progress(folder: &TempFolder) -> (Progress, Repository<Position>) {
+    let store = Arc::new(Store::open(folder.join("store.json")).unwrap());
+    (Progress::new(store.clone()), Repository::new(store, "progress"))
+}
+
+fn saved(positions: &Repository<Position>, key: &str) -> Option<f64> {
+    positions.get(key).map(|position| position.position)
+}
+
+#[test]
+fn a_file_that_just_started_is_saved_right_away() {
+    let folder = TempFolder::new();
+    let (progress, positions) = progress(&folder);
+
+    progress.set("movie.mkv", 40.0, Some(6000.0));
+
+    assert_eq!(Some(40.0), saved(&positions, "movie.mkv"));
+}
+
+#[test]
+fn the_position_of_a_playing_file_is_saved_once_in_a_while() {
+    let folder = TempFolder::new();
+    let (progress, positions) = progress(&folder);
+
+    progress.set("movie.mkv", 40.0, Some(6000.0));
+    progress.set("movie.mkv", 50.0, Some(6000.0));
+
+    assert_eq!(Some(40.0), saved(&positions, "movie.mkv"));
+    assert_eq!(Some(50.0), progress.get("movie.mkv").resume_at);
+}
+
+#[test]
+fn the_latest_position_is_saved_once_the_player_stopped() {
+    let folder = TempFolder::new();
+    let (progress, positions) = progress(&folder);
+
+    progress.set("movie.mkv", 40.0, Some(6000.0));
+    progress.set("movie.mkv", 50.0, Some(6000.0));
+    progress.flush();
+
+    assert_eq!(Some(50.0), saved(&positions, "movie.mkv"));
+}
+
+#[test]
+fn a_paused_player_is_not_saved_again() {
+    let folder = TempFolder::new();
+    let (progress, _) = progress(&folder);
+
+    progress.set("movie.mkv", 40.0, Some(6000.0));
+    progress.set("movie.mkv", 40.2, Some(6000.0));
+
+    assert!(progress.pending.lock().unwrap().is_none());
+}
+
+#[test]
+fn switching_the_file_saves_both() {
+    let folder = TempFolder::new();
+    let (progress, positions) = progress(&folder);
+
+    progress.set("first.mkv", 40.0, Some(6000.0));
+    progress.set("first.mkv", 50.0, Some(6000.0));
+    progress.set("second.mkv", 35.0, Some(6000.0));
+
+    assert_eq!(Some(50.0), saved(&positions, "first.mkv"));
+    assert_eq!(Some(35.0), saved(&positions, "second.mkv"));
+}
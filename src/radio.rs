@@ -0,0 +1,223 @@
+use std::env;
+use std::io::{self, Read};
+use std::process::{Child, Stdio};
+use std::sync::{mpsc as sync_mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use actix_web::web::Bytes;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
+use log::{info, warn};
+use crate::dvbc::Channel;
+use crate::process;
+use crate::tuners::{PlayerTuner, Tuners};
+
+// small, so the speakers get every frame as soon as ffmpeg has it
+const CHUNK_SIZE: usize = 4 * 1024;
+// a few seconds of audio, a listener that falls further behind is dropped
+const MAX_QUEUED_CHUNKS: usize = 32;
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+// restarts in a row without getting anything, then the listeners are sent away
+const MAX_RESTARTS: u32 = 5;
+// zapping stops the old channel right before the next one starts, the listeners stay for that
+const SWITCH_WINDOW: Duration = Duration::from_secs(5);
+// ffmpeg waits for the router forever, without anything for this long it is killed and started again
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEFAULT_KBITS: u32 = 192;
+
+// restreams the radio channel the player plays as MP3, for speakers in other rooms
+// one ffmpeg is shared by all listeners, it only runs while someone listens and follows the player to the next channel
+pub struct RadioRelay {
+    relayed: Mutex<Relayed>,
+    changed: Condvar,
+    switch_window: Duration,
+    enabled: bool,
+    kbits: u32,
+    tuners: Arc<Tuners>,
+    player_tuner: Arc<PlayerTuner>,
+}
+
+#[derive(Default)]
+struct Relayed {
+    playing: Option<Channel>,
+    listeners: Vec<mpsc::Sender<Bytes>>,
+}
+
+impl RadioRelay {
+
+    pub fn from_env(tuners: Arc<Tuners>, player_tuner: Arc<PlayerTuner>) -> Arc<Self> {
+        let enabled = env::var("RADIO_RELAY").is_ok_and(|value| value == "true");
+        let kbits = env::var("RADIO_RELAY_KBITS").ok().and_then(|kbits| kbits.parse().ok()).unwrap_or(DEFAULT_KBITS);
+        Self::new(tuners, player_tuner, enabled, kbits)
+    }
+
+    pub fn new(tuners: Arc<Tuners>, player_tuner: Arc<PlayerTuner>, enabled: bool, kbits: u32) -> Arc<Self> {
+        Self::with_switch_window(tuners, player_tuner, enabled, kbits, SWITCH_WINDOW)
+    }
+
+    fn with_switch_window(tuners: Arc<Tuners>, player_tuner: Arc<PlayerTuner>, enabled: bool, kbits: u32, switch_window: Duration) -> Arc<Self> {
+        Arc::new(Self { relayed: Mutex::default(), changed: Condvar::new(), switch_window, enabled, kbits, tuners, player_tuner })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn start(self: &Arc<Self>) {
+        if self.enabled {
+            let relay = self.clone();
+            thread::spawn(move || relay.relay());
+        }
+    }
+
+    // the radio channel the player started, None once it stopped or plays something else
+    // the streams end unless another radio channel starts within a few seconds
+    pub fn set_playing(&self, channel: Option<Channel>) {
+        self.relayed.lock().unwrap().playing = channel;
+        self.changed.notify_all();
+    }
+
+    // the stream of the playing radio channel, None if the player plays none
+    pub fn listen(&self) -> Option<impl Stream<Item = Result<Bytes, io::Error>>> {
+        let mut relayed = self.relayed.lock().unwrap();
+        relayed.playing.as_ref()?;
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED_CHUNKS);
+        relayed.listeners.push(sender);
+        self.changed.notify_all();
+        Some(receiver.map(Ok))
+    }
+
+    pub fn listeners(&self) -> usize {
+        self.relayed.lock().unwrap().listeners.iter().filter(|listener| !listener.is_closed()).count()
+    }
+
+    // waits for a channel and listeners, then runs ffmpeg until either is gone
+    fn relay(&self) {
+        let mut restarts = 0;
+        loop {
+            let Some(channel) = self.next_channel() else { continue };
+            // the player holds the tuner of the channel it plays, the relay shares it
+            let _tuner = match self.player_tuner.is_playing(&channel.name) {
+                true => None,
+                false => match self.tuners.acquire("radio relay", &channel.name) {
+                    Ok(tuner) => Some(tuner),
+                    Err(busy) => {
+                        warn!("no tuner left to relay {}, {} are in use", channel.name, busy.occupied.len());
+                        self.relayed.lock().unwrap().listeners.clear();
+                        continue;
+                    },
+                },
+            };
+
+            info!("relaying {} to {} listeners", channel.name, self.listeners());
+            let received = self.stream(&channel);
+            restarts = if received { 0 } else { restarts + 1 };
+            if restarts > MAX_RESTARTS {
+                warn!("gave up relaying {} after {} restarts", channel.name, MAX_RESTARTS);
+                self.relayed.lock().unwrap().listeners.clear();
+                restarts = 0;
+            }
+            thread::sleep(RESTART_DELAY);
+        }
+    }
+
+    // the channel to relay once there are listeners, None if the player stopped and the listeners were sent away
+    fn next_channel(&self) -> Option<Channel> {
+        let mut relayed = self.relayed.lock().unwrap();
+        loop {
+            relayed.listeners.retain(|listener| !listener.is_closed());
+            match &relayed.playing {
+                Some(channel) if !relayed.listeners.is_empty() => return Some(channel.clone()),
+                None if !relayed.listeners.is_empty() => {
+                    let (guard, waited) = self.changed.wait_timeout(relayed, self.switch_window).unwrap();
+                    relayed = guard;
+                    if waited.timed_out() && relayed.playing.is_none() {
+                        relayed.listeners.clear();
+                        return None;
+                    }
+                },
+                _ => relayed = self.changed.wait(relayed).unwrap(),
+            }
+        }
+    }
+
+    // returns whether ffmpeg sent anything
+    fn stream(&self, channel: &Channel) -> bool {
+        let mut child = match process::scoped_command("radio", "ffmpeg")
+            .arg("-hide_banner").arg("-loglevel").arg("error")
+            .arg("-fflags").arg("nobuffer")
+            .arg("-i").arg(&channel.url)
+            .arg("-vn").arg("-c:a").arg("libmp3lame").arg("-b:a").arg(format!("{}k", self.kbits))
+            .arg("-flush_packets").arg("1")
+            .arg("-f").arg("mp3").arg("pipe:1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn() {
+            Ok(child) => child,
+            Err(error) => { warn!("could not relay {}: {}", channel.name, error); return false; },
+        };
+        let pid = child.id();
+        process::register("radio", pid);
+
+        let mut stdout = child.stdout.take().unwrap();
+        let child = Arc::new(Mutex::new(child));
+        let heartbeat = watch_stall(child.clone(), &channel.name, STALL_TIMEOUT);
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut received = false;
+        loop {
+            let read = match stdout.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            received = true;
+            let _ = heartbeat.send(());
+            let bytes = Bytes::copy_from_slice(&chunk[..read]);
+            let mut relayed = self.relayed.lock().unwrap();
+            if relayed.playing.as_ref().is_none_or(|playing| playing.name != channel.name) {
+                break;
+            }
+            relayed.send(&bytes);
+            if relayed.listeners.is_empty() {
+                break;
+            }
+        }
+
+        drop(heartbeat);
+        let mut child = child.lock().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+        process::unregister(pid);
+        info!("stopped relaying {}", channel.name);
+        received
+    }
+}
+
+impl Relayed {
+    // a full queue is a listener that can't keep up, it would only fall further behind
+    fn send(&mut self, bytes: &Bytes) {
+        self.listeners.retain_mut(|listener| listener.try_send(bytes.clone()).is_ok());
+    }
+}
+
+// kills ffmpeg once nothing was sent on the heartbeat for a while, the watcher ends when the heartbeat is dropped
+fn watch_stall(child: Arc<Mutex<Child>>, channel: &str, timeout: Duration) -> sync_mpsc::Sender<()> {
+    let (heartbeat, beats) = sync_mpsc::channel();
+    let channel = channel.to_string();
+    thread::spawn(move || loop {
+        match beats.recv_timeout(timeout) {
+            Ok(()) => {},
+            Err(sync_mpsc::RecvTimeoutError::Timeout) => {
+                warn!("{} sent nothing for {} seconds, restarting", channel, timeout.as_secs());
+                let _ = child.lock().unwrap().kill();
+                return;
+            },
+            Err(sync_mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    });
+    heartbeat
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,98 @@
+use std::process::Command;
+use futures::FutureExt;
+use super::*;
+
+fn channel(name: &str) -> Channel {
+    Channel { name: name.to_string(), url: format!("rtsp://router/{}", name) }
+}
+
+fn relay(switch_window: Duration) -> Arc<RadioRelay> {
+    let tuners = Tuners::new(1);
+    RadioRelay::with_switch_window(tuners.clone(), PlayerTuner::new(tuners), true, DEFAULT_KBITS, switch_window)
+}
+
+#[test]
+fn nobody_can_listen_without_radio() {
+    let relay = relay(SWITCH_WINDOW);
+
+    assert!(relay.listen().is_none());
+}
+
+#[test]
+fn a_slow_listener_is_dropped() {
+    let relay = relay(SWITCH_WINDOW);
+    relay.set_playing(Some(channel("Bayern 3")));
+    let mut fast = Box::pin(relay.listen().unwrap());
+    let _slow = relay.listen().unwrap();
+
+    let mut relayed = relay.relayed.lock().unwrap();
+    for _ in 0..MAX_QUEUED_CHUNKS + 2 {
+        relayed.send(&Bytes::from_static(b"mp3"));
+        while let Some(Some(_)) = fast.next().now_or_never() {}
+    }
+
+    assert_eq!(1, relayed.listeners.len());
+}
+
+#[test]
+fn a_listener_that_left_is_dropped() {
+    let relay = relay(SWITCH_WINDOW);
+    relay.set_playing(Some(channel("Bayern 3")));
+    drop(relay.listen().unwrap());
+
+    relay.relayed.lock().unwrap().send(&Bytes::from_static(b"mp3"));
+
+    assert_eq!(0, relay.listeners());
+}
+
+#[test]
+fn the_listeners_follow_the_player_to_the_next_channel() {
+    let relay = relay(Duration::from_secs(5));
+    relay.set_playing(Some(channel("Bayern 3")));
+    let _listener = relay.listen().unwrap();
+    relay.set_playing(None);
+
+    let zapping = relay.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        zapping.set_playing(Some(channel("Antenne Bayern")));
+    });
+
+    assert_eq!("Antenne Bayern", relay.next_channel().unwrap().name);
+    assert_eq!(1, relay.listeners());
+}
+
+#[test]
+fn the_listeners_are_sent_away_once_the_player_stopped() {
+    let relay = relay(Duration::from_millis(50));
+    relay.set_playing(Some(channel("Bayern 3")));
+    let _listener = relay.listen().unwrap();
+    relay.set_playing(None);
+
+    assert!(relay.next_channel().is_none());
+    assert_eq!(0, relay.listeners());
+}
+
+#[test]
+fn a_stalled_ffmpeg_is_killed() {
+    let child = Arc::new(Mutex::new(Command::new("sleep").arg("60").spawn().unwrap()));
+    let _heartbeat = watch_stall(child.clone(), "Bayern 3", Duration::from_millis(100));
+
+    thread::sleep(Duration::from_millis(500));
+
+    assert!(child.lock().unwrap().try_wait().unwrap().is_some());
+}
+
+#[test]
+fn a_sending_ffmpeg_keeps_running() {
+    let child = Arc::new(Mutex::new(Command::new("sleep").arg("60").spawn().unwrap()));
+    let heartbeat = watch_stall(child.clone(), "Bayern 3", Duration::from_millis(200));
+
+    for _ in 0..5 {
+        thread::sleep(Duration::from_millis(50));
+        heartbeat.send(()).unwrap();
+    }
+
+    assert!(child.lock().unwrap().try_wait().unwrap().is_none());
+    let _ = child.lock().unwrap().kill();
+}
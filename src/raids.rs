@@ -0,0 +1,137 @@
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use crate::events::Event;
+use crate::process::VideoPlayerArgs;
+use crate::state::AppState;
+use crate::validation;
+
+const DEFAULT_IRC: &str = "irc.chat.twitch.tv:6667";
+// checks this often whether the stream is still the one being watched
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+// the raid can be announced after the stream already ended and took the player with it
+const GRACE_PERIOD: Duration = Duration::from_secs(120);
+
+// the stream the player shows, or showed until it exited on its own
+struct Following {
+    stream: String,
+    audio_only: bool,
+    until: Option<Instant>,
+}
+
+type Shared = Arc<Mutex<Option<Following>>>;
+
+// with TWITCH_FOLLOW_RAIDS, reads the chat of the playing Twitch stream and moves the player and chat along when it raids another channel
+pub fn follow(state: Arc<AppState>) {
+    if !env::var("TWITCH_FOLLOW_RAIDS").is_ok_and(|value| value == "true") {
+        return;
+    }
+    let irc = env::var("TWITCH_IRC").unwrap_or(DEFAULT_IRC.to_string());
+    let following: Shared = Arc::default();
+
+    // weak, the hooks are owned by the state
+    let (watched, weak) = (following.clone(), Arc::downgrade(&state));
+    state.video_player.on_start(move |args, _| {
+        let mut current = watched.lock().unwrap();
+        *current = match args {
+            VideoPlayerArgs::Twitch { stream, audio_only } => {
+                let (watched, state, watched_stream, irc) = (watched.clone(), weak.clone(), stream.clone(), irc.clone());
+                thread::spawn(move || watch(state, watched, watched_stream, &irc));
+                Some(Following { stream: stream.clone(), audio_only: *audio_only, until: None })
+            },
+            _ => None,
+        };
+    });
+    let watched = following.clone();
+    state.video_player.on_stop(move |_, _| *watched.lock().unwrap() = None);
+    // called after the on_stop hooks
+    state.video_player.on_unexpected_exit(move |args, _| if let VideoPlayerArgs::Twitch { stream, audio_only } = args {
+        *following.lock().unwrap() = Some(Following { stream: stream.clone(), audio_only: *audio_only, until: Some(Instant::now() + GRACE_PERIOD) });
+    });
+}
+
+// whether it played audio only, if the stream is still followed
+fn is_following(following: &Shared, stream: &str) -> Option<bool> {
+    following.lock().unwrap().as_ref()
+        .filter(|following| following.stream == stream && following.until.is_none_or(|until| Instant::now() < until))
+        .map(|following| following.audio_only)
+}
+
+fn watch(state: Weak<AppState>, following: Shared, stream: String, irc: &str) {
+    let (target, audio_only) = match read_chat(irc, &following, &stream) {
+        Ok(Some(target)) => target,
+        Ok(None) => return,
+        Err(error) => { warn!("could not read the chat of {} for raids: {}", stream, error); return },
+    };
+    let Some(state) = state.upgrade() else {
+        return;
+    };
+    info!("{} raided {}, following along", stream, target);
+
+    // stopping the old stream closes its chat
+    let chat = state.chat.running().is_some();
+    if let Err(error) = state.video_player.start(VideoPlayerArgs::Twitch { stream: target.clone(), audio_only }) {
+        warn!("could not follow the raid to {}: {}", target, error);
+        return;
+    }
+    if chat {
+        if let Err(error) = state.chat.start(target.clone()) {
+            warn!("could not open the chat of {}: {}", target, error);
+        }
+    }
+    state.events.publish(Event::TwitchRaid { from: stream, to: target });
+}
+
+// anonymous, so it works without a login, until the stream raids or isn't watched anymore
+fn read_chat(irc: &str, following: &Shared, stream: &str) -> io::Result<Option<(String, bool)>> {
+    let mut connection = TcpStream::connect(irc)?;
+    connection.set_read_timeout(Some(READ_TIMEOUT))?;
+    // the raid is only told in the tags
+    write!(connection, "CAP REQ :twitch.tv/tags twitch.tv/commands\r\nNICK justinfan{}\r\nJOIN #{}\r\n", std::process::id(), stream)?;
+    debug!("reading the chat of {} for raids", stream);
+
+    let mut reader = BufReader::new(connection.try_clone()?);
+    let mut line = String::new();
+    while let Some(audio_only) = is_following(following, stream) {
+        match reader.read_line(&mut line) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the chat closed the connection")),
+            Ok(_) => {},
+            // a partial line stays in the buffer and is completed by the next read
+            Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(error) => return Err(error),
+        }
+        let message = line.trim_end();
+        if let Some(server) = message.strip_prefix("PING ") {
+            write!(connection, "PONG {}\r\n", server)?;
+        } else if let Some(raid) = parse_raid(message).filter(|raid| raid.from == stream && raid.to != stream) {
+            return Ok(Some((raid.to, audio_only)));
+        }
+        line.clear();
+    }
+    Ok(None)
+}
+
+#[derive(PartialEq, Debug)]
+struct Raid {
+    from: String,
+    to: String,
+}
+
+// "@...;msg-id=raid;msg-param-login=<from>;... :tmi.twitch.tv USERNOTICE #<to>", the raiding channel is in the tags
+fn parse_raid(line: &str) -> Option<Raid> {
+    let (tags, message) = line.strip_prefix('@')?.split_once(' ')?;
+    let tag = |name: &str| tags.split(';').find_map(|tag| tag.strip_prefix(name)?.strip_prefix('='));
+    if tag("msg-id")? != "raid" {
+        return None;
+    }
+    let to = message.strip_prefix(":tmi.twitch.tv USERNOTICE #")?.split_whitespace().next()?.to_lowercase();
+    let from = tag("msg-param-login")?.to_lowercase();
+    Some(Raid { from, to }).filter(|raid| validation::is_twitch_login(&raid.from) && validation::is_twitch_login(&raid.to))
+}
+
+#[cfg(test)]
+mod tests;
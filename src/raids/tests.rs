@@ -0,0 +1,31 @@
+use super::*;
+
+// as twitch sends them, with the tags of twitch.tv/tags
+const RAID: &str = "@badge-info=;badges=partner/1;color=#1E90FF;display-name=Streamer;emotes=;flags=;id=3d830f12-795c-447d-af3c-ea05e40fbddb;login=streamer;mod=0;msg-id=raid;msg-param-displayName=Streamer;msg-param-login=streamer;msg-param-profileImageURL=https://static-cdn.jtvnw.net/jtv_user_pictures/streamer-profile_image-70x70.png;msg-param-viewerCount=1337;room-id=33332222;subscriber=0;system-msg=1337\\sraiders\\sfrom\\sStreamer\\shave\\sjoined!;tmi-sent-ts=1507246572675;user-id=123456;user-type= :tmi.twitch.tv USERNOTICE #othertestchannel";
+const SUB: &str = "@badge-info=subscriber/8;badges=subscriber/6;color=#0D4200;display-name=ronni;emotes=;flags=;id=db25007f-7a18-43eb-9379-80131e44d633;login=ronni;mod=0;msg-id=resub;msg-param-cumulative-months=8;msg-param-should-share-streak=0;msg-param-sub-plan=Prime;msg-param-sub-plan-name=Prime;room-id=12345678;subscriber=1;system-msg=ronni\\shas\\ssubscribed\\sfor\\s8\\smonths!;tmi-sent-ts=1507246572675;user-id=87654321;user-type= :tmi.twitch.tv USERNOTICE #streamer :Great stream -- keep it up!";
+const PRIVMSG: &str = "@badge-info=;badges=;color=;display-name=viewer;emotes=;id=b34ccfc7-4977-403a-8a94-33c6bac34fb8;mod=0;room-id=33332222;subscriber=0;tmi-sent-ts=1507246572675;turbo=0;user-id=1;user-type= :viewer!viewer@viewer.tmi.twitch.tv PRIVMSG #streamer :msg-id=raid USERNOTICE #elsewhere";
+
+#[test]
+fn parses_a_raid() {
+    assert_eq!(Some(Raid { from: "streamer".to_string(), to: "othertestchannel".to_string() }), parse_raid(RAID));
+}
+
+#[test]
+fn ignores_other_notices_and_messages() {
+    assert_eq!(None, parse_raid(SUB));
+    assert_eq!(None, parse_raid(PRIVMSG));
+    assert_eq!(None, parse_raid(":tmi.twitch.tv HOSTTARGET #streamer :othertestchannel 1337"));
+    assert_eq!(None, parse_raid("PING :tmi.twitch.tv"));
+}
+
+#[test]
+fn ignores_a_raid_without_the_raider() {
+    assert_eq!(None, parse_raid(&RAID.replace("msg-param-login=streamer;", "")));
+    assert_eq!(None, parse_raid(&RAID.replace("msg-param-login=streamer", "msg-param-login=not/a/login")));
+}
+
+#[test]
+fn does_not_take_a_tag_for_another_one_with_the_same_start() {
+    let line = RAID.replace("msg-id=raid;", "msg-id=unraid;msg-idx=raid;");
+    assert_eq!(None, parse_raid(&line));
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::process::{Child, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use log::{info, warn, error};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use crate::dvbc::Channel;
+use crate::epg::{self, Programme};
+use crate::files::{self, Root};
+use crate::podcast;
+use crate::process;
+use crate::state::AppState;
+use crate::store::{Repository, Store};
+use crate::tuners::{TunerLease, Tuners};
+use crate::viewing;
+
+const FOLDER: &str = "recordings";
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+// programmes rarely start and end on the minute the guide says
+const DEFAULT_PADDING_MINUTES: u64 = 2;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingState {
+    Scheduled,
+    Recording,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Recording {
+    pub id: Uuid,
+    pub channel: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subtitle: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub episode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    pub start: u64, // seconds since the epoch, as the guide has them
+    pub stop: u64,
+    pub state: RecordingState,
+    // relative to the DOWNLOAD_FOLDER, once it started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// records every programme with that title on the channel, with `new_only` an episode that was recorded before is left out
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SeriesRule {
+    pub id: Uuid,
+    pub title: String,
+    pub channel: String,
+    #[serde(default)]
+    pub new_only: bool,
+}
+
+struct Running {
+    child: Child,
+    _tuner: TunerLease,
+}
+
+// records DvbC channels with ffmpeg into DOWNLOAD_FOLDER/recordings, each recording takes a tuner while it runs
+pub struct Recorder {
+    tuners: Arc<Tuners>,
+    padding: u64, // seconds before the start and after the stop
+    recordings: Repository<Recording>,
+    rules: Repository<SeriesRule>,
+    // held while the recordings change, so a cancel can't be overwritten by the start of the same recording
+    running: Mutex<HashMap<Uuid, Running>>,
+}
+
+impl Recorder {
+
+    pub fn from_env(store: Arc<Store>, tuners: Arc<Tuners>) -> Self {
+        let padding = env::var("RECORDING_PADDING_MINUTES").ok().and_then(|minutes| minutes.parse().ok()).unwrap_or(DEFAULT_PADDING_MINUTES);
+        Self::new(store, tuners, padding * 60)
+    }
+
+    pub fn new(store: Arc<Store>, tuners: Arc<Tuners>, padding: u64) -> Self {
+        let recorder = Self { tuners, padding, recordings: Repository::new(store.clone(), "recordings"), rules: Repository::new(store, "series_rules"), running: Mutex::default() };
+        // ffmpeg didn't survive the last stop, what it wrote until then is kept
+        for (key, mut recording) in recorder.recordings.all().into_iter().filter(|(_, recording)| recording.state == RecordingState::Recording) {
+            recording.state = RecordingState::Failed;
+            recording.error = Some("HomeBack was stopped while recording".to_string());
+            recorder.recordings.put(&key, &recording);
+        }
+        recorder
+    }
+
+    // all recordings, past ones included, ordered by their start
+    pub fn recordings(&self) -> Vec<Recording> {
+        let mut recordings: Vec<Recording> = self.recordings.all().into_iter().map(|(_, recording)| recording).collect();
+        recordings.sort_by_key(|recording| (recording.start, recording.channel.clone()));
+        recordings
+    }
+
+    pub fn rules(&self) -> Vec<SeriesRule> {
+        let mut rules: Vec<SeriesRule> = self.rules.all().into_iter().map(|(_, rule)| rule).collect();
+        rules.sort_by(|a, b| a.title.cmp(&b.title).then_with(|| a.channel.cmp(&b.channel)));
+        rules
+    }
+
+    // adds the rule and schedules what the guide already has for it
+    pub fn add_rule(&self, title: String, channel: String, new_only: bool, programmes: &HashMap<String, Vec<Programme>>, now: u64) -> SeriesRule {
+        let rule = SeriesRule { id: Uuid::new_v4(), title, channel, new_only };
+        self.rules.put(&rule.id.to_string(), &rule);
+        self.apply_rules(programmes, now);
+        rule
+    }
+
+    // removes the rule and cancels the recordings it scheduled that didn't start yet
+    pub fn remove_rule(&self, id: &Uuid) -> bool {
+        let _running = self.running.lock().unwrap();
+        if self.rules.get(&id.to_string()).is_none() {
+            return false;
+        }
+        self.rules.remove(&id.to_string());
+        for mut recording in self.recordings().into_iter().filter(|recording| recording.rule == Some(*id) && recording.state == RecordingState::Scheduled) {
+            recording.state = RecordingState::Cancelled;
+            self.put(&recording);
+        }
+        true
+    }
+
+    // schedules the programmes of the guide that match a rule, the number of new recordings
+    // a broadcast is only ever scheduled once, so a cancelled one doesn't come back with the next import
+    pub fn apply_rules(&self, programmes: &HashMap<String, Vec<Programme>>, now: u64) -> usize {
+        let _running = self.running.lock().unwrap();
+        let mut recordings = self.recordings();
+        let mut scheduled = 0;
+        for rule in self.rules() {
+            let title = normalize(&rule.title);
+            for programme in programmes.get(&rule.channel).into_iter().flatten().filter(|programme| programme.stop > now && normalize(&programme.title) == title) {
+                // without anything to tell the episodes apart, every broadcast could be a new one
+                let episode = episode_key(&programme.title, &programme.episode, &programme.subtitle, &programme.description);
+                let known = recordings.iter().any(|recording| {
+                    let same_broadcast = recording.channel == rule.channel && recording.start == programme.start;
+                    // one that failed or was cancelled still misses the episode
+                    let recorded = matches!(recording.state, RecordingState::Scheduled | RecordingState::Recording | RecordingState::Done);
+                    same_broadcast || (rule.new_only && recorded && episode.is_some() && episode == episode_key(&recording.title, &recording.episode, &recording.subtitle, &recording.description))
+                });
+                if known {
+                    continue;
+                }
+                let recording = Recording {
+                    id: Uuid::new_v4(),
+                    channel: rule.channel.clone(),
+                    title: programme.title.clone(),
+                    subtitle: programme.subtitle.clone(),
+                    episode: programme.episode.clone(),
+                    description: programme.description.clone(),
+                    start: programme.start,
+                    stop: programme.stop,
+                    state: RecordingState::Scheduled,
+                    path: None,
+                    rule: Some(rule.id),
+                    error: None,
+                };
+                info!("scheduled {} on {} at {} for a series rule", recording.title, recording.channel, epg::format_time(recording.start));
+                self.put(&recording);
+                recordings.push(recording);
+                scheduled += 1;
+            }
+        }
+        scheduled
+    }
+
+    // cancels a recording that is scheduled or stops one that runs, None if there is none with that id, false if it is over already
+    pub fn cancel(&self, id: &Uuid) -> Option<bool> {
+        let mut running = self.running.lock().unwrap();
+        let mut recording = self.recordings.get(&id.to_string())?;
+        if !matches!(recording.state, RecordingState::Scheduled | RecordingState::Recording) {
+            return Some(false);
+        }
+        if let Some(running) = running.remove(id) {
+            stop(running);
+        }
+        info!("cancelled the recording of {} on {}", recording.title, recording.channel);
+        recording.state = RecordingState::Cancelled;
+        self.put(&recording);
+        Some(true)
+    }
+
+    // whether a recording runs or starts within the next hour
+    pub fn is_busy(&self, now: u64) -> bool {
+        self.recordings().iter().any(|recording| match recording.state {
+            RecordingState::Recording => true,
+            RecordingState::Scheduled => recording.start.saturating_sub(self.padding) < now + 60 * 60,
+            _ => false,
+        })
+    }
+
+    // starts the recordings that are due and finishes the ones whose ffmpeg exited
+    pub fn tick(&self, channels: &[Channel], now: u64) {
+        let mut running = self.running.lock().unwrap();
+        self.reap_exited(&mut running);
+        // until the router answered for the first time
+        if channels.is_empty() {
+            return;
+        }
+        for mut recording in self.recordings().into_iter().filter(|recording| recording.state == RecordingState::Scheduled && recording.start.saturating_sub(self.padding) <= now) {
+            if recording.stop + self.padding <= now {
+                self.fail(&mut recording, "no tuner was free while it ran".to_string());
+                continue;
+            }
+            let Some(channel) = channels.iter().find(|channel| channel.name == recording.channel) else {
+                let error = format!("the router has no channel {}", recording.channel);
+                self.fail(&mut recording, error);
+                continue;
+            };
+            // tried again with the next tick, the rest of the programme is still worth it
+            let tuner = match self.tuners.acquire("recording", &channel.name) {
+                Ok(tuner) => tuner,
+                Err(_) => { warn!("no free tuner to record {} on {}", recording.title, recording.channel); continue; },
+            };
+            match self.spawn(&recording, channel, recording.stop + self.padding - now) {
+                Ok((path, child)) => {
+                    info!("recording {} on {} into {}", recording.title, recording.channel, path);
+                    running.insert(recording.id, Running { child, _tuner: tuner });
+                    recording.state = RecordingState::Recording;
+                    recording.path = Some(path);
+                    self.put(&recording);
+                },
+                Err(error) => self.fail(&mut recording, error.to_string()),
+            }
+        }
+    }
+
+    fn spawn(&self, recording: &Recording, channel: &Channel, seconds: u64) -> io::Result<(String, Child)> {
+        let path = recording_path(recording);
+        let file = files::resolve(Root::Download, &path)?;
+        if let Some(folder) = file.parent() {
+            fs::create_dir_all(folder)?;
+        }
+        let child = process::scoped_command("recording", "ffmpeg")
+            .arg("-hide_banner").arg("-loglevel").arg("error").arg("-nostdin")
+            .arg("-i").arg(&channel.url)
+            // everything the channel sends, post processing can drop what isn't needed
+            .arg("-map").arg("0").arg("-c").arg("copy")
+            .arg("-t").arg(seconds.to_string())
+            .arg("-f").arg("mpegts")
+            .arg("-n").arg(&file)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        process::register("recording", child.id());
+        Ok((path, child))
+    }
+
+    fn reap_exited(&self, running: &mut HashMap<Uuid, Running>) {
+        let exited: Vec<(Uuid, io::Result<std::process::ExitStatus>)> = running.iter_mut()
+            .filter_map(|(id, recording)| Some((*id, recording.child.try_wait().transpose()?)))
+            .collect();
+        for (id, status) in exited {
+            if let Some(recording) = running.remove(&id) {
+                process::unregister(recording.child.id());
+            }
+            let Some(mut recording) = self.recordings.get(&id.to_string()) else { continue };
+            match status {
+                Ok(status) if status.success() => {
+                    info!("recorded {} on {}", recording.title, recording.channel);
+                    recording.state = RecordingState::Done;
+                    self.put(&recording);
+                },
+                Ok(status) => self.fail(&mut recording, format!("ffmpeg exited with {}", status)),
+                Err(error) => self.fail(&mut recording, error.to_string()),
+            }
+        }
+    }
+
+    fn fail(&self, recording: &mut Recording, error: String) {
+        error!("could not record {} on {}: {}", recording.title, recording.channel, error);
+        recording.state = RecordingState::Failed;
+        recording.error = Some(error);
+        self.put(recording);
+    }
+
+    fn put(&self, recording: &Recording) {
+        self.recordings.put(&recording.id.to_string(), recording);
+    }
+}
+
+fn stop(mut running: Running) {
+    let pid = running.child.id();
+    let _ = running.child.kill();
+    let _ = running.child.wait();
+    process::unregister(pid);
+}
+
+fn normalize(text: &str) -> String {
+    text.chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+// what tells the episodes of a show apart, the number if the guide has one, otherwise the subtitle or the description
+fn episode_key(title: &str, episode: &Option<String>, subtitle: &Option<String>, description: &Option<String>) -> Option<String> {
+    let episode = episode.clone().or_else(|| subtitle.as_deref().map(normalize)).or_else(|| description.as_deref().map(normalize))?;
+    Some(format!("{}/{}", normalize(title), episode))
+}
+
+// e.g. "recordings/Tatort/Tatort 2026-10-14 2015.ts", in UTC like the guide
+fn recording_path(recording: &Recording) -> String {
+    let (year, month, day) = viewing::civil_from_days((recording.start / (24 * 60 * 60)) as i64);
+    let minutes = recording.start % (24 * 60 * 60) / 60;
+    let title = podcast::file_name(&recording.title);
+    let subtitle = recording.subtitle.as_deref().map(|subtitle| format!(" - {}", podcast::file_name(subtitle))).unwrap_or_default();
+    format!("{}/{}/{} {}-{:02}-{:02} {:02}{:02}{}.ts", FOLDER, title, title, year, month, day, minutes / 60, minutes % 60, subtitle)
+}
+
+// starts the recordings when they are due and finishes them once ffmpeg exits
+pub fn start(state: Arc<AppState>) {
+    thread::spawn(move || loop {
+        let channels: Vec<Channel> = state.dvbc.get_channels().map(|channels| channels.tv.iter().chain(&channels.radio).cloned().collect()).unwrap_or_default();
+        state.recorder.tick(&channels, epg::now());
+        thread::sleep(TICK_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,108 @@
+use super::*;
+use crate::testing::TempFolder;
+
+const HOUR: u64 = 60 * 60;
+const NOW: u64 = 1792008000;
+
+struct Harness {
+    _folder: TempFolder, // holds the store
+    tuners: Arc<Tuners>,
+    recorder: Recorder,
+}
+
+impl Harness {
+
+    fn new() -> Self {
+        let folder = TempFolder::new();
+        let store = Arc::new(Store::open(folder.join("store.json")).unwrap());
+        let tuners = Tuners::new(1);
+        let recorder = Recorder::new(store, tuners.clone(), 120);
+        Self { _folder: folder, tuners, recorder }
+    }
+
+    fn states(&self) -> Vec<(u64, RecordingState)> {
+        self.recorder.recordings().into_iter().map(|recording| (recording.start, recording.state)).collect()
+    }
+}
+
+fn programme(title: &str, start: u64, episode: Option<&str>) -> Programme {
+    Programme { start, stop: start + HOUR, title: title.to_string(), subtitle: None, description: None, episode: episode.map(str::to_string) }
+}
+
+fn guide(channel: &str, programmes: Vec<Programme>) -> HashMap<String, Vec<Programme>> {
+    HashMap::from([(channel.to_string(), programmes)])
+}
+
+#[test]
+fn schedules_every_broadcast_of_the_show_once() {
+    let harness = Harness::new();
+    let programmes = guide("ZDF HD", vec![programme("Heute", NOW + HOUR, None), programme("Wetter", NOW + 2 * HOUR, None), programme("heute", NOW + 3 * HOUR, None)]);
+    harness.recorder.add_rule("Heute".to_string(), "ZDF HD".to_string(), false, &programmes, NOW);
+
+    assert_eq!(vec![(NOW + HOUR, RecordingState::Scheduled), (NOW + 3 * HOUR, RecordingState::Scheduled)], harness.states());
+    assert_eq!(0, harness.recorder.apply_rules(&programmes, NOW));
+    assert_eq!(0, harness.recorder.apply_rules(&guide("Das Erste HD", vec![programme("Heute", NOW + HOUR, None)]), NOW));
+}
+
+#[test]
+fn records_only_new_episodes() {
+    let harness = Harness::new();
+    let first = guide("Das Erste HD", vec![programme("Tatort", NOW + HOUR, Some("2.6.")), programme("Tatort", NOW + 2 * HOUR, Some("2.6.")), programme("Tatort", NOW + 3 * HOUR, None)]);
+    harness.recorder.add_rule("Tatort".to_string(), "Das Erste HD".to_string(), true, &first, NOW);
+    // the repeat is left out, the one without a number could be new
+    assert_eq!(vec![(NOW + HOUR, RecordingState::Scheduled), (NOW + 3 * HOUR, RecordingState::Scheduled)], harness.states());
+
+    let cancelled = harness.recorder.recordings()[0].id;
+    assert_eq!(Some(true), harness.recorder.cancel(&cancelled));
+    // the next import doesn't bring the cancelled one back, but the repeat replaces it
+    let scheduled = harness.recorder.apply_rules(&guide("Das Erste HD", vec![programme("Tatort", NOW + HOUR, Some("2.6.")), programme("Tatort", NOW + 2 * HOUR, Some("2.6."))]), NOW);
+
+    assert_eq!(1, scheduled);
+    assert_eq!(vec![(NOW + HOUR, RecordingState::Cancelled), (NOW + 2 * HOUR, RecordingState::Scheduled), (NOW + 3 * HOUR, RecordingState::Scheduled)], harness.states());
+    assert_eq!(Some(false), harness.recorder.cancel(&cancelled));
+}
+
+#[test]
+fn removing_a_rule_cancels_what_it_scheduled() {
+    let harness = Harness::new();
+    let rule = harness.recorder.add_rule("Heute".to_string(), "ZDF HD".to_string(), false, &guide("ZDF HD", vec![programme("Heute", NOW + HOUR, None)]), NOW);
+
+    assert!(harness.recorder.remove_rule(&rule.id));
+
+    assert_eq!(vec![(NOW + HOUR, RecordingState::Cancelled)], harness.states());
+    assert!(harness.recorder.rules().is_empty());
+    assert!(!harness.recorder.remove_rule(&rule.id));
+}
+
+#[test]
+fn fails_what_could_not_be_started() {
+    let harness = Harness::new();
+    let programmes = HashMap::from([
+        ("ZDF HD".to_string(), vec![programme("Heute", NOW - 2 * HOUR, None), programme("Heute", NOW - 60, None)]),
+        ("Gone".to_string(), vec![programme("Heute", NOW, None)]),
+    ]);
+    harness.recorder.add_rule("Heute".to_string(), "ZDF HD".to_string(), false, &programmes, NOW - 3 * HOUR);
+    harness.recorder.add_rule("Heute".to_string(), "Gone".to_string(), false, &programmes, NOW - 3 * HOUR);
+    assert!(!harness.recorder.is_busy(NOW - 4 * HOUR));
+    assert!(harness.recorder.is_busy(NOW - 3 * HOUR));
+    let _tuner = harness.tuners.acquire("player", "arte").unwrap();
+
+    harness.recorder.tick(&[Channel { name: "ZDF HD".to_string(), url: "rtsp://router/2".to_string() }], NOW);
+
+    let recordings = harness.recorder.recordings();
+    // the running one waits for a tuner
+    assert_eq!(vec![RecordingState::Failed, RecordingState::Scheduled, RecordingState::Failed], recordings.iter().map(|recording| recording.state).collect::<Vec<_>>());
+    assert_eq!(Some("no tuner was free while it ran"), recordings[0].error.as_deref());
+    assert_eq!(Some("the router has no channel Gone"), recordings[2].error.as_deref());
+}
+
+#[test]
+fn names_the_file_after_the_show() {
+    let mut recording = Recording {
+        id: Uuid::new_v4(), channel: "Das Erste HD".to_string(), title: "Tatort: Köln".to_string(), subtitle: Some("Tod/Teufel".to_string()), episode: None,
+        description: None, start: NOW + 15 * 60, stop: NOW + HOUR, state: RecordingState::Scheduled, path: None, rule: None, error: None,
+    };
+    assert_eq!("recordings/Tatort_ Köln/Tatort_ Köln 2026-10-14 2015 - Tod_Teufel.ts", recording_path(&recording));
+    recording.subtitle = None;
+    assert_eq!("recordings/Tatort_ Köln/Tatort_ Köln 2026-10-14 2015.ts", recording_path(&recording));
+}
@@ -0,0 +1,169 @@
+use std::env;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use actix_web::web::Bytes;
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::{SinkExt, Stream, StreamExt};
+use log::{info, warn};
+use reqwest::Url;
+use crate::dvbc::Channel;
+use crate::process;
+use crate::tuners::TunerLease;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+// about 20 seconds of an HD channel, the player reads as fast as the channel sends once the buffer went out
+const MAX_QUEUED_CHUNKS: usize = 64;
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+// reconnects in a row without getting anything, then the channel is given up
+const MAX_RECONNECTS: u32 = 5;
+
+const DEFAULT_DELAY_SECONDS: u64 = 2;
+
+// with DVBC_RELAY the player plays the channels through the relay instead of from the router
+pub struct Relay {
+    enabled: bool,
+    // how far the player starts behind the channel, a reconnect that is quicker than that doesn't stall it
+    start_delay: Duration,
+    base_url: String, // where the player reaches the API
+}
+
+// the one stream that shares the tuner of the player, every other one takes its own
+static PLAYER_STREAM: AtomicBool = AtomicBool::new(false);
+
+// what a relayed stream holds on to while it is read, both free their slot when dropped
+pub enum StreamLease {
+    Tuner(TunerLease),
+    Player(PlayerStream),
+}
+
+// the stream of the player, on the tuner the player already holds. Frees the slot for the next one when dropped
+pub struct PlayerStream(());
+
+// none if the player already has its stream, a second one takes a tuner like other clients
+pub fn player_stream() -> Option<PlayerStream> {
+    PLAYER_STREAM.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).ok().map(|_| PlayerStream(()))
+}
+
+impl Drop for PlayerStream {
+    fn drop(&mut self) {
+        PLAYER_STREAM.store(false, Ordering::Release);
+    }
+}
+
+impl Relay {
+
+    // the first of ADDR unless DVBC_RELAY_URL is set
+    pub fn from_env() -> Self {
+        let base_url = env::var("DVBC_RELAY_URL").unwrap_or_else(|_| {
+            let addr = env::var("ADDR").ok()
+                .and_then(|addrs| addrs.split(',').next().and_then(|addr| addr.trim().parse::<SocketAddr>().ok()))
+                .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 23559));
+            let ip = match addr.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                ip => ip,
+            };
+            format!("http://{}/api/v1", SocketAddr::new(ip, addr.port()))
+        });
+        let seconds = env::var("DVBC_RELAY_DELAY_SECONDS").ok().and_then(|seconds| seconds.parse().ok()).unwrap_or(DEFAULT_DELAY_SECONDS);
+        Self::new(env::var("DVBC_RELAY").is_ok_and(|value| value == "true"), Duration::from_secs(seconds), base_url)
+    }
+
+    pub fn new(enabled: bool, start_delay: Duration, base_url: String) -> Self {
+        Self { enabled, start_delay, base_url }
+    }
+
+    // the relay url of the channel, if the player should use it
+    pub fn player_url(&self, channel: &Channel) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let mut url = Url::parse(&self.base_url).ok()?;
+        url.path_segments_mut().ok()?.extend(["dvbc", "relay", &channel.name]);
+        Some(url.to_string())
+    }
+
+    // the channel as MPEG-TS, read by ffmpeg and restarted whenever the router drops the stream
+    // the first seconds are held back and then sent at once, so the player runs that far behind and has them in hand while the relay reconnects
+    // the lease is held for as long as the stream is read
+    pub fn open(&self, channel: Channel, lease: StreamLease) -> impl Stream<Item = Result<Bytes, io::Error>> {
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED_CHUNKS);
+        let start_delay = self.start_delay;
+        thread::spawn(move || {
+            let (_tuner, _shared) = match lease {
+                StreamLease::Tuner(tuner) => (Some(tuner), None),
+                StreamLease::Player(shared) => (None, Some(shared)),
+            };
+            relay(&channel, start_delay, sender);
+        });
+        receiver.map(Ok)
+    }
+}
+
+fn relay(channel: &Channel, start_delay: Duration, mut sender: mpsc::Sender<Bytes>) {
+    let mut delayed: Option<(Instant, Vec<Bytes>)> = None;
+    let mut reconnects = 0;
+    loop {
+        let mut child = match process::scoped_command("relay", "ffmpeg")
+            .arg("-hide_banner").arg("-loglevel").arg("error")
+            .arg("-i").arg(&channel.url)
+            .arg("-map").arg("0").arg("-c").arg("copy")
+            .arg("-f").arg("mpegts").arg("pipe:1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn() {
+            Ok(child) => child,
+            Err(error) => { warn!("could not relay {}: {}", channel.name, error); return; },
+        };
+        let pid = child.id();
+        process::register("relay", pid);
+
+        let mut stdout = child.stdout.take().unwrap();
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let client_left = loop {
+            let read = match stdout.read(&mut chunk) {
+                Ok(0) | Err(_) => break false,
+                Ok(read) => read,
+            };
+            reconnects = 0;
+            let bytes = Bytes::copy_from_slice(&chunk[..read]);
+            let sent = match &mut delayed {
+                None if !start_delay.is_zero() => { delayed = Some((Instant::now(), vec![bytes])); Ok(()) },
+                Some((started, chunks)) if started.elapsed() < start_delay => { chunks.push(bytes); Ok(()) },
+                Some((_, chunks)) if !chunks.is_empty() => {
+                    chunks.push(bytes);
+                    // stays Some, so the start is only held back once
+                    std::mem::take(chunks).into_iter().try_for_each(|chunk| block_on(sender.send(chunk)))
+                },
+                _ => block_on(sender.send(bytes)),
+            };
+            if sent.is_err() {
+                break true;
+            }
+        };
+
+        let _ = child.kill();
+        let _ = child.wait();
+        process::unregister(pid);
+        if client_left || sender.is_closed() {
+            info!("stopped relaying {}", channel.name);
+            return;
+        }
+        reconnects += 1;
+        if reconnects > MAX_RECONNECTS {
+            warn!("gave up relaying {} after {} reconnects", channel.name, MAX_RECONNECTS);
+            return;
+        }
+        info!("the stream of {} dropped, reconnecting", channel.name);
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,12 @@
+use super::*;
+
+#[test]
+fn only_one_stream_shares_the_tuner_of_the_player() {
+    let first = player_stream();
+    assert!(first.is_some());
+    assert!(player_stream().is_none());
+
+    drop(first);
+
+    assert!(player_stream().is_some());
+}
@@ -0,0 +1,24 @@
+use std::sync::Arc;
+use serde_json::{Map, Value};
+use crate::store::{Repository, Store};
+
+// preferences of the frontend (theme, channel ordering, grid size...), shared by every device
+// HomeBack doesn't look into them, each namespace is just a json object the frontend owns
+pub struct Settings {
+    namespaces: Repository<Map<String, Value>>,
+}
+
+impl Settings {
+
+    pub fn new(store: Arc<Store>) -> Self {
+        Self { namespaces: Repository::new(store, "settings") }
+    }
+
+    pub fn get(&self, namespace: &str) -> Map<String, Value> {
+        self.namespaces.get(namespace).unwrap_or_default()
+    }
+
+    pub fn put(&self, namespace: &str, settings: &Map<String, Value>) {
+        self.namespaces.put(namespace, settings);
+    }
+}
@@ -0,0 +1,95 @@
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use log::info;
+use reqwest::Client;
+use serde::Serialize;
+use tokio::time::timeout;
+
+// 100 MB, so even a fast line is still downloading when the test ends
+const DEFAULT_URL: &str = "https://speed.cloudflare.com/__down?bytes=100000000";
+const DEFAULT_SECONDS: u64 = 10;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug)]
+pub struct SpeedTest {
+    url: String,
+    latency_ms: u64, // until the response headers arrived
+    bytes: u64,
+    seconds: f64,
+    mbit_per_second: f64,
+    complete: bool, // whether the whole file came within SPEEDTEST_SECONDS
+}
+
+#[derive(Debug)]
+pub enum SpeedTestError {
+    Running,
+    Request(reqwest::Error),
+}
+
+// downloads SPEEDTEST_URL for at most SPEEDTEST_SECONDS and measures how fast it came in
+pub struct SpeedTester {
+    client: Client,
+    url: String,
+    max_duration: Duration,
+    running: AtomicBool, // a second test at the same time would only measure half the line
+}
+
+struct Guard<'a>(&'a AtomicBool);
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl SpeedTester {
+
+    pub fn from_env() -> Self {
+        let seconds = env::var("SPEEDTEST_SECONDS").ok().and_then(|seconds| seconds.parse().ok()).unwrap_or(DEFAULT_SECONDS);
+        Self::new(env::var("SPEEDTEST_URL").unwrap_or(DEFAULT_URL.to_string()), Duration::from_secs(seconds))
+    }
+
+    pub fn new(url: String, max_duration: Duration) -> Self {
+        Self { client: Client::builder().connect_timeout(CONNECT_TIMEOUT).build().unwrap(), url, max_duration, running: AtomicBool::new(false) }
+    }
+
+    pub async fn run(&self) -> Result<SpeedTest, SpeedTestError> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(SpeedTestError::Running);
+        }
+        let _guard = Guard(&self.running);
+
+        let started = Instant::now();
+        let mut response = self.client.get(&self.url).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(SpeedTestError::Request)?;
+        let latency = started.elapsed();
+
+        let body_started = Instant::now();
+        let mut bytes = 0;
+        let complete = loop {
+            if body_started.elapsed() >= self.max_duration {
+                break false;
+            }
+            match timeout(self.max_duration.saturating_sub(body_started.elapsed()), response.chunk()).await {
+                Ok(Ok(Some(chunk))) => bytes += chunk.len() as u64,
+                Ok(Ok(None)) => break true,
+                Ok(Err(error)) => return Err(SpeedTestError::Request(error)),
+                Err(_) => break false,
+            }
+        };
+        let seconds = body_started.elapsed().as_secs_f64();
+
+        let test = SpeedTest {
+            url: self.url.clone(),
+            latency_ms: latency.as_millis() as u64,
+            bytes,
+            seconds,
+            mbit_per_second: if seconds > 0.0 { bytes as f64 * 8.0 / seconds / 1_000_000.0 } else { 0.0 },
+            complete,
+        };
+        info!("speed test: {:.1} Mbit/s, {} ms latency", test.mbit_per_second, test.latency_ms);
+        Ok(test)
+    }
+}
@@ -0,0 +1,305 @@
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::error;
+use crate::audio;
+use crate::auth::Auth;
+use crate::cec;
+use crate::display;
+use crate::process::{self, ProcessHandler, ProcessStarter, VideoPlayerArgs};
+use crate::twitch::Twitch;
+use crate::dlna::Dlna;
+use crate::download::{Clock, DownloadManager, SystemClock};
+use crate::dvbc::{ChannelSettings, DvbC, RouterPlaylists};
+use crate::dvbc_preview::DvbCPreviews;
+use crate::epg::Epg;
+use crate::events::{Event, Events};
+use crate::files::Folders;
+use crate::health::Health;
+use crate::hls::HlsRestream;
+use crate::idle::IdleShutdown;
+use crate::lastfm::LastFm;
+use crate::library::Library;
+use crate::media::MediaIndex;
+use crate::podcast::Podcasts;
+use crate::power::PowerControl;
+use crate::postprocess::PostProcessing;
+use crate::profiles::Profiles;
+use crate::progress::Progress;
+use crate::radio::RadioRelay;
+use crate::recorder::Recorder;
+use crate::relay::Relay;
+use crate::settings::Settings;
+use crate::speedtest::SpeedTester;
+use crate::viewing::Viewing;
+use crate::store::Store;
+use crate::subtitles::OpenSubtitles;
+use crate::tuners::{PlayerTuner, Tuners};
+use crate::upload::Uploads;
+use crate::wol::Wol;
+
+const SPOTIFY_RESTART_DELAY: Duration = Duration::from_secs(10);
+// switching the player stops the old stream right before the new one starts
+const SWITCH_WINDOW: Duration = Duration::from_secs(5);
+
+// what the state is built from, the tests put in fakes for the processes and a mock server as the router
+pub struct Parts {
+    pub router_url: String,
+    pub folders: Folders,
+    pub store: Arc<Store>,
+    pub clock: Arc<dyn Clock>,
+    pub twitch: Twitch,
+    pub auth: Auth,
+    pub chat: Box<dyn ProcessStarter<String>>,
+    pub spotify: Box<dyn ProcessStarter<String>>,
+    pub video_player: Option<Box<dyn ProcessStarter<VideoPlayerArgs>>>, // mpv unless it is replaced
+}
+
+pub struct AppState {
+    pub chat:             Arc<ProcessHandler<String>>,
+    pub video_player:     ProcessHandler<VideoPlayerArgs>,
+    pub spotify:          Arc<ProcessHandler<String>>,
+    pub spotify_name:     String, // how the speaker shows up in the Spotify apps
+    pub night_mode:       Arc<AtomicBool>,
+    pub quiet_mode:       AtomicBool, // the pollers skip their work while it is on
+    pub twitch:           Twitch,
+    pub download_manager: DownloadManager,
+    pub postprocessing:   Arc<PostProcessing>,
+    pub dvbc:             Arc<DvbC>,
+    pub dvbc_previews:    Arc<DvbCPreviews>,
+    pub epg:              Epg,
+    pub recorder:         Recorder,
+    pub tuners:           Arc<Tuners>,
+    pub player_tuner:     Arc<PlayerTuner>,
+    pub radio_relay:      Arc<RadioRelay>,
+    pub relay:            Arc<Relay>,
+    pub hls:              Arc<HlsRestream>,
+    pub health:           Health,
+    pub idle_shutdown:    IdleShutdown,
+    pub power:            PowerControl,
+    pub auth:             Auth,
+    pub wol:              Wol,
+    pub speedtest:        SpeedTester,
+    pub uploads:          Uploads,
+    pub events:           Arc<Events>,
+    pub library:          Option<Library>,
+    pub podcasts:         Podcasts,
+    pub media:            MediaIndex,
+    pub progress:         Arc<Progress>,
+    pub profiles:         Profiles,
+    pub settings:         Settings,
+    pub viewing:          Arc<Viewing>,
+    pub subtitles:        Option<OpenSubtitles>,
+    pub lastfm:           Option<LastFm>,
+    pub dlna:             Option<Arc<Dlna>>,
+    pub store:            Arc<Store>,
+}
+
+impl AppState {
+
+    // fails on a missing or invalid setting, or a store that can't be opened
+    pub fn from_env() -> io::Result<Self> {
+        let router_url = required("ROUTER_URL")?;
+        let folders = Folders::from_env()?;
+        let store_file = PathBuf::from(env::var("STORE_FILE").unwrap_or("home_back.json".to_string()));
+        let store = Arc::new(Store::open(store_file.clone()).map_err(|error| io::Error::new(error.kind(), format!("could not open the STORE_FILE {:?}: {}", store_file, error)))?);
+        Self::new(Parts {
+            router_url,
+            folders,
+            twitch:       Twitch::from_env(store.clone())?,
+            auth:         Auth::from_env(),
+            store,
+            clock:        Arc::new(SystemClock),
+            chat:         Box::new(process::Chat{}),
+            spotify:      Box::new(process::Librespot{}),
+            video_player: None,
+        })
+    }
+
+    pub fn new(parts: Parts) -> io::Result<Self> {
+        let Parts { router_url, folders, store, clock, twitch, auth, chat, spotify, video_player } = parts;
+        let folders = folders.set();
+        let health_folders = vec![("SCAN_FOLDER", folders.scan.clone()), ("DOWNLOAD_FOLDER", folders.download.clone()), ("WEB_BASE_FOLDER", folders.web_base.clone())];
+
+        let events = Arc::new(Events::default());
+        let chat = Arc::new(ProcessHandler::new(chat, events.clone()));
+        let progress = Arc::new(Progress::new(store.clone()));
+        let spotify = Arc::new(ProcessHandler::new(spotify, events.clone()));
+        let night_mode = Arc::new(AtomicBool::new(false));
+        let relay = Arc::new(Relay::from_env());
+        let video_player = video_player.unwrap_or_else(|| Box::new(process::VideoPlayer::from_env(night_mode.clone(), progress.clone(), ChannelSettings::repository(store.clone()), relay.clone())));
+        let video_player = ProcessHandler::new(video_player, events.clone());
+        let viewing = Arc::new(Viewing::from_env(store.clone()));
+        let tuners = Tuners::from_env();
+        let player_tuner = PlayerTuner::new(tuners.clone());
+        let dvbc_previews = Arc::new(DvbCPreviews::from_env(tuners.clone())?);
+        let dvbc = Arc::new(DvbC::new(RouterPlaylists::new(&router_url), store.clone()));
+        let radio_relay = RadioRelay::from_env(tuners.clone(), player_tuner.clone());
+        let hls = HlsRestream::from_env(tuners.clone());
+        connect_hooks(&video_player, &chat, &spotify, &events, &viewing, &dvbc_previews, &player_tuner);
+        connect_radio_relay(&video_player, &dvbc, &radio_relay);
+
+        Ok(Self {
+            chat,
+            video_player,
+            spotify,
+            spotify_name:     env::var("SPOTIFY_NAME").unwrap_or("HomeBack".to_string()),
+            night_mode,
+            quiet_mode:       AtomicBool::new(false),
+            twitch,
+            download_manager: DownloadManager::from_env(clock, store.clone(), events.clone())?,
+            postprocessing:   Arc::new(PostProcessing::from_env(events.clone())),
+            podcasts:         Podcasts::from_env(store.clone()),
+            media:            MediaIndex::from_env(store.clone(), progress.clone()),
+            progress,
+            profiles:         Profiles::new(store.clone()),
+            settings:         Settings::new(store.clone()),
+            viewing,
+            subtitles:        OpenSubtitles::from_env(),
+            lastfm:           LastFm::from_env(store.clone()),
+            dvbc,
+            dvbc_previews,
+            epg:              Epg::from_env(store.clone()),
+            recorder:         Recorder::from_env(store.clone(), tuners.clone()),
+            tuners,
+            player_tuner,
+            radio_relay,
+            relay,
+            hls,
+            health:           Health::new(&router_url, health_folders),
+            idle_shutdown:    IdleShutdown::from_env(),
+            power:            PowerControl::from_env(),
+            auth,
+            wol:              Wol::from_env(),
+            speedtest:        SpeedTester::from_env(),
+            uploads:          Uploads::from_env(),
+            events,
+            library:          Library::from_env(),
+            dlna:             Dlna::from_env().map(Arc::new),
+            store,
+        })
+    }
+}
+
+fn required(name: &str) -> io::Result<String> {
+    env::var(name).map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("{} not set", name)))
+}
+
+// how the processes affect each other and the rest of the system
+fn connect_hooks(video_player: &ProcessHandler<VideoPlayerArgs>, chat: &Arc<ProcessHandler<String>>, spotify: &Arc<ProcessHandler<String>>, events: &Arc<Events>, viewing: &Arc<Viewing>, dvbc_previews: &Arc<DvbCPreviews>, player_tuner: &Arc<PlayerTuner>) {
+    let player_events = events.clone();
+    video_player.on_start(move |args, _| player_events.publish(args.started_event()));
+    let player_events = events.clone();
+    video_player.on_stop(move |_, _| player_events.publish(Event::PlayerStopped));
+
+    let watched = viewing.clone();
+    video_player.on_start(move |args, _| {
+        let (source, name) = args.source_and_name();
+        watched.started(source, name);
+    });
+    let watched = viewing.clone();
+    video_player.on_stop(move |_, _| watched.stopped());
+
+    let previews = dvbc_previews.clone();
+    video_player.on_start(move |args, _| previews.set_tuned(match args {
+        VideoPlayerArgs::DvbC(channel) => Some(channel.name.clone()),
+        _ => None,
+    }));
+    let previews = dvbc_previews.clone();
+    video_player.on_stop(move |_, _| previews.set_tuned(None));
+
+    // the tuner was taken before the channel started
+    let tuner = player_tuner.clone();
+    video_player.on_start(move |args, _| if let VideoPlayerArgs::DvbC(_) = args {
+        tuner.started();
+    });
+    let tuner = player_tuner.clone();
+    video_player.on_stop(move |_, _| tuner.stopped());
+
+    // audio only leaves the TV as it is
+    if env::var("CEC_AUTO_POWER_ON").is_ok_and(|value| value == "true") {
+        video_player.on_start(|args, _| if !args.is_audio_only() {
+            cec::auto_power_on();
+        });
+    }
+    // otherwise DPMS turns off the display in the middle of a stream
+    video_player.on_start(|args, _| if !args.is_audio_only() {
+        if let Err(error) = display::inhibit_screensaver() {
+            error!("could not inhibit screensaver: {}", error);
+        }
+    });
+    video_player.on_stop(|_, _| if let Err(error) = display::allow_screensaver() {
+        error!("could not allow screensaver: {}", error);
+    });
+
+    // librespot can't be paused from the outside, but dropping its session pauses on the phone
+    // zapping stops the player right before the next start, Spotify was paused by the first one already
+    let player_stopped: Arc<Mutex<Option<Instant>>> = Arc::default();
+    let stopped = player_stopped.clone();
+    video_player.on_stop(move |_, _| *stopped.lock().unwrap() = Some(Instant::now()));
+    let paused = spotify.clone();
+    video_player.on_start(move |_, _| {
+        let switched = player_stopped.lock().unwrap().take().is_some_and(|stopped| stopped.elapsed() < SWITCH_WINDOW);
+        if switched || paused.running().is_none() {
+            return;
+        }
+        match audio::is_playing("librespot") {
+            Ok(false) => {},
+            Ok(true) => if let Err(error) = paused.restart() {
+                error!("could not pause Spotify: {}", error);
+            },
+            Err(error) => error!("could not check whether Spotify plays: {}", error),
+        }
+    });
+
+    let chat_closed: Arc<Mutex<Option<Instant>>> = Arc::default();
+    let (twitch_chat, closed) = (chat.clone(), chat_closed.clone());
+    video_player.on_stop(move |args, _| if let VideoPlayerArgs::Twitch { .. } = args {
+        if twitch_chat.running().is_some() {
+            *closed.lock().unwrap() = Some(Instant::now());
+        }
+        if let Err(error) = twitch_chat.stop() {
+            error!("could not stop chat: {}", error);
+        }
+    });
+    // so zapping between streams takes the chat along
+    if env::var("CHAT_FOLLOWS_PLAYER").is_ok_and(|value| value == "true") {
+        let twitch_chat = chat.clone();
+        video_player.on_start(move |args, _| {
+            let closed = chat_closed.lock().unwrap().take();
+            if let VideoPlayerArgs::Twitch { stream, .. } = args {
+                if closed.is_some_and(|closed| closed.elapsed() < SWITCH_WINDOW) {
+                    if let Err(error) = twitch_chat.start(stream.clone()) {
+                        error!("could not open the chat of {}: {}", stream, error);
+                    }
+                }
+            }
+        });
+    }
+
+    // librespot exits when it loses the connection, the speaker should come back once the network does
+    let restarted = Arc::downgrade(spotify);
+    spotify.on_unexpected_exit(move |name, _| {
+        thread::sleep(SPOTIFY_RESTART_DELAY);
+        if let Some(spotify) = restarted.upgrade().filter(|spotify| spotify.running().is_none()) {
+            if let Err(error) = spotify.start(name.clone()) {
+                error!("could not restart Spotify: {}", error);
+            }
+        }
+    });
+}
+
+// the speakers in the other rooms play along with the player
+fn connect_radio_relay(video_player: &ProcessHandler<VideoPlayerArgs>, dvbc: &Arc<DvbC>, radio_relay: &Arc<RadioRelay>) {
+    let (channels, relay) = (dvbc.clone(), radio_relay.clone());
+    video_player.on_start(move |args, _| relay.set_playing(match args {
+        VideoPlayerArgs::DvbC(channel) if channels.get_channels().is_some_and(|channels| channels.radio.iter().any(|radio| radio.name == channel.name)) => Some(channel.clone()),
+        _ => None,
+    }));
+    let relay = radio_relay.clone();
+    video_player.on_stop(move |_, _| relay.set_playing(None));
+}
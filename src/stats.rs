@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::thread;
+use std::time::Duration;
+use log::warn;
+use serde::Serialize;
+use systemstat::{Filesystem, Platform, System};
+use crate::tools;
+
+// cpu load and network throughput are measured over this window
+const SAMPLE_DURATION: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Debug)]
+pub struct SystemStats {
+    cpu: Option<CpuStats>,
+    memory: Option<MemoryStats>,
+    disks: Vec<DiskStats>,
+    network: BTreeMap<String, NetworkStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    throttling: Option<Throttling>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CpuStats {
+    load: f32, // 0 to 1, over all cores
+    load_average: [f32; 3],
+}
+
+#[derive(Serialize, Debug)]
+pub struct MemoryStats {
+    total: u64,
+    available: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DiskStats {
+    name: &'static str,
+    path: PathBuf,
+    mounted_on: String,
+    total: u64,
+    available: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NetworkStats {
+    rx_bytes_per_second: u64,
+    tx_bytes_per_second: u64,
+}
+
+// current state of the Raspberry Pi firmware throttling, as reported by vcgencmd
+#[derive(Serialize, Debug)]
+pub struct Throttling {
+    under_voltage: bool,
+    frequency_capped: bool,
+    throttled: bool,
+    soft_temperature_limit: bool,
+}
+
+// blocks for SAMPLE_DURATION
+pub fn collect(folders: &[(&'static str, PathBuf)]) -> SystemStats {
+    let system = System::new();
+
+    let cpu_measurement = system.cpu_load_aggregate().map_err(|error| warn!("could not measure cpu load: {}", error)).ok();
+    let network_before = network_bytes(&system);
+    thread::sleep(SAMPLE_DURATION);
+    let network_after = network_bytes(&system);
+
+    let cpu = cpu_measurement
+        .and_then(|measurement| measurement.done().map_err(|error| warn!("could not measure cpu load: {}", error)).ok())
+        .map(|load| CpuStats {
+            load: 1.0 - load.idle,
+            load_average: system.load_average().map(|load| [load.one, load.five, load.fifteen]).unwrap_or_default(),
+        });
+
+    let memory = system.memory()
+        .map(|memory| MemoryStats { total: memory.total.as_u64(), available: memory.free.as_u64() })
+        .map_err(|error| warn!("could not get memory usage: {}", error))
+        .ok();
+
+    let seconds = SAMPLE_DURATION.as_secs_f64();
+    let network = network_after.into_iter()
+        .filter_map(|(interface, (rx_after, tx_after))| {
+            let (rx_before, tx_before) = network_before.get(&interface)?;
+            Some((interface, NetworkStats {
+                rx_bytes_per_second: (rx_after.saturating_sub(*rx_before) as f64 / seconds) as u64,
+                tx_bytes_per_second: (tx_after.saturating_sub(*tx_before) as f64 / seconds) as u64,
+            }))
+        })
+        .collect();
+
+    SystemStats {
+        cpu,
+        memory,
+        disks: disks(&system, folders),
+        network,
+        temperature: system.cpu_temp().ok(),
+        throttling: throttling(),
+    }
+}
+
+fn network_bytes(system: &System) -> BTreeMap<String, (u64, u64)> {
+    system.networks().unwrap_or_default().into_keys()
+        .filter(|interface| interface != "lo")
+        .filter_map(|interface| {
+            let stats = system.network_stats(&interface).ok()?;
+            Some((interface, (stats.rx_bytes.as_u64(), stats.tx_bytes.as_u64())))
+        })
+        .collect()
+}
+
+fn disks(system: &System, folders: &[(&'static str, PathBuf)]) -> Vec<DiskStats> {
+    let mounts = match system.mounts() {
+        Ok(mounts) => mounts,
+        Err(error) => { warn!("could not get mounts: {}", error); return Vec::new() },
+    };
+
+    folders.iter()
+        .filter_map(|(name, path)| {
+            let mount = mount_of(&mounts, path)?;
+            Some(DiskStats {
+                name,
+                path: path.clone(),
+                mounted_on: mount.fs_mounted_on.clone(),
+                total: mount.total.as_u64(),
+                available: mount.avail.as_u64(),
+            })
+        })
+        .collect()
+}
+
+// the folder lives on the mount with the longest mount point that contains it
+fn mount_of<'a>(mounts: &'a [Filesystem], path: &Path) -> Option<&'a Filesystem> {
+    let canonical = path.canonicalize().ok()?;
+    mounts.iter()
+        .filter(|mount| canonical.starts_with(Path::new(&mount.fs_mounted_on)))
+        .max_by_key(|mount| mount.fs_mounted_on.len())
+}
+
+// the bytes left on the disk that holds the folder, None if there is no such folder or mount
+pub fn available(path: &Path) -> Option<u64> {
+    let mounts = System::new().mounts().map_err(|error| warn!("could not get mounts: {}", error)).ok()?;
+    mount_of(&mounts, path).map(|mount| mount.avail.as_u64())
+}
+
+fn throttling() -> Option<Throttling> {
+    // looks like "throttled=0x50005", the lower bits are the current state, the upper ones what happened since boot
+    let output = tools::command("vcgencmd").arg("get_throttled").stdin(Stdio::null()).stderr(Stdio::null()).output().ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let bits = u32::from_str_radix(output.trim().strip_prefix("throttled=0x")?, 16).ok()?;
+    Some(Throttling {
+        under_voltage:          bits & 0x1 != 0,
+        frequency_capped:       bits & 0x2 != 0,
+        throttled:              bits & 0x4 != 0,
+        soft_temperature_limit: bits & 0x8 != 0,
+    })
+}
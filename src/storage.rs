@@ -0,0 +1,40 @@
+use std::env;
+use std::time::Duration;
+use actix_web::rt::time::sleep;
+use actix_web::web;
+use log::{info, warn};
+use crate::events::Event;
+use crate::files::Root;
+use crate::state::AppState;
+use crate::stats;
+
+const DEFAULT_MIN_FREE_MB: u64 = 2048;
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// checks the space left for downloads every minute, below MIN_FREE_DISK_MB new downloads wait and `disk.low` is sent
+pub async fn watch(state: web::Data<AppState>) {
+    let folder = Root::Download.folder();
+    let min_free = env::var("MIN_FREE_DISK_MB").ok().and_then(|mb| mb.parse().ok()).unwrap_or(DEFAULT_MIN_FREE_MB) * 1024 * 1024;
+    let mut low = false;
+    loop {
+        // an unknown mount is not a reason to stop the downloads
+        if let Some(available) = stats::available(folder) {
+            match (low, available < min_free) {
+                (false, true) => {
+                    warn!("only {} MB left in {:?}, pausing new downloads", available / 1024 / 1024, folder);
+                    state.download_manager.set_paused("low disk space", true);
+                    state.events.publish(Event::DiskSpaceLow { folder: "DOWNLOAD_FOLDER", available });
+                    low = true;
+                },
+                (true, false) => {
+                    info!("{} MB free in {:?} again, resuming downloads", available / 1024 / 1024, folder);
+                    state.download_manager.set_paused("low disk space", false);
+                    state.events.publish(Event::DiskSpaceRecovered { folder: "DOWNLOAD_FOLDER", available });
+                    low = false;
+                },
+                _ => {},
+            }
+        }
+        sleep(CHECK_INTERVAL).await;
+    }
+}
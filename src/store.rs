@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use log::{error, info};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+type Collections = BTreeMap<String, Map<String, Value>>;
+type Migration = fn(&mut Collections);
+
+// append only, the position of a migration is the schema version it migrates to
+const MIGRATIONS: &[Migration] = &[
+    |collections| for name in ["twitch_logins", "downloads", "dvbc_channels"] {
+        collections.entry(name.to_string()).or_default();
+    },
+    |collections| { collections.entry("podcasts".to_string()).or_default(); },
+    |collections| { collections.entry("media".to_string()).or_default(); },
+    |collections| { collections.entry("progress".to_string()).or_default(); },
+    |collections| { collections.entry("profiles".to_string()).or_default(); },
+    |collections| { collections.entry("settings".to_string()).or_default(); },
+    |collections| { collections.entry("viewing".to_string()).or_default(); },
+    |collections| { collections.entry("twitch_bookmarks".to_string()).or_default(); },
+    // the ids of the podcasts were hashed with the DefaultHasher, which can change with any Rust release
+    |collections| if let Some(podcasts) = collections.get_mut("podcasts") {
+        *podcasts = std::mem::take(podcasts).into_iter()
+            .map(|(id, mut podcast)| match podcast["url"].as_str().map(crate::podcast::hash) {
+                Some(rekeyed) => {
+                    podcast["id"] = Value::String(rekeyed.clone());
+                    (rekeyed, podcast)
+                },
+                None => (id, podcast),
+            })
+            .collect();
+    },
+];
+
+// the access and refresh tokens of the Twitch logins
+const SECRET_COLLECTIONS: [&str; 2] = ["twitch_logins", "lastfm"];
+
+// a small json file that holds everything that should survive a restart
+// every write goes straight to disk, so there is nothing to flush on shutdown
+pub struct Store {
+    path: PathBuf,
+    document: Mutex<Document>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Document {
+    version: usize,
+    collections: Collections,
+}
+
+impl Store {
+
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut document: Document = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Document::default(),
+            Err(error) => return Err(error),
+        };
+
+        migrate(&mut document).map_err(|error| io::Error::new(error.kind(), format!("{:?}: {}", path, error)))?;
+
+        let store = Self { path, document: Mutex::new(document) };
+        store.write(&store.document.lock().unwrap())?;
+        Ok(store)
+    }
+
+    // everything in the store, to move HomeBack to another machine
+    // without the secrets the collections with tokens are empty
+    pub fn backup(&self, with_secrets: bool) -> io::Result<Vec<u8>> {
+        let document = self.document.lock().unwrap();
+        if with_secrets {
+            return Ok(serde_json::to_vec_pretty(&*document)?);
+        }
+        let mut collections = document.collections.clone();
+        for name in SECRET_COLLECTIONS {
+            if let Some(collection) = collections.get_mut(name) {
+                collection.clear();
+            }
+        }
+        Ok(serde_json::to_vec_pretty(&Document { version: document.version, collections })?)
+    }
+
+    // replaces everything with a backup, older backups are migrated just like the file on startup
+    // the state that was already loaded from the store is not updated, HomeBack has to be restarted for that
+    pub fn restore(&self, backup: &[u8]) -> io::Result<()> {
+        let mut restored: Document = serde_json::from_slice(backup).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        migrate(&mut restored)?;
+        let mut document = self.document.lock().unwrap();
+        self.write(&restored)?;
+        info!("Restored {:?} from a backup", self.path);
+        *document = restored;
+        Ok(())
+    }
+
+    fn write(&self, document: &Document) -> io::Result<()> {
+        // write to a temporary file first, so a crash can't leave a half written store behind
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(document)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    fn update(&self, collection: &str, f: impl FnOnce(&mut Map<String, Value>)) {
+        let mut document = self.document.lock().unwrap();
+        f(document.collections.entry(collection.to_string()).or_default());
+        if let Err(err) = self.write(&document) {
+            error!("Could not write store {:?}: {}", self.path, err);
+        }
+    }
+}
+
+fn migrate(document: &mut Document) -> io::Result<()> {
+    if document.version > MIGRATIONS.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("schema version {}, but this build only knows {}", document.version, MIGRATIONS.len())));
+    }
+    for migration in &MIGRATIONS[document.version..] {
+        migration(&mut document.collections);
+    }
+    if document.version < MIGRATIONS.len() {
+        info!("Migrated from schema version {} to {}", document.version, MIGRATIONS.len());
+        document.version = MIGRATIONS.len();
+    }
+    Ok(())
+}
+
+// typed view on one collection of the store
+pub struct Repository<T> {
+    store: Arc<Store>,
+    collection: &'static str,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Repository<T> {
+    fn clone(&self) -> Self {
+        Self { store: self.store.clone(), collection: self.collection, _type: PhantomData }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Repository<T> {
+
+    pub fn new(store: Arc<Store>, collection: &'static str) -> Self {
+        Self { store, collection, _type: PhantomData }
+    }
+
+    pub fn get(&self, key: &str) -> Option<T> {
+        let document = self.store.document.lock().unwrap();
+        let value = document.collections.get(self.collection)?.get(key)?;
+        self.deserialize(key, value.clone())
+    }
+
+    pub fn all(&self) -> Vec<(String, T)> {
+        let document = self.store.document.lock().unwrap();
+        document.collections.get(self.collection)
+            .map(|entries| entries.iter()
+                .filter_map(|(key, value)| self.deserialize(key, value.clone()).map(|value| (key.clone(), value)))
+                .collect())
+            .unwrap_or_default()
+    }
+
+    pub fn put(&self, key: &str, value: &T) {
+        match serde_json::to_value(value) {
+            Ok(value) => self.store.update(self.collection, |entries| { entries.insert(key.to_string(), value); }),
+            Err(err) => error!("Could not serialize {}/{}: {}", self.collection, key, err),
+        }
+    }
+
+    // replaces the whole collection with a single write, for collections that are rebuilt at once
+    pub fn replace_all(&self, values: Vec<(String, T)>) {
+        let mut entries = Map::new();
+        for (key, value) in values {
+            match serde_json::to_value(&value) {
+                Ok(value) => { entries.insert(key, value); },
+                Err(err) => error!("Could not serialize {}/{}: {}", self.collection, key, err),
+            }
+        }
+        self.store.update(self.collection, |collection| *collection = entries);
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.store.update(self.collection, |entries| { entries.remove(key); });
+    }
+
+    fn deserialize(&self, key: &str, value: Value) -> Option<T> {
+        serde_json::from_value(value)
+            .map_err(|err| error!("Could not deserialize {}/{}: {}", self.collection, key, err))
+            .ok()
+    }
+}
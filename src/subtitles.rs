@@ -0,0 +1,168 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use log::info;
+use reqwest::{Client, RequestBuilder};
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+
+const API_URL: &str = "https://api.opensubtitles.com/api/v1";
+// the hash only looks at the start and the end of the file
+const HASH_CHUNK_SIZE: u64 = 64 * 1024;
+
+type Error = Box<dyn std::error::Error>;
+
+// finds subtitles on OpenSubtitles by the hash of the file, so they match the exact release
+pub struct OpenSubtitles {
+    client: Client,
+    url: String,
+    api_key: String,
+    login: Option<(String, String)>,
+    token: Mutex<Option<String>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Subtitle {
+    pub file_id: u64,
+    pub language: String,
+    pub release: String,
+    pub downloads: u64,
+    pub hash_match: bool,  // found by the hash instead of the name, so it is certainly in sync
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResponse {
+    data: Vec<SearchResult>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResult {
+    attributes: SubtitleAttributes,
+}
+
+#[derive(Deserialize, Debug)]
+struct SubtitleAttributes {
+    language: Option<String>,
+    #[serde(default)]
+    release: String,
+    #[serde(default)]
+    download_count: u64,
+    #[serde(default)]
+    moviehash_match: bool,
+    files: Vec<SubtitleFile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SubtitleFile {
+    file_id: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct DownloadResponse {
+    link: String,
+}
+
+impl OpenSubtitles {
+
+    pub fn from_env() -> Option<Self> {
+        let api_key = env::var("OPENSUBTITLES_API_KEY").ok()?;
+        let login = match (env::var("OPENSUBTITLES_USERNAME"), env::var("OPENSUBTITLES_PASSWORD")) {
+            (Ok(username), Ok(password)) => Some((username, password)),
+            _ => None,
+        };
+        Some(Self {
+            client: Client::builder().timeout(Duration::from_secs(10)).build().unwrap(),
+            url: env::var("OPENSUBTITLES_URL").unwrap_or(API_URL.to_string()),
+            api_key,
+            login,
+            token: Mutex::new(None),
+        })
+    }
+
+    fn request(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request
+            .header("Api-Key", &self.api_key)
+            .header("User-Agent", concat!("HomeBack v", env!("CARGO_PKG_VERSION")));
+        match &*self.token.lock().unwrap() {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    pub async fn search(&self, path: &Path, languages: &str) -> Result<Vec<Subtitle>, Error> {
+        let hash = hash_file(path)?;
+        let name = path.file_stem().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let response: SearchResponse = self.request(self.client.get(format!("{}/subtitles", self.url)))
+            .query(&[("moviehash", hash.as_str()), ("query", name.as_str()), ("languages", languages)])
+            .send().await?.error_for_status()?.json().await?;
+
+        let mut subtitles: Vec<Subtitle> = response.data.into_iter()
+            .filter_map(|result| {
+                let attributes = result.attributes;
+                Some(Subtitle {
+                    file_id: attributes.files.first()?.file_id,
+                    language: attributes.language.unwrap_or_default(),
+                    release: attributes.release,
+                    downloads: attributes.download_count,
+                    hash_match: attributes.moviehash_match,
+                })
+            })
+            .collect();
+        subtitles.sort_by(|a, b| b.hash_match.cmp(&a.hash_match).then(b.downloads.cmp(&a.downloads)));
+        Ok(subtitles)
+    }
+
+    // downloads the subtitle next to the media file, where mpv finds it on its own
+    pub async fn download(&self, file_id: u64, path: &Path) -> Result<PathBuf, Error> {
+        // downloads without a login are limited to very few per day
+        if self.login.is_some() && self.token.lock().unwrap().is_none() {
+            self.log_in().await?;
+        }
+        let response: DownloadResponse = self.request(self.client.post(format!("{}/download", self.url)))
+            .json(&json!({ "file_id": file_id }))
+            .send().await?.error_for_status()?.json().await?;
+        let subtitle = self.client.get(&response.link).send().await?.error_for_status()?.bytes().await?;
+
+        let target = path.with_extension("srt");
+        info!("saving subtitle {} as {:?}", file_id, target);
+        std::fs::write(&target, subtitle)?;
+        Ok(target)
+    }
+
+    async fn log_in(&self) -> Result<(), Error> {
+        #[derive(Deserialize)]
+        struct LoginResponse {
+            token: String,
+        }
+        let (username, password) = self.login.as_ref().unwrap();
+        let response: LoginResponse = self.request(self.client.post(format!("{}/login", self.url)))
+            .json(&json!({ "username": username, "password": password }))
+            .send().await?.error_for_status()?.json().await?;
+        *self.token.lock().unwrap() = Some(response.token);
+        Ok(())
+    }
+}
+
+// the size plus the sum of the first and last 64 KiB as little endian u64s
+// see https://trac.opensubtitles.org/projects/opensubtitles/wiki/HashSourceCodes
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    if size < HASH_CHUNK_SIZE * 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "file is too small to be hashed"));
+    }
+
+    let mut hash = size;
+    let mut buffer = vec![0; HASH_CHUNK_SIZE as usize];
+    for offset in [0, size - HASH_CHUNK_SIZE] {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+        for word in buffer.chunks_exact(8) {
+            hash = hash.wrapping_add(u64::from_le_bytes(word.try_into().unwrap()));
+        }
+    }
+    Ok(format!("{:016x}", hash))
+}
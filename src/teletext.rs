@@ -0,0 +1,95 @@
+use std::io;
+use std::process::Stdio;
+use std::time::Duration;
+use log::info;
+use serde::Serialize;
+use crate::dvbc::Channel;
+use crate::process;
+use crate::tools;
+
+// the pages are broadcast one after another, so it can take a while until the requested one comes around
+const MAX_WAIT_SECONDS: u32 = 15;
+// ffmpeg only counts the time of the stream, a router that stops sending would keep it waiting forever
+const READ_TIMEOUT: Duration = Duration::from_secs(MAX_WAIT_SECONDS as u64 + 5);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+pub struct TeletextPage {
+    pub channel: String,
+    pub page: u16,
+    pub lines: Vec<String>,
+}
+
+pub fn is_page(page: u16) -> bool {
+    (100..=899).contains(&page)
+}
+
+// decodes a page from the stream of the channel, this needs an ffmpeg built with libzvbi
+// returns None if the channel has no teletext or the page didn't come in time
+pub fn read_page(channel: &Channel, page: u16) -> io::Result<Option<TeletextPage>> {
+    let stream = match find_teletext_stream(&channel.url)? {
+        Some(stream) => stream,
+        None => return Ok(None),
+    };
+
+    info!("reading teletext page {} of {}", page, channel.name);
+    let child = process::scoped_command("teletext", "ffmpeg")
+        .arg("-hide_banner")
+        .arg("-loglevel").arg("error")
+        .arg("-txt_format").arg("text")
+        .arg("-txt_page").arg(page.to_string())
+        .arg("-t").arg(MAX_WAIT_SECONDS.to_string())
+        .arg("-i").arg(&channel.url)
+        .arg("-map").arg(format!("0:{}", stream))
+        .arg("-frames:s").arg("1")
+        .arg("-f").arg("srt")
+        .arg("-")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let pid = child.id();
+    process::register("teletext", pid);
+    let output = process::output_within(child, READ_TIMEOUT);
+    process::unregister(pid);
+    let output = output?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("ffmpeg exited with {}: {}", output.status, stderr.lines().last().unwrap_or_default())));
+    }
+    Ok(first_cue(&String::from_utf8_lossy(&output.stdout)).map(|lines| TeletextPage { channel: channel.name.clone(), page, lines }))
+}
+
+// the index of the first dvb_teletext stream
+fn find_teletext_stream(url: &str) -> io::Result<Option<u32>> {
+    let child = tools::command("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("s")
+        .arg("-show_entries").arg("stream=index,codec_name")
+        .arg("-of").arg("csv=p=0")
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let output = process::output_within(child, PROBE_TIMEOUT)?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("ffprobe exited with {}", output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| line.trim().split_once(','))
+        .find(|(_, codec)| *codec == "dvb_teletext")
+        .and_then(|(index, _)| index.parse().ok()))
+}
+
+// the text of the first subtitle in srt, which is the counter, the times and then the lines until an empty one
+fn first_cue(srt: &str) -> Option<Vec<String>> {
+    let text: Vec<String> = srt.lines()
+        .skip_while(|line| !line.contains("-->"))
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty())
+        .map(|line| line.trim_end().to_string())
+        .collect();
+    Some(text).filter(|text| !text.is_empty())
+}
@@ -0,0 +1,175 @@
+// What the tests share: a local HTTP server with canned responses, temporary folders and a clock that only moves when told to.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use actix_web::rt::time::sleep;
+use uuid::Uuid;
+use crate::download::Clock;
+
+// what the server answers on a path
+#[derive(Clone, Debug)]
+pub enum Fixture {
+    Body(Vec<u8>),
+    // sent a chunk at a time, for downloads that are still running while the test looks at them
+    // with ranges a `Range: bytes=<start>-` gets the rest of the body as a 206
+    Slow { body: Vec<u8>, chunk: usize, delay: Duration, ranges: bool },
+    Status(u16),
+}
+
+// a body that is the same on every run, large enough files can't be mistaken for each other
+pub fn body(size: usize, seed: u8) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+// answers every connection from its own thread, one request per connection
+pub struct MockServer {
+    address: SocketAddr,
+    fixtures: Arc<Mutex<HashMap<String, Fixture>>>,
+    hits: Arc<Mutex<HashMap<String, usize>>>,
+    headers: Arc<Mutex<HashMap<String, Headers>>>, // of the last request to the path
+}
+
+type Headers = HashMap<String, String>;
+
+impl MockServer {
+
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let fixtures: Arc<Mutex<HashMap<String, Fixture>>> = Arc::default();
+        let hits: Arc<Mutex<HashMap<String, usize>>> = Arc::default();
+        let headers: Arc<Mutex<HashMap<String, Headers>>> = Arc::default();
+        let (served, counted, received) = (fixtures.clone(), hits.clone(), headers.clone());
+        thread::spawn(move || for stream in listener.incoming().flatten() {
+            let (fixtures, hits, headers) = (served.clone(), counted.clone(), received.clone());
+            thread::spawn(move || serve(stream, &fixtures, &hits, &headers));
+        });
+        Self { address, fixtures, hits, headers }
+    }
+
+    // the url the fixture is served under
+    pub fn serve(&self, path: &str, fixture: Fixture) -> String {
+        self.fixtures.lock().unwrap().insert(path.to_string(), fixture);
+        format!("http://{}{}", self.address, path)
+    }
+
+    pub fn hits(&self, path: &str) -> usize {
+        self.hits.lock().unwrap().get(path).copied().unwrap_or(0)
+    }
+
+    // the header of the last request to the path, the name in lowercase
+    pub fn header(&self, path: &str, name: &str) -> Option<String> {
+        self.headers.lock().unwrap().get(path).and_then(|headers| headers.get(name).cloned())
+    }
+}
+
+fn serve(stream: TcpStream, fixtures: &Mutex<HashMap<String, Fixture>>, hits: &Mutex<HashMap<String, usize>>, headers: &Mutex<HashMap<String, Headers>>) {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let mut received = Headers::new();
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|read| read > 2) {
+        if let Some((name, value)) = header.split_once(':') {
+            received.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+        header.clear();
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/").to_string();
+    *hits.lock().unwrap().entry(path.clone()).or_default() += 1;
+    headers.lock().unwrap().insert(path.clone(), received.clone());
+
+    let mut stream = reader.into_inner();
+    let fixture = fixtures.lock().unwrap().get(&path).cloned().unwrap_or(Fixture::Status(404));
+    let head = |status: u16, length: usize| format!("HTTP/1.1 {} Fixture\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, length);
+    // a write fails once the client hung up, e.g. a cancelled download
+    let _ = match fixture {
+        Fixture::Body(body) => stream.write_all(head(200, body.len()).as_bytes()).and_then(|_| stream.write_all(&body)),
+        Fixture::Status(status) => stream.write_all(head(status, 0).as_bytes()),
+        Fixture::Slow { body, chunk, delay, ranges } => {
+            let start = received.get("range").filter(|_| ranges)
+                .and_then(|range| range.strip_prefix("bytes=")?.strip_suffix('-')?.parse::<usize>().ok())
+                .filter(|start| *start < body.len());
+            let head = match start {
+                Some(start) => format!("HTTP/1.1 206 Fixture\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                    body.len() - start, start, body.len() - 1, body.len()),
+                None => head(200, body.len()),
+            };
+            stream.write_all(head.as_bytes()).and_then(|_| {
+                for part in body[start.unwrap_or(0)..].chunks(chunk) {
+                    stream.write_all(part)?;
+                    stream.flush()?;
+                    thread::sleep(delay);
+                }
+                Ok(())
+            })
+        },
+    };
+}
+
+lazy_static! {
+    // for what HomeBack keeps at runtime, e.g. the PID_FILE, so the tests don't write into the working directory
+    pub static ref RUNTIME_FOLDER: TempFolder = TempFolder::new();
+}
+
+// removed with everything in it when dropped
+pub struct TempFolder(PathBuf);
+
+impl TempFolder {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("home_back-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TempFolder {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFolder {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+pub struct ManualClock(Mutex<Instant>);
+
+impl ManualClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self(Mutex::new(Instant::now())))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+// waits up to 5 seconds for the condition, the tasks under test run in between
+pub async fn eventually(mut condition: impl FnMut() -> bool) -> bool {
+    for _ in 0..500 {
+        if condition() {
+            return true;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    condition()
+}
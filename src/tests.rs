@@ -0,0 +1,190 @@
+use std::fs;
+use std::io;
+use std::process::Command;
+use actix_web::{test, App};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use futures::future::{self, BoxFuture, FutureExt};
+use serde_json::{json, Value};
+use super::*;
+use crate::auth::Auth;
+use crate::download::SystemClock;
+use crate::files::Folders;
+use crate::process::ProcessStarter;
+use crate::state::Parts;
+use crate::store::Store;
+use crate::testing::{Fixture, MockServer, TempFolder, RUNTIME_FOLDER};
+use crate::twitch::Twitch;
+use crate::twitch::twitch_http::{ApiRequest, TwitchHttp};
+
+// stands in for mpv, librespot and the chat, it runs until it is stopped
+struct Sleeping(&'static str);
+
+impl <Args> ProcessStarter<Args> for Sleeping {
+    fn kind(&self) -> &'static str { self.0 }
+    fn command(&self, _args: &Args) -> io::Result<Command> {
+        let mut command = Command::new("sleep");
+        command.arg("60");
+        Ok(command)
+    }
+}
+
+// none of the tested endpoints reach Twitch
+struct Offline;
+
+impl TwitchHttp for Offline {
+    fn send_blocking(&self, _request: ApiRequest) -> Result<reqwest::blocking::Response, reqwest::Error> {
+        Ok(::http::Response::builder().status(503).body(String::new()).unwrap().into())
+    }
+
+    fn send(&self, _request: ApiRequest) -> BoxFuture<'static, Result<reqwest::Response, reqwest::Error>> {
+        future::ready(Ok(::http::Response::builder().status(503).body(String::new()).unwrap().into())).boxed()
+    }
+}
+
+fn playlist(channels: &[(&str, &str)]) -> Fixture {
+    let entries: String = channels.iter().map(|(name, url)| format!("#EXTINF:0,{}\n#EXTVLCOPT:network-caching=1000\n{}\n", name, url)).collect();
+    Fixture::Body(format!("#EXTM3U\n{}", entries).into_bytes())
+}
+
+// the folders are only set once per process, so every state gets the same ones
+fn folders() -> Folders {
+    let folder = |name: &str| {
+        let folder = RUNTIME_FOLDER.join(name);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    };
+    Folders { scan: folder("scan"), download: folder("download"), web_base: folder("web"), preview: folder("preview") }
+}
+
+fn state(server: &MockServer, folder: &TempFolder, admin_token: Option<&str>) -> web::Data<AppState> {
+    let url = server.serve("/dvb/m3u/tvhd.m3u", playlist(&[("Das Erste HD", "rtsp://router/1"), ("ZDF HD", "rtsp://router/2")]));
+    server.serve("/dvb/m3u/tvsd.m3u", playlist(&[]));
+    server.serve("/dvb/m3u/radio.m3u", playlist(&[("Radio1", "rtsp://router/4")]));
+    let store = Arc::new(Store::open(folder.join("store.json")).unwrap());
+    let state = AppState::new(Parts {
+        router_url:   url.trim_end_matches("/dvb/m3u/tvhd.m3u").to_string(),
+        folders:      folders(),
+        twitch:       Twitch::new(store.clone(), Arc::new(Offline), "id".to_string(), "secret".to_string(), Vec::new()),
+        auth:         Auth::new(admin_token.map(str::to_string)),
+        store,
+        clock:        Arc::new(SystemClock),
+        chat:         Box::new(Sleeping("chat")),
+        spotify:      Box::new(Sleeping("librespot")),
+        video_player: Some(Box::new(Sleeping("videoplayer"))),
+    }).unwrap();
+    web::Data::new(state)
+}
+
+fn app(state: web::Data<AppState>) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error, InitError = ()>> {
+    App::new()
+        .app_data(validation::json_config())
+        .app_data(state)
+        .service(web::scope("/api/v1")
+            .service(get_version)
+            .configure(configure_api))
+}
+
+#[actix_web::test]
+async fn answers_with_the_version() {
+    let (server, folder) = (MockServer::start(), TempFolder::new());
+    let api = test::init_service(app(state(&server, &folder, None))).await;
+
+    let version: Value = test::call_and_read_body_json(&api, test::TestRequest::get().uri("/api/v1/version").to_request()).await;
+
+    assert_eq!(env!("CARGO_PKG_VERSION"), version["version"]);
+}
+
+#[actix_web::test]
+async fn lists_the_channels_of_the_router() {
+    let (server, folder) = (MockServer::start(), TempFolder::new());
+    let api = test::init_service(app(state(&server, &folder, None))).await;
+
+    // until the poller or a fresh request fetched them
+    let unknown = test::call_service(&api, test::TestRequest::get().uri("/api/v1/dvbc/tv").to_request()).await;
+    assert_eq!(StatusCode::NO_CONTENT, unknown.status());
+
+    let tv: Value = test::call_and_read_body_json(&api, test::TestRequest::get().uri("/api/v1/dvbc/tv?fresh=true").to_request()).await;
+    let radio: Value = test::call_and_read_body_json(&api, test::TestRequest::get().uri("/api/v1/dvbc/radio").to_request()).await;
+
+    assert_eq!(json!(["Das Erste HD", "ZDF HD"]), tv);
+    assert_eq!(json!(["Radio1"]), radio);
+    // the radio came from the cache
+    assert_eq!(1, server.hits("/dvb/m3u/radio.m3u"));
+}
+
+#[actix_web::test]
+async fn starts_and_stops_the_player() {
+    let (server, folder) = (MockServer::start(), TempFolder::new());
+    let state = state(&server, &folder, None);
+    let api = test::init_service(app(state.clone())).await;
+    test::call_service(&api, test::TestRequest::get().uri("/api/v1/dvbc/tv?fresh=true").to_request()).await;
+
+    let unknown = test::call_service(&api, test::TestRequest::put().uri("/api/v1/videoplayer").set_json(json!({ "type": "DvbC", "uri": "arte" })).to_request()).await;
+    assert_eq!(StatusCode::NOT_FOUND, unknown.status());
+
+    let started: Value = test::call_and_read_body_json(&api, test::TestRequest::put().uri("/api/v1/videoplayer").set_json(json!({ "type": "DvbC", "uri": "ZDF HD" })).to_request()).await;
+    assert_eq!(json!({ "type": "DvbC", "uri": "ZDF HD" }), started);
+    let running: Value = test::call_and_read_body_json(&api, test::TestRequest::get().uri("/api/v1/videoplayer").to_request()).await;
+    assert_eq!(started, running);
+    assert!(state.player_tuner.is_playing("ZDF HD"));
+
+    let stopped = test::call_service(&api, test::TestRequest::delete().uri("/api/v1/videoplayer").to_request()).await;
+    assert!(stopped.status().is_success());
+    let idle = test::call_service(&api, test::TestRequest::get().uri("/api/v1/videoplayer").to_request()).await;
+    assert_eq!(StatusCode::NO_CONTENT, idle.status());
+}
+
+#[actix_web::test]
+async fn rejects_invalid_requests() {
+    let (server, folder) = (MockServer::start(), TempFolder::new());
+    let api = test::init_service(app(state(&server, &folder, None))).await;
+
+    let malformed = test::call_service(&api, test::TestRequest::put().uri("/api/v1/videoplayer").insert_header(("Content-Type", "application/json")).set_payload("{").to_request()).await;
+    assert_eq!(StatusCode::BAD_REQUEST, malformed.status());
+    let invalid = test::call_service(&api, test::TestRequest::post().uri("/api/v1/profiles").set_json(json!({ "name": " " })).to_request()).await;
+    assert_eq!(StatusCode::BAD_REQUEST, invalid.status());
+    let namespace = test::call_service(&api, test::TestRequest::get().uri("/api/v1/settings/Not%20This").to_request()).await;
+    assert_eq!(StatusCode::BAD_REQUEST, namespace.status());
+}
+
+#[actix_web::test]
+async fn keeps_the_profiles_and_settings_in_the_store() {
+    let (server, folder) = (MockServer::start(), TempFolder::new());
+    let api = test::init_service(app(state(&server, &folder, None))).await;
+
+    let created = test::call_service(&api, test::TestRequest::post().uri("/api/v1/profiles").set_json(json!({ "name": " Kids " })).to_request()).await;
+    assert_eq!(StatusCode::CREATED, created.status());
+    let location = created.headers().get(http::header::LOCATION).unwrap().to_str().unwrap().to_string();
+    let profile: Value = test::read_body_json(created).await;
+    assert_eq!("Kids", profile["name"]);
+    let fetched: Value = test::call_and_read_body_json(&api, test::TestRequest::get().uri(&format!("/api/v1{}", location)).to_request()).await;
+    assert_eq!(profile, fetched);
+
+    test::call_service(&api, test::TestRequest::put().uri("/api/v1/settings/frontend").set_json(json!({ "theme": "dark" })).to_request()).await;
+    let settings: Value = test::call_and_read_body_json(&api, test::TestRequest::get().uri("/api/v1/settings/frontend").to_request()).await;
+    assert_eq!(json!({ "theme": "dark" }), settings);
+
+    // a new state on the same store still has them
+    let api = test::init_service(app(state(&server, &folder, None))).await;
+    let profiles: Value = test::call_and_read_body_json(&api, test::TestRequest::get().uri("/api/v1/profiles").to_request()).await;
+    assert_eq!(json!([profile]), profiles);
+}
+
+#[actix_web::test]
+async fn needs_the_admin_token_to_change_the_quiet_mode() {
+    let (server, folder) = (MockServer::start(), TempFolder::new());
+    let api = test::init_service(app(state(&server, &folder, Some("token")))).await;
+    let quiet_mode = || test::TestRequest::put().uri("/api/v1/admin/quiet-mode").set_json(json!({ "enabled": true }));
+
+    let anonymous = test::call_service(&api, quiet_mode().to_request()).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, anonymous.status());
+    let wrong = test::call_service(&api, quiet_mode().insert_header(("Authorization", "Bearer other")).to_request()).await;
+    assert_eq!(StatusCode::UNAUTHORIZED, wrong.status());
+
+    let admin: Value = test::call_and_read_body_json(&api, quiet_mode().insert_header(("Authorization", "Bearer token")).to_request()).await;
+    assert_eq!(json!({ "enabled": true }), admin);
+    let enabled: Value = test::call_and_read_body_json(&api, test::TestRequest::get().uri("/api/v1/admin/quiet-mode").to_request()).await;
+    assert_eq!(json!({ "enabled": true }), enabled);
+}
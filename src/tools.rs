@@ -0,0 +1,74 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// every external program can be moved with <NAME>_PATH (e.g. STREAMLINK_PATH=/usr/local/bin/streamlink) and get extra
+// arguments in front of the ones HomeBack passes with <NAME>_ARGS (e.g. MPV_PATH=flatpak MPV_ARGS="run io.mpv.Mpv")
+// cec-client is configured with CEC_CLIENT_PATH
+fn variable(name: &str, suffix: &str) -> String {
+    format!("{}_{}", name.to_uppercase().replace('-', "_"), suffix)
+}
+
+fn configured(name: &str) -> Option<String> {
+    env::var(variable(name, "PATH")).ok().filter(|path| !path.is_empty())
+}
+
+// whether the tool was moved with <NAME>_PATH
+pub fn is_configured(name: &str) -> bool {
+    configured(name).is_some()
+}
+
+// what is started for the tool, either the configured path or the name, which is looked up in the PATH
+pub fn program(name: &str) -> OsString {
+    configured(name).unwrap_or(name.to_string()).into()
+}
+
+pub fn extra_args(name: &str) -> Vec<String> {
+    env::var(variable(name, "ARGS")).unwrap_or_default().split_whitespace().map(str::to_string).collect()
+}
+
+// a command for the tool that already has the extra args
+pub fn command(name: &str) -> Command {
+    let mut command = Command::new(program(name));
+    command.args(extra_args(name));
+    command
+}
+
+// where the tool would be started from, None if it can't be found
+pub fn path(name: &str) -> Option<PathBuf> {
+    let program = PathBuf::from(program(name));
+    if program.components().count() > 1 {
+        return Some(program).filter(|path| path.is_file());
+    }
+    find_in_path(&program.to_string_lossy())
+}
+
+// on Windows the binaries end with .exe, which the names leave out
+pub fn find_in_path(binary: &str) -> Option<PathBuf> {
+    let names = [binary.to_string(), format!("{}{}", binary, env::consts::EXE_SUFFIX)];
+    env::var_os("PATH")
+        .and_then(|paths| env::split_paths(&paths).flat_map(|dir| names.iter().map(move |name| dir.join(name))).find(|path| path.is_file()))
+}
+
+// a missing tool only breaks the features using it, but a configured path that doesn't exist is a typo
+pub fn validate<'a>(names: impl IntoIterator<Item = &'a str>) {
+    for name in names {
+        if let Some(configured) = configured(name) {
+            if path(name).is_none() {
+                panic!("{} {} not found", variable(name, "PATH"), configured);
+            }
+        }
+    }
+}
+
+// writes a file only this user can read, for secrets a tool would otherwise get as an argument, which anyone can see
+pub fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    // a leftover from another user would keep its permissions
+    let _ = fs::remove_file(path);
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(content.as_bytes())
+}
@@ -0,0 +1,129 @@
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::debug;
+use serde::Serialize;
+
+// what a FRITZ!Box with a cable tuner streams at once
+const DEFAULT_TUNERS: usize = 4;
+const PLAYER: &str = "player";
+
+// the streams the cable tuner can deliver at once, everything that opens a channel takes one
+pub struct Tuners {
+    capacity: usize,
+    used: Mutex<Vec<(u64, TunerUse)>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TunerUse {
+    pub purpose: &'static str,  // player, preview, teletext, probe, relay, radio relay, cast or recording
+    pub channel: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TunerStatus {
+    capacity: usize,
+    used: Vec<TunerUse>,
+}
+
+// why a channel can't be opened, with what occupies the tuners
+#[derive(Serialize, Debug)]
+pub struct TunersBusy {
+    pub occupied: Vec<TunerUse>,
+}
+
+// frees its tuner when dropped
+pub struct TunerLease {
+    tuners: Arc<Tuners>,
+    id: u64,
+}
+
+impl Tuners {
+
+    pub fn from_env() -> Arc<Self> {
+        Self::new(env::var("DVBC_TUNERS").ok().and_then(|tuners| tuners.parse().ok()).unwrap_or(DEFAULT_TUNERS))
+    }
+
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self { capacity, used: Mutex::default(), next_id: AtomicU64::new(0) })
+    }
+
+    pub fn acquire(self: &Arc<Self>, purpose: &'static str, channel: &str) -> Result<TunerLease, TunersBusy> {
+        let mut used = self.used.lock().unwrap();
+        if used.len() >= self.capacity {
+            return Err(TunersBusy { occupied: used.iter().map(|(_, tuner)| tuner.clone()).collect() });
+        }
+        Ok(self.lease(&mut used, purpose, channel))
+    }
+
+    // the player gives up its tuner when it switches the channel, so that one counts as free
+    fn acquire_player(self: &Arc<Self>, channel: &str) -> Result<TunerLease, TunersBusy> {
+        let mut used = self.used.lock().unwrap();
+        if used.iter().filter(|(_, tuner)| tuner.purpose != PLAYER).count() >= self.capacity {
+            return Err(TunersBusy { occupied: used.iter().map(|(_, tuner)| tuner.clone()).collect() });
+        }
+        Ok(self.lease(&mut used, PLAYER, channel))
+    }
+
+    fn lease(self: &Arc<Self>, used: &mut Vec<(u64, TunerUse)>, purpose: &'static str, channel: &str) -> TunerLease {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        debug!("tuner {} of {} for the {} of {}", used.len() + 1, self.capacity, purpose, channel);
+        used.push((id, TunerUse { purpose, channel: channel.to_string() }));
+        TunerLease { tuners: self.clone(), id }
+    }
+
+    pub fn status(&self) -> TunerStatus {
+        TunerStatus { capacity: self.capacity, used: self.used.lock().unwrap().iter().map(|(_, tuner)| tuner.clone()).collect() }
+    }
+}
+
+impl Drop for TunerLease {
+    fn drop(&mut self) {
+        self.tuners.used.lock().unwrap().retain(|(id, _)| *id != self.id);
+    }
+}
+
+// the tuner of the player, taken before a channel starts and held until the player stops
+pub struct PlayerTuner {
+    tuners: Arc<Tuners>,
+    starting: Mutex<()>, // one start at a time, so the reserved tuner goes to the channel it was taken for
+    reserved: Mutex<Option<TunerLease>>,
+    playing: Mutex<Option<TunerLease>>,
+}
+
+impl PlayerTuner {
+
+    pub fn new(tuners: Arc<Tuners>) -> Arc<Self> {
+        Arc::new(Self { tuners, starting: Mutex::default(), reserved: Mutex::default(), playing: Mutex::default() })
+    }
+
+    // takes a tuner for the channel and starts it, the tuner is freed again if it doesn't start
+    pub fn start<T>(&self, channel: &str, start: impl FnOnce() -> T) -> Result<T, TunersBusy> {
+        let _starting = self.starting.lock().unwrap();
+        *self.reserved.lock().unwrap() = Some(self.tuners.acquire_player(channel)?);
+        let started = start();
+        self.reserved.lock().unwrap().take();
+        Ok(started)
+    }
+
+    // from the hooks of the player
+    pub fn started(&self) {
+        if let Some(lease) = self.reserved.lock().unwrap().take() {
+            *self.playing.lock().unwrap() = Some(lease);
+        }
+    }
+
+    pub fn stopped(&self) {
+        self.playing.lock().unwrap().take();
+    }
+
+    // whether the player holds its tuner for the channel, other streams of that channel share it
+    pub fn is_playing(&self, channel: &str) -> bool {
+        let playing = self.playing.lock().unwrap();
+        playing.as_ref().is_some_and(|lease| self.tuners.used.lock().unwrap().iter().any(|(id, tuner)| *id == lease.id && tuner.channel == channel))
+    }
+}
+
+#[cfg(test)]
+mod tests;
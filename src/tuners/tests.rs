@@ -0,0 +1,66 @@
+use super::*;
+
+fn purposes(tuners: &Tuners) -> Vec<&'static str> {
+    tuners.used.lock().unwrap().iter().map(|(_, tuner)| tuner.purpose).collect()
+}
+
+#[test]
+fn the_player_keeps_its_tuner_while_it_plays() {
+    let tuners = Tuners::new(2);
+    let player = PlayerTuner::new(tuners.clone());
+
+    player.start("ZDF HD", || player.started()).unwrap();
+    assert_eq!(vec!["player"], purposes(&tuners));
+
+    player.stopped();
+    assert!(purposes(&tuners).is_empty());
+}
+
+#[test]
+fn a_channel_that_does_not_start_frees_its_tuner() {
+    let tuners = Tuners::new(1);
+    let player = PlayerTuner::new(tuners.clone());
+
+    player.start("ZDF HD", || ()).unwrap();
+
+    assert!(purposes(&tuners).is_empty());
+    assert!(tuners.acquire("preview", "arte").is_ok());
+}
+
+#[test]
+fn switching_the_channel_reuses_the_tuner_of_the_player() {
+    let tuners = Tuners::new(2);
+    let player = PlayerTuner::new(tuners.clone());
+    let _preview = tuners.acquire("preview", "arte").unwrap();
+    player.start("ZDF HD", || player.started()).unwrap();
+
+    // like the player, which stops the old channel right before the next one starts
+    player.start("Das Erste HD", || { player.stopped(); player.started() }).unwrap();
+
+    assert_eq!(vec!["preview", "player"], purposes(&tuners));
+}
+
+#[test]
+fn the_player_gets_no_tuner_the_others_use() {
+    let tuners = Tuners::new(1);
+    let player = PlayerTuner::new(tuners.clone());
+    let _preview = tuners.acquire("preview", "arte").unwrap();
+
+    let busy = player.start("ZDF HD", || panic!("started without a tuner")).unwrap_err();
+
+    assert_eq!("arte", busy.occupied[0].channel);
+    assert_eq!(vec!["preview"], purposes(&tuners));
+}
+
+#[test]
+fn other_streams_of_the_playing_channel_share_its_tuner() {
+    let tuners = Tuners::new(1);
+    let player = PlayerTuner::new(tuners.clone());
+
+    player.start("Bayern 3", || player.started()).unwrap();
+
+    assert!(player.is_playing("Bayern 3"));
+    assert!(!player.is_playing("Antenne Bayern"));
+    player.stopped();
+    assert!(!player.is_playing("Bayern 3"));
+}
@@ -4,10 +4,26 @@ mod twitch_auth;
 use twitch_auth::*;
 mod twitch_follows;
 use twitch_follows::*;
+pub(crate) mod twitch_http;
+use twitch_http::*;
+#[cfg(test)]
+mod tests;
 
+use crate::events::Event;
+use crate::state::AppState;
+use crate::store::{Repository, Store};
+use crate::viewing;
+
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use actix_web::rt::time::sleep;
+use actix_web::web;
 use uuid::Uuid;
-use log::info;
+use log::{info, warn};
 use itertools::Itertools;
 use serde::{Serialize, Deserialize};
 
@@ -15,14 +31,55 @@ pub struct Twitch {
     connections: FrontendConnections,
     auth_client: TwitchAuthClient,
     follows: TwitchFollows,
+    live: Mutex<Option<HashSet<String>>>, // user ids of the followed streams that were live on the last check
+    bookmarks: Repository<Bookmark>,
+    // per login, only for the ones a frontend asked for changes
+    snapshots: Mutex<HashMap<Uuid, LiveSnapshot>>,
+}
+
+const LIVE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// only reading, the scopes that write to the account are opted into with TWITCH_SCOPES:
+// channel:manage:broadcast for the stream markers of bookmarks, if the user is the broadcaster or an editor, and clips:edit for clips
+const DEFAULT_SCOPES: &str = "user:read:follows";
+const MAX_LIVE_CHANGES: usize = 200;
+
+// what was live on the last poll and how that changed over time
+struct LiveSnapshot {
+    taken: u64,
+    live: HashMap<String, String>, // user id to login
+    changes: VecDeque<LiveChange>,
+    // changes before this are not known, because the first snapshot was taken then or they were dropped
+    known_since: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LiveChange {
+    channel: String,
+    live: bool,
+    at: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LiveChanges {
+    changes: Vec<LiveChange>,
+    // the since for the next request
+    until: u64,
+    // false if changes before since could be missing, then the full list has to be fetched again
+    complete: bool,
 }
 
 #[derive(Serialize, Debug)]
 pub struct LoginResponse {
-    id: Uuid,
+    pub id: Uuid,
     logged_in: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     verification_uri: Option<String>,
+    // to type in at the verification uri, e.g. when it is opened on a phone instead of scanned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_code: Option<String>,
+    // what the login may do, the frontend enables its features by them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scopes: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Debug)]
@@ -33,6 +90,30 @@ pub struct FollowResponse {
     stream: Stream,
 }
 
+#[derive(Serialize, Debug)]
+pub struct LiveGame {
+    game_id: String,
+    game_name: String,
+    streams: usize,
+    viewers: u64,
+    top: FollowResponse, // the stream with the most viewers
+}
+
+// a moment in a stream to find later in the VOD
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Bookmark {
+    pub id: Uuid,
+    pub stream: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub created: u64,
+    // seconds since the broadcast started, only known with a login
+    pub offset: Option<u64>,
+    // the id of the stream marker, if the login was allowed to create one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub marker: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 struct Data<T> {
     data: Vec<T>,
@@ -51,78 +132,174 @@ struct Pagination {
 
 impl Twitch {
 
-    pub fn new() -> Self {
-        let client_id: String = env::var("TWITCH_CLIENT_ID").expect("TWITCH_CLIENT_ID not set");
-        let client_secret = env::var("TWITCH_CLIENT_SECRET").expect("TWITCH_CLIENT_SECRET not set");
-        return Self {connections: FrontendConnections::new(), follows: TwitchFollows::new(&client_id), auth_client: TwitchAuthClient::new(client_id, client_secret)};
+    pub fn from_env(store: Arc<Store>) -> io::Result<Self> {
+        let not_set = |name: &str| io::Error::new(io::ErrorKind::NotFound, format!("{} not set", name));
+        let client_id: String = env::var("TWITCH_CLIENT_ID").map_err(|_| not_set("TWITCH_CLIENT_ID"))?;
+        let client_secret = env::var("TWITCH_CLIENT_SECRET").map_err(|_| not_set("TWITCH_CLIENT_SECRET"))?;
+        let scopes = env::var("TWITCH_SCOPES").unwrap_or(DEFAULT_SCOPES.to_string())
+            .split([' ', ','])
+            .filter(|scope| !scope.is_empty())
+            .map(str::to_string)
+            .collect();
+        let http = Arc::new(ReqwestHttp::new(&client_id));
+        Ok(Self::new(store, http, client_id, client_secret, scopes))
+    }
+
+    pub fn new(store: Arc<Store>, http: Arc<dyn TwitchHttp>, client_id: String, client_secret: String, scopes: Vec<String>) -> Self {
+        let connections = FrontendConnections::new(Repository::new(store.clone(), "twitch_logins"));
+        let bookmarks = Repository::new(store, "twitch_bookmarks");
+        Self {connections, follows: TwitchFollows::new(http.clone()), auth_client: TwitchAuthClient::new(http, client_id, client_secret, scopes), live: Mutex::new(None), bookmarks, snapshots: Mutex::default()}
     }
 
     pub fn create_user_login(&self) -> Result<LoginResponse, reqwest::Error> {
         let auth_request = self.auth_client.create_authorization_request()?;
         let verification_uri = auth_request.verification_uri.clone();
+        let user_code = auth_request.user_code.clone();
         let id = self.connections.create(auth_request);
 
-        let login_response = LoginResponse { id, logged_in: false, verification_uri: Some(verification_uri) };
+        let login_response = LoginResponse { id, logged_in: false, verification_uri: Some(verification_uri), user_code: Some(user_code), scopes: None };
         info!("Starting User Login: {:?}", &login_response);
         Ok(login_response)
     }
 
     pub fn get_user_login(&self, id: Uuid) -> Option<LoginResponse> {
         self.get_user_login_from_pending(id)
-        .or_else(|| self.get_valid_access_token(&id).map(|_token| LoginResponse{id, logged_in: true, verification_uri: None}))
+        .or_else(|| self.get_valid_access_token(&id).map(|(_, validation)| LoginResponse{id, logged_in: true, verification_uri: None, user_code: None, scopes: Some(validation.scopes)}))
     }
 
     fn get_user_login_from_pending(&self, id: Uuid) -> Option<LoginResponse> {
-        let (device_code, verification_uri) = self.connections.get_pending(&id)?;
+        let (device_code, verification_uri, user_code) = self.connections.get_pending(&id)?;
    
         match self.auth_client.activate_authorization_request(&device_code) {
             Ok(Some(auth)) => {
                 info!("User Authentication Successful: {:?}", &auth);
+                let scopes = auth.scope.clone();
                 self.connections.log_in(id, auth);
-                Some(LoginResponse { id, logged_in: true, verification_uri: None })
+                Some(LoginResponse { id, logged_in: true, verification_uri: None, user_code: None, scopes: Some(scopes) })
             },
-            Ok(None) =>  Some(LoginResponse{id, logged_in: false, verification_uri: Some(verification_uri)}),
+            Ok(None) =>  Some(LoginResponse{id, logged_in: false, verification_uri: Some(verification_uri), user_code: Some(user_code), scopes: None}),
             Err(_) => None, // in theory we could also delete the pending login here, but that's not worth the effort
         }
      }
 
      fn get_valid_access_token(&self, id: &Uuid) -> Option<(String, Validation)> {
          let (access_token, refresh_token) = self.connections.get_logged_in(id)?;
-         let valid_token = self.validate_token(id, access_token, refresh_token);
-         if valid_token.is_none() {
-            info!("User Authentication for {} has become invalid", id);
-            self.connections.remove(id);
+         match self.validate_token(id, access_token, refresh_token) {
+            Ok(Some(valid_token)) => Some(valid_token),
+            Ok(None) => {
+                info!("User Authentication for {} has become invalid", id);
+                self.connections.remove(id);
+                None
+            },
+            // twitch being unreachable or rate limiting is no reason to log out
+            Err(error) => {
+                warn!("Could not validate the User Authentication for {}: {}", id, error);
+                None
+            },
          }
-         valid_token
      }
 
-     fn validate_token(&self, id: &Uuid, access_token: String, refresh_token: String) -> Option<(String, Validation)> {        
-        match self.auth_client.validate_authorization(&access_token) {
-            Ok(Some(validation)) => Some((access_token, validation)),
-            Ok(None) => {
-                info!("User Authentication for {}/{} expired, attempting refresh", id, &access_token);
-                let new_auth = self.auth_client.refresh_authorization(&refresh_token).ok()?;
-                let new_token = new_auth.access_token.clone();
-                let validation = self.auth_client.validate_authorization(&new_token).ok()??;
-
-                info!("User Authentication Refresh Successful: {}/{:?}", &id, &new_auth);
-                self.connections.update_logged_in(id, new_auth)?;
-                Some((new_token, validation))
-            },
-            Err(_) => None,
+     fn validate_token(&self, id: &Uuid, access_token: String, refresh_token: String) -> Result<Option<(String, Validation)>, reqwest::Error> {
+        if let Some(validation) = self.auth_client.validate_authorization(&access_token)? {
+            return Ok(Some((access_token, validation)));
+        }
+        info!("User Authentication for {}/{} expired, attempting refresh", id, &access_token);
+        let new_auth = match self.auth_client.refresh_authorization(&refresh_token) {
+            Ok(new_auth) => new_auth,
+            // a refresh token that was revoked or already used
+            Err(error) if error.status().is_some_and(|status| status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let new_token = new_auth.access_token.clone();
+        let Some(validation) = self.auth_client.validate_authorization(&new_token)? else {
+            return Ok(None);
+        };
+
+        info!("User Authentication Refresh Successful: {}/{:?}", &id, &new_auth);
+        if self.connections.update_logged_in(id, new_auth).is_none() {
+            return Ok(None);
         }
+        Ok(Some((new_token, validation)))
      }
 
-    pub fn get_online_following(&self, id: Uuid) -> Result<Option<Vec<FollowResponse>>, reqwest::Error> {
-        if let Some((access_token, validation)) = self.get_valid_access_token(&id) {
-            
-            let following = self.follows.get_following(&access_token, &validation.user_id, &validation.login)?;
-            let online = self.follows.query_streams(&access_token, &following)?
+    // this uses the blocking auth client
+    pub fn access_token(&self, id: &Uuid) -> Option<String> {
+        self.get_valid_access_token(id).map(|(access_token, _)| access_token)
+    }
+
+    // the access token and the scopes the login was granted, for features that need a scope TWITCH_SCOPES has to opt into
+    pub fn access_token_with_scopes(&self, id: &Uuid) -> Option<(String, Vec<String>)> {
+        self.get_valid_access_token(id).map(|(access_token, validation)| (access_token, validation.scopes))
+    }
+
+    // remembers the current moment of the stream. With an access token the offset into the broadcast is looked up
+    // and a stream marker is created, which only works if the user is the broadcaster or one of their editors
+    pub async fn bookmark(&self, stream: String, description: Option<String>, access_token: Option<String>) -> Bookmark {
+        let created = now();
+        let mut bookmark = Bookmark { id: Uuid::new_v4(), stream, description, created, offset: None, marker: None };
+        if let Some(access_token) = access_token {
+            match self.follows.query_stream(&access_token, &bookmark.stream).await {
+                Ok(Some(live)) => {
+                    let started_at = live.extra.get("started_at").and_then(|value| value.as_str()).and_then(parse_timestamp);
+                    bookmark.offset = started_at.map(|started_at| created.saturating_sub(started_at));
+                    match self.follows.create_marker(&access_token, &live.user_id, bookmark.description.as_deref().unwrap_or_default()).await {
+                        Ok(Some(marker)) => {
+                            bookmark.offset = Some(marker.position_seconds);
+                            bookmark.marker = Some(marker.id);
+                        },
+                        Ok(None) => {},
+                        Err(error) => info!("Could not create a stream marker on {}: {}", bookmark.stream, error),
+                    }
+                },
+                Ok(None) => info!("{} is not live, bookmarking without an offset", bookmark.stream),
+                Err(error) => warn!("Could not look up the stream {}: {}", bookmark.stream, error),
+            }
+        }
+        info!("Bookmarked {:?}", bookmark);
+        self.bookmarks.put(&bookmark.id.to_string(), &bookmark);
+        bookmark
+    }
+
+    // clips the last seconds of the stream, None if it isn't live
+    pub async fn clip(&self, stream: &str, access_token: &str) -> Result<Option<Clip>, reqwest::Error> {
+        let Some(live) = self.follows.query_stream(access_token, stream).await? else {
+            return Ok(None);
+        };
+        let clip = self.follows.create_clip(access_token, &live.user_id).await?;
+        if let Some(clip) = &clip {
+            info!("Created the clip {} of {}", clip.id, stream);
+        }
+        Ok(clip)
+    }
+
+    // the bookmarks, newest first
+    pub fn bookmarks(&self) -> Vec<Bookmark> {
+        let mut bookmarks: Vec<Bookmark> = self.bookmarks.all().into_iter().map(|(_, bookmark)| bookmark).collect();
+        bookmarks.sort_by_key(|bookmark| std::cmp::Reverse(bookmark.created));
+        bookmarks
+    }
+
+    pub fn delete_bookmark(&self, id: &Uuid) -> bool {
+        let known = self.bookmarks.get(&id.to_string()).is_some();
+        self.bookmarks.remove(&id.to_string());
+        known
+    }
+
+    pub fn clear_follow_cache(&self) {
+        self.follows.clear_cache();
+    }
+
+    // the token from valid_access_token, None if the login isn't valid
+    pub async fn get_online_following(&self, token: Option<(String, Validation)>) -> Result<Option<Vec<FollowResponse>>, reqwest::Error> {
+        if let Some((access_token, validation)) = token {
+
+            let following = self.follows.get_following(&access_token, &validation.user_id, &validation.login).await?;
+            let online = self.follows.query_streams(&access_token, &following).await?
                 .into_iter()
-                .map(|stream| {
-                    let user = following.iter().find(|user| user.id == stream.user_id)
-                        .expect(&format!("Twitch API Response to Streams contained a Stream that was not in the Request: {:?}", stream));
-                    FollowResponse { profile_image_url: user.profile_image_url.clone(), offline_image_url: user.offline_image_url.clone(), stream }
+                // a stream that was not asked for is Twitch's mistake, it is left out instead of failing the whole list
+                .filter_map(|stream| {
+                    let user = following.iter().find(|user| user.id == stream.user_id)?;
+                    Some(FollowResponse { profile_image_url: user.profile_image_url.clone(), offline_image_url: user.offline_image_url.clone(), stream })
                 }).collect_vec();
     
             info!("Checked the {} streams {} is following. {} are online", following.len(), validation.login, online.len());
@@ -131,4 +308,167 @@ impl Twitch {
             Ok(None)
         }
     }
-}
\ No newline at end of file
+
+    // the online follows grouped by game, the games with the most streams first
+    pub async fn get_live_by_game(&self, token: Option<(String, Validation)>) -> Result<Option<Vec<LiveGame>>, reqwest::Error> {
+        let Some(online) = self.get_online_following(token).await? else {
+            return Ok(None);
+        };
+        let field = |stream: &FollowResponse, name: &str| stream.stream.extra.get(name).and_then(|value| value.as_str()).unwrap_or_default().to_string();
+        let viewers = |stream: &FollowResponse| stream.stream.extra.get("viewer_count").and_then(|value| value.as_u64()).unwrap_or(0);
+        let games = online.into_iter()
+            // streams without a category have an empty game_id, they end up in a group of their own
+            .into_group_map_by(|stream| field(stream, "game_id"))
+            .into_iter()
+            .map(|(game_id, streams)| {
+                let count = streams.len();
+                let total = streams.iter().map(viewers).sum();
+                let top = streams.into_iter().max_by_key(viewers).unwrap();
+                LiveGame { game_id, game_name: field(&top, "game_name"), streams: count, viewers: total, top }
+            })
+            .sorted_by(|a, b| b.streams.cmp(&a.streams).then(b.viewers.cmp(&a.viewers)))
+            .collect();
+        Ok(Some(games))
+    }
+
+    fn snapshot_outdated(&self, id: &Uuid) -> bool {
+        self.snapshots.lock().unwrap().get(id).is_none_or(|snapshot| now() >= snapshot.taken + LIVE_CHECK_INTERVAL.as_secs())
+    }
+
+    fn live_changes(&self, id: &Uuid, since: u64) -> Option<LiveChanges> {
+        let snapshots = self.snapshots.lock().unwrap();
+        let snapshot = snapshots.get(id)?;
+        Some(LiveChanges {
+            changes: snapshot.changes.iter().filter(|change| change.at > since).cloned().collect(),
+            until: snapshot.taken,
+            complete: since >= snapshot.known_since,
+        })
+    }
+
+    // false if the login isn't valid anymore
+    async fn take_snapshot(&self, id: Uuid, token: Option<(String, Validation)>) -> Result<bool, reqwest::Error> {
+        let Some((access_token, validation)) = token else {
+            self.snapshots.lock().unwrap().remove(&id);
+            return Ok(false);
+        };
+        let following = self.follows.get_following(&access_token, &validation.user_id, &validation.login).await?;
+        let live: HashMap<String, String> = self.follows.query_streams(&access_token, &following).await?
+            .into_iter()
+            .map(|stream| {
+                let login = stream.extra.get("user_login").and_then(|login| login.as_str()).unwrap_or_default().to_string();
+                (stream.user_id, login)
+            })
+            .collect();
+
+        let taken = now();
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let snapshot = snapshots.entry(id).or_insert_with(|| LiveSnapshot { taken, live: live.clone(), changes: VecDeque::new(), known_since: taken });
+        let went_live = live.iter().filter(|(user_id, _)| !snapshot.live.contains_key(*user_id)).map(|(_, login)| (login.clone(), true));
+        let went_offline = snapshot.live.iter().filter(|(user_id, _)| !live.contains_key(*user_id)).map(|(_, login)| (login.clone(), false));
+        let changes: Vec<(String, bool)> = went_live.chain(went_offline).collect();
+        for (channel, live) in changes {
+            snapshot.changes.push_back(LiveChange { channel, live, at: taken });
+        }
+        while snapshot.changes.len() > MAX_LIVE_CHANGES {
+            let dropped = snapshot.changes.pop_front().unwrap();
+            snapshot.known_since = dropped.at;
+        }
+        snapshot.live = live;
+        snapshot.taken = taken;
+        Ok(true)
+    }
+
+    // this uses the blocking auth client
+    fn valid_access_tokens(&self) -> Vec<(String, Validation)> {
+        self.connections.logged_in_ids().iter().filter_map(|id| self.get_valid_access_token(id)).collect()
+    }
+
+    // the streams followed by any logged in user that went live since the last call, the first call only remembers what is live
+    async fn check_went_live(&self, tokens: Vec<(String, Validation)>) -> Result<Vec<Stream>, reqwest::Error> {
+        let mut streams = Vec::new();
+        for (access_token, validation) in tokens {
+            let following = self.follows.get_following(&access_token, &validation.user_id, &validation.login).await?;
+            streams.extend(self.follows.query_streams(&access_token, &following).await?);
+        }
+
+        let now_live: HashSet<String> = streams.iter().map(|stream| stream.user_id.clone()).collect();
+        let previously_live = self.live.lock().unwrap().replace(now_live);
+        Ok(match previously_live {
+            Some(previously_live) => streams.into_iter()
+                .filter(|stream| !previously_live.contains(&stream.user_id))
+                .unique_by(|stream| stream.user_id.clone())
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+}
+
+// the blocking auth client can't run on the async runtime
+pub async fn valid_access_token(state: &web::Data<AppState>, id: Uuid) -> Option<(String, Validation)> {
+    web::block({ let state = state.clone(); move || state.twitch.get_valid_access_token(&id) }).await.ok().flatten()
+}
+
+// the followed channels that went live or offline since the given time, by comparing the snapshots of the server side polling
+// none if the login is not valid
+pub async fn get_live_changes(state: &web::Data<AppState>, id: Uuid, since: u64) -> Result<Option<LiveChanges>, reqwest::Error> {
+    if state.twitch.snapshot_outdated(&id) {
+        let token = valid_access_token(state, id).await;
+        if !state.twitch.take_snapshot(id, token).await? {
+            return Ok(None);
+        }
+    }
+    Ok(state.twitch.live_changes(&id, since))
+}
+
+// the logins frontends asked for changes, so they are kept up to date between their requests
+async fn refresh_snapshots(state: &web::Data<AppState>) {
+    let ids: Vec<Uuid> = state.twitch.snapshots.lock().unwrap().keys().copied().collect();
+    for id in ids {
+        let token = valid_access_token(state, id).await;
+        if let Err(error) = state.twitch.take_snapshot(id, token).await {
+            warn!("Could not check which Twitch streams of {} changed: {}", id, error);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+// helix timestamps look like 2024-01-31T18:02:45Z
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+    let date: Vec<u32> = date.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let time: Vec<u64> = time.split('.').next()?.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+    let days = viewing::days_from_civil(date[0] as i64, date[1], date[2]);
+    u64::try_from(days).ok().map(|days| days * 24 * 60 * 60 + time[0] * 60 * 60 + time[1] * 60 + time[2])
+}
+
+// publishes a twitch.live event whenever a followed channel goes live, but only if someone listens for events
+// also keeps the snapshots for the changes endpoint up to date
+pub async fn watch_live(state: web::Data<AppState>) {
+    loop {
+        sleep(LIVE_CHECK_INTERVAL).await;
+        if state.quiet_mode.load(Ordering::Relaxed) {
+            continue;
+        }
+        refresh_snapshots(&state).await;
+        if !state.events.has_subscribers() {
+            continue;
+        }
+        let tokens = match web::block({ let state = state.clone(); move || state.twitch.valid_access_tokens() }).await {
+            Ok(tokens) => tokens,
+            Err(_) => continue,
+        };
+        match state.twitch.check_went_live(tokens).await {
+            Ok(streams) => for stream in streams {
+                let field = |name: &str| stream.extra.get(name).and_then(|value| value.as_str()).unwrap_or_default().to_string();
+                state.events.publish(Event::TwitchLive { channel: field("user_login"), title: field("title"), game: field("game_name") });
+            },
+            Err(error) => warn!("Could not check which Twitch streams went live: {}", error),
+        }
+    }
+}
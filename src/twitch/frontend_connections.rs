@@ -1,13 +1,16 @@
 
 use super::twitch_auth::{AuthorizationRequest, Authorization};
+use crate::store::Repository;
 
 use std::sync::Mutex;
 use std::time::Instant;
+use log::info;
 use uuid::Uuid;
 
 pub struct FrontendConnections {
     pending: Mutex<Vec<Pending>>,
     logged_in: Mutex<Vec<LoggedIn>>, // TODO think about how/when to remove from this list
+    persisted: Repository<Authorization>,
 }
 
 struct Pending {
@@ -23,8 +26,12 @@ struct LoggedIn {
 
 impl FrontendConnections {
 
-    pub fn new() -> Self {
-        Self { pending: Mutex::from(Vec::new()), logged_in: Mutex::from(Vec::new()) }
+    pub fn new(persisted: Repository<Authorization>) -> Self {
+        let logged_in: Vec<LoggedIn> = persisted.all().into_iter()
+            .filter_map(|(id, auth)| Some(LoggedIn{ id: id.parse().ok()?, auth }))
+            .collect();
+        info!("Restored {} Twitch logins", logged_in.len());
+        Self { pending: Mutex::from(Vec::new()), logged_in: Mutex::from(logged_in), persisted }
     }
 
     pub fn create(&self, auth_request: AuthorizationRequest) -> Uuid {
@@ -36,17 +43,19 @@ impl FrontendConnections {
         id
     }
 
-    pub fn get_pending(&self, id: &Uuid) -> Option<(String, String)> {
+    // the device code, the verification uri and the code the user enters there
+    pub fn get_pending(&self, id: &Uuid) -> Option<(String, String, String)> {
         self.clean_pending();   
         let pending = self.pending.lock().unwrap();
         pending.iter()
             .find(|login| login.id == *id)
-            .map(|login| (login.auth_request.device_code.clone(), login.auth_request.verification_uri.clone()))
+            .map(|login| (login.auth_request.device_code.clone(), login.auth_request.verification_uri.clone(), login.auth_request.user_code.clone()))
     }
 
     pub fn log_in(&self, id: Uuid, auth: Authorization) {
         self.remove(&id);
         let mut logged_in = self.logged_in.lock().unwrap();
+        self.persisted.put(&id.to_string(), &auth);
         logged_in.push( LoggedIn{id, auth} );
     }
 
@@ -55,9 +64,14 @@ impl FrontendConnections {
         logged_in.iter().find(|login| login.id == *id).map(|login| (login.auth.access_token.clone(), login.auth.refresh_token.clone()))
     }
 
+    pub fn logged_in_ids(&self) -> Vec<Uuid> {
+        self.logged_in.lock().unwrap().iter().map(|login| login.id).collect()
+    }
+
     pub fn update_logged_in(&self, id: &Uuid, auth: Authorization) -> Option<()> {
         let mut logged_in = self.logged_in.lock().unwrap();
         let i = logged_in.iter().position(|login| login.id == *id)?;
+        self.persisted.put(&id.to_string(), &auth);
         logged_in[i].auth = auth;
         Some(())
     }
@@ -69,6 +83,9 @@ impl FrontendConnections {
         }
         {
             let mut logged_in = self.logged_in.lock().unwrap();
+            if logged_in.iter().any(|login| login.id == *id) {
+                self.persisted.remove(&id.to_string());
+            }
             logged_in.retain(|login| login.id != *id);
         }
     }
@@ -0,0 +1,220 @@
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use futures::future::{self, BoxFuture, FutureExt};
+use reqwest::StatusCode;
+use super::*;
+use crate::testing::TempFolder;
+
+// answers the requests with responses recorded from Twitch, the first one whose pattern is part of the url
+#[derive(Default)]
+struct Recorded {
+    responses: Mutex<Vec<(&'static str, http::Response<String>)>>,
+    requests: Mutex<Vec<ApiRequest>>,
+}
+
+impl Recorded {
+
+    fn answer(&self, pattern: &'static str, status: u16, body: &str) -> &Self {
+        let response = http::Response::builder().status(status).header("Content-Type", "application/json").body(body.to_string()).unwrap();
+        self.responses.lock().unwrap().push((pattern, response));
+        self
+    }
+
+    fn rate_limited(&self, pattern: &'static str, reset_in: u64) -> &Self {
+        let reset = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + reset_in;
+        let response = http::Response::builder().status(429)
+            .header("Ratelimit-Limit", "800")
+            .header("Ratelimit-Remaining", "0")
+            .header("Ratelimit-Reset", reset.to_string())
+            .body(include_str!("fixtures/rate_limited.json").to_string()).unwrap();
+        self.responses.lock().unwrap().push((pattern, response));
+        self
+    }
+
+    // a valid token, following three streams on two pages, of which one is live
+    fn follows(&self) -> &Self {
+        self.answer("oauth2/validate", 200, include_str!("fixtures/validate.json"))
+            .answer("helix/channels/followed", 200, include_str!("fixtures/followed_page_1.json"))
+            .answer("helix/channels/followed", 200, include_str!("fixtures/followed_page_2.json"))
+            .answer("helix/users", 200, include_str!("fixtures/users.json"))
+            .answer("helix/streams", 200, include_str!("fixtures/streams.json"))
+    }
+
+    fn respond(&self, request: ApiRequest) -> http::Response<String> {
+        let mut responses = self.responses.lock().unwrap();
+        let position = responses.iter().position(|(pattern, _)| request.url.contains(pattern))
+            .unwrap_or_else(|| panic!("no recorded response for {} {}", request.method, request.url));
+        self.requests.lock().unwrap().push(request);
+        responses.remove(position).1
+    }
+
+    fn requested(&self, pattern: &str) -> Vec<ApiRequest> {
+        self.requests.lock().unwrap().iter().filter(|request| request.url.contains(pattern)).cloned().collect()
+    }
+
+    fn unanswered(&self) -> usize {
+        self.responses.lock().unwrap().len()
+    }
+}
+
+impl TwitchHttp for Recorded {
+
+    fn send_blocking(&self, request: ApiRequest) -> Result<reqwest::blocking::Response, reqwest::Error> {
+        Ok(self.respond(request).into())
+    }
+
+    fn send(&self, request: ApiRequest) -> BoxFuture<'static, Result<reqwest::Response, reqwest::Error>> {
+        future::ready(Ok(self.respond(request).into())).boxed()
+    }
+}
+
+struct Harness {
+    _folder: TempFolder,
+    http: Arc<Recorded>,
+    logins: Repository<Authorization>,
+    twitch: Arc<Twitch>,
+    id: Uuid,
+}
+
+impl Harness {
+
+    // with a login that was persisted before the start
+    fn logged_in() -> Self {
+        let folder = TempFolder::new();
+        let store = Arc::new(Store::open(folder.join("store.json")).unwrap());
+        let logins = Repository::new(store.clone(), "twitch_logins");
+        let id = Uuid::new_v4();
+        logins.put(&id.to_string(), &Authorization { access_token: "access-token".to_string(), refresh_token: "refresh-token".to_string(), scope: Vec::new() });
+        let http = Arc::new(Recorded::default());
+        let twitch = Arc::new(Twitch::new(store, http.clone(), "client-id".to_string(), "client-secret".to_string(), Vec::new()));
+        Self { _folder: folder, http, logins, twitch, id }
+    }
+
+    // like the handler, the blocking auth client has to run outside of the runtime
+    async fn online(&self) -> Result<Option<Vec<FollowResponse>>, reqwest::Error> {
+        let (twitch, id) = (self.twitch.clone(), self.id);
+        let token = web::block(move || twitch.get_valid_access_token(&id)).await.unwrap();
+        self.twitch.get_online_following(token).await
+    }
+
+    fn access_token(&self) -> Option<String> {
+        self.logins.get(&self.id.to_string()).map(|auth| auth.access_token)
+    }
+}
+
+fn logins(online: &[FollowResponse]) -> Vec<String> {
+    online.iter().map(|follow| follow.stream.extra["user_login"].as_str().unwrap().to_string()).collect()
+}
+
+#[actix_web::test]
+async fn all_pages_of_the_follows_are_read() {
+    let harness = Harness::logged_in();
+    harness.http.follows();
+
+    let online = harness.online().await.unwrap().unwrap();
+
+    assert_eq!(logins(&online), ["second"]);
+    assert_eq!(online[0].profile_image_url, "https://static-cdn.jtvnw.net/jtv_user_pictures/second-profile_image-300x300.png");
+    let pages = harness.http.requested("helix/channels/followed");
+    assert_eq!(pages[0].url, "https://api.twitch.tv/helix/channels/followed?user_id=141981764&first=100");
+    assert_eq!(pages[1].url, "https://api.twitch.tv/helix/channels/followed?user_id=141981764&first=100&after=eyJiIjpudWxsLCJhIjp7Ik9mZnNldCI6Mn19");
+    // the user is shown too when they are live
+    assert_eq!(harness.http.requested("helix/users")[0].url, "https://api.twitch.tv/helix/users?id=11111&id=22222&id=33333&id=141981764");
+    assert_eq!(harness.http.requested("helix/streams")[0].url, "https://api.twitch.tv/helix/streams?first=100&user_id=11111&user_id=22222&user_id=33333&user_id=141981764");
+    assert_eq!(harness.http.unanswered(), 0);
+}
+
+#[actix_web::test]
+async fn the_follows_are_cached() {
+    let harness = Harness::logged_in();
+    harness.http.follows();
+    harness.online().await.unwrap().unwrap();
+
+    harness.http
+        .answer("oauth2/validate", 200, include_str!("fixtures/validate.json"))
+        .answer("helix/streams", 200, include_str!("fixtures/streams.json"));
+    let online = harness.online().await.unwrap().unwrap();
+
+    assert_eq!(logins(&online), ["second"]);
+    assert_eq!(harness.http.requested("helix/channels/followed").len(), 2);
+    assert_eq!(harness.http.unanswered(), 0);
+}
+
+#[actix_web::test]
+async fn an_expired_token_is_refreshed() {
+    let harness = Harness::logged_in();
+    harness.http
+        .answer("oauth2/validate", 401, r#"{"status":401,"message":"invalid access token"}"#)
+        .answer("oauth2/token", 200, include_str!("fixtures/token.json"))
+        .follows();
+
+    let online = harness.online().await.unwrap().unwrap();
+
+    assert_eq!(logins(&online), ["second"]);
+    let refresh = &harness.http.requested("oauth2/token")[0];
+    assert_eq!(refresh.url, "https://id.twitch.tv/oauth2/token?grant_type=refresh_token&refresh_token=refresh-token&client_id=client-id&client_secret=client-secret");
+    // the new token is validated, used and kept for the next start
+    assert_eq!(harness.http.requested("oauth2/validate")[1].access_token.as_deref(), Some("refreshed-access-token"));
+    assert!(harness.http.requested("helix/").iter().all(|request| request.access_token.as_deref() == Some("refreshed-access-token")));
+    assert_eq!(harness.access_token().as_deref(), Some("refreshed-access-token"));
+}
+
+#[actix_web::test]
+async fn a_revoked_refresh_token_logs_out() {
+    let harness = Harness::logged_in();
+    harness.http
+        .answer("oauth2/validate", 401, r#"{"status":401,"message":"invalid access token"}"#)
+        .answer("oauth2/token", 400, r#"{"status":400,"message":"Invalid refresh token"}"#);
+
+    assert!(harness.online().await.unwrap().is_none());
+    assert_eq!(harness.access_token(), None);
+    // nothing is asked for a login that is gone
+    assert!(harness.online().await.unwrap().is_none());
+    assert_eq!(harness.http.requested("oauth2/validate").len(), 1);
+}
+
+#[actix_web::test]
+async fn a_rate_limited_validation_keeps_the_login() {
+    let harness = Harness::logged_in();
+    harness.http.rate_limited("oauth2/validate", 1);
+
+    assert!(harness.online().await.unwrap().is_none());
+    assert_eq!(harness.access_token().as_deref(), Some("access-token"));
+
+    harness.http.follows();
+    let online = harness.online().await.unwrap().unwrap();
+    assert_eq!(logins(&online), ["second"]);
+}
+
+#[actix_web::test]
+async fn a_rate_limited_request_is_retried_once_the_bucket_is_full() {
+    let harness = Harness::logged_in();
+    harness.http
+        .answer("oauth2/validate", 200, include_str!("fixtures/validate.json"))
+        .answer("helix/channels/followed", 200, include_str!("fixtures/followed_page_1.json"))
+        .rate_limited("helix/channels/followed", 0)
+        .answer("helix/channels/followed", 200, include_str!("fixtures/followed_page_2.json"))
+        .answer("helix/users", 200, include_str!("fixtures/users.json"))
+        .answer("helix/streams", 200, include_str!("fixtures/streams.json"));
+
+    let online = harness.online().await.unwrap().unwrap();
+
+    assert_eq!(logins(&online), ["second"]);
+    let pages = harness.http.requested("helix/channels/followed");
+    assert_eq!(pages.len(), 3);
+    assert_eq!(pages[1].url, pages[2].url);
+}
+
+#[actix_web::test]
+async fn a_rate_limit_that_resets_too_late_fails() {
+    let harness = Harness::logged_in();
+    harness.http
+        .answer("oauth2/validate", 200, include_str!("fixtures/validate.json"))
+        .rate_limited("helix/channels/followed", 60);
+
+    let error = harness.online().await.unwrap_err();
+
+    assert_eq!(error.status(), Some(StatusCode::TOO_MANY_REQUESTS));
+    assert_eq!(harness.http.requested("helix/channels/followed").len(), 1);
+    assert_eq!(harness.access_token().as_deref(), Some("access-token"));
+}
@@ -1,36 +1,40 @@
-use std::time::Duration;
-use reqwest::blocking::Client;
+use std::sync::Arc;
 use reqwest:: StatusCode;
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
+use super::twitch_http::{ApiRequest, TwitchHttp};
 
 // TODO switch to non-blocking reqwest
 
 pub struct TwitchAuthClient {
-    client: Client,
+    http: Arc<dyn TwitchHttp>,
     client_id: String,
     client_secret: String,
+    scopes: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct AuthorizationRequest {
     pub device_code: String,
     pub expires_in: u64,
-    pub interval: i32,
     pub user_code: String,
     pub verification_uri: String,    
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Authorization {
     pub access_token: String,
     pub refresh_token: String,
-    pub expires_in: u64,
+    // what the user granted, older logins were persisted without it
+    #[serde(default)]
+    pub scope: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Validation {
     pub user_id: String,
     pub login: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -39,19 +43,18 @@ struct BadRequestBody {
 }
 
 impl TwitchAuthClient {
-    pub fn new(client_id: String, client_secret: String) -> Self {
-        let client = Client::builder().timeout(Duration::from_secs(1)).build().unwrap();
-        return Self{client, client_id, client_secret};
+    pub fn new(http: Arc<dyn TwitchHttp>, client_id: String, client_secret: String, scopes: Vec<String>) -> Self {
+        Self{http, client_id, client_secret, scopes}
     }
 
     pub fn create_authorization_request(&self) -> Result<AuthorizationRequest, reqwest::Error> {
-        let url = format!("https://id.twitch.tv/oauth2/device?client_id={}&scopes=user:read:follows", self.client_id);
-        self.client.post(url).send()?.error_for_status()?.json()
+        let url = format!("https://id.twitch.tv/oauth2/device?client_id={}&scopes={}", self.client_id, self.scopes.join("%20"));
+        self.http.send_blocking(ApiRequest::post(url))?.error_for_status()?.json()
     }
 
     pub fn activate_authorization_request(&self, device_code: &str) -> Result<Option<Authorization>, reqwest::Error> {
         let url = format!("https://id.twitch.tv/oauth2/token?client_id={}&device_code={}&grant_type=urn:ietf:params:oauth:grant-type:device_code", self.client_id, device_code);
-        let response = self.client.post(url).send()?;
+        let response = self.http.send_blocking(ApiRequest::post(url))?;
 
         match response.error_for_status_ref() {
             Ok(_) =>  Ok(Some(response.json()?)),
@@ -66,7 +69,7 @@ impl TwitchAuthClient {
     }
 
     pub fn validate_authorization(&self, access_token: &str) -> Result<Option<Validation>, reqwest::Error> {
-        let response = self.client.get("https://id.twitch.tv/oauth2/validate").bearer_auth(&access_token).send()?;
+        let response = self.http.send_blocking(ApiRequest::get("https://id.twitch.tv/oauth2/validate").bearer(access_token))?;
         if response.status() == StatusCode::UNAUTHORIZED {
             Ok(None)
         } else {
@@ -76,7 +79,7 @@ impl TwitchAuthClient {
 
     pub fn refresh_authorization(&self, refresh_token: &str) -> Result<Authorization, reqwest::Error> {
         let url = format!("https://id.twitch.tv/oauth2/token?grant_type=refresh_token&refresh_token={}&client_id={}&client_secret={}", refresh_token, self.client_id, self.client_secret);
-        self.client.post(url).send()?.error_for_status()?.json()
+        self.http.send_blocking(ApiRequest::post(url))?.error_for_status()?.json()
     }
 
 }
\ No newline at end of file
@@ -1,19 +1,24 @@
 use super::{Data, PagedData};
+use super::twitch_http::{ApiRequest, TwitchHttp};
 
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use reqwest::blocking::Client;
-use reqwest::header;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use actix_web::rt::time::sleep;
+use futures::{stream, StreamExt, TryStreamExt};
+use reqwest::StatusCode;
 use serde::{Serialize, Deserialize};
 use itertools::Itertools;
 
-use log::info;
+use log::{info, warn};
 
-// TODO switch to non-blocking reqwest
+// the ids are queried in chunks of 100, this many at a time
+const MAX_CONCURRENT_QUERIES: usize = 4;
+// a rate limited request is retried once the bucket is full again, unless that takes longer
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(5);
 
 pub struct TwitchFollows {
-    client: Client,
+    http: Arc<dyn TwitchHttp>,
     follow_cache: Mutex<Vec<FollowCacheEntry>>,
 }
 
@@ -37,6 +42,18 @@ pub struct User {
     pub offline_image_url: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct Marker {
+    pub id: String,
+    pub position_seconds: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Clip {
+    pub id: String,
+    pub edit_url: String,
+}
+
 #[derive(Deserialize, Debug)]
 struct Follow {
     broadcaster_id: String,
@@ -44,15 +61,25 @@ struct Follow {
 
 impl TwitchFollows {
 
-    pub fn new(client_id: &str) -> Self {        
-        let mut headers = header::HeaderMap::new();
-        headers.append("Client-Id", client_id.parse().unwrap());
-        let client = Client::builder()
-            .timeout(Duration::from_secs(2))
-            .default_headers(headers)
-            .build().unwrap();
+    pub fn new(http: Arc<dyn TwitchHttp>) -> Self {
+        Self { http, follow_cache: Mutex::from(Vec::new()) }
+    }
 
-        Self { client, follow_cache: Mutex::from(Vec::new()) }
+    // helix answers 429 once the token used up its bucket, Ratelimit-Reset is when it is full again
+    async fn send(&self, request: ApiRequest) -> Result<reqwest::Response, reqwest::Error> {
+        let response = self.http.send(request.clone()).await?;
+        if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            return response.error_for_status();
+        }
+        let wait = response.headers().get("Ratelimit-Reset")
+            .and_then(|reset| reset.to_str().ok()?.parse::<u64>().ok())
+            .map_or(Duration::from_secs(1), |reset| Duration::from_secs(reset.saturating_sub(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs()))));
+        if wait > MAX_RATE_LIMIT_WAIT {
+            return response.error_for_status();
+        }
+        warn!("Rate limited by Twitch, retrying {} in {:?}", request.url, wait);
+        sleep(wait).await;
+        self.http.send(request).await?.error_for_status()
     }
 
     fn get_cached(&self, user_id: &str) -> Option<Arc<Vec<User>>> {
@@ -72,57 +99,81 @@ impl TwitchFollows {
         arc
     }    
 
-    pub fn get_following(&self, access_token: &str, user_id: &str, user_name: &str) -> Result<Arc<Vec<User>>, reqwest::Error> {
+    pub fn clear_cache(&self) {
+        info!("Clearing Twitch Follow cache");
+        self.follow_cache.lock().unwrap().clear();
+    }
+
+    pub async fn get_following(&self, access_token: &str, user_id: &str, user_name: &str) -> Result<Arc<Vec<User>>, reqwest::Error> {
         if let Some(cached) = self.get_cached(user_id) {        
             return Ok(cached);
         }
 
-        let users = self.cache(user_id, self.query_users(access_token, self.query_following(access_token, &user_id)?)?);
+        let following = self.query_following(access_token, user_id).await?;
+        let users = self.cache(user_id, self.query_users(access_token, following).await?);
         info!("Loaded & Cached the {} streams {} is following", users.len(), user_name);
-        return Ok(users);
+        Ok(users)
     }
 
-    fn query_following(&self, access_token: &str, from_id: &str) -> Result<Vec<String>, reqwest::Error> {
+    // the pages have to be fetched one after another, each one has the cursor for the next
+    async fn query_following(&self, access_token: &str, from_id: &str) -> Result<Vec<String>, reqwest::Error> {
         let url = format!("https://api.twitch.tv/helix/channels/followed?user_id={}&first=100", from_id);
-        let mut response: PagedData<Follow>= self.client.get(&url)
-            .bearer_auth(access_token)
-            .send()?.error_for_status()?.json()?;
+        let mut response: PagedData<Follow> = self.send(ApiRequest::get(url).bearer(access_token)).await?.json().await?;
         let mut following: Vec<String> = response.data.into_iter().map(|follow| follow.broadcaster_id).collect();
         
         while response.pagination.cursor.is_some() {
             let url_after = format!("https://api.twitch.tv/helix/channels/followed?user_id={}&first=100&after={}", from_id, response.pagination.cursor.unwrap());
-            response = self.client.get(&url_after)
-                .bearer_auth(access_token)
-                .send()?.error_for_status()?.json()?;            
+            response = self.send(ApiRequest::get(url_after).bearer(access_token)).await?.json().await?;
             following.extend(response.data.into_iter().map(|follow| follow.broadcaster_id));
         }
 
         following.push(from_id.to_owned()); // also show the user if he is online
-        return Ok(following);
+        Ok(following)
     }
 
-    fn query_users(&self, access_token: &str, ids: Vec<String>) -> Result<Vec<User>, reqwest::Error> {
-        let mut users = Vec::new();
-        for chunk in ids.chunks(100) {
-            let url = format!("https://api.twitch.tv/helix/users?id={}", chunk.join("&id="));
-            let mut response: Data<User> = self.client.get(&url)
-                .bearer_auth(access_token)
-                .send()?.error_for_status()?.json()?;
-            users.append(&mut response.data);
-        }
-        Ok(users)
+    async fn query_users(&self, access_token: &str, ids: Vec<String>) -> Result<Vec<User>, reqwest::Error> {
+        let urls = ids.chunks(100).map(|chunk| format!("https://api.twitch.tv/helix/users?id={}", chunk.join("&id="))).collect();
+        self.query_chunks(access_token, urls).await
     }
 
-    pub fn query_streams(&self, access_token: &str, users: &Vec<User>) -> Result<Vec<Stream>, reqwest::Error>  {
-        let mut streams: Vec<Stream> = Vec::new();
-        for chunk in users.chunks(100) {
-            let url = format!("https://api.twitch.tv/helix/streams?first=100&user_id={}", chunk.iter().map(|user| &user.id).join("&user_id="));
-            let mut response: Data<Stream> = self.client.get(&url)
-                .bearer_auth(&access_token)
-                .send()?.error_for_status()?.json()?;
-            streams.append(&mut response.data);
-        }
-        Ok(streams)
+    pub async fn query_streams(&self, access_token: &str, users: &[User]) -> Result<Vec<Stream>, reqwest::Error>  {
+        let urls = users.chunks(100)
+            .map(|chunk| format!("https://api.twitch.tv/helix/streams?first=100&user_id={}", chunk.iter().map(|user| &user.id).join("&user_id=")))
+            .collect();
+        self.query_chunks(access_token, urls).await
+    }
+
+    pub async fn query_stream(&self, access_token: &str, login: &str) -> Result<Option<Stream>, reqwest::Error> {
+        let url = format!("https://api.twitch.tv/helix/streams?user_login={}", login);
+        Ok(self.query_chunks(access_token, vec![url]).await?.pop())
+    }
+
+    // only works for the broadcaster and their editors, with the channel:manage:broadcast scope
+    pub async fn create_marker(&self, access_token: &str, user_id: &str, description: &str) -> Result<Option<Marker>, reqwest::Error> {
+        let request = ApiRequest::post("https://api.twitch.tv/helix/streams/markers")
+            .bearer(access_token)
+            .json(serde_json::json!({ "user_id": user_id, "description": description }));
+        let response: Data<Marker> = self.send(request).await?.json().await?;
+        Ok(response.data.into_iter().next())
+    }
+
+    // needs the clips:edit scope, twitch captures the last seconds of the live stream
+    pub async fn create_clip(&self, access_token: &str, broadcaster_id: &str) -> Result<Option<Clip>, reqwest::Error> {
+        let request = ApiRequest::post(format!("https://api.twitch.tv/helix/clips?broadcaster_id={}", broadcaster_id)).bearer(access_token);
+        let response: Data<Clip> = self.send(request).await?.json().await?;
+        Ok(response.data.into_iter().next())
+    }
+
+    // the results stay in the order of the urls
+    async fn query_chunks<T: for<'de> Deserialize<'de>>(&self, access_token: &str, urls: Vec<String>) -> Result<Vec<T>, reqwest::Error> {
+        stream::iter(urls)
+            .map(|url| async move {
+                let response: Data<T> = self.send(ApiRequest::get(url).bearer(access_token)).await?.json().await?;
+                Ok(response.data)
+            })
+            .buffered(MAX_CONCURRENT_QUERIES)
+            .try_concat()
+            .await
     }
     
 }
\ No newline at end of file
@@ -0,0 +1,87 @@
+use std::time::Duration;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use reqwest::{blocking, header, Client, Method};
+use serde_json::Value;
+
+// a request to Twitch, the access token is sent as bearer
+#[derive(Clone, Debug)]
+pub struct ApiRequest {
+    pub method: Method,
+    pub url: String,
+    pub access_token: Option<String>,
+    pub json: Option<Value>,
+}
+
+impl ApiRequest {
+
+    pub fn get(url: impl Into<String>) -> Self {
+        Self { method: Method::GET, url: url.into(), access_token: None, json: None }
+    }
+
+    pub fn post(url: impl Into<String>) -> Self {
+        Self { method: Method::POST, url: url.into(), access_token: None, json: None }
+    }
+
+    pub fn bearer(mut self, access_token: &str) -> Self {
+        self.access_token = Some(access_token.to_string());
+        self
+    }
+
+    pub fn json(mut self, json: Value) -> Self {
+        self.json = Some(json);
+        self
+    }
+}
+
+// how the OAuth and Helix requests reach Twitch, the tests answer them with recorded responses instead
+pub trait TwitchHttp: Send + Sync {
+    // for the auth client, which is still blocking
+    fn send_blocking(&self, request: ApiRequest) -> Result<blocking::Response, reqwest::Error>;
+    fn send(&self, request: ApiRequest) -> BoxFuture<'static, Result<reqwest::Response, reqwest::Error>>;
+}
+
+pub struct ReqwestHttp {
+    blocking: blocking::Client,
+    client: Client,
+}
+
+impl ReqwestHttp {
+
+    pub fn new(client_id: &str) -> Self {
+        let blocking = blocking::Client::builder().timeout(Duration::from_secs(1)).build().unwrap();
+        // helix wants the id on every request
+        let mut headers = header::HeaderMap::new();
+        headers.append("Client-Id", client_id.parse().unwrap());
+        let client = Client::builder()
+            .timeout(Duration::from_secs(2))
+            .default_headers(headers)
+            .build().unwrap();
+        Self { blocking, client }
+    }
+}
+
+impl TwitchHttp for ReqwestHttp {
+
+    fn send_blocking(&self, request: ApiRequest) -> Result<blocking::Response, reqwest::Error> {
+        let mut builder = self.blocking.request(request.method, request.url);
+        if let Some(access_token) = request.access_token {
+            builder = builder.bearer_auth(access_token);
+        }
+        if let Some(json) = request.json {
+            builder = builder.json(&json);
+        }
+        builder.send()
+    }
+
+    fn send(&self, request: ApiRequest) -> BoxFuture<'static, Result<reqwest::Response, reqwest::Error>> {
+        let mut builder = self.client.request(request.method, request.url);
+        if let Some(access_token) = request.access_token {
+            builder = builder.bearer_auth(access_token);
+        }
+        if let Some(json) = request.json {
+            builder = builder.json(&json);
+        }
+        builder.send().boxed()
+    }
+}
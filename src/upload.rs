@@ -0,0 +1,244 @@
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use actix_web::error::PayloadError;
+use actix_web::web::Bytes;
+use futures::{Stream, StreamExt};
+use log::info;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+use crate::download::File;
+
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+const MAX_NAME_LENGTH: usize = 255;
+
+const DEFAULT_MAX_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum UploadError {
+    Invalid(String),
+    TooLarge(u64), // the limit
+    Exists(String),
+    Io(io::Error),
+    Payload(PayloadError),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadError::Invalid(message) => write!(f, "invalid upload: {}", message),
+            UploadError::TooLarge(limit) => write!(f, "upload is larger than {} bytes", limit),
+            UploadError::Exists(name) => write!(f, "{} already exists", name),
+            UploadError::Io(error) => write!(f, "{}", error),
+            UploadError::Payload(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(error: io::Error) -> Self {
+        UploadError::Io(error)
+    }
+}
+
+// the boundary from a "multipart/form-data; boundary=..." content type
+pub fn boundary(content_type: &str) -> Option<String> {
+    let (mime, parameters) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    parameters.split(';')
+        .filter_map(|parameter| parameter.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, boundary)| boundary.trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+// only the last component, so the name can't point somewhere else
+fn sanitize_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?;
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    if name.is_empty() || name.starts_with('.') || name.len() > MAX_NAME_LENGTH {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+fn file_name(headers: &str) -> Option<String> {
+    // looks like "Content-Disposition: form-data; name="file"; filename="video.mkv""
+    let disposition = headers.lines().find(|line| line.to_ascii_lowercase().starts_with("content-disposition:"))?;
+    let start = disposition.find("filename=\"")? + "filename=\"".len();
+    let end = start + disposition[start..].find('"')?;
+    Some(disposition[start..end].to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+struct Part {
+    file: fs::File,
+    partial: PathBuf,
+    target: PathBuf,
+    name: String,
+    size: u64,
+}
+
+impl Part {
+    async fn create(folder: &Path, name: String) -> Result<Part, UploadError> {
+        let target = folder.join(&name);
+        if fs::symlink_metadata(&target).await.is_ok() {
+            return Err(UploadError::Exists(name));
+        }
+        // per upload, two uploads of the same name would write into each other otherwise
+        let partial = folder.join(format!(".{}.{}.part", name, Uuid::new_v4()));
+        Ok(Part { file: fs::File::create(&partial).await?, partial, target, name, size: 0 })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.size += data.len() as u64;
+        self.file.write_all(data).await
+    }
+
+    async fn finish(mut self) -> Result<File, UploadError> {
+        self.file.flush().await?;
+        drop(self.file);
+        if fs::symlink_metadata(&self.target).await.is_ok() {
+            let _ = fs::remove_file(&self.partial).await;
+            return Err(UploadError::Exists(self.name));
+        }
+        fs::rename(&self.partial, &self.target).await?;
+        info!("received upload {:?} ({} bytes)", self.target, self.size);
+        Ok(File { name: self.name, size: Some(self.size) })
+    }
+}
+
+enum State {
+    Preamble,
+    Delimiter,          // directly after a boundary, the next part or the end follows
+    Headers,
+    Body(Option<Part>), // parts without a file name are form fields, their content is dropped
+    Done,
+}
+
+// takes uploads of up to UPLOAD_MAX_SIZE bytes per request
+pub struct Uploads {
+    max_size: u64,
+}
+
+impl Uploads {
+
+    pub fn from_env() -> Self {
+        Self::new(env::var("UPLOAD_MAX_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_MAX_SIZE))
+    }
+
+    pub fn new(max_size: u64) -> Self {
+        Self { max_size }
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    // writes the files of a multipart/form-data upload into the folder, while they come in
+    pub async fn receive(&self, payload: impl Stream<Item = Result<Bytes, PayloadError>> + Unpin, boundary: &str, folder: &Path) -> Result<Vec<File>, UploadError> {
+        receive(payload, boundary, folder, self.max_size).await
+    }
+}
+
+async fn receive(mut payload: impl Stream<Item = Result<Bytes, PayloadError>> + Unpin, boundary: &str, folder: &Path, max_size: u64) -> Result<Vec<File>, UploadError> {
+    fs::create_dir_all(folder).await?;
+    let mut state = State::Preamble;
+    let result = receive_parts(&mut payload, boundary, folder, max_size, &mut state).await;
+    // nothing half written is left behind
+    if let State::Body(Some(part)) = state {
+        drop(part.file);
+        let _ = fs::remove_file(part.partial).await;
+    }
+    result
+}
+
+async fn receive_parts(payload: &mut (impl Stream<Item = Result<Bytes, PayloadError>> + Unpin), boundary: &str, folder: &Path, max_size: u64, state: &mut State) -> Result<Vec<File>, UploadError> {
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    // the first boundary has no line break in front of it
+    let mut buffer = b"\r\n".to_vec();
+    let mut received = 0;
+    let mut files = Vec::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(UploadError::Payload)?;
+        received += chunk.len() as u64;
+        if received > max_size {
+            return Err(UploadError::TooLarge(max_size));
+        }
+        buffer.extend_from_slice(&chunk);
+
+        loop {
+            match state {
+                State::Preamble => match find(&buffer, &delimiter) {
+                    Some(position) => { buffer.drain(..position + delimiter.len()); *state = State::Delimiter },
+                    None => { buffer.drain(..buffer.len().saturating_sub(delimiter.len())); break },
+                },
+                State::Delimiter => {
+                    if buffer.len() < 2 {
+                        break;
+                    }
+                    *state = match &buffer[..2] {
+                        b"--" => State::Done,
+                        b"\r\n" => State::Headers,
+                        _ => return Err(UploadError::Invalid("malformed boundary".to_string())),
+                    };
+                    buffer.drain(..2);
+                },
+                State::Headers => match find(&buffer, b"\r\n\r\n") {
+                    Some(end) => {
+                        let headers = String::from_utf8_lossy(&buffer[..end]).into_owned();
+                        buffer.drain(..end + 4);
+                        let part = match file_name(&headers) {
+                            Some(name) => {
+                                let name = sanitize_name(&name).ok_or_else(|| UploadError::Invalid(format!("invalid file name {}", name)))?;
+                                Some(Part::create(folder, name).await?)
+                            },
+                            None => None,
+                        };
+                        *state = State::Body(part);
+                    },
+                    None if buffer.len() > MAX_HEADER_SIZE => return Err(UploadError::Invalid("headers are too long".to_string())),
+                    None => break,
+                },
+                State::Body(part) => match find(&buffer, &delimiter) {
+                    Some(position) => {
+                        if let Some(part) = part.as_mut() {
+                            part.write(&buffer[..position]).await?;
+                        }
+                        buffer.drain(..position + delimiter.len());
+                        if let State::Body(Some(part)) = std::mem::replace(state, State::Delimiter) {
+                            files.push(part.finish().await?);
+                        }
+                    },
+                    None => {
+                        // the end could be the start of a boundary that isn't complete yet
+                        let safe = buffer.len().saturating_sub(delimiter.len());
+                        if let Some(part) = part.as_mut() {
+                            part.write(&buffer[..safe]).await?;
+                        }
+                        buffer.drain(..safe);
+                        break;
+                    },
+                },
+                State::Done => { buffer.clear(); break },
+            }
+        }
+    }
+
+    match state {
+        State::Done => Ok(files),
+        _ => Err(UploadError::Invalid("the upload ended early".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests;
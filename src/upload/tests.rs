@@ -0,0 +1,132 @@
+use std::fs;
+use futures::stream;
+use super::*;
+use crate::testing::{body, TempFolder};
+
+const BOUNDARY: &str = "----WebKitFormBoundary7MA4YWxkTrZu0gW";
+
+fn part(name: &str, file_name: Option<&str>, content: &[u8]) -> Vec<u8> {
+    let disposition = match file_name {
+        Some(file_name) => format!("Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream", name, file_name),
+        None => format!("Content-Disposition: form-data; name=\"{}\"", name),
+    };
+    let mut part = format!("--{}\r\n{}\r\n\r\n", BOUNDARY, disposition).into_bytes();
+    part.extend_from_slice(content);
+    part.extend_from_slice(b"\r\n");
+    part
+}
+
+fn form(parts: &[Vec<u8>]) -> Vec<u8> {
+    let mut form = parts.concat();
+    form.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    form
+}
+
+// in chunks of the size, so boundaries and headers get split like on the network
+fn chunked(body: Vec<u8>, size: usize) -> impl Stream<Item = Result<Bytes, PayloadError>> + Unpin {
+    stream::iter(body.chunks(size).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect::<Vec<_>>())
+}
+
+fn files_in(folder: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(folder).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn reads_the_boundary() {
+    assert_eq!(Some(BOUNDARY.to_string()), boundary(&format!("multipart/form-data; boundary={}", BOUNDARY)));
+    assert_eq!(Some("abc".to_string()), boundary("Multipart/Form-Data; charset=utf-8; Boundary=\"abc\""));
+    assert_eq!(None, boundary("application/json; boundary=abc"));
+    assert_eq!(None, boundary("multipart/form-data"));
+    assert_eq!(None, boundary("multipart/form-data; boundary="));
+    assert_eq!(None, boundary(&format!("multipart/form-data; boundary={}", "x".repeat(71))));
+}
+
+#[test]
+fn reads_the_file_name() {
+    assert_eq!(Some("video.mkv".to_string()), file_name("Content-Disposition: form-data; name=\"file\"; filename=\"video.mkv\"\r\nContent-Type: video/x-matroska"));
+    assert_eq!(Some("video.mkv".to_string()), file_name("content-disposition: form-data; name=\"file\"; filename=\"video.mkv\""));
+    assert_eq!(None, file_name("Content-Disposition: form-data; name=\"comment\""));
+}
+
+#[test]
+fn keeps_only_the_last_component_of_a_name() {
+    assert_eq!(Some("video.mkv".to_string()), sanitize_name("../../etc/video.mkv"));
+    assert_eq!(Some("video.mkv".to_string()), sanitize_name("C:\\Users\\me\\video.mkv"));
+    assert_eq!(Some("video.mkv".to_string()), sanitize_name("vid\u{0}eo.mkv\r\n"));
+    assert_eq!(None, sanitize_name(".bashrc"));
+    assert_eq!(None, sanitize_name("folder/"));
+    assert_eq!(None, sanitize_name(&"x".repeat(256)));
+}
+
+#[actix_web::test]
+async fn writes_the_files_and_drops_the_fields() {
+    let folder = TempFolder::new();
+    let (first, second) = (body(200_000, 1), body(3_000, 2));
+    let upload = form(&[part("comment", None, b"not a file"), part("file", Some("a.mkv"), &first), part("file", Some("b.srt"), &second)]);
+
+    let files = receive(chunked(upload, 777), BOUNDARY, &folder, DEFAULT_MAX_SIZE).await.unwrap();
+
+    assert_eq!(vec![("a.mkv".to_string(), Some(200_000)), ("b.srt".to_string(), Some(3_000))], files.into_iter().map(|file| (file.name, file.size)).collect::<Vec<_>>());
+    assert_eq!(first, fs::read(folder.join("a.mkv")).unwrap());
+    assert_eq!(second, fs::read(folder.join("b.srt")).unwrap());
+    assert_eq!(vec!["a.mkv", "b.srt"], files_in(&folder));
+}
+
+#[actix_web::test]
+async fn a_body_that_contains_the_delimiter_almost_is_kept() {
+    let folder = TempFolder::new();
+    let content = format!("line\r\n--{}x but not quite\r\n--", &BOUNDARY[..BOUNDARY.len() - 1]).into_bytes();
+
+    receive(chunked(form(&[part("file", Some("a.txt"), &content)]), 5), BOUNDARY, &folder, DEFAULT_MAX_SIZE).await.unwrap();
+
+    assert_eq!(content, fs::read(folder.join("a.txt")).unwrap());
+}
+
+#[actix_web::test]
+async fn an_upload_that_ends_early_leaves_nothing_behind() {
+    let folder = TempFolder::new();
+    let mut upload = form(&[part("file", Some("a.mkv"), &body(50_000, 1))]);
+    upload.truncate(20_000);
+
+    assert!(matches!(receive(chunked(upload, 4096), BOUNDARY, &folder, DEFAULT_MAX_SIZE).await, Err(UploadError::Invalid(_))));
+    assert!(files_in(&folder).is_empty());
+}
+
+#[actix_web::test]
+async fn does_not_overwrite_a_file() {
+    let folder = TempFolder::new();
+    fs::write(folder.join("a.mkv"), b"old").unwrap();
+
+    let result = receive(chunked(form(&[part("file", Some("a.mkv"), b"new")]), 64), BOUNDARY, &folder, DEFAULT_MAX_SIZE).await;
+
+    assert!(matches!(result, Err(UploadError::Exists(_))));
+    assert_eq!(b"old".to_vec(), fs::read(folder.join("a.mkv")).unwrap());
+}
+
+#[actix_web::test]
+async fn uploads_of_the_same_name_do_not_share_a_partial_file() {
+    let folder = TempFolder::new();
+    let (first, second) = (body(100_000, 1), body(100_000, 2));
+    let (a, b) = futures::join!(
+        receive(chunked(form(&[part("file", Some("a.mkv"), &first)]), 1000), BOUNDARY, &folder, DEFAULT_MAX_SIZE),
+        receive(chunked(form(&[part("file", Some("a.mkv"), &second)]), 1000), BOUNDARY, &folder, DEFAULT_MAX_SIZE),
+    );
+
+    // one of them wins, the other finds the file already there, but the winner is never a mix of both
+    assert!(a.is_ok() != b.is_ok());
+    let written = fs::read(folder.join("a.mkv")).unwrap();
+    assert!(written == first || written == second);
+    assert_eq!(vec!["a.mkv"], files_in(&folder));
+}
+
+#[actix_web::test]
+async fn rejects_a_malformed_upload() {
+    let folder = TempFolder::new();
+    let upload = format!("--{}garbage\r\n", BOUNDARY).into_bytes();
+    assert!(matches!(receive(chunked(upload, 64), BOUNDARY, &folder, DEFAULT_MAX_SIZE).await, Err(UploadError::Invalid(_))));
+
+    let upload = form(&[part("file", Some(".hidden"), b"x")]);
+    assert!(matches!(receive(chunked(upload, 64), BOUNDARY, &folder, DEFAULT_MAX_SIZE).await, Err(UploadError::Invalid(_))));
+}
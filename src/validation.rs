@@ -0,0 +1,109 @@
+use actix_web::{error, web, HttpResponse};
+use regex::Regex;
+use reqwest::Url;
+use reqwest::header::HeaderValue;
+use serde::Serialize;
+
+pub const MAX_JSON_SIZE: usize = 64 * 1024;
+pub const MAX_CHANNEL_NAME_LENGTH: usize = 100;
+pub const MAX_PATH_LENGTH: usize = 255;
+pub const MAX_PREVIEWS_PER_REQUEST: usize = 100;
+pub const MAX_ORDERED_CHANNELS: usize = 1000;
+pub const MAX_HEADER_LENGTH: usize = 512;
+
+// body of every 400 we send, so the frontend can render the problems next to the inputs
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    field: String,
+    message: String,
+}
+
+pub trait Validate {
+    fn validate(&self, validator: &mut Validator);
+}
+
+#[derive(Default)]
+pub struct Validator {
+    fields: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn check(&mut self, ok: bool, field: &str, message: &str) -> &mut Self {
+        if !ok {
+            self.fields.push(FieldError { field: field.to_string(), message: message.to_string() });
+        }
+        self
+    }
+
+    pub fn finish(self) -> Result<(), HttpResponse> {
+        if self.fields.is_empty() {
+            Ok(())
+        } else {
+            Err(HttpResponse::BadRequest().json(ErrorBody { error: "validation_failed", message: "the request contains invalid fields".to_string(), fields: self.fields }))
+        }
+    }
+}
+
+pub fn validate(payload: &impl Validate) -> Result<(), HttpResponse> {
+    let mut validator = Validator::default();
+    payload.validate(&mut validator);
+    validator.finish()
+}
+
+pub fn bad_request(field: &str, message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(ErrorBody {
+        error: "validation_failed",
+        message: message.clone(),
+        fields: vec![FieldError { field: field.to_string(), message }],
+    })
+}
+
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(MAX_JSON_SIZE)
+        .error_handler(|err, _req| {
+            let body = ErrorBody { error: "invalid_json", message: err.to_string(), fields: Vec::new() };
+            error::InternalError::from_response(err, HttpResponse::BadRequest().json(body)).into()
+        })
+}
+
+pub fn is_http_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| (url.scheme() == "http" || url.scheme() == "https") && url.has_host())
+}
+
+pub fn is_header_value(value: &str) -> bool {
+    !value.is_empty() && value.len() <= MAX_HEADER_LENGTH && HeaderValue::from_str(value).is_ok()
+}
+
+pub fn is_twitch_login(name: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[A-Za-z0-9_]{1,25}$").unwrap();
+    }
+    RE.is_match(name)
+}
+
+pub fn is_library_id(id: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[A-Za-z0-9_-]{1,100}$").unwrap();
+    }
+    RE.is_match(id)
+}
+
+pub fn is_settings_namespace(namespace: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^[a-z0-9_-]{1,64}$").unwrap();
+    }
+    RE.is_match(namespace)
+}
+
+pub fn is_channel_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_CHANNEL_NAME_LENGTH
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::debug;
+use serde::{Serialize, Deserialize};
+use crate::store::{Repository, Store};
+
+const DAY: i64 = 24 * 60 * 60;
+const HOUR: i64 = 60 * 60;
+
+const DEFAULT_KEEP_DAYS: u64 = 400;
+
+// something that was played, from start to stop
+#[derive(Serialize, Deserialize)]
+struct Session {
+    source: String,
+    name: String,
+    started: u64,
+    seconds: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Week,
+    Month,
+}
+
+#[derive(Serialize)]
+pub struct ViewingStats {
+    periods: Vec<PeriodStats>,
+    // hours watched in each hour of the day, 0 is midnight
+    hours_of_day: Vec<f64>,
+}
+
+#[derive(Serialize)]
+struct PeriodStats {
+    period: String, // 2024-W05 or 2024-02
+    hours: f64,
+    watched: Vec<Watched>,
+}
+
+#[derive(Serialize)]
+struct Watched {
+    source: String,
+    name: String,
+    hours: f64,
+}
+
+pub struct Viewing {
+    sessions: Repository<Session>,
+    current: Mutex<Option<(String, String, u64)>>,
+    // the sessions are stored in UTC, the days, weeks and hours of the statistics are shifted by this many seconds
+    utc_offset: i64,
+    // older sessions are dropped, otherwise the store grows with every channel switch for as long as the box runs
+    keep: u64,
+}
+
+impl Viewing {
+
+    pub fn from_env(store: Arc<Store>) -> Self {
+        let utc_offset = env::var("STATS_UTC_OFFSET").ok().and_then(|minutes| minutes.parse::<i64>().ok()).unwrap_or(0) * 60;
+        let keep_days = env::var("STATS_KEEP_DAYS").ok().and_then(|days| days.parse::<u64>().ok()).unwrap_or(DEFAULT_KEEP_DAYS);
+        Self::new(store, utc_offset, keep_days * DAY as u64)
+    }
+
+    pub fn new(store: Arc<Store>, utc_offset: i64, keep: u64) -> Self {
+        Self { sessions: Repository::new(store, "viewing"), current: Mutex::new(None), utc_offset, keep }
+    }
+
+    pub fn started(&self, source: &str, name: &str) {
+        *self.current.lock().unwrap() = Some((source.to_string(), name.to_string(), now()));
+    }
+
+    pub fn stopped(&self) {
+        if let Some((source, name, started)) = self.current.lock().unwrap().take() {
+            let seconds = now().saturating_sub(started);
+            debug!("watched {} for {}s", name, seconds);
+            self.sessions.put(&format!("{}-{}", started, name), &Session { source, name, started, seconds });
+            self.expire(now().saturating_sub(self.keep));
+        }
+    }
+
+    // one write for all of them, and none if nothing is that old
+    fn expire(&self, oldest: u64) {
+        let mut sessions = self.sessions.all();
+        let count = sessions.len();
+        sessions.retain(|(_, session)| session.started >= oldest);
+        if sessions.len() < count {
+            debug!("dropping {} viewing sessions", count - sessions.len());
+            self.sessions.replace_all(sessions);
+        }
+    }
+
+    // hours per channel or streamer in each week or month, the most recent first
+    pub fn stats(&self, period: Period) -> ViewingStats {
+        let mut periods: HashMap<String, HashMap<(String, String), f64>> = HashMap::new();
+        let mut hours_of_day = vec![0.0; 24];
+        for (_, session) in self.sessions.all() {
+            let start = session.started as i64 + self.utc_offset;
+            let key = match period {
+                Period::Week => iso_week(start.div_euclid(DAY)),
+                Period::Month => month(start.div_euclid(DAY)),
+            };
+            *periods.entry(key).or_default().entry((session.source, session.name)).or_default() += session.seconds as f64 / HOUR as f64;
+
+            // split the session at the full hours, so a long evening counts for every hour it spans
+            let (mut time, end) = (start, start + session.seconds as i64);
+            while time < end {
+                let next = (time.div_euclid(HOUR) + 1) * HOUR;
+                hours_of_day[time.rem_euclid(DAY) as usize / HOUR as usize] += (next.min(end) - time) as f64 / HOUR as f64;
+                time = next;
+            }
+        }
+
+        let mut periods: Vec<PeriodStats> = periods.into_iter()
+            .map(|(period, watched)| {
+                let mut watched: Vec<Watched> = watched.into_iter().map(|((source, name), hours)| Watched { source, name, hours: round(hours) }).collect();
+                watched.sort_by(|a, b| b.hours.total_cmp(&a.hours).then_with(|| a.name.cmp(&b.name)));
+                PeriodStats { period, hours: round(watched.iter().map(|watched| watched.hours).sum()), watched }
+            })
+            .collect();
+        // both formats sort like the dates they stand for
+        periods.sort_by(|a, b| b.period.cmp(&a.period));
+        ViewingStats { periods, hours_of_day: hours_of_day.into_iter().map(round).collect() }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+fn round(hours: f64) -> f64 {
+    (hours * 100.0).round() / 100.0
+}
+
+fn month(days: i64) -> String {
+    let (year, month, _) = civil_from_days(days);
+    format!("{}-{:02}", year, month)
+}
+
+// the ISO week belongs to the year its thursday is in
+fn iso_week(days: i64) -> String {
+    let weekday = (days + 3).rem_euclid(7); // 1970-01-01 was a thursday, 0 is monday
+    let thursday = days - weekday + 3;
+    let (year, _, _) = civil_from_days(thursday);
+    let week = (thursday - days_from_civil(year, 1, 1)) / 7 + 1;
+    format!("{}-W{:02}", year, week)
+}
+
+// the algorithms from http://howardhinnant.github.io/date_algorithms.html
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests;
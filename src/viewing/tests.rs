@@ -0,0 +1,30 @@
+use super::*;
+use crate::testing::TempFolder;
+
+fn session(started: u64) -> Session {
+    Session { source: "dvbc".to_string(), name: "ZDF HD".to_string(), started, seconds: 3600 }
+}
+
+#[test]
+fn old_sessions_are_dropped() {
+    let folder = TempFolder::new();
+    let viewing = Viewing::new(Arc::new(Store::open(folder.join("store.json")).unwrap()), 0, DEFAULT_KEEP_DAYS * DAY as u64);
+    viewing.sessions.put("old", &session(1_000));
+    viewing.sessions.put("new", &session(5_000));
+
+    viewing.expire(2_000);
+
+    let kept: Vec<String> = viewing.sessions.all().into_iter().map(|(key, _)| key).collect();
+    assert_eq!(vec!["new".to_string()], kept);
+}
+
+#[test]
+fn a_finished_session_is_kept() {
+    let folder = TempFolder::new();
+    let viewing = Viewing::new(Arc::new(Store::open(folder.join("store.json")).unwrap()), 0, DEFAULT_KEEP_DAYS * DAY as u64);
+
+    viewing.started("dvbc", "ZDF HD");
+    viewing.stopped();
+
+    assert_eq!(1, viewing.sessions.all().len());
+}
@@ -0,0 +1,105 @@
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use log::{info, warn, error};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use crate::events::{Event, Events};
+
+const RETRY_DELAYS: [Duration; 3] = [Duration::from_secs(1), Duration::from_secs(10), Duration::from_secs(60)];
+
+// one entry of WEBHOOKS_FILE
+#[derive(Deserialize, Debug)]
+pub struct Webhook {
+    url: String,
+    // event names like "player.started", or "player.*", all events if empty
+    #[serde(default)]
+    events: Vec<String>,
+    // json to send instead of the event, "{{name}}" in strings is replaced with that field of the event
+    payload: Option<Value>,
+}
+
+impl Webhook {
+
+    fn wants(&self, event: &Event) -> bool {
+        self.events.is_empty() || self.events.iter().any(|filter| event.matches(filter))
+    }
+
+    fn payload(&self, event: &Value) -> Value {
+        match &self.payload {
+            Some(template) => fill_template(template, event),
+            None => event.clone(),
+        }
+    }
+}
+
+fn fill_template(template: &Value, event: &Value) -> Value {
+    match template {
+        Value::String(string) => {
+            let mut filled = string.clone();
+            if let Value::Object(fields) = event {
+                for (name, value) in fields {
+                    let value = match value {
+                        Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    };
+                    filled = filled.replace(&format!("{{{{{}}}}}", name), &value);
+                }
+            }
+            Value::String(filled)
+        },
+        Value::Array(values) => Value::Array(values.iter().map(|value| fill_template(value, event)).collect()),
+        Value::Object(fields) => Value::Object(fields.iter().map(|(name, value)| (name.clone(), fill_template(value, event))).collect()),
+        value => value.clone(),
+    }
+}
+
+// sends the events to the webhooks configured in WEBHOOKS_FILE
+pub fn start(events: &Events) {
+    let path = match env::var("WEBHOOKS_FILE") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let webhooks: Vec<Webhook> = match fs::read_to_string(&path).map_err(|error| error.to_string())
+        .and_then(|content| serde_json::from_str(&content).map_err(|error| error.to_string()))
+    {
+        Ok(webhooks) => webhooks,
+        Err(error) => { error!("Could not read WEBHOOKS_FILE {}: {}", path, error); return },
+    };
+    info!("Loaded {} webhooks", webhooks.len());
+
+    let receiver = events.subscribe();
+    let webhooks = Arc::new(webhooks);
+    thread::spawn(move || {
+        let client = Client::builder().timeout(Duration::from_secs(10)).build().unwrap();
+        for event in receiver {
+            let event_json = serde_json::to_value(&*event).unwrap();
+            for (i, webhook) in webhooks.iter().enumerate() {
+                if !webhook.wants(&event) {
+                    continue;
+                }
+                // every delivery gets its own thread, so retries don't hold up the other webhooks
+                let (client, webhooks, payload, name) = (client.clone(), webhooks.clone(), webhook.payload(&event_json), event.name());
+                thread::spawn(move || deliver(&client, &webhooks[i], &payload, name));
+            }
+        }
+    });
+}
+
+fn deliver(client: &Client, webhook: &Webhook, payload: &Value, event_name: &str) {
+    for delay in RETRY_DELAYS.iter().map(Some).chain([None]) {
+        match client.post(&webhook.url).json(payload).send().and_then(|response| response.error_for_status()) {
+            Ok(_) => return,
+            Err(error) => match delay {
+                Some(delay) => {
+                    warn!("Could not send {} to webhook {}, retrying in {:?}: {}", event_name, webhook.url, delay, error);
+                    thread::sleep(*delay);
+                },
+                None => error!("Giving up sending {} to webhook {}: {}", event_name, webhook.url, error),
+            },
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+use log::{info, error};
+
+pub type MacAddress = [u8; 6];
+
+// the machines in WOL_DEVICES, by their name
+pub struct Wol {
+    devices: BTreeMap<String, MacAddress>,
+}
+
+fn parse_mac(mac: &str) -> Option<MacAddress> {
+    let bytes: Vec<u8> = mac.split([':', '-'])
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<_, _>>().ok()?;
+    bytes.try_into().ok()
+}
+
+impl Wol {
+
+    // looks like "nas=00:11:22:33:44:55,pc=66:77:88:99:aa:bb"
+    pub fn from_env() -> Self {
+        let devices = env::var("WOL_DEVICES").unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .filter_map(|entry| match entry.split_once('=').and_then(|(name, mac)| Some((name.trim().to_string(), parse_mac(mac.trim())?))) {
+                Some(device) => Some(device),
+                None => { error!("could not parse WOL_DEVICES entry: {}", entry); None },
+            })
+            .collect();
+        Self::new(devices)
+    }
+
+    pub fn new(devices: BTreeMap<String, MacAddress>) -> Self {
+        Self { devices }
+    }
+
+    pub fn devices(&self) -> Vec<&str> {
+        self.devices.keys().map(String::as_str).collect()
+    }
+
+    // returns false if there is no device with that name
+    pub fn wake(&self, device: &str) -> io::Result<bool> {
+        let mac = match self.devices.get(device) {
+            Some(mac) => mac,
+            None => return Ok(false),
+        };
+
+        // the magic packet is 6 times 0xFF followed by 16 repetitions of the mac address
+        let mut packet = vec![0xFF; 6];
+        for _ in 0..16 {
+            packet.extend_from_slice(mac);
+        }
+
+        info!("sending wake-on-LAN packet to {}", device);
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.send_to(&packet, (Ipv4Addr::BROADCAST, 9))?;
+        Ok(true)
+    }
+}
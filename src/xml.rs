@@ -0,0 +1,80 @@
+// finds "<name" followed by the end of the tag name, so "<item" doesn't match "<itemCount"
+fn find_start_tag(xml: &str, name: &str) -> Option<usize> {
+    let tag = format!("<{}", name);
+    let mut offset = 0;
+    while let Some(position) = xml[offset..].find(&tag) {
+        let start = offset + position;
+        match xml[start + tag.len()..].chars().next() {
+            Some('>') | Some('/') => return Some(start),
+            Some(c) if c.is_whitespace() => return Some(start),
+            _ => offset = start + tag.len(),
+        }
+    }
+    None
+}
+
+// the content of the first element with that name and where the element ends
+fn find_element<'a>(xml: &'a str, name: &str) -> Option<(&'a str, usize)> {
+    let start = find_start_tag(xml, name)?;
+    let content_start = start + xml[start..].find('>')? + 1;
+    if xml[..content_start].ends_with("/>") {
+        return Some(("", content_start));
+    }
+    let end_tag = format!("</{}>", name);
+    let content_end = content_start + xml[content_start..].find(&end_tag)?;
+    Some((&xml[content_start..content_end], content_end + end_tag.len()))
+}
+
+// the content of the first element with that name, good enough for the flat documents we read, this is not a full xml parser
+pub fn xml_element(xml: &str, name: &str) -> Option<String> {
+    find_element(xml, name).map(|(content, _)| content.to_string())
+}
+
+// the contents of all elements with that name
+pub fn xml_elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut elements = Vec::new();
+    let mut rest = xml;
+    while let Some((content, end)) = find_element(rest, name) {
+        elements.push(content);
+        rest = &rest[end..];
+    }
+    elements
+}
+
+// the whole elements with that name, with their start tag for the attributes
+pub fn xml_nodes<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut nodes = Vec::new();
+    let mut rest = xml;
+    while let (Some(start), Some((_, end))) = (find_start_tag(rest, name), find_element(rest, name)) {
+        nodes.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    nodes
+}
+
+// the unescaped value of an attribute of the first element with that name
+pub fn xml_attribute(xml: &str, name: &str, attribute: &str) -> Option<String> {
+    let start = find_start_tag(xml, name)?;
+    let tag = &xml[start..start + xml[start..].find('>')?];
+    let value_start = tag.find(&format!(" {}=", attribute))? + attribute.len() + 2;
+    let quote = tag[value_start..].chars().next()?;
+    let value = &tag[value_start + 1..];
+    Some(xml_unescape(&value[..value.find(quote)?]))
+}
+
+// the text of an element, which feeds often wrap in CDATA
+pub fn xml_text(content: &str) -> String {
+    let content = content.trim();
+    match content.strip_prefix("<![CDATA[").and_then(|content| content.strip_suffix("]]>")) {
+        Some(text) => text.to_string(),
+        None => xml_unescape(content),
+    }
+}
+
+pub fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}